@@ -0,0 +1,366 @@
+use alloc::borrow::ToOwned;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::{token_list, token_name, tokens};
+use crate::authority::Authority;
+use crate::command::OpalCommandBuilder;
+use crate::defs::{method, token, uid, OpalError, SimpleToken, BS8};
+use crate::io::{Capabilities, SecureProtocol};
+use crate::session::{locking_range_uid, OpalSession};
+use crate::{util, PasswordOrRaw};
+
+/// ACE columns
+const BOOLEAN_EXPR: u64 = 3;
+/// Locking columns
+const RANGE_START: u64 = 3;
+const RANGE_LENGTH: u64 = 4;
+const READ_LOCK_ENABLED: u64 = 5;
+const WRITE_LOCK_ENABLED: u64 = 6;
+const ACTIVE_KEY: u64 = 10;
+/// LockingInfo columns
+const MAX_RANGES: u64 = 4;
+/// added by the Single User Mode feature set
+const SINGLE_USER_MODE_RANGES: u64 = 0x3C;
+/// Authority columns
+const ENABLED: u64 = 5;
+/// C_PIN columns
+pub(crate) const PIN: u64 = 3;
+const TRY_LIMIT: u64 = 5;
+const TRIES: u64 = 6;
+const PERSISTENCE: u64 = 7;
+
+/// How many bad attempts the drive tolerates for an authority
+#[derive(Debug, Copy, Clone)]
+pub struct PinLimits {
+    /// 0 means unlimited
+    pub try_limit: u64,
+    /// bad attempts so far
+    pub tries: u64,
+    /// whether `tries` survives a power cycle
+    pub persistence: bool,
+}
+
+/// Which access control element of a locking range
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Ace {
+    /// who may set ReadLocked
+    ReadLocked,
+    /// who may set WriteLocked
+    WriteLocked,
+}
+
+impl Ace {
+    fn uid(self, range: u8) -> BS8 {
+        let kind = match self {
+            Ace::ReadLocked => 0xE0,
+            Ace::WriteLocked => 0xE8,
+        };
+        BS8::new([0, 0, 0, 8, 0, 3, kind, range], "ACE_LOCKING_RANGE")
+    }
+}
+
+/// Range names take the start of the DataStore table: this line, then `<range> <name>` lines, then NUL padding
+const RANGE_NAMES_MAGIC: &str = "#range-names\n";
+const RANGE_NAMES_SIZE: usize = 1024;
+
+/// The drive's factory credential in C_PIN_MSID, which is SID's PIN until someone takes ownership
+pub(crate) fn msid<P: SecureProtocol>(session: &mut OpalSession<'_, P>) -> crate::Result<Vec<u8>, P::Error> {
+    let response = session.get(uid::OPAL_C_PIN_MSID, PIN, PIN)?;
+    response.find_column(PIN).and_then(|i| response.bytes(i)).map(<[u8]>::to_vec)
+        .ok_or_else(|| crate::Error::Opal { source: OpalError::NoMethodStatus, msg: "C_PIN_MSID has no PIN".to_owned() })
+}
+
+/// Names of locking ranges kept in the DataStore table; empty if the drive has none or they were never set
+pub(crate) fn range_names<P: SecureProtocol>(session: &mut OpalSession<'_, P>) -> crate::Result<BTreeMap<u8, String>, P::Error> {
+    if !session.device().capabilities().contains(Capabilities::DATASTORE) {
+        return Ok(BTreeMap::new());
+    }
+    let data = session.read_datastore(0, RANGE_NAMES_SIZE)?;
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    let text = String::from_utf8_lossy(&data[..end]);
+    let Some(lines) = text.strip_prefix(RANGE_NAMES_MAGIC) else {
+        return Ok(BTreeMap::new());
+    };
+    Ok(lines.lines()
+        .filter_map(|line| line.split_once(' '))
+        .filter_map(|(range, name)| Some((range.parse().ok()?, name.to_owned())))
+        .collect())
+}
+
+/// UID of the Locking table, which SingleUserModeRanges holds when all ranges are in Single User Mode
+const LOCKING_TABLE: [u8; 8] = [0, 0, 8, 2, 0, 0, 0, 0];
+
+/// Locking ranges in Single User Mode as LockingInfo lists them, `None` if its list can't be read;
+/// LockingInfo is readable by Anybody, so this needs no credential
+pub(crate) fn single_user_ranges<P: SecureProtocol>(session: &mut OpalSession<'_, P>) -> crate::Result<Option<Vec<u8>>, P::Error> {
+    let response = session.get(uid::OPAL_LOCKING_INFO_TABLE, MAX_RANGES, SINGLE_USER_MODE_RANGES)?;
+    let Some(i) = response.find_column(SINGLE_USER_MODE_RANGES) else { return Ok(None) };
+    if response.bytes(i) == Some(&LOCKING_TABLE[..]) {
+        let max_ranges = response.find_column(MAX_RANGES).and_then(|i| response.uint(i)).unwrap_or(0) as u8;
+        return Ok(Some((0..=max_ranges).collect()));
+    }
+    if !response.is(i, token::STARTLIST) {
+        return Ok(None);
+    }
+    let global = uid::OPAL_LOCKINGRANGE_GLOBAL.bytes;
+    let mut ranges = Vec::new();
+    for index in i + 1..response.len() {
+        if response.is(index, token::ENDLIST) {
+            return Ok(Some(ranges));
+        }
+        match response.bytes(index) {
+            Some(object) if object == global => ranges.push(0),
+            Some(&[a, b, c, d, e, 0x03, 0, range]) if [a, b, c, d, e] == global[..5] => ranges.push(range),
+            _ => return Ok(None),
+        }
+    }
+    Ok(None)
+}
+
+/// Locking ranges besides the global one that have read or write locking enabled
+pub(crate) fn lock_enabled_ranges<P: SecureProtocol>(session: &mut OpalSession<'_, P>) -> crate::Result<Vec<u8>, P::Error> {
+    let response = session.get(uid::OPAL_LOCKING_INFO_TABLE, MAX_RANGES, MAX_RANGES)?;
+    let max_ranges = response.find_column(MAX_RANGES).and_then(|i| response.uint(i)).unwrap_or(0) as u8;
+    let mut ranges = Vec::new();
+    for range in 1..=max_ranges {
+        let response = session.get(locking_range_uid(range), READ_LOCK_ENABLED, WRITE_LOCK_ENABLED)?;
+        let enabled = |column| response.find_column(column).and_then(|i| response.uint(i)).unwrap_or(0) != 0;
+        if enabled(READ_LOCK_ENABLED) || enabled(WRITE_LOCK_ENABLED) {
+            ranges.push(range);
+        }
+    }
+    Ok(ranges)
+}
+
+/// A session to the Locking SP authenticated as Admin1
+pub struct AdminSession<'d, P: SecureProtocol> {
+    pub(crate) session: OpalSession<'d, P>,
+}
+
+impl<'d, P: SecureProtocol> AdminSession<'d, P> {
+    /// The authorities which are ORed together in the ACE of the given range
+    pub fn locking_ace(&mut self, range: u8, ace: Ace) -> crate::Result<Vec<Authority>, P::Error> {
+        let response = self.session.get(ace.uid(range), BOOLEAN_EXPR, BOOLEAN_EXPR)?;
+        let half_uid = &uid::OPAL_HALF_UID_AUTHORITY_OBJ_REF.bytes[..4];
+        let authorities = (0..response.len().saturating_sub(1))
+            .filter(|&i| response.bytes(i) == Some(half_uid))
+            .filter_map(|i| response.bytes(i + 1)?.try_into().ok().map(Authority))
+            .collect();
+        Ok(authorities)
+    }
+
+    /// Replaces the ACE of the given range with `authorities` ORed together
+    pub fn set_locking_ace(&mut self, range: u8, ace: Ace, authorities: &[Authority]) -> crate::Result<(), P::Error> {
+        use crate::defs::Token;
+
+        // postfix expression: `a b OR c OR …`
+        let mut expr = Vec::new();
+        token::STARTLIST.write(&mut expr);
+        for (i, authority) in authorities.iter().enumerate() {
+            token_name!(&uid::OPAL_HALF_UID_AUTHORITY_OBJ_REF.bytes[..4], authority.uid()).write(&mut expr);
+            if i != 0 {
+                token_name!(&uid::OPAL_HALF_UID_BOOLEAN_ACE.bytes[..4], token::OPAL_TRUE).write(&mut expr);
+            }
+        }
+        token::ENDLIST.write(&mut expr);
+        tracing::debug!("setting {:?} of range {} to {:?}", ace, range, authorities);
+        self.session.set(ace.uid(range), BOOLEAN_EXPR, crate::defs::TokenStream(Some(expr)))
+    }
+
+    pub fn authority_enabled(&mut self, authority: Authority) -> crate::Result<bool, P::Error> {
+        let response = self.session.get(authority.uid(), ENABLED, ENABLED)?;
+        Ok(response.find_column(ENABLED).and_then(|i| response.uint(i)) == Some(1))
+    }
+
+    pub fn set_authority_enabled(&mut self, authority: Authority, enabled: bool) -> crate::Result<(), P::Error> {
+        tracing::debug!("setting {} enabled to {}", authority, enabled);
+        self.session.set(authority.uid(), ENABLED, SimpleToken::from(enabled))
+    }
+
+    /// Sets the authority's credential, derived the same way as for unlocking
+    pub fn set_pin(&mut self, authority: Authority, pwd: PasswordOrRaw) -> crate::Result<(), P::Error> {
+        let mut hash = crate::hash(self.session.device().proto().serial_num(), pwd)?;
+        tracing::debug!("setting PIN of {}", authority);
+        let res = self.session.set(authority.c_pin(), PIN, hash.as_slice());
+        util::wipe(&mut hash);
+        res
+    }
+
+    pub fn pin_limits(&mut self, authority: Authority) -> crate::Result<PinLimits, P::Error> {
+        let response = self.session.get(authority.c_pin(), TRY_LIMIT, PERSISTENCE)?;
+        let column = |column| response.find_column(column).and_then(|i| response.uint(i)).unwrap_or(0);
+        Ok(PinLimits {
+            try_limit: column(TRY_LIMIT),
+            tries: column(TRIES),
+            persistence: column(PERSISTENCE) == 1,
+        })
+    }
+
+    /// Sets TryLimit and Persistence; many drives only allow this before the SP is activated, if at all
+    pub fn set_pin_limits(&mut self, authority: Authority, try_limit: u64, persistence: bool) -> crate::Result<(), P::Error> {
+        tracing::debug!("setting TryLimit of {} to {}, Persistence to {}", authority, try_limit, persistence);
+        self.session.set(authority.c_pin(), TRY_LIMIT, try_limit)?;
+        self.session.set(authority.c_pin(), PERSISTENCE, SimpleToken::from(persistence))
+    }
+
+    /// number of locking ranges besides the global one
+    pub fn max_ranges(&mut self) -> crate::Result<u8, P::Error> {
+        let response = self.session.get(uid::OPAL_LOCKING_INFO_TABLE, MAX_RANGES, MAX_RANGES)?;
+        Ok(response.find_column(MAX_RANGES).and_then(|i| response.uint(i)).unwrap_or(0) as u8)
+    }
+
+    /// Sets the bounds of a locking range, unless it's the global one, and whether it locks for reading and writing
+    pub fn configure_range(&mut self, range: u8, bounds: Option<(u64, u64)>, lock_enabled: bool) -> crate::Result<(), P::Error> {
+        tracing::debug!("configuring locking range {} with bounds {:?}, locking enabled {}", range, bounds, lock_enabled);
+        let bounds = match bounds {
+            Some((start, length)) if range != 0 => tokens![token_name!(RANGE_START, start), token_name!(RANGE_LENGTH, length)],
+            _ => tokens![],
+        };
+        // all columns in one Set, as drives may reject bounds that overlap while half-written
+        let command = OpalCommandBuilder::new(locking_range_uid(range), method::SET)
+            .payload(token_list![token_name!(
+                token::VALUES,
+                token_list![
+                    bounds,
+                    token_name!(READ_LOCK_ENABLED, SimpleToken::from(lock_enabled)),
+                    token_name!(WRITE_LOCK_ENABLED, SimpleToken::from(lock_enabled)),
+                ]
+            )])
+            .build();
+        unsafe { self.session.send_raw_command(command) }?;
+        Ok(())
+    }
+
+    /// Moves or resizes a locking range, leaving its locking alone; a length of 0 leaves it unused
+    pub fn set_range_bounds(&mut self, range: u8, start: u64, length: u64) -> crate::Result<(), P::Error> {
+        tracing::debug!("setting bounds of locking range {} to LBA {} + {}", range, start, length);
+        let command = OpalCommandBuilder::new(locking_range_uid(range), method::SET)
+            .payload(token_list![token_name!(
+                token::VALUES,
+                token_list![token_name!(RANGE_START, start), token_name!(RANGE_LENGTH, length)]
+            )])
+            .build();
+        unsafe { self.session.send_raw_command(command) }?;
+        Ok(())
+    }
+
+    /// Whether the range locks for reading and for writing at all, i.e. its ReadLockEnabled and WriteLockEnabled
+    pub fn lock_enabled(&mut self, range: u8) -> crate::Result<(bool, bool), P::Error> {
+        let response = self.session.get(locking_range_uid(range), READ_LOCK_ENABLED, WRITE_LOCK_ENABLED)?;
+        let enabled = |column| response.find_column(column).and_then(|i| response.uint(i)).unwrap_or(0) != 0;
+        Ok((enabled(READ_LOCK_ENABLED), enabled(WRITE_LOCK_ENABLED)))
+    }
+
+    /// Sets ReadLockEnabled and WriteLockEnabled of a range without touching its bounds
+    pub fn set_lock_enabled(&mut self, range: u8, read: bool, write: bool) -> crate::Result<(), P::Error> {
+        tracing::debug!("setting locking range {} read locking enabled {}, write locking enabled {}", range, read, write);
+        let command = OpalCommandBuilder::new(locking_range_uid(range), method::SET)
+            .payload(token_list![token_name!(
+                token::VALUES,
+                token_list![
+                    token_name!(READ_LOCK_ENABLED, SimpleToken::from(read)),
+                    token_name!(WRITE_LOCK_ENABLED, SimpleToken::from(write)),
+                ]
+            )])
+            .build();
+        unsafe { self.session.send_raw_command(command) }?;
+        Ok(())
+    }
+
+    /// Replaces the media encryption key of a locking range by calling GenKey on its active K_AES object,
+    /// which leaves everything stored in the range unreadable for good
+    pub fn crypto_erase(&mut self, range: u8) -> crate::Result<(), P::Error> {
+        use snafu::OptionExt;
+
+        let response = self.session.get(locking_range_uid(range), ACTIVE_KEY, ACTIVE_KEY)?;
+        let key: [u8; 8] = response.find_column(ACTIVE_KEY)
+            .and_then(|i| response.bytes(i)?.try_into().ok())
+            .context(crate::UnsupportedSnafu)?;
+        tracing::debug!("generating a new key {:X?} for locking range {}", key, range);
+        let command = OpalCommandBuilder::new(BS8::new(key, "K_AES"), method::GENKEY)
+            .payload(token_list![])
+            .build();
+        unsafe { self.session.send_raw_command(command) }?;
+        Ok(())
+    }
+
+    /// Takes the Locking SP back to Manufactured-Inactive: Admin and User passwords, ranges and the shadow MBR are reset,
+    /// and all ranges get new keys unless `keep_global_range_key` saves the global range's data. The session ends with it
+    pub fn revert_sp(mut self, keep_global_range_key: bool) -> crate::Result<(), P::Error> {
+        tracing::debug!("reverting the Locking SP, keeping the global range key: {}", keep_global_range_key);
+        self.session.revert_sp(keep_global_range_key)
+    }
+
+    /// Writes a pre-boot image into the MBR table from its start, reporting the bytes written so far
+    pub fn write_shadow_mbr(&mut self, image: &[u8], progress: &mut dyn FnMut(usize)) -> crate::Result<(), P::Error> {
+        tracing::debug!("writing {} bytes to the shadow MBR", image.len());
+        self.session.write_byte_table(uid::OPAL_MBR, 0, image, progress)
+    }
+
+    /// Friendly names of locking ranges, e.g. "Windows" or "Data", as stored in the DataStore table
+    pub fn range_names(&mut self) -> crate::Result<BTreeMap<u8, String>, P::Error> {
+        range_names(&mut self.session)
+    }
+
+    /// Replaces all range names; needs the DataStore feature. Refuses to if the start of the DataStore
+    /// holds something else, which the names would overwrite
+    pub fn set_range_names(&mut self, names: &BTreeMap<u8, String>) -> crate::Result<(), P::Error> {
+        let current = self.session.read_datastore(0, RANGE_NAMES_SIZE)?;
+        if !current.starts_with(RANGE_NAMES_MAGIC.as_bytes()) && current.iter().any(|&b| b != 0) {
+            return Err(crate::Error::Opal {
+                source: OpalError::Status { code: crate::StatusCode::INVALID_PARAMETER },
+                msg: alloc::format!("the first {} bytes of the DataStore hold other data, not storing range names there", RANGE_NAMES_SIZE),
+            });
+        }
+        let mut data = String::from(RANGE_NAMES_MAGIC);
+        for (range, name) in names {
+            // names are single lines
+            data.push_str(&alloc::format!("{} {}\n", range, name.replace(['\n', '\r', '\0'], " ")));
+        }
+        let mut data = data.into_bytes();
+        if data.len() > RANGE_NAMES_SIZE {
+            return Err(crate::Error::Opal {
+                source: OpalError::Status { code: crate::StatusCode::INSUFFICIENT_SPACE },
+                msg: alloc::format!("range names take {} bytes, only {} are reserved", data.len(), RANGE_NAMES_SIZE),
+            });
+        }
+        data.resize(RANGE_NAMES_SIZE, 0);
+        tracing::debug!("setting range names to {:?}", names);
+        self.session.write_datastore(0, &data)
+    }
+
+    /// Reads `len` bytes from `offset` of the DataStore table; the first 1024 bytes hold the range names
+    pub fn read_datastore(&mut self, offset: u64, len: usize) -> crate::Result<Vec<u8>, P::Error> {
+        self.session.read_datastore(offset, len)
+    }
+
+    /// Writes `data` at `offset` of the DataStore table, e.g. metadata or key blobs kept on the drive itself.
+    /// The first 1024 bytes are refused while they hold range names
+    pub fn write_datastore(&mut self, offset: u64, data: &[u8]) -> crate::Result<(), P::Error> {
+        if offset < RANGE_NAMES_SIZE as u64 && !data.is_empty() {
+            let names = self.session.read_datastore(0, RANGE_NAMES_MAGIC.len())?;
+            if names == RANGE_NAMES_MAGIC.as_bytes() {
+                return Err(crate::Error::Opal {
+                    source: OpalError::Status { code: crate::StatusCode::INVALID_PARAMETER },
+                    msg: alloc::format!("the first {} bytes of the DataStore hold range names", RANGE_NAMES_SIZE),
+                });
+            }
+        }
+        tracing::debug!("writing {} bytes to the DataStore at {}", data.len(), offset);
+        self.session.write_datastore(offset, data)
+    }
+
+    /// Whether the shadow MBR is presented instead of the real one until MBRDone is set
+    pub fn set_mbr_enable(&mut self, enable: bool) -> crate::Result<(), P::Error> {
+        tracing::debug!("setting MBRControl Enable to {}", enable);
+        self.session.set_mbr_enable(enable)
+    }
+
+    /// start LBA and length in blocks of a locking range
+    pub fn range_bounds(&mut self, range: u8) -> crate::Result<(u64, u64), P::Error> {
+        let response = self.session.get(locking_range_uid(range), RANGE_START, RANGE_LENGTH)?;
+        let column = |column| response.find_column(column).and_then(|i| response.uint(i)).unwrap_or(0);
+        Ok((column(RANGE_START), column(RANGE_LENGTH)))
+    }
+}
@@ -0,0 +1,62 @@
+use core::fmt::{self, Display};
+use crate::defs::BS8;
+
+/// UID of a row in the Locking SP's Authority table
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Authority(pub [u8; 8]);
+
+impl Authority {
+    pub const ANYBODY: Authority = Authority([0, 0, 0, 9, 0, 0, 0, 1]);
+    /// the class of all AdminN authorities
+    pub const ADMINS: Authority = Authority([0, 0, 0, 9, 0, 0, 0, 2]);
+    /// the class of all UserN authorities
+    pub const USERS: Authority = Authority([0, 0, 0, 9, 0, 0, 0, 3]);
+
+    pub fn admin(n: u8) -> Self {
+        Authority([0, 0, 0, 9, 0, 1, 0, n])
+    }
+
+    pub fn user(n: u8) -> Self {
+        Authority([0, 0, 0, 9, 0, 3, 0, n])
+    }
+
+    /// UserN+1, the only one who may unlock locking range N once it's in Single User Mode
+    pub fn range_owner(range: u8) -> Self {
+        Self::user(range.saturating_add(1))
+    }
+
+    /// BandMasterN of Enterprise SSC drives, which owns band N; its credential is in C_PIN_BandMasterN.
+    /// `None` for band 255, whose UID doesn't fit in the last byte
+    pub fn band_master(n: u8) -> Option<Self> {
+        Some(Authority([0, 0, 0, 9, 0, 0, 0x80, n.checked_add(1)?]))
+    }
+
+    /// whether this is one of the UserN authorities
+    pub fn is_user(&self) -> bool {
+        matches!(self.0, [0, 0, 0, 9, 0, 3, 0, _])
+    }
+
+    /// the row of this authority's credential in the C_PIN table
+    pub fn c_pin(&self) -> BS8 {
+        let [_, _, _, _, a, b, c, d] = self.0;
+        BS8::new([0, 0, 0, 0x0B, a, b, c, d], "C_PIN")
+    }
+
+    pub(crate) fn uid(&self) -> BS8 {
+        BS8::new(self.0, "AUTHORITY")
+    }
+}
+
+impl Display for Authority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            [0, 0, 0, 9, 0, 0, 0, 1] => f.write_str("Anybody"),
+            [0, 0, 0, 9, 0, 0, 0, 2] => f.write_str("Admins"),
+            [0, 0, 0, 9, 0, 0, 0, 3] => f.write_str("Users"),
+            [0, 0, 0, 9, 0, 1, 0, n] => write!(f, "Admin{n}"),
+            [0, 0, 0, 9, 0, 3, 0, n] => write!(f, "User{n}"),
+            [0, 0, 0, 9, 0, 0, 0x80, n @ 1..] => write!(f, "BandMaster{}", n - 1),
+            uid => write!(f, "{:016X}", u64::from_be_bytes(uid)),
+        }
+    }
+}
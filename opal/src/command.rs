@@ -138,6 +138,41 @@ impl OpalResponse {
         self.tokens.get(index).map(Vec::as_slice) == Some(&[token.token])
     }
 
+    /// Index of the value of the column named `column` in a Get response
+    pub fn find_column(&self, column: u64) -> Option<usize> {
+        (0..self.len().saturating_sub(2)).find(|&i| {
+            self.is(i, token::STARTNAME) && self.uint(i + 1) == Some(column)
+        }).map(|i| i + 2)
+    }
+
+    /// Like `get_uint`, but `None` for anything that isn't an unsigned integer atom
+    pub fn uint(&self, index: usize) -> Option<u64> {
+        let token = self.tokens.get(index)?;
+        match token[0] {
+            // tiny atom
+            b if b & 0xC0 == 0 => Some(b as u64),
+            // unsigned short atom
+            b if b & 0xF0 == 0x80 && token.len() <= 9 => {
+                Some(token[1..].iter().fold(0, |value, &b| value << 8 | b as u64))
+            }
+            _ => None,
+        }
+    }
+
+    /// The payload of a byte string atom
+    pub fn bytes(&self, index: usize) -> Option<&[u8]> {
+        let token = self.tokens.get(index)?;
+        match token[0] {
+            // short atom
+            b if b & 0xF0 == 0xA0 => Some(&token[1..]),
+            // medium atom
+            b if b & 0xF8 == 0xD0 => Some(&token[2..]),
+            // long atom
+            b if b & 0xFE == 0xE2 => Some(&token[4..]),
+            _ => None,
+        }
+    }
+
     pub fn get_uint(&self, index: usize) -> u64 {
         let token = &self.tokens[index];
 
@@ -171,6 +171,7 @@ pub mod uid {
         OPAL_MBRCONTROL_SET_DONE_TO_DOR = 0x80003F801;
         OPAL_MBRCONTROL = 0x80300000001;
         OPAL_MBR = 0x80400000000;
+        OPAL_DATASTORE = 0x100100000000;
         OPAL_AUTHORITY_TABLE = 0x900000000;
         OPAL_C_PIN_TABLE = 0xB00000000;
         OPAL_LOCKING_INFO_TABLE = 0x80100000001;
@@ -413,9 +414,13 @@ impl Token for &[u8] {
             // medium atom len
             buffer.push(0xD0 | ((self.len() >> 8) & 0x07) as u8);
             buffer.push((self.len() & 0xff) as u8);
+        } else if self.len() < 1 << 24 {
+            // long atom len, for chunks of TPers with large packets
+            buffer.push(0xE2);
+            buffer.extend(&(self.len() as u32).to_be_bytes()[1..]);
         } else {
             panic!(
-                "Bytestring too large ({} > 2048) to use in OpalPacket",
+                "Bytestring too large ({} >= 16 MiB) to use in OpalPacket",
                 self.len()
             );
         }
@@ -0,0 +1,262 @@
+use uefi_raw::newtype_enum;
+
+use crate::io::Capabilities;
+
+newtype_enum! {
+    pub enum FeatureCodes: u16 => {
+        TPER       = 0x0001,
+        LOCKING    = 0x0002,
+        GEOMETRY   = 0x0003,
+        SECURE_MESSAGING = 0x0004,
+        ENTERPRISE = 0x0100,
+        // OPAL_V1    = 0x0200,
+        SINGLEUSER = 0x0201,
+        DATASTORE  = 0x0202,
+        OPAL_V2    = 0x0203,
+        PYRITE_V1  = 0x0302,
+        PYRITE_V2  = 0x0303,
+        KEY_PER_IO = 0x0305,
+        BLOCK_SID  = 0x0402,
+    }
+}
+
+bitflags::bitflags! {
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub struct TperFlags: u8 {
+        const SYNC_SUPPORTED      = 0x01;
+        const ASYNC_SUPPORTED     = 0x02;
+        const ACK_NAK_SUPPORTED   = 0x04;
+        const BUFFER_MANAGEMENT   = 0x08;
+        const STREAMING_SUPPORTED = 0x10;
+        const COMID_MANAGEMENT    = 0x40;
+    }
+}
+
+bitflags::bitflags! {
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub struct LockingFlags: u8 {
+        const LOCKING_SUPPORTED = 0x01;
+        const LOCKING_ENABLED   = 0x02;
+        const LOCKED            = 0x04;
+        const MEDIA_ENCRYPTION  = 0x08;
+        const MBR_ENABLED       = 0x10;
+        const MBR_DONE          = 0x20;
+        /// Opal 2.02+
+        const MBR_SHADOWING_NOT_SUPPORTED = 0x40;
+    }
+}
+
+/// Geometry Reporting feature
+#[derive(Debug, Copy, Clone)]
+pub struct Geometry {
+    /// ranges must start and end on the alignment granularity
+    pub align_required: bool,
+    pub logical_block_size: u32,
+    /// in logical blocks
+    pub alignment_granularity: u64,
+    pub lowest_aligned_lba: u64,
+}
+
+impl Geometry {
+    pub fn is_aligned(&self, lba: u64) -> bool {
+        match self.alignment_granularity {
+            0 => true,
+            granularity => lba >= self.lowest_aligned_lba && (lba - self.lowest_aligned_lba) % granularity == 0,
+        }
+    }
+
+    /// the closest aligned LBAs at or below and at or above `lba`
+    pub fn nearest_aligned(&self, lba: u64) -> (u64, u64) {
+        let granularity = self.alignment_granularity.max(1);
+        let base = self.lowest_aligned_lba;
+        if lba <= base {
+            return (base, base);
+        }
+        let down = base + (lba - base) / granularity * granularity;
+        let up = if down == lba { lba } else { down + granularity };
+        (down, up)
+    }
+
+    /// Checks that a range of `length` blocks at `start` begins and ends on aligned LBAs
+    pub fn check_range(&self, start: u64, length: u64) -> Result<(), alloc::string::String> {
+        let end = start + length;
+        for (what, lba) in [("start", start), ("end", end)] {
+            if !self.is_aligned(lba) {
+                let (down, up) = self.nearest_aligned(lba);
+                return Err(alloc::format!("{what} LBA {lba} is not aligned to {} blocks, use {down} or {up}", self.alignment_granularity));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The SSC feature descriptors (Opal 2, Pyrite, Enterprise, Key Per I/O), which share their layout up to the ComIDs
+#[derive(Debug, Copy, Clone)]
+pub struct SscFeature {
+    /// 0 if ComIDs have to be allocated dynamically
+    pub base_com_id: u16,
+    pub num_com_ids: u16,
+    /// whether one command may span several locking ranges; not reported by Pyrite
+    pub range_crossing: bool,
+    /// Locking SP Admin and User authorities; only reported by Opal 2
+    pub admins: u16,
+    pub users: u16,
+    /// whether SID's PIN is the MSID in factory state and after a revert; not reported by Enterprise
+    pub initial_sid_pin_is_msid: bool,
+    pub revert_sid_pin_is_msid: bool,
+}
+
+/// Single User Mode feature
+#[derive(Debug, Copy, Clone)]
+pub struct SingleUserMode {
+    /// locking objects that can be put into single user mode
+    pub locking_objects: u32,
+    /// whether any, all or policy-selected locking objects are in single user mode
+    pub any: bool,
+    pub all: bool,
+    pub policy: bool,
+}
+
+/// DataStore Table feature
+#[derive(Debug, Copy, Clone)]
+pub struct DataStoreFeature {
+    pub max_tables: u16,
+    /// combined size of all DataStore tables in bytes
+    pub max_size: u32,
+    pub alignment: u32,
+}
+
+/// Block SID Authentication feature
+#[derive(Debug, Copy, Clone)]
+pub struct BlockSid {
+    /// whether SID's PIN differs from the MSID, i.e. someone took ownership
+    pub sid_pin_changed: bool,
+    /// whether authenticating as SID is blocked until the next power cycle
+    pub sid_blocked: bool,
+    /// whether a hardware reset clears the block
+    pub hardware_reset_clears: bool,
+}
+
+/// Level 0 discovery: what a TPer advertises before any session is opened
+#[derive(Debug, Clone, Default)]
+pub struct Discovery0 {
+    pub major_version: u16,
+    pub minor_version: u16,
+    pub tper: Option<TperFlags>,
+    pub locking: Option<LockingFlags>,
+    pub geometry: Option<Geometry>,
+    pub secure_messaging: bool,
+    pub enterprise: Option<SscFeature>,
+    pub opal_v2: Option<SscFeature>,
+    /// major version and descriptor
+    pub pyrite: Option<(u8, SscFeature)>,
+    /// keys are injected by the host per command instead of locking ranges; only its ComIDs are parsed
+    pub key_per_io: Option<SscFeature>,
+    pub single_user_mode: Option<SingleUserMode>,
+    pub datastore: Option<DataStoreFeature>,
+    pub block_sid: Option<BlockSid>,
+}
+
+impl Discovery0 {
+    /// Parses a Level 0 discovery response; `None` if it's no version 1 response.
+    /// Unknown and truncated feature descriptors are skipped.
+    pub fn parse(buffer: &[u8]) -> Option<Discovery0> {
+        let header = buffer.get(..48)?;
+        let major_version = u16::from_be_bytes([header[4], header[5]]);
+        let minor_version = u16::from_be_bytes([header[6], header[7]]);
+        if (major_version, minor_version) != (0, 1) {
+            return None;
+        }
+        // the length excludes the length field itself
+        let length = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+        let end = length.saturating_add(4).min(buffer.len());
+
+        let mut discovery = Discovery0 { major_version, minor_version, ..Default::default() };
+        let mut offset = 48;
+        while let Some(descriptor) = buffer[..end].get(offset..offset + 4) {
+            let code = FeatureCodes(u16::from_be_bytes([descriptor[0], descriptor[1]]));
+            let len = descriptor[3] as usize + 4;
+            match buffer[..end].get(offset..offset + len) {
+                Some(feature) => discovery.feature(code, feature),
+                None => tracing::debug!("truncated discovery feature {:?}", code),
+            }
+            offset += len;
+        }
+        Some(discovery)
+    }
+
+    fn feature(&mut self, code: FeatureCodes, feature: &[u8]) {
+        // everything is big endian; bytes past a descriptor's reported length read as 0
+        let u8_at = |i: usize| feature.get(i).copied().unwrap_or(0);
+        let u16_at = |i: usize| u16::from_be_bytes([u8_at(i), u8_at(i + 1)]);
+        let u32_at = |i: usize| u32::from_be_bytes([u8_at(i), u8_at(i + 1), u8_at(i + 2), u8_at(i + 3)]);
+        let u64_at = |i: usize| u64::from(u32_at(i)) << 32 | u64::from(u32_at(i + 4));
+        let ssc = || SscFeature {
+            base_com_id: u16_at(4),
+            num_com_ids: u16_at(6),
+            range_crossing: u8_at(8) & 0x01 != 0,
+            admins: u16_at(9),
+            users: u16_at(11),
+            initial_sid_pin_is_msid: u8_at(13) == 0,
+            revert_sid_pin_is_msid: u8_at(14) == 0,
+        };
+
+        match code {
+            FeatureCodes::TPER => self.tper = Some(TperFlags::from_bits_truncate(u8_at(4))),
+            FeatureCodes::LOCKING => self.locking = Some(LockingFlags::from_bits_truncate(u8_at(4))),
+            FeatureCodes::GEOMETRY => {
+                self.geometry = Some(Geometry {
+                    align_required: u8_at(4) & 0x01 != 0,
+                    logical_block_size: u32_at(12),
+                    alignment_granularity: u64_at(16),
+                    lowest_aligned_lba: u64_at(24),
+                })
+            }
+            FeatureCodes::SECURE_MESSAGING => self.secure_messaging = true,
+            FeatureCodes::ENTERPRISE => self.enterprise = Some(SscFeature { admins: 0, users: 0, ..ssc() }),
+            FeatureCodes::OPAL_V2 => self.opal_v2 = Some(ssc()),
+            FeatureCodes::PYRITE_V1 => self.pyrite = Some((1, SscFeature { range_crossing: false, admins: 0, users: 0, ..ssc() })),
+            FeatureCodes::PYRITE_V2 => self.pyrite = Some((2, SscFeature { range_crossing: false, admins: 0, users: 0, ..ssc() })),
+            FeatureCodes::KEY_PER_IO => self.key_per_io = Some(SscFeature { range_crossing: false, admins: 0, users: 0, ..ssc() }),
+            FeatureCodes::SINGLEUSER => {
+                self.single_user_mode = Some(SingleUserMode {
+                    locking_objects: u32_at(4),
+                    any: u8_at(8) & 0x01 != 0,
+                    all: u8_at(8) & 0x02 != 0,
+                    policy: u8_at(8) & 0x04 != 0,
+                })
+            }
+            FeatureCodes::DATASTORE => {
+                self.datastore = Some(DataStoreFeature {
+                    max_tables: u16_at(6),
+                    max_size: u32_at(8),
+                    alignment: u32_at(12),
+                })
+            }
+            FeatureCodes::BLOCK_SID => {
+                self.block_sid = Some(BlockSid {
+                    sid_pin_changed: u8_at(4) & 0x01 != 0,
+                    sid_blocked: u8_at(4) & 0x02 != 0,
+                    hardware_reset_clears: u8_at(5) & 0x01 != 0,
+                })
+            }
+            _ => tracing::trace!("ignoring discovery feature {:?}", code),
+        }
+    }
+
+    /// the optional features the rest of the crate cares about
+    pub fn capabilities(&self) -> Capabilities {
+        let mut capabilities = Capabilities::empty();
+        capabilities.set(Capabilities::SINGLE_USER_MODE, self.single_user_mode.is_some());
+        capabilities.set(Capabilities::DATASTORE, self.datastore.is_some());
+        capabilities.set(Capabilities::SECURE_MESSAGING, self.secure_messaging);
+        let mbr_shadow = self.locking.map_or(false, |l| !l.contains(LockingFlags::MBR_SHADOWING_NOT_SUPPORTED));
+        capabilities.set(Capabilities::MBR_SHADOW, mbr_shadow);
+        capabilities
+    }
+
+    /// whether the drive reported itself locked
+    pub fn locked(&self) -> bool {
+        self.locking.map_or(false, |locking| locking.contains(LockingFlags::LOCKED))
+    }
+}
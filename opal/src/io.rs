@@ -1,7 +1,10 @@
 use alloc::fmt::{Debug, Display};
+use alloc::format;
+use core::time::Duration;
 
-use snafu::{ResultExt, AsErrorSource};
-use uefi_raw::newtype_enum;
+use snafu::{ensure, ResultExt, AsErrorSource};
+
+use crate::discovery::{Discovery0, TperFlags};
 
 pub trait SecureProtocol {
     type Error: Debug + Display + AsErrorSource;
@@ -23,68 +26,133 @@ pub trait SecureProtocol {
     fn align(&self) -> usize;
 
     fn serial_num(&self) -> &[u8];
+
+    fn model_num(&self) -> &[u8];
+
+    fn firmware_rev(&self) -> &[u8];
+
+    /// Fills the buffer with cryptographically secure random bytes, used for session nonces
+    fn fill_random(&mut self, buf: &mut [u8]);
+
+    /// Pauses between polls for a response that isn't ready yet, e.g. with a timer event;
+    /// without an implementation, polls follow each other immediately (but are still bounded)
+    fn wait(&mut self, _duration: Duration) {}
+
+    /// bytes per logical block, the unit of locking range bounds, if the transport knows it
+    fn block_size(&self) -> Option<u32> {
+        None
+    }
+
+    /// capacity in logical blocks, if the transport knows it
+    fn block_count(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// Security Subsystem Class the drive implements
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Ssc {
+    Opal2,
+    /// Opal's Locking SP without media encryption, locking only restricts access
+    Pyrite { version: u8 },
+    Enterprise,
 }
 
-newtype_enum! {
-    pub enum FeatureCodes: u16 => {
-        // TPER       = 0x0001,
-        LOCKING    = 0x0002,
-        // GEOMETRY   = 0x0003,
-        ENTERPRISE = 0x0100,
-        // DATASTORE  = 0x0202,
-        // SINGLEUSER = 0x0201,
-        // OPAL_V1    = 0x0200,
-        OPAL_V2    = 0x0203,
+impl core::fmt::Display for Ssc {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Ssc::Opal2 => f.write_str("Opal 2"),
+            Ssc::Pyrite { version } => write!(f, "Pyrite {version}.0"),
+            Ssc::Enterprise => f.write_str("Enterprise"),
+        }
     }
 }
 
 bitflags::bitflags! {
-    #[derive(Debug)]
-    pub struct LockingFlags: u8 {
-        const LOCKING_SUPPORTED = 0x01;
-        const LOCKING_ENABLED   = 0x02;
-        const LOCKED            = 0x04;
-        const MEDIA_ENCRYPTION  = 0x08;
-        const MBR_ENABLED       = 0x10;
-        const MBR_DONE          = 0x20;
+    /// Optional features as advertised in Level 0 discovery
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub struct Capabilities: u8 {
+        const SINGLE_USER_MODE = 0x01;
+        const DATASTORE        = 0x02;
+        const MBR_SHADOW       = 0x04;
+        const SECURE_MESSAGING = 0x08;
     }
 }
 
-#[derive(Debug)]
-pub struct SecureDeviceInfo {
-    pub locking: Option<LockingFlags>,
-    pub opal_v2: Option<ComIdInfo>,
-    pub enterprise: Option<ComIdInfo>,
+/// request code of STACK_RESET, sent with protocol 2 to the ComID in use
+const STACK_RESET: u32 = 2;
+/// TPER_RESET is an IF-SEND with protocol 2 to this ComID
+const TPER_RESET_COM_ID: u16 = 0x0004;
+
+/// Packet sizes agreed on with the Properties method, the minimums every TPer supports until then
+#[derive(Debug, Copy, Clone)]
+pub struct PacketLimits {
+    /// largest ComPacket the TPer accepts
+    pub max_com_packet_size: u32,
+    /// largest single token the TPer accepts, e.g. a byte string written to a table
+    pub max_ind_token_size: u32,
+    /// Packets the TPer accepts per ComPacket; commands are always sent as one
+    pub max_packets: u32,
+    /// largest ComPacket the TPer sends back, as it accepted from our host properties
+    pub host_max_com_packet_size: u32,
 }
 
-#[derive(Debug)]
-pub struct ComIdInfo {
-    pub base_com_id: u16,
-    pub num_com_ids: u16,
+impl Default for PacketLimits {
+    fn default() -> Self {
+        PacketLimits { max_com_packet_size: 2048, max_ind_token_size: 1992, max_packets: 1, host_max_com_packet_size: 2048 }
+    }
 }
 
 pub struct SecureDevice<P> {
     device: P,
     com_id: u16,
-    is_eprise: bool,
+    /// whether `com_id` came from GET_COMID rather than the feature descriptor
+    dynamic_com_id: bool,
+    /// `None` until the first session exchanged properties, and again after a reset
+    limits: Option<PacketLimits>,
+    ssc: Ssc,
     was_locked: bool,
+    capabilities: Capabilities,
+    discovery: Discovery0,
 }
 
 impl<P: SecureProtocol> SecureDevice<P> {
     pub fn new(mut device: P) -> crate::Result<Self, P::Error> {
-        let info = recv_info(&mut device)?;
-        tracing::debug!(?info);
-        let is_eprise = info.enterprise.is_some();
-        let com_id = match info.enterprise.or(info.opal_v2) {
-            Some(x) => x,
-            None => super::UnsupportedSnafu.fail()?,
+        let discovery = discover(&mut device)?;
+        tracing::debug!(?discovery);
+        // Pyrite shares the Opal Locking SP, so it's only a fallback for drives without Opal
+        let (ssc, com_id) = match (discovery.enterprise, discovery.opal_v2, discovery.pyrite) {
+            (Some(x), _, _) => (Ssc::Enterprise, x),
+            (None, Some(x), _) => (Ssc::Opal2, x),
+            (None, None, Some((version, x))) => (Ssc::Pyrite { version }, x),
+            (None, None, None) if discovery.key_per_io.is_some() => super::KeyPerIoSnafu.fail()?,
+            (None, None, None) => super::UnsupportedSnafu.fail()?,
+        };
+        if let Ssc::Pyrite { .. } = ssc {
+            tracing::info!("{} drive, data is not encrypted", ssc);
         }
-        .base_com_id;
+        let management = discovery.tper.map_or(false, |tper| tper.contains(TperFlags::COMID_MANAGEMENT));
+        let (com_id, dynamic_com_id) = match (com_id.base_com_id, management) {
+            (0, _) => (allocate_com_id(&mut device)?, true),
+            // some TPers reserve their static ComIDs, e.g. for the firmware, and only serve allocated ones
+            (base, true) => match allocate_com_id(&mut device) {
+                Ok(com_id) => (com_id, true),
+                Err(e) => {
+                    tracing::debug!("GET_COMID failed, using the base ComID {:#06x}: {:?}", base, e);
+                    (base, false)
+                }
+            },
+            (base, false) => (base, false),
+        };
         Ok(Self {
             device,
             com_id,
-            is_eprise,
-            was_locked: info.locking.map_or(false, |l| l.contains(LockingFlags::LOCKED)),
+            dynamic_com_id,
+            limits: None,
+            ssc,
+            was_locked: discovery.locked(),
+            capabilities: discovery.capabilities(),
+            discovery,
         })
     }
 
@@ -93,6 +161,20 @@ impl<P: SecureProtocol> SecureDevice<P> {
         self.was_locked
     }
 
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// Treats the given features as supported even if discovery doesn't list them
+    pub fn force(&mut self, capabilities: Capabilities) {
+        self.capabilities |= capabilities;
+    }
+
+    /// Level 0 discovery as of the SecureDevice's creation
+    pub fn discovery(&self) -> &Discovery0 {
+        &self.discovery
+    }
+
     pub fn reconnect_controller(&mut self) -> crate::Result<(), P::Error> {
         self.device.reconnect_controller().context(super::IoSnafu)?;
         Ok(())
@@ -102,71 +184,128 @@ impl<P: SecureProtocol> SecureDevice<P> {
         self.com_id
     }
 
-    pub fn is_eprise(&self) -> bool {
-        self.is_eprise
+    pub fn dynamic_com_id(&self) -> bool {
+        self.dynamic_com_id
     }
 
-    pub fn proto(&mut self) -> &mut P {
-        &mut self.device
+    pub fn limits(&self) -> PacketLimits {
+        self.limits.unwrap_or_default()
     }
 
-    pub fn recv_locked(&mut self) -> crate::Result<bool, P::Error> {
-        Ok(recv_info(self.proto())?
-            .locking
-            .map_or(false, |locking| {
-                locking.contains(LockingFlags::LOCKED)
-            }))
+    pub(crate) fn limits_negotiated(&self) -> bool {
+        self.limits.is_some()
     }
-}
 
-/// Level 0 discovery subset
-fn recv_info<P: SecureProtocol>(proto: &mut P) -> crate::Result<SecureDeviceInfo, P::Error> {
-    let mut device_info = SecureDeviceInfo {
-        locking: None,
-        opal_v2: None,
-        enterprise: None,
-    };
+    pub(crate) fn set_limits(&mut self, limits: PacketLimits) {
+        self.limits = Some(limits);
+    }
 
-    let mut buffer = crate::util::alloc_aligned(1024, proto.align());
+    pub fn ssc(&self) -> Ssc {
+        self.ssc
+    }
 
-    // level 0 discovery
-    unsafe { proto.secure_recv(1, 1, buffer.as_mut()) }.context(super::IoSnafu)?;
+    pub fn is_eprise(&self) -> bool {
+        self.ssc == Ssc::Enterprise
+    }
 
-    // check the version for sanity
-    if buffer[4..8] != [0, 0, 0, 1] {
-        return Err(crate::Error::IncompatibleVersion);
+    pub fn proto(&mut self) -> &mut P {
+        &mut self.device
     }
 
-    // ignore the rest of the header
-    let mut offset = 48;
+    /// Aborts all sessions and discards everything queued on the ComID with a STACK_RESET,
+    /// for drives the firmware or an earlier boot attempt left with a session open
+    pub fn stack_reset(&mut self) -> crate::Result<(), P::Error> {
+        let com_id = self.com_id;
+        let mut request = crate::util::alloc_aligned(512, self.device.align());
+        request[..2].copy_from_slice(&com_id.to_be_bytes());
+        request[4..8].copy_from_slice(&STACK_RESET.to_be_bytes());
+        unsafe { self.device.secure_send(2, com_id, request.as_mut()) }.context(super::IoSnafu)?;
 
-    fn get_com_id(buffer: &[u8], offset: usize) -> ComIdInfo {
-        ComIdInfo {
-            base_com_id: (buffer[offset] as u16) << 8 | buffer[offset + 1] as u16,
-            num_com_ids: (buffer[offset + 2] as u16) << 8 | buffer[offset + 3] as u16,
+        // ComID, extension, request code, reserved, available data length and the result
+        let mut response = crate::util::alloc_aligned(512, self.device.align());
+        let mut poll = Poll::new(Duration::from_secs(5));
+        loop {
+            unsafe { self.device.secure_recv(2, com_id, response.as_mut()) }.context(super::IoSnafu)?;
+            // no data yet while the reset is pending
+            if u16::from_be_bytes([response[10], response[11]]) >= 4 {
+                break;
+            }
+            poll.wait(&mut self.device)?;
+        }
+        let result = u32::from_be_bytes([response[12], response[13], response[14], response[15]]);
+        tracing::debug!("STACK_RESET of ComID {:#06x}: result {}", com_id, result);
+        if result != 0 {
+            return Err(crate::Error::Opal {
+                source: crate::OpalError::Status { code: crate::StatusCode::FAIL },
+                msg: format!("STACK_RESET of ComID {:#06x} failed", com_id),
+            });
         }
+        // the reset also discards the host properties
+        self.limits = None;
+        Ok(())
     }
 
-    while offset < buffer.len() - 1 {
-        match FeatureCodes((buffer[offset] as u16) << 8 | buffer[offset + 1] as u16) {
-            FeatureCodes::LOCKING => {
-                device_info.locking = LockingFlags::from_bits(match buffer.get(offset + 4) {
-                    Some(&bits) => bits,
-                    None => break,
-                })
-            }
-            FeatureCodes::ENTERPRISE => {
-                device_info.enterprise = Some(get_com_id(&buffer, offset + 4));
+    /// Resets the TPer as a power cycle would, ending all sessions and relocking ranges set to lock on reset.
+    /// Drives ignore it unless ProgrammaticResetEnable was set in their TPerInfo table
+    pub fn tper_reset(&mut self) -> crate::Result<(), P::Error> {
+        // the payload is ignored
+        let mut request = crate::util::alloc_aligned(512, self.device.align());
+        unsafe { self.device.secure_send(2, TPER_RESET_COM_ID, request.as_mut()) }.context(super::IoSnafu)?;
+        tracing::debug!("sent TPER_RESET");
+        self.limits = None;
+        // the reset releases allocated ComIDs
+        if self.dynamic_com_id {
+            match allocate_com_id(&mut self.device) {
+                Ok(com_id) => self.com_id = com_id,
+                Err(e) => tracing::warn!("can't allocate a ComID after TPER_RESET, keeping {:#06x}: {:?}", self.com_id, e),
             }
-            FeatureCodes::OPAL_V2 => device_info.opal_v2 = Some(get_com_id(&buffer, offset + 4)),
-            _ => {}
         }
-        let len = match buffer.get(offset + 3) {
-            Some(&len) => len as usize,
-            None => break,
-        };
-        offset += len + 4;
+        Ok(())
     }
 
-    Ok(device_info)
+    pub fn recv_locked(&mut self) -> crate::Result<bool, P::Error> {
+        Ok(discover(self.proto())?.locked())
+    }
+}
+
+/// Bounded polling for a response that isn't ready yet: pauses start at 1 ms and double up to 50 ms,
+/// and the pauses are counted towards the timeout so the polls end even if the transport can't wait
+pub(crate) struct Poll {
+    pause: Duration,
+    waited: Duration,
+    timeout: Duration,
+}
+
+impl Poll {
+    pub(crate) fn new(timeout: Duration) -> Self {
+        Poll { pause: Duration::from_millis(1), waited: Duration::ZERO, timeout }
+    }
+
+    /// Pauses before the next poll, or fails once the timeout is used up
+    pub(crate) fn wait<P: SecureProtocol>(&mut self, proto: &mut P) -> crate::Result<(), P::Error> {
+        ensure!(self.waited < self.timeout, super::TimeoutSnafu);
+        proto.wait(self.pause);
+        self.waited += self.pause;
+        self.pause = (self.pause * 2).min(Duration::from_millis(50));
+        Ok(())
+    }
+}
+
+/// Requests a dynamic ComID via GET_COMID, for drives without a static base ComID or with ComID management
+fn allocate_com_id<P: SecureProtocol>(proto: &mut P) -> crate::Result<u16, P::Error> {
+    let mut buffer = crate::util::alloc_aligned(512, proto.align());
+    unsafe { proto.secure_recv(2, 0, buffer.as_mut()) }.context(super::IoSnafu)?;
+    let com_id = u16::from_be_bytes([buffer[0], buffer[1]]);
+    tracing::debug!("allocated dynamic ComID {:#06x}", com_id);
+    if com_id == 0 {
+        return super::UnsupportedSnafu.fail();
+    }
+    Ok(com_id)
+}
+
+/// Level 0 discovery
+fn discover<P: SecureProtocol>(proto: &mut P) -> crate::Result<Discovery0, P::Error> {
+    let mut buffer = crate::util::alloc_aligned(1024, proto.align());
+    unsafe { proto.secure_recv(1, 1, buffer.as_mut()) }.context(super::IoSnafu)?;
+    Discovery0::parse(&buffer).ok_or(crate::Error::IncompatibleVersion)
 }
@@ -5,22 +5,33 @@ extern crate alloc;
 
 use alloc::fmt::{Debug, Display};
 use alloc::string::String;
-use defs::uid;
+use defs::{uid, BS8};
 use io::SecureDevice;
 use session::OpalSession;
+use crate::token_list;
 use snafu::{Snafu, Location, AsErrorSource, OptionExt, ensure};
 
 mod defs;
 mod util;
 mod io;
+mod discovery;
 mod command;
 mod session;
+mod admin;
+mod authority;
+mod raw;
 
-pub use defs::{OpalError, StatusCode};
+pub use defs::{LockingState, OpalError, StatusCode};
 #[derive(Debug, Snafu)]
 pub enum Error<E: Debug + Display + AsErrorSource> {
     Io { source: E, location: Location },
     Unsupported,
+    /// the drive only implements the Key Per I/O SSC, which has no locking ranges to unlock
+    KeyPerIo,
+    /// the firmware blocked authenticating as SID until the drive is power cycled
+    SidBlocked,
+    /// the TPer didn't deliver the response to a method in time
+    Timeout,
     IncompatibleVersion,
     Pbkdf,
     RawKeyInvalidLength,
@@ -28,54 +39,553 @@ pub enum Error<E: Debug + Display + AsErrorSource> {
 }
 type Result<O, E> = core::result::Result<O, Error<E>>;
 
-pub use io::SecureProtocol;
+pub use io::{Capabilities, PacketLimits, SecureProtocol, Ssc};
+pub use discovery::{BlockSid, DataStoreFeature, Discovery0, Geometry, LockingFlags, SingleUserMode, SscFeature, TperFlags};
+pub use util::{constant_time_eq, wipe};
+pub use admin::{Ace, AdminSession, PinLimits};
+pub use authority::Authority;
+pub use raw::{RawSession, Value, KNOWN_METHODS, KNOWN_OBJECTS, KNOWN_SPS};
+
+/// A locking range (0 being the global range) or Enterprise band that was unlocked, with the name stored for it on the drive
+#[derive(Debug, Clone)]
+pub struct UnlockedRange {
+    pub range: u8,
+    pub name: Option<String>,
+}
 
 pub struct OpalDrive<P> {
     dev: SecureDevice<P>,
+    mbr_enable: Option<bool>,
+    unlock_ranges: Option<alloc::vec::Vec<u8>>,
+    mbr_done_refused: bool,
 }
 impl<P: SecureProtocol> OpalDrive<P> {
     pub fn new(p: P) -> Result<Self, P::Error> {
         let dev = io::SecureDevice::new(p)?;
-        Ok(Self { dev })
+        Ok(Self { dev, mbr_enable: None, unlock_ranges: None, mbr_done_refused: false })
     }
 
     pub fn serial(&mut self) -> &[u8] {
         self.dev.proto().serial_num()
     }
 
+    pub fn model(&mut self) -> &[u8] {
+        self.dev.proto().model_num()
+    }
+
+    pub fn firmware_rev(&mut self) -> &[u8] {
+        self.dev.proto().firmware_rev()
+    }
+
     pub fn was_locked(&self) -> bool {
         self.dev.was_locked()
     }
 
-    pub fn unlock(&mut self, pwd: PasswordOrRaw) -> Result<(), P::Error> {
-        let mut hash = alloc::vec![0; 32];
+    /// Repeats Level 0 discovery; benign, so it's also usable as a health probe
+    pub fn is_locked(&mut self) -> Result<bool, P::Error> {
+        self.dev.recv_locked()
+    }
+
+    /// Whether the drive advertises secure messaging.
+    ///
+    /// Sessions are always opened in cleartext for now, as there is no TLS stack to secure them with.
+    pub fn supports_secure_messaging(&self) -> bool {
+        self.capabilities().contains(Capabilities::SECURE_MESSAGING)
+    }
+
+    /// Optional features the drive advertises, plus those forced on
+    pub fn capabilities(&self) -> Capabilities {
+        self.dev.capabilities()
+    }
+
+    /// Enterprise SSC drives have bands with BandMasters instead of the Opal Locking SP,
+    /// Pyrite drives have the Opal one, but don't encrypt
+    pub fn ssc(&self) -> Ssc {
+        self.dev.ssc()
+    }
+
+    /// Enables features the drive under-reports in discovery
+    pub fn force(&mut self, capabilities: Capabilities) {
+        if !self.capabilities().contains(capabilities) {
+            tracing::warn!("forcing capabilities {:?} not advertised by the drive", capabilities.difference(self.capabilities()));
+        }
+        self.dev.force(capabilities);
+    }
+
+    /// Sets MBRControl Enable to this while unlocking, next to MBRDone; `None` leaves it as provisioned
+    pub fn mbr_enable(&mut self, enable: Option<bool>) {
+        self.mbr_enable = enable;
+    }
+
+    /// Unlocks only these ranges (0 being the global range) instead of all, e.g. to keep a data range locked until the OS is up
+    pub fn unlock_only(&mut self, ranges: Option<alloc::vec::Vec<u8>>) {
+        self.unlock_ranges = ranges;
+    }
+
+    /// alignment requirements for locking ranges, if the drive reports them
+    pub fn geometry(&self) -> Option<Geometry> {
+        self.dev.discovery().geometry
+    }
+
+    /// bytes per logical block, the unit of locking range bounds, from the transport or else the Geometry feature
+    pub fn block_size(&mut self) -> Option<u32> {
+        self.dev.proto().block_size()
+            .or_else(|| self.geometry().map(|geometry| geometry.logical_block_size))
+            .filter(|&size| size != 0)
+    }
+
+    /// capacity in logical blocks, if the transport knows it
+    pub fn block_count(&mut self) -> Option<u64> {
+        self.dev.proto().block_count()
+    }
+
+    /// Whether the last unlock left MBRDone unset because its User may not write it,
+    /// so the drive still shows the shadow MBR instead of its real start
+    pub fn mbr_done_refused(&self) -> bool {
+        self.mbr_done_refused
+    }
+
+    /// everything the drive advertised in Level 0 discovery when it was opened
+    pub fn discovery(&self) -> &Discovery0 {
+        self.dev.discovery()
+    }
+
+    /// Aborts any sessions left open on the ComID, e.g. by the firmware or a crashed boot attempt
+    pub fn stack_reset(&mut self) -> Result<(), P::Error> {
+        self.dev.stack_reset()
+    }
+
+    /// Resets the TPer like a power cycle; only has an effect if ProgrammaticResetEnable is set
+    pub fn tper_reset(&mut self) -> Result<(), P::Error> {
+        self.dev.tper_reset()
+    }
+
+    /// packet sizes negotiated with the Properties method when the first session was opened
+    pub fn packet_limits(&self) -> PacketLimits {
+        self.dev.limits()
+    }
+
+    /// the ComID sessions are opened on, from discovery or allocated dynamically
+    pub fn com_id(&self) -> u16 {
+        self.dev.com_id()
+    }
+
+    /// whether the ComID was allocated with GET_COMID
+    pub fn dynamic_com_id(&self) -> bool {
+        self.dev.dynamic_com_id()
+    }
+
+    pub fn unlock(&mut self, pwd: PasswordOrRaw) -> Result<alloc::vec::Vec<UnlockedRange>, P::Error> {
+        self.unlock_as(Authority::admin(1), pwd)
+    }
+
+    /// Unlocks the global range and every other range with locking enabled as the given authority,
+    /// which needs to be in the ranges' ACEs; ranges a User may not unlock are skipped.
+    /// With `unlock_only`, exactly those ranges are unlocked instead.
+    ///
+    /// Drives with a shadow MBR get MBRDone set, so the real MBR shows from now on.
+    /// MBRControl is only writable by Admins by default, so failing to set it as a User isn't an error,
+    /// but is told by `mbr_done_refused`.
+    ///
+    /// Returns the unlocked ranges; their names are only readable by Admins.
+    pub fn unlock_as(&mut self, authority: Authority, pwd: PasswordOrRaw) -> Result<alloc::vec::Vec<UnlockedRange>, P::Error> {
+        let mut hash = self.hash(pwd)?;
+        let res = self.unlock_with(authority, &hash);
+        util::wipe(&mut hash);
+        res
+    }
+
+    /// Unlocks like `unlock_as` with the MSID, the credential of drives whose owner never set a password.
+    ///
+    /// Fails with NOT_AUTHORIZED on drives with a real password, which costs one of the authority's tries.
+    pub fn unlock_with_msid(&mut self, authority: Authority) -> Result<alloc::vec::Vec<UnlockedRange>, P::Error> {
+        let mut msid = {
+            let mut session = OpalSession::start(&mut self.dev, uid::OPAL_ADMINSP, uid::OPAL_ANYBODY, None)?;
+            admin::msid(&mut session)?
+        };
+        let res = self.unlock_with(authority, &msid);
+        util::wipe(&mut msid);
+        res
+    }
 
-        match pwd {
-            PasswordOrRaw::Password(pwd) => {
-                pbkdf2::pbkdf2::<hmac::Hmac<sha1::Sha1>>(
-                    pwd,
-                    self.dev.proto().serial_num(),
-                    75000,
-                    &mut hash,
-                ).ok().context(PbkdfSnafu)?;
+    /// Unlocks as `unlock_as` describes with the credential as sent to the drive
+    fn unlock_with(&mut self, authority: Authority, credential: &[u8]) -> Result<alloc::vec::Vec<UnlockedRange>, P::Error> {
+        self.mbr_done_refused = false;
+        if self.dev.is_eprise() {
+            return self.unlock_enterprise(authority, credential);
+        }
+        if self.single_user_mode() {
+            return self.unlock_single_user(authority, credential);
+        }
+        let unlocked = match self.unlock_once(authority, credential) {
+            // a TPer that stays busy or stops answering often recovers once the session is closed
+            Err(Error::Timeout) => {
+                tracing::warn!("the drive didn't answer in time, unlocking again in a new session");
+                self.unlock_once(authority, credential)?
             }
-            PasswordOrRaw::Raw(r) => {
-                ensure!(r.len() == hash.len(), RawKeyInvalidLengthSnafu);
-                hash.copy_from_slice(r);
+            res => res?,
+        };
+        self.dev.reconnect_controller()?;
+
+        Ok(unlocked)
+    }
+
+    /// Unlocks in one session, which is closed before returning
+    fn unlock_once(&mut self, authority: Authority, credential: &[u8]) -> Result<alloc::vec::Vec<UnlockedRange>, P::Error> {
+        let capabilities = self.capabilities();
+        let mbr_enable = self.mbr_enable;
+        let unlock_ranges = self.unlock_ranges.clone();
+        let mut session = OpalSession::start(&mut self.dev, uid::OPAL_LOCKINGSP, authority.uid(), Some(credential))?;
+        unlock_in_session(&mut session, authority, capabilities, mbr_enable, unlock_ranges, &mut self.mbr_done_refused)
+    }
+
+    /// whether locking ranges may be in Single User Mode, either as discovery reports it or because the feature is forced
+    pub fn single_user_mode(&self) -> bool {
+        self.capabilities().contains(Capabilities::SINGLE_USER_MODE)
+            && self.discovery().single_user_mode.map_or(true, |sum| sum.any || sum.all)
+    }
+
+    /// A range in Single User Mode is owned by the UserN+1 of its number N, whose ACEs no Admin is in.
+    /// Each range is unlocked in its own session, as its owner if it's in Single User Mode and as `authority` otherwise.
+    /// Which ranges are comes from discovery if it says all are, or else from LockingInfo; only if neither tells,
+    /// a range that rejects its owner is unlocked as `authority` instead, at the cost of one of the owner's tries.
+    ///
+    /// Unlocks the ranges given to `unlock_only`, or else the one `authority` owns if it's a User, or else the global range.
+    fn unlock_single_user(&mut self, authority: Authority, credential: &[u8]) -> Result<alloc::vec::Vec<UnlockedRange>, P::Error> {
+        let all = self.discovery().single_user_mode.map_or(false, |sum| sum.all);
+        let in_single_user_mode = match all {
+            true => None,
+            false => self.single_user_ranges(),
+        };
+        let ranges = match (self.unlock_ranges.clone(), authority.is_user()) {
+            (Some(ranges), _) => ranges,
+            // UserN owns range N-1
+            (None, true) => alloc::vec![authority.0[7].saturating_sub(1)],
+            (None, false) => alloc::vec![0],
+        };
+        let mut unlocked = alloc::vec::Vec::new();
+        for range in ranges {
+            let owner = Authority::range_owner(range);
+            if in_single_user_mode.as_ref().map_or(false, |listed| !listed.contains(&range)) {
+                tracing::debug!("locking range {} is not in single user mode, unlocking it as {}", range, authority);
+                unlocked.append(&mut self.unlock_range_as(authority, credential, range)?);
+                continue;
             }
+            tracing::debug!("unlocking locking range {} in single user mode as {}", range, owner);
+            let res = match self.unlock_range_as(owner, credential, range) {
+                Err(Error::Opal { source: OpalError::Status { code: StatusCode::NOT_AUTHORIZED }, .. })
+                    if !all && in_single_user_mode.is_none() && owner != authority =>
+                {
+                    tracing::debug!("{} was rejected, unlocking locking range {} as {}", owner, range, authority);
+                    self.unlock_range_as(authority, credential, range)
+                }
+                res => res,
+            };
+            unlocked.append(&mut res?);
         }
+        self.dev.reconnect_controller()?;
+        Ok(unlocked)
+    }
 
-        tracing::info!("{hash:x?}");
+    /// The ranges LockingInfo lists as in Single User Mode, read in a session as Anybody
+    fn single_user_ranges(&mut self) -> Option<alloc::vec::Vec<u8>> {
+        let res = OpalSession::start(&mut self.dev, uid::OPAL_LOCKINGSP, uid::OPAL_ANYBODY, None)
+            .and_then(|mut session| admin::single_user_ranges(&mut session));
+        match res {
+            Ok(ranges) => {
+                tracing::debug!("ranges in single user mode: {:?}", ranges);
+                ranges
+            }
+            Err(e) => {
+                tracing::debug!("can't read which ranges are in single user mode: {:?}", e);
+                None
+            }
+        }
+    }
 
-        let mut session = OpalSession::start(&mut self.dev, uid::OPAL_LOCKINGSP, uid::OPAL_ADMIN1, Some(&hash))?;
-        session.set_locking_range(0, defs::LockingState::ReadWrite)?;
-        session.set_mbr_done(true)?;
+    fn unlock_range_as(&mut self, authority: Authority, credential: &[u8], range: u8) -> Result<alloc::vec::Vec<UnlockedRange>, P::Error> {
+        let capabilities = self.capabilities();
+        let mbr_enable = self.mbr_enable;
+        let mut session = OpalSession::start(&mut self.dev, uid::OPAL_LOCKINGSP, authority.uid(), Some(credential))?;
+        unlock_in_session(&mut session, authority, capabilities, mbr_enable, Some(alloc::vec![range]), &mut self.mbr_done_refused)
+    }
+
+    /// Starts unlocking as `authority` for ACEs that require further authorities together with it, e.g. `Admin1 AND User1`.
+    /// Those are added with `PendingUnlock::authenticate`, which can be retried without starting over, before `finish`.
+    pub fn begin_unlock(&mut self, authority: Authority, pwd: PasswordOrRaw) -> Result<PendingUnlock<'_, P>, P::Error> {
+        ensure!(!self.dev.is_eprise(), UnsupportedSnafu);
+        let capabilities = self.capabilities();
+        let mbr_enable = self.mbr_enable;
+        let unlock_ranges = self.unlock_ranges.clone();
+        let mut hash = self.hash(pwd)?;
+        self.mbr_done_refused = false;
+        let res = OpalSession::start(&mut self.dev, uid::OPAL_LOCKINGSP, authority.uid(), Some(&hash));
+        util::wipe(&mut hash);
+        Ok(PendingUnlock { session: res?, authority, capabilities, mbr_enable, unlock_ranges, mbr_done_refused: &mut self.mbr_done_refused })
+    }
+
+    /// Enterprise SSC drives have a BandMaster with its own credential per band instead of Admins and Users,
+    /// and no shadow MBR. Band 0, or those given to `unlock_only`, are unlocked by their BandMaster with the password.
+    fn unlock_enterprise(&mut self, authority: Authority, credential: &[u8]) -> Result<alloc::vec::Vec<UnlockedRange>, P::Error> {
+        ensure!(!authority.is_user(), UnsupportedSnafu);
+        let bands = self.unlock_ranges.clone().unwrap_or_else(|| alloc::vec![0]);
+        for &band in &bands {
+            let band_master = Authority::band_master(band).context(UnsupportedSnafu)?;
+            tracing::debug!("unlocking band {} as {}", band, band_master);
+            let mut session = OpalSession::start(&mut self.dev, uid::ENTERPRISE_LOCKINGSP, band_master.uid(), Some(credential))?;
+            session.set_band_unlocked(band)?;
+        }
+        self.dev.reconnect_controller()?;
+        Ok(bands.into_iter().map(|range| UnlockedRange { range, name: None }).collect())
+    }
+
+    /// Replaces the authority's credential, after the drive verified the old one by opening a session with it.
+    ///
+    /// On Enterprise SSC drives, the BandMasters of band 0, or those given to `unlock_only`, are changed instead.
+    /// Fails with NOT_AUTHORIZED if the old password is wrong.
+    pub fn change_password(&mut self, authority: Authority, old: PasswordOrRaw, new: PasswordOrRaw) -> Result<(), P::Error> {
+        let mut old = self.hash(old)?;
+        let mut new = match self.hash(new) {
+            Ok(new) => new,
+            Err(e) => {
+                util::wipe(&mut old);
+                return Err(e);
+            }
+        };
+        let res = self.change_pin(authority, &old, &new);
+        util::wipe(&mut old);
+        util::wipe(&mut new);
+        res
+    }
+
+    fn change_pin(&mut self, authority: Authority, old: &[u8], new: &[u8]) -> Result<(), P::Error> {
+        if !self.dev.is_eprise() {
+            let mut session = OpalSession::start(&mut self.dev, uid::OPAL_LOCKINGSP, authority.uid(), Some(old))?;
+            tracing::debug!("changing PIN of {}", authority);
+            session.set(authority.c_pin(), admin::PIN, new)?;
+            return session.close();
+        }
+        ensure!(!authority.is_user(), UnsupportedSnafu);
+        for band in self.unlock_ranges.clone().unwrap_or_else(|| alloc::vec![0]) {
+            let band_master = Authority::band_master(band).context(UnsupportedSnafu)?;
+            let mut session = OpalSession::start(&mut self.dev, uid::ENTERPRISE_LOCKINGSP, band_master.uid(), Some(old))?;
+            tracing::debug!("changing PIN of {}", band_master);
+            session.set_enterprise_pin(band_master.c_pin(), new)?;
+            session.close()?;
+        }
+        Ok(())
+    }
 
+    /// Sets the lock state of each given range (0 being the global range) as Admin1
+    pub fn set_range_states(&mut self, pwd: PasswordOrRaw, ranges: &[(u8, LockingState)]) -> Result<(), P::Error> {
+        let mut hash = self.hash(pwd)?;
+        let res = OpalSession::start(&mut self.dev, uid::OPAL_LOCKINGSP, uid::OPAL_ADMIN1, Some(&hash));
+        util::wipe(&mut hash);
+        let mut session = res?;
+        for &(range, state) in ranges {
+            tracing::debug!("setting locking range {} to {:?}", range, state);
+            session.set_locking_range(range, state)?;
+        }
         drop(session);
         self.dev.reconnect_controller()?;
+        Ok(())
+    }
 
+    /// Reverts the drive to its factory state with the PSID from its label, for when all passwords are lost.
+    ///
+    /// This erases all data. The PSID is sent as printed, it's not derived like passwords.
+    pub fn revert_with_psid(&mut self, psid: &[u8]) -> Result<(), P::Error> {
+        let mut session = OpalSession::start(&mut self.dev, uid::OPAL_ADMINSP, uid::OPAL_PSID, Some(psid))?;
+        session.revert_tper()?;
+        drop(session);
+        self.dev.reconnect_controller()?;
         Ok(())
     }
+
+    /// Whether the firmware issued Block SID, so sessions as SID fail until the drive is power cycled
+    pub fn sid_blocked(&self) -> bool {
+        self.discovery().block_sid.map_or(false, |block_sid| block_sid.sid_blocked)
+    }
+
+    /// Takes ownership of a drive in factory state by changing SID's PIN from the MSID to the password.
+    ///
+    /// Fails with NOT_AUTHORIZED if someone already did, and with SidBlocked if the firmware blocked SID.
+    pub fn take_ownership(&mut self, pwd: PasswordOrRaw) -> Result<(), P::Error> {
+        ensure!(!self.sid_blocked(), SidBlockedSnafu);
+        let mut msid = {
+            let mut session = OpalSession::start(&mut self.dev, uid::OPAL_ADMINSP, uid::OPAL_ANYBODY, None)?;
+            admin::msid(&mut session)?
+        };
+        let res = OpalSession::start(&mut self.dev, uid::OPAL_ADMINSP, uid::OPAL_SID, Some(&msid));
+        util::wipe(&mut msid);
+        let mut session = res?;
+        let mut hash = hash(session.device().proto().serial_num(), pwd)?;
+        tracing::debug!("setting PIN of SID");
+        let res = session.set(uid::OPAL_C_PIN_SID, admin::PIN, hash.as_slice());
+        util::wipe(&mut hash);
+        res?;
+        session.close()
+    }
+
+    /// Activates the Locking SP as SID, which copies SID's PIN to Admin1; a no-op if it's active already
+    pub fn activate_locking_sp(&mut self, pwd: PasswordOrRaw) -> Result<(), P::Error> {
+        ensure!(!self.sid_blocked(), SidBlockedSnafu);
+        let mut hash = self.hash(pwd)?;
+        let res = OpalSession::start(&mut self.dev, uid::OPAL_ADMINSP, uid::OPAL_SID, Some(&hash));
+        util::wipe(&mut hash);
+        let mut session = res?;
+        tracing::debug!("activating the Locking SP");
+        let command = command::OpalCommandBuilder::new(uid::OPAL_LOCKINGSP, defs::method::ACTIVATE)
+            .payload(token_list![])
+            .build();
+        unsafe { session.send_raw_command(command) }?;
+        session.close()
+    }
+
+    /// Opens a session to the Locking SP as Admin1 for setup tasks; it's closed on drop
+    pub fn admin_session(&mut self, pwd: PasswordOrRaw) -> Result<AdminSession<'_, P>, P::Error> {
+        let mut hash = self.hash(pwd)?;
+        let res = OpalSession::start(&mut self.dev, uid::OPAL_LOCKINGSP, uid::OPAL_ADMIN1, Some(&hash));
+        util::wipe(&mut hash);
+        Ok(AdminSession { session: res? })
+    }
+
+    /// Opens a session to `sp` for raw method calls, as `authority` with its password or as Anybody without one
+    pub fn raw_session(&mut self, sp: [u8; 8], authority: Authority, pwd: Option<PasswordOrRaw>) -> Result<RawSession<'_, P>, P::Error> {
+        let mut hash = pwd.map(|pwd| self.hash(pwd)).transpose()?;
+        let res = OpalSession::start(&mut self.dev, BS8::new(sp, "SP"), authority.uid(), hash.as_deref());
+        if let Some(hash) = &mut hash {
+            util::wipe(hash);
+        }
+        Ok(RawSession { session: res? })
+    }
+
+    /// The credential as sent to the drive; must be wiped after use
+    fn hash(&mut self, pwd: PasswordOrRaw) -> Result<alloc::vec::Vec<u8>, P::Error> {
+        hash(self.dev.proto().serial_num(), pwd)
+    }
+}
+
+/// An unlock in progress, authenticated as the first of several authorities
+pub struct PendingUnlock<'d, P: SecureProtocol> {
+    session: OpalSession<'d, P>,
+    authority: Authority,
+    capabilities: Capabilities,
+    mbr_enable: Option<bool>,
+    unlock_ranges: Option<alloc::vec::Vec<u8>>,
+    /// the drive's, so `mbr_done_refused` tells about this unlock too
+    mbr_done_refused: &'d mut bool,
+}
+
+impl<'d, P: SecureProtocol> PendingUnlock<'d, P> {
+    /// Adds another authority; fails with NOT_AUTHORIZED on a wrong password, after which this can be retried
+    pub fn authenticate(&mut self, authority: Authority, pwd: PasswordOrRaw) -> Result<(), P::Error> {
+        let mut hash = hash(self.session.device().proto().serial_num(), pwd)?;
+        tracing::debug!("authenticating {} in addition to {}", authority, self.authority);
+        let res = self.session.authenticate(authority.uid(), &hash);
+        util::wipe(&mut hash);
+        res
+    }
+
+    /// Unlocks like `unlock_as` with all authorities authenticated so far
+    pub fn finish(mut self) -> Result<alloc::vec::Vec<UnlockedRange>, P::Error> {
+        let unlocked = unlock_in_session(&mut self.session, self.authority, self.capabilities, self.mbr_enable, self.unlock_ranges.take(), self.mbr_done_refused)?;
+        self.session.close_and_reconnect()?;
+        Ok(unlocked)
+    }
+}
+
+/// Unlocks the ranges and sets MBRDone as described for `unlock_as` in a session authenticated as `authority`
+fn unlock_in_session<P: SecureProtocol>(
+    session: &mut OpalSession<'_, P>,
+    authority: Authority,
+    capabilities: Capabilities,
+    mbr_enable: Option<bool>,
+    unlock_ranges: Option<alloc::vec::Vec<u8>>,
+    mbr_done_refused: &mut bool,
+) -> Result<alloc::vec::Vec<UnlockedRange>, P::Error> {
+    let mut unlocked = alloc::vec::Vec::new();
+    if let Some(ranges) = unlock_ranges {
+        // explicitly configured, so each of them has to unlock
+        for range in ranges {
+            tracing::debug!("unlocking locking range {}", range);
+            session.set_locking_range(range, defs::LockingState::ReadWrite)?;
+            unlocked.push(range);
+        }
+    } else {
+        session.set_locking_range(0, defs::LockingState::ReadWrite)?;
+        unlocked.push(0);
+        match admin::lock_enabled_ranges(session) {
+            Ok(ranges) => for range in ranges {
+                tracing::debug!("unlocking locking range {}", range);
+                match session.set_locking_range(range, defs::LockingState::ReadWrite) {
+                    Ok(()) => unlocked.push(range),
+                    Err(e) if authority.is_user() => tracing::warn!("{} may not unlock locking range {}: {:?}", authority, range, e),
+                    Err(e) => return Err(e),
+                }
+            },
+            Err(e) => tracing::warn!("can't enumerate locking ranges, only the global range is unlocked: {:?}", e),
+        }
+    }
+    if !capabilities.contains(Capabilities::MBR_SHADOW) {
+        tracing::debug!("drive doesn't support MBR shadowing, not setting MBRDone");
+    } else {
+        if let Err(e) = session.set_mbr_done(true) {
+            if !authority.is_user() {
+                return Err(e);
+            }
+            tracing::warn!("{} may not set MBRDone: {:?}", authority, e);
+            *mbr_done_refused = true;
+        }
+        if let Some(enable) = mbr_enable {
+            tracing::debug!("setting MBR Enable to {}", enable);
+            if let Err(e) = session.set_mbr_enable(enable) {
+                if !authority.is_user() {
+                    return Err(e);
+                }
+                tracing::warn!("{} may not set MBR Enable: {:?}", authority, e);
+            }
+        }
+    }
+    // the DataStore is only readable by Admins by default
+    let mut names = match authority.is_user() {
+        true => Default::default(),
+        false => admin::range_names(session).unwrap_or_else(|e| {
+            tracing::warn!("can't read range names: {:?}", e);
+            Default::default()
+        }),
+    };
+    Ok(unlocked.into_iter().map(|range| UnlockedRange { range, name: names.remove(&range) }).collect())
+}
+
+/// PBKDF2-HMAC-SHA1 rounds for passwords
+const PBKDF2_ROUNDS: u32 = 75000;
+
+/// Stretches a secret into `out` with the same PBKDF2 as drive passwords, for keys derived outside the drive
+pub fn pbkdf2(secret: &[u8], salt: &[u8], out: &mut [u8]) {
+    // HMAC takes keys of any length, so this can't fail
+    let _ = pbkdf2::pbkdf2::<hmac::Hmac<sha1::Sha1>>(secret, salt, PBKDF2_ROUNDS, out);
+}
+
+/// Derives the credential sent to the drive from a password, salted with the drive's serial
+fn hash<E: Debug + Display + AsErrorSource>(serial: &[u8], pwd: PasswordOrRaw) -> Result<alloc::vec::Vec<u8>, E> {
+    let mut hash = alloc::vec![0; 32];
+
+    match pwd {
+        PasswordOrRaw::Password(pwd) => {
+            pbkdf2::pbkdf2::<hmac::Hmac<sha1::Sha1>>(
+                pwd,
+                serial,
+                PBKDF2_ROUNDS,
+                &mut hash,
+            ).ok().context(PbkdfSnafu)?;
+        }
+        PasswordOrRaw::Raw(r) => {
+            ensure!(r.len() == hash.len(), RawKeyInvalidLengthSnafu);
+            hash.copy_from_slice(r);
+        }
+    }
+    Ok(hash)
 }
 
 pub enum PasswordOrRaw<'a> {
@@ -0,0 +1,198 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt::{self, Display};
+use crate::command::{OpalCommandBuilder, OpalResponse};
+use crate::defs::{method, token, uid, Token, BS8};
+use crate::io::SecureProtocol;
+use crate::session::OpalSession;
+
+/// SPs a raw session can be opened to
+pub const KNOWN_SPS: &[(&str, [u8; 8])] = &[
+    ("AdminSP", uid::OPAL_ADMINSP.bytes),
+    ("LockingSP", uid::OPAL_LOCKINGSP.bytes),
+    ("Enterprise LockingSP", uid::ENTERPRISE_LOCKINGSP.bytes),
+];
+
+/// Objects methods are commonly invoked on
+pub const KNOWN_OBJECTS: &[(&str, [u8; 8])] = &[
+    ("ThisSP", uid::OPAL_THISSP.bytes),
+    ("Session Manager", uid::OPAL_SMUID.bytes),
+    ("AdminSP (SP table row)", uid::OPAL_ADMINSP.bytes),
+    ("LockingSP (SP table row)", uid::OPAL_LOCKINGSP.bytes),
+    ("Authority table", uid::OPAL_AUTHORITY_TABLE.bytes),
+    ("C_PIN table", uid::OPAL_C_PIN_TABLE.bytes),
+    ("C_PIN_MSID", uid::OPAL_C_PIN_MSID.bytes),
+    ("C_PIN_SID", uid::OPAL_C_PIN_SID.bytes),
+    ("C_PIN_Admin1", uid::OPAL_C_PIN_ADMIN1.bytes),
+    ("LockingInfo", uid::OPAL_LOCKING_INFO_TABLE.bytes),
+    ("Locking_GlobalRange", uid::OPAL_LOCKINGRANGE_GLOBAL.bytes),
+    ("MBRControl", uid::OPAL_MBRCONTROL.bytes),
+    ("MBR table", uid::OPAL_MBR.bytes),
+    ("DataStore table", uid::OPAL_DATASTORE.bytes),
+];
+
+/// Methods by name
+pub const KNOWN_METHODS: &[(&str, [u8; 8])] = &[
+    ("Get", method::GET.bytes),
+    ("Set", method::SET.bytes),
+    ("Next", method::NEXT.bytes),
+    ("GetACL", method::GETACL.bytes),
+    ("Authenticate", method::AUTHENTICATE.bytes),
+    ("Random", method::RANDOM.bytes),
+    ("GenKey", method::GENKEY.bytes),
+    ("Activate", method::ACTIVATE.bytes),
+    ("Revert", method::REVERT.bytes),
+    ("RevertSP", method::REVERTSP.bytes),
+    ("Erase", method::ERASE.bytes),
+    ("Properties", method::PROPERTIES.bytes),
+    ("Get (Enterprise)", method::EGET.bytes),
+    ("Set (Enterprise)", method::ESET.bytes),
+];
+
+/// A method argument, or a value decoded from a response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Uint(u64),
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    /// `name = value`; names are column numbers or strings
+    Named(Box<Value>, Box<Value>),
+    /// a token without a value, like EndOfData
+    Control(u8),
+}
+
+impl Token for Value {
+    fn write(&self, buffer: &mut Vec<u8>) {
+        match self {
+            Value::Uint(n) => n.write(buffer),
+            // tiny atom, or an 8 byte signed short atom
+            Value::Int(n) if (-32..32).contains(n) => buffer.push(0x40 | (*n as u8 & 0x3F)),
+            Value::Int(n) => {
+                buffer.push(0x98);
+                buffer.extend(n.to_be_bytes());
+            }
+            Value::Bytes(bytes) => bytes.as_slice().write(buffer),
+            Value::List(values) => {
+                token::STARTLIST.write(buffer);
+                values.iter().for_each(|value| value.write(buffer));
+                token::ENDLIST.write(buffer);
+            }
+            Value::Named(name, value) => {
+                token::STARTNAME.write(buffer);
+                name.write(buffer);
+                value.write(buffer);
+                token::ENDNAME.write(buffer);
+            }
+            Value::Control(token) => buffer.push(*token),
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Uint(n) if *n > 0xFFFF => write!(f, "{n:#X}"),
+            Value::Uint(n) => write!(f, "{n}"),
+            Value::Int(n) => write!(f, "{n}"),
+            Value::Bytes(bytes) if !bytes.is_empty() && bytes.iter().all(|b| (0x20..0x7F).contains(b)) => {
+                write!(f, "\"{}\"", core::str::from_utf8(bytes).unwrap_or_default())
+            }
+            Value::Bytes(bytes) => {
+                f.write_str("h:")?;
+                bytes.iter().try_for_each(|b| write!(f, "{b:02X}"))
+            }
+            Value::List(values) => {
+                f.write_str("[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{value}")?;
+                }
+                f.write_str("]")
+            }
+            Value::Named(name, value) => write!(f, "{name} = {value}"),
+            Value::Control(0xF8) => f.write_str("<Call>"),
+            Value::Control(0xF9) => f.write_str("<EndOfData>"),
+            Value::Control(0xFA) => f.write_str("<EndOfSession>"),
+            Value::Control(0xFB) => f.write_str("<StartTransaction>"),
+            Value::Control(0xFC) => f.write_str("<EndTransaction>"),
+            Value::Control(token) => write!(f, "<token {token:02X}>"),
+        }
+    }
+}
+
+/// Decodes the tokens of a response, nesting lists and names
+fn decode(response: &OpalResponse) -> Vec<Value> {
+    // the token that opened each level, and the values in it so far
+    let mut stack: Vec<(u8, Vec<Value>)> = alloc::vec![(0, Vec::new())];
+    let close = |opener: u8, mut values: Vec<Value>| match (opener, values.len()) {
+        (0xF2, 2) => {
+            let value = values.pop().unwrap();
+            let name = values.pop().unwrap();
+            Value::Named(Box::new(name), Box::new(value))
+        }
+        _ => Value::List(values),
+    };
+    for (i, atom) in response.tokens.iter().enumerate() {
+        match atom[0] {
+            0xF0 | 0xF2 => stack.push((atom[0], Vec::new())),
+            0xF1 | 0xF3 if stack.len() > 1 => {
+                let (opener, values) = stack.pop().unwrap();
+                let value = close(opener, values);
+                stack.last_mut().unwrap().1.push(value);
+            }
+            b => {
+                let value = response.uint(i).map(Value::Uint)
+                    .or_else(|| response.bytes(i).map(|bytes| Value::Bytes(bytes.to_vec())))
+                    .or_else(|| signed(atom).map(Value::Int))
+                    .unwrap_or_else(|| match atom.len() {
+                        1 => Value::Control(b),
+                        // integers too long for 64 bits, continued atoms and the like
+                        _ => Value::Bytes(atom.clone()),
+                    });
+                stack.last_mut().unwrap().1.push(value);
+            }
+        }
+    }
+    // a truncated response leaves levels open
+    while stack.len() > 1 {
+        let (opener, values) = stack.pop().unwrap();
+        let value = close(opener, values);
+        stack.last_mut().unwrap().1.push(value);
+    }
+    stack.pop().unwrap().1
+}
+
+/// The value of a signed tiny or short atom
+fn signed(atom: &[u8]) -> Option<i64> {
+    match atom[0] {
+        // sign extend the 6 bit value
+        b if b & 0xC0 == 0x40 => Some(((b << 2) as i8 >> 2) as i64),
+        b if b & 0xF0 == 0x90 && (2..=9).contains(&atom.len()) => {
+            let unsigned = atom[1..].iter().fold(0u64, |value, &b| value << 8 | b as u64);
+            let shift = 64 - 8 * (atom.len() as u32 - 1);
+            Some((unsigned << shift) as i64 >> shift)
+        }
+        _ => None,
+    }
+}
+
+/// A session that sends arbitrary method calls, for diagnosing drives; nothing stops these from
+/// changing or erasing anything the authority may
+pub struct RawSession<'d, P: SecureProtocol> {
+    pub(crate) session: OpalSession<'d, P>,
+}
+
+impl<'d, P: SecureProtocol> RawSession<'d, P> {
+    /// Invokes `method` on `object` with `args` as its parameter list and decodes the whole response,
+    /// including the status list. Fails like any other call if the status isn't SUCCESS
+    pub fn call(&mut self, object: [u8; 8], method: [u8; 8], args: &[Value]) -> crate::Result<Vec<Value>, P::Error> {
+        let command = OpalCommandBuilder::new(BS8::new(object, "RAW_OBJECT"), BS8::new(method, "RAW_METHOD"))
+            .payload(Value::List(args.to_vec()).to_token_stream())
+            .build();
+        let response = unsafe { self.session.send_raw_command(command) }?;
+        Ok(decode(&response))
+    }
+}
@@ -1,19 +1,37 @@
 use alloc::format;
 use alloc::string::String;
 use alloc::borrow::ToOwned;
-use core::{fmt::Write, mem::size_of_val};
-use snafu::ResultExt;
+use alloc::vec::Vec;
+use core::fmt::{Debug, Display, Write};
+use core::mem::{size_of, size_of_val};
+use core::time::Duration;
+use snafu::{AsErrorSource, OptionExt, ResultExt};
 
 use crate::{tokens, token_list, token_name};
 use crate::defs::*;
 use crate::command::*;
-use crate::io::{SecureProtocol, SecureDevice};
+use crate::io::{Capabilities, PacketLimits, Poll, SecureProtocol, SecureDevice};
+
+/// room left in a ComPacket for the headers and the tokens of a byte table Get or Set around the data
+const BYTE_TABLE_OVERHEAD: u32 = 256;
+/// bytes of byte tables like the shadow MBR read or written per command if the TPer's limits make no sense
+const BYTE_TABLE_MIN_CHUNK: u32 = 1024;
+
+/// largest ComPacket offered to the TPer for responses, so big Gets don't have to be split as much
+const HOST_MAX_COM_PACKET_SIZE: u64 = 8192;
+
+/// how long to keep sending a method again while the TPer answers SP_BUSY, e.g. right after power-on
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+/// how long a method may take in total; reverts and erases of large drives are the slowest
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
 
 pub struct OpalSession<'d, P: SecureProtocol> {
     device: &'d mut SecureDevice<P>,
     tsn: u32,
     hsn: u32,
     protocol: u8,
+    /// whether the TPer acknowledged the session and it wasn't closed yet
+    open: bool,
 }
 
 impl<'d, P: SecureProtocol> OpalSession<'d, P> {
@@ -28,6 +46,7 @@ impl<'d, P: SecureProtocol> OpalSession<'d, P> {
             tsn: 0,
             hsn: 0,
             protocol: 0x01,
+            open: false,
         };
 
         let challenge_tokens = match challenge {
@@ -40,9 +59,23 @@ impl<'d, P: SecureProtocol> OpalSession<'d, P> {
             _ => tokens![],
         };
 
+        if !s.device.limits_negotiated() {
+            let limits = s.exchange_properties().unwrap_or_else(|e| {
+                tracing::warn!("Properties failed ({:?}), keeping to the minimum packet sizes", e);
+                PacketLimits::default()
+            });
+            tracing::debug!(?limits);
+            s.device.set_limits(limits);
+        }
+
+        // the HSN can be arbitrary, so don't make it predictable; must not be 0
+        let mut hsn = [0; 4];
+        s.device.proto().fill_random(&mut hsn);
+        let hsn = u64::from(u32::from_be_bytes(hsn).max(1));
+
         let command = OpalCommandBuilder::new(uid::OPAL_SMUID, method::STARTSESSION)
             .payload(token_list![
-                105, // our HSN, same as in sedutil, although looks like it can be arbitrary
+                hsn,
                 sp_uid,
                 tiny_atom::UINT_01,
                 challenge_tokens,
@@ -58,56 +91,94 @@ impl<'d, P: SecureProtocol> OpalSession<'d, P> {
 
         s.hsn = response.get_uint(4) as _;
         s.tsn = response.get_uint(5) as _;
+        s.open = true;
 
-        match &challenge {
-            Some(_challenge) if s.device.is_eprise() => {
-                unimplemented!();
-                // LOG(D1) << "Entering DtaSession::authenticate ";
-                // 	vector<uint8_t> hash;
-                // 	DtaCommand *cmd = new DtaCommand();
-                // 	if (NULL == cmd) {
-                // 		LOG(E) << "Unable to create session object ";
-                // 		return DTAERROR_OBJECT_CREATE_FAILED;
-                // 	}
-                // 	DtaResponse response;
-                // 	cmd->reset(OPAL_UID::OPAL_THISSP_UID, OPAL_METHOD::EAUTHENTICATE);
-                // 	cmd->addToken(OPAL_TOKEN::STARTLIST); // [  (Open Bracket)
-                // 	cmd->addToken(Authority);
-                //     if (Challenge && *Challenge)
-                //     {
-                // 		cmd->addToken(OPAL_TOKEN::STARTNAME);
-                // 		if (d->isEprise())
-                // 			cmd->addToken("Challenge");
-                // 		else
-                // 			cmd->addToken(OPAL_TINY_ATOM::UINT_00);
-                // 		if (hashPwd) {
-                // 			hash.clear();
-                // 			DtaHashPwd(hash, Challenge, d);
-                // 			cmd->addToken(hash);
-                // 		}
-                // 		else
-                // 			cmd->addToken(Challenge);
-                // 		cmd->addToken(OPAL_TOKEN::ENDNAME);
-                //     }
-                // 	cmd->addToken(OPAL_TOKEN::ENDLIST); // ]  (Close Bracket)
-                // 	cmd->complete();
-                // 	if ((lastRC = sendCommand(cmd, response)) != 0) {
-                // 		LOG(E) << "Session Authenticate failed";
-                // 		delete cmd;
-                // 		return lastRC;
-                // 	}
-                // 	if (0 == response.getUint8(1)) {
-                // 		LOG(E) << "Session Authenticate failed (response = false)";
-                // 		delete cmd;
-                // 		return DTAERROR_AUTH_FAILED;
-                // 	}
-            }
-            _ => {}
+        if let (Some(challenge), true) = (challenge, s.device.is_eprise()) {
+            // Enterprise SSC authenticates within the session instead of in StartSession
+            s.authenticate(sign_authority, challenge)?;
         }
 
         Ok(s)
     }
 
+    /// Tells the TPer how large our packets may be and learns its limits, outside of any session
+    fn exchange_properties(&mut self) -> crate::Result<PacketLimits, P::Error> {
+        let host = HOST_MAX_COM_PACKET_SIZE;
+        let command = OpalCommandBuilder::new(uid::OPAL_SMUID, method::PROPERTIES)
+            .payload(token_list![token_name!(
+                token::HOSTPROPERTIES,
+                token_list![
+                    token_name!(b"MaxComPacketSize", host),
+                    // less the ComPacket and Packet headers
+                    token_name!(b"MaxPacketSize", host - 20),
+                    token_name!(b"MaxIndTokenSize", host - 56),
+                    token_name!(b"MaxPackets", 1u64),
+                    token_name!(b"MaxSubpackets", 1u64),
+                    token_name!(b"MaxMethods", 1u64),
+                ]
+            )])
+            .build();
+        let response = unsafe { self.send_raw_command(command) }?;
+
+        // `[ [ TPer properties ], HostProperties = [ accepted host properties ] ]`, each a list of `name = value`
+        let mut properties: [Vec<(&[u8], u64)>; 2] = [Vec::new(), Vec::new()];
+        let (mut depth, mut list, mut lists) = (0, None, 0);
+        for i in 0..response.len() {
+            if response.is(i, token::STARTLIST) {
+                depth += 1;
+                if depth == 2 {
+                    list = properties.get(lists).map(|_| lists);
+                    lists += 1;
+                }
+            } else if response.is(i, token::ENDLIST) {
+                if depth == 2 {
+                    list = None;
+                }
+                depth -= 1;
+            } else if let (true, Some(list)) = (response.is(i, token::STARTNAME), list) {
+                if let (Some(name), Some(value)) = (response.bytes(i + 1), response.uint(i + 2)) {
+                    properties[list].push((name, value));
+                }
+            }
+        }
+        let [tper, host] = &properties;
+        tracing::debug!("TPer properties: {:?}", tper.iter().map(|(name, value)| (String::from_utf8_lossy(name), value)).collect::<Vec<_>>());
+        let find = |list: &[(&[u8], u64)], name: &[u8]| list.iter().find(|(n, _)| *n == name).map(|&(_, value)| value as u32);
+        let defaults = PacketLimits::default();
+        Ok(PacketLimits {
+            max_com_packet_size: find(tper, b"MaxComPacketSize").unwrap_or(defaults.max_com_packet_size),
+            max_ind_token_size: find(tper, b"MaxIndTokenSize").unwrap_or(defaults.max_ind_token_size),
+            max_packets: find(tper, b"MaxPackets").unwrap_or(defaults.max_packets),
+            host_max_com_packet_size: find(host, b"MaxComPacketSize")
+                .map_or(defaults.host_max_com_packet_size, |size| size.clamp(defaults.host_max_com_packet_size, HOST_MAX_COM_PACKET_SIZE as u32)),
+        })
+    }
+
+    /// Authenticates an authority within the session, in addition to those it was started with
+    pub fn authenticate(&mut self, authority: BS8, proof: &[u8]) -> crate::Result<(), P::Error> {
+        let command = if self.device.is_eprise() {
+            // Enterprise SSC names the parameter instead of numbering it
+            OpalCommandBuilder::new(uid::OPAL_THISSP, method::EAUTHENTICATE)
+                .payload(token_list![authority, token_name!(b"Challenge", proof)])
+        } else {
+            OpalCommandBuilder::new(uid::OPAL_THISSP, method::AUTHENTICATE)
+                .payload(token_list![authority, token_name!(tiny_atom::UINT_00, proof)])
+        };
+        let response = unsafe { self.send_raw_command(command.build()) }?;
+        // the method succeeds either way and returns whether the proof was right
+        if response.uint(1) != Some(1) {
+            return Err(super::Error::Opal {
+                source: OpalError::Status { code: StatusCode::NOT_AUTHORIZED },
+                msg: format!("authentication of {:?} failed", authority),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn device(&mut self) -> &mut SecureDevice<P> {
+        self.device
+    }
+
     pub fn protocol(mut self, protocol: u8) -> Self {
         self.protocol = protocol;
         self
@@ -121,6 +192,16 @@ impl<'d, P: SecureProtocol> OpalSession<'d, P> {
         let mut header = command.header;
 
         let offset = size_of_val(&header);
+        let limits = self.device.limits();
+        if command.payload.len() + offset > limits.max_com_packet_size as usize {
+            return Err(super::Error::Opal {
+                source: OpalError::Status { code: StatusCode::INVALID_PARAMETER },
+                msg: format!(
+                    "a ComPacket of {} bytes is larger than the TPer's MaxComPacketSize of {}",
+                    command.payload.len() + offset, limits.max_com_packet_size,
+                ),
+            });
+        }
         let mut buffer = crate::util::alloc_aligned(
             command.payload.len() + offset,
             self.device.proto().align(),
@@ -138,40 +219,81 @@ impl<'d, P: SecureProtocol> OpalSession<'d, P> {
 
         dump("sending", &buffer);
 
+        // a busy SP didn't run the method, so sending it again is safe
+        let mut busy = Poll::new(BUSY_TIMEOUT);
+        loop {
+            match self.transact(&mut buffer, eod, limits) {
+                Err(super::Error::Opal { source: OpalError::Status { code: StatusCode::SP_BUSY }, .. }) => {
+                    tracing::debug!("SP busy, sending the method again");
+                    busy.wait(self.device.proto())?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Sends a ComPacket and receives the response to it, checking its method status
+    unsafe fn transact(&mut self, buffer: &mut [u8], eod: bool, limits: PacketLimits) -> crate::Result<OpalResponse, P::Error> {
         let com_id = self.device.com_id();
         self.device
             .proto()
-            .secure_send(self.protocol, com_id, buffer.as_mut())
+            .secure_send(self.protocol, com_id, buffer)
             .context(super::IoSnafu)?;
 
-        let mut buffer = crate::util::alloc_aligned(2048, self.device.proto().align());
+        let align = self.device.proto().align();
+        let mut buffer = crate::util::alloc_aligned(limits.host_max_com_packet_size as usize, align);
 
-        let mut header: OpalHeader;
+        // the headers of the first ComPacket with data, and the Packets of every ComPacket of the response
+        let mut header = OpalHeader::default();
+        let mut packets = Vec::new();
+        let mut poll = Poll::new(RESPONSE_TIMEOUT);
         loop {
-            //sleep(Duration::from_millis(25));
-
             self.device
                 .proto()
                 .secure_recv(self.protocol, com_id, &mut buffer)
                 .context(super::IoSnafu)?;
 
-            header = core::ptr::read(buffer.as_ptr() as _);
+            let cp: ComPacketHeader = core::ptr::read(buffer.as_ptr() as _);
+            let length = u32::from_be(cp.length) as usize;
+            let min_transfer = u32::from_be(cp.min_transfer) as usize;
+            if min_transfer > buffer.len() {
+                // the rest doesn't fit the buffer, so the TPer kept it and says how much room it needs
+                ensure_response_size(min_transfer)?;
+                tracing::debug!("receiving the response again with a {} byte buffer", min_transfer);
+                buffer = crate::util::alloc_aligned(min_transfer, align);
+                continue;
+            }
+            if length == 0 {
+                // zeroes are zeroes in any endianess
+                if cp.outstanding_data == 0 {
+                    break;
+                }
+                // still processing
+                poll.wait(self.device.proto())?;
+                continue;
+            }
 
-            // zeroes are zeroes in any endianess
-            if header.cp.outstanding_data == 0 || header.cp.min_transfer != 0 {
+            let end = (size_of_val(&cp) + length).min(buffer.len());
+            dump("received", &buffer[..end]);
+            if packets.is_empty() {
+                header = core::ptr::read(buffer.as_ptr() as _);
+            }
+            packets.extend_from_slice(&buffer[size_of_val(&cp)..end]);
+            ensure_response_size(packets.len())?;
+            if cp.outstanding_data == 0 {
                 break;
             }
+            // more ComPackets of the response are waiting, fetch them right away
         }
+
+        // one token stream from all data Subpackets, parsed as if it came in a single one
+        let data = reassemble(&packets);
         header.cp.length = u32::from_be(header.cp.length);
         header.pkt.length = u32::from_be(header.pkt.length);
-        header.subpkt.length = u32::from_be(header.subpkt.length);
-
-        dump(
-            "received",
-            &buffer[..header.cp.length as usize + size_of_val(&header.cp)],
-        );
-
-        let response = OpalResponse::parse(header, buffer.as_ref());
+        header.subpkt.length = data.len() as u32;
+        let mut bytes = alloc::vec![0; size_of_val(&header)];
+        bytes.extend_from_slice(&data);
+        let response = OpalResponse::parse(header, &bytes);
 
         let len = response.len();
         if eod
@@ -215,10 +337,175 @@ impl<'d, P: SecureProtocol> OpalSession<'d, P> {
         Ok(())
     }
 
+    /// Reads the columns `start_column..=end_column` of a table row
+    pub fn get(&mut self, object: BS8, start_column: u64, end_column: u64) -> crate::Result<OpalResponse, P::Error> {
+        let command = OpalCommandBuilder::new(object, method::GET)
+            .payload(token_list![token_list![
+                token_name!(token::STARTCOLUMN, start_column),
+                token_name!(token::ENDCOLUMN, end_column),
+            ]])
+            .build();
+        unsafe { self.send_raw_command(command) }
+    }
+
+    /// Writes a single column of a table row
+    pub fn set(&mut self, object: BS8, column: u64, value: impl Token) -> crate::Result<(), P::Error> {
+        let command = OpalCommandBuilder::new(object, method::SET)
+            .payload(token_list![token_name!(
+                token::VALUES,
+                token_list![token_name!(column, value)]
+            )])
+            .build();
+        unsafe { self.send_raw_command(command) }?;
+        Ok(())
+    }
+
+    /// Bytes per byte table Set: a chunk is a single token that has to fit MaxIndTokenSize, and the call a ComPacket
+    fn write_chunk(&self) -> usize {
+        let limits = self.device.limits();
+        // less the long atom header
+        let token = limits.max_ind_token_size.saturating_sub(4);
+        let packet = limits.max_com_packet_size.saturating_sub(BYTE_TABLE_OVERHEAD);
+        token.min(packet).max(BYTE_TABLE_MIN_CHUNK) as usize
+    }
+
+    /// Bytes per byte table Get, limited by the ComPackets the TPer may send us
+    fn read_chunk(&self) -> usize {
+        let limits = self.device.limits();
+        limits.host_max_com_packet_size.saturating_sub(BYTE_TABLE_OVERHEAD).max(BYTE_TABLE_MIN_CHUNK) as usize
+    }
+
+    /// Reads `len` bytes from `offset` of a byte table like the MBR or the DataStore, in chunks
+    pub fn read_byte_table(&mut self, table: BS8, offset: u64, len: usize) -> crate::Result<Vec<u8>, P::Error> {
+        let chunk_size = self.read_chunk();
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            let start = offset + data.len() as u64;
+            let chunk = (len - data.len()).min(chunk_size);
+            let command = OpalCommandBuilder::new(table, method::GET)
+                .payload(token_list![token_list![
+                    token_name!(token::STARTROW, start),
+                    token_name!(token::ENDROW, start + chunk as u64 - 1),
+                ]])
+                .build();
+            let response = unsafe { self.send_raw_command(command) }?;
+            let bytes = response.bytes(1)
+                .ok_or_else(|| crate::Error::Opal { source: OpalError::NoMethodStatus, msg: "byte table Get returned no bytes".to_owned() })?;
+            data.extend_from_slice(&bytes[..bytes.len().min(chunk)]);
+            // a TPer returning less than asked for would otherwise be asked forever
+            if bytes.len() < chunk {
+                break;
+            }
+        }
+        Ok(data)
+    }
+
+    /// Writes `data` at `offset` of a byte table in chunks, reporting the bytes written so far
+    pub fn write_byte_table(&mut self, table: BS8, offset: u64, data: &[u8], progress: &mut dyn FnMut(usize)) -> crate::Result<(), P::Error> {
+        let chunk_size = self.write_chunk();
+        for (i, chunk) in data.chunks(chunk_size).enumerate() {
+            let written = i * chunk_size;
+            let command = OpalCommandBuilder::new(table, method::SET)
+                .payload(token_list![
+                    token_name!(token::WHERE, offset + written as u64),
+                    token_name!(token::VALUES, chunk),
+                ])
+                .build();
+            unsafe { self.send_raw_command(command) }?;
+            progress(written + chunk.len());
+        }
+        Ok(())
+    }
+
+    /// Reads from the DataStore table of the Locking SP; needs the DataStore feature
+    pub fn read_datastore(&mut self, offset: u64, len: usize) -> crate::Result<Vec<u8>, P::Error> {
+        self.check_datastore_bounds(offset, len)?;
+        self.read_byte_table(uid::OPAL_DATASTORE, offset, len)
+    }
+
+    /// Writes into the DataStore table of the Locking SP; needs the DataStore feature
+    pub fn write_datastore(&mut self, offset: u64, data: &[u8]) -> crate::Result<(), P::Error> {
+        self.check_datastore_bounds(offset, data.len())?;
+        self.write_byte_table(uid::OPAL_DATASTORE, offset, data, &mut |_| ())
+    }
+
+    /// Refuses accesses past the size discovery reports, which TPers answer with less helpful errors
+    fn check_datastore_bounds(&mut self, offset: u64, len: usize) -> crate::Result<(), P::Error> {
+        snafu::ensure!(self.device.capabilities().contains(Capabilities::DATASTORE), crate::UnsupportedSnafu);
+        let size = self.device.discovery().datastore.map_or(u64::MAX, |datastore| datastore.max_size as u64);
+        if offset.saturating_add(len as u64) > size {
+            return Err(crate::Error::Opal {
+                source: OpalError::Status { code: StatusCode::INVALID_PARAMETER },
+                msg: format!("{} bytes at {} are past the end of the {} byte DataStore", len, offset, size),
+            });
+        }
+        Ok(())
+    }
+
+    /// Reverts the TPer to its factory state; the TPer ends the session by itself when that succeeds
+    pub fn revert_tper(&mut self) -> crate::Result<(), P::Error> {
+        let command = OpalCommandBuilder::new(uid::OPAL_ADMINSP, method::REVERT)
+            .payload(token_list![])
+            .build();
+        unsafe { self.send_raw_command(command) }?;
+        self.open = false;
+        Ok(())
+    }
+
+    /// Reverts the SP of this session to its factory state with RevertSP; a Locking SP reverted with
+    /// `keep_global_range_key` keeps the global range's key and so its data. The TPer ends the session by itself when that succeeds
+    pub fn revert_sp(&mut self, keep_global_range_key: bool) -> crate::Result<(), P::Error> {
+        // the KeepGlobalRangeKey parameter
+        let params = match keep_global_range_key {
+            true => tokens![token_name!(0x060000u64, token::OPAL_TRUE)],
+            false => tokens![],
+        };
+        let command = OpalCommandBuilder::new(uid::OPAL_THISSP, method::REVERTSP)
+            .payload(token_list![params])
+            .build();
+        unsafe { self.send_raw_command(command) }?;
+        self.open = false;
+        Ok(())
+    }
+
+    /// Unlocks a band of an Enterprise SSC drive, which uses ESET with named columns instead of Opal's SET
+    pub fn set_band_unlocked(&mut self, band: u8) -> crate::Result<(), P::Error> {
+        let band = band_uid(band).context(crate::UnsupportedSnafu)?;
+        let command = OpalCommandBuilder::new(band, method::ESET)
+            .payload(token_list![
+                // no row filter
+                token_list![],
+                token_list![token_list![
+                    token_name!(b"ReadLocked", token::OPAL_FALSE),
+                    token_name!(b"WriteLocked", token::OPAL_FALSE),
+                ]],
+            ])
+            .build();
+        unsafe { self.send_raw_command(command) }?;
+        Ok(())
+    }
+
+    /// Sets the PIN column of a C_PIN row of an Enterprise SSC drive, with ESET and named columns like `set_band_unlocked`
+    pub fn set_enterprise_pin(&mut self, c_pin: BS8, pin: &[u8]) -> crate::Result<(), P::Error> {
+        let command = OpalCommandBuilder::new(c_pin, method::ESET)
+            .payload(token_list![
+                // no row filter
+                token_list![],
+                token_list![token_list![token_name!(b"PIN", pin)]],
+            ])
+            .build();
+        unsafe { self.send_raw_command(command) }?;
+        Ok(())
+    }
+
     pub fn set_mbr_done(&mut self, done: bool) -> crate::Result<(), P::Error> {
         unsafe { self.set_locking_sp_value(uid::OPAL_MBRCONTROL, token::MBRDONE, done.into()) }
     }
 
+    pub fn set_mbr_enable(&mut self, enable: bool) -> crate::Result<(), P::Error> {
+        unsafe { self.set_locking_sp_value(uid::OPAL_MBRCONTROL, token::MBRENABLE, enable.into()) }
+    }
+
     pub fn set_locking_range(&mut self, locking_range: u8, locking_state: LockingState) -> crate::Result<(), P::Error> {
         let mut archive_user = false;
         let mut read_lock = token::OPAL_FALSE;
@@ -238,16 +525,7 @@ impl<'d, P: SecureProtocol> OpalSession<'d, P> {
             }
         }
 
-        let locking_range = if locking_range != 0 {
-            let mut bytes = uid::OPAL_LOCKINGRANGE_GLOBAL.bytes;
-            bytes[5] = 0x03;
-            bytes[7] = locking_range;
-            BS8::new(bytes, "LOCKING_RANGE_N")
-        } else {
-            uid::OPAL_LOCKINGRANGE_GLOBAL
-        };
-
-        let command = OpalCommandBuilder::new(locking_range, method::SET)
+        let command = OpalCommandBuilder::new(locking_range_uid(locking_range), method::SET)
             .payload(token_list![token_name!(
                 token::VALUES,
                 token_list![
@@ -266,17 +544,131 @@ impl<'d, P: SecureProtocol> OpalSession<'d, P> {
     }
 }
 
-impl<'d, P: SecureProtocol> Drop for OpalSession<'d, P> {
-    fn drop(&mut self) {
+/// the row of a locking range in the Locking table; 0 is the global range
+pub fn locking_range_uid(locking_range: u8) -> BS8 {
+    if locking_range != 0 {
+        let mut bytes = uid::OPAL_LOCKINGRANGE_GLOBAL.bytes;
+        bytes[5] = 0x03;
+        bytes[7] = locking_range;
+        BS8::new(bytes, "LOCKING_RANGE_N")
+    } else {
+        uid::OPAL_LOCKINGRANGE_GLOBAL
+    }
+}
+
+/// the row of a band in an Enterprise SSC drive's Locking table; band 0 is the global band.
+/// `None` for band 255, whose UID doesn't fit in the last byte
+pub fn band_uid(band: u8) -> Option<BS8> {
+    let mut bytes = uid::OPAL_LOCKINGRANGE_GLOBAL.bytes;
+    bytes[7] = band.checked_add(1)?;
+    Some(BS8::new(bytes, "BAND_N"))
+}
+
+impl<'d, P: SecureProtocol> OpalSession<'d, P> {
+    /// Closes the session, reporting failures instead of only logging them like drop does
+    pub fn close(mut self) -> crate::Result<(), P::Error> {
+        self.end()
+    }
+
+    /// Closes the session and reconnects the controller, so the firmware picks up ranges unlocked in it
+    pub fn close_and_reconnect(mut self) -> crate::Result<(), P::Error> {
+        self.end()?;
+        self.device.reconnect_controller()
+    }
+
+    fn end(&mut self) -> crate::Result<(), P::Error> {
+        if !self.open {
+            return Ok(());
+        }
+        self.open = false;
+        match self.send_end_of_session() {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                // a failed method may have left a response behind that blocks the EOS reply
+                tracing::warn!("failed to close session ({:?}), draining the ComID and retrying", e);
+                self.drain();
+                self.send_end_of_session()
+            }
+        }
+    }
+
+    fn send_end_of_session(&mut self) -> crate::Result<(), P::Error> {
         let command = OpalCommandBuilder::empty()
             .payload(tokens![token::ENDOFSESSION])
             .build_no_end_of_data();
         match unsafe { self.send_raw_command(command) } {
-            Err(super::Error::Opal { source: OpalError::NoMethodStatus, .. }) => {} // that's expected
-            Err(e) => tracing::error!("failed to send close session message: {:?}", e),
-            _ => tracing::warn!("somehow got successful method status after CloseSession"),
+            Err(super::Error::Opal { source: OpalError::NoMethodStatus, .. }) => Ok(()), // that's expected
+            Err(e) => Err(e),
+            Ok(_) => {
+                tracing::warn!("somehow got successful method status after CloseSession");
+                Ok(())
+            }
+        }
+    }
+
+    /// Reads and discards responses still pending on the ComID
+    fn drain(&mut self) {
+        let com_id = self.device.com_id();
+        let size = self.device.limits().host_max_com_packet_size as usize;
+        let mut buffer = crate::util::alloc_aligned(size, self.device.proto().align());
+        for _ in 0..8 {
+            if unsafe { self.device.proto().secure_recv(self.protocol, com_id, &mut buffer) }.is_err() {
+                return;
+            }
+            let header: ComPacketHeader = unsafe { core::ptr::read(buffer.as_ptr() as _) };
+            if header.length == 0 && header.outstanding_data == 0 {
+                return;
+            }
+            tracing::debug!("discarded pending response of {} bytes", u32::from_be(header.length));
+        }
+    }
+}
+
+impl<'d, P: SecureProtocol> Drop for OpalSession<'d, P> {
+    fn drop(&mut self) {
+        // also runs when unwinding, so the TPer never keeps a stale session around
+        if let Err(e) = self.end() {
+            tracing::error!("failed to send close session message: {:?}", e);
+        }
+    }
+}
+
+/// most bytes of a response reassembled from several ComPackets, against TPers that never stop sending
+const MAX_RESPONSE_SIZE: usize = 1024 * 1024;
+
+fn ensure_response_size<E: AsErrorSource + Debug + Display>(size: usize) -> crate::Result<(), E> {
+    if size > MAX_RESPONSE_SIZE {
+        return Err(super::Error::Opal {
+            source: OpalError::Status { code: StatusCode::RESPONSE_OVERFLOW },
+            msg: format!("the TPer sends a response of more than {} bytes", MAX_RESPONSE_SIZE),
+        });
+    }
+    Ok(())
+}
+
+/// Concatenates the data Subpackets of the Packets of a response, which may come in several ComPackets
+fn reassemble(packets: &[u8]) -> Vec<u8> {
+    let be32 = |pos: usize| u32::from_be_bytes([packets[pos], packets[pos + 1], packets[pos + 2], packets[pos + 3]]) as usize;
+    let (packet_header, subpacket_header) = (size_of::<PacketHeader>(), size_of::<SubpacketHeader>());
+    let mut data = Vec::new();
+    let mut pos = 0;
+    while pos + packet_header <= packets.len() {
+        let end = (pos + packet_header + be32(pos + 20)).min(packets.len());
+        let mut subpacket = pos + packet_header;
+        while subpacket + subpacket_header <= end {
+            let kind = u16::from_be_bytes([packets[subpacket + 6], packets[subpacket + 7]]);
+            let length = be32(subpacket + 8);
+            let start = subpacket + subpacket_header;
+            // kind 0 is data, the others carry credit control
+            if kind == 0 {
+                data.extend_from_slice(&packets[start.min(end)..(start + length).min(end)]);
+            }
+            // Subpackets are padded to a multiple of 4 bytes
+            subpacket = start + (length + 3) / 4 * 4;
         }
+        pos = end;
     }
+    data
 }
 
 fn dump(title: &str, buffer: impl AsRef<[u8]>) {
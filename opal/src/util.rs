@@ -8,3 +8,21 @@ pub fn alloc_aligned(len: usize, align: usize) -> Box<[u8]> {
         Box::from_raw(core::slice::from_raw_parts_mut(ptr, len))
     }
 }
+
+/// Compares two secrets in time only depending on their length, not their content
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let diff = a.iter().zip(b).fold(0u8, |diff, (a, b)| diff | (a ^ b));
+    // keep the compiler from short-circuiting the fold
+    unsafe { core::ptr::read_volatile(&diff) == 0 }
+}
+
+/// Overwrites a secret in a way that is not optimized out
+pub fn wipe(secret: &mut [u8]) {
+    for b in secret.iter_mut() {
+        unsafe { core::ptr::write_volatile(b, 0) };
+    }
+    core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+}
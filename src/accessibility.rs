@@ -0,0 +1,52 @@
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::time::Duration;
+use uefi::proto::console::text::{Color, Key};
+use uefi::table::{Boot, SystemTable};
+use crate::config::Accessibility;
+use crate::{console, ui, Result};
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static HIGH_CONTRAST: AtomicBool = AtomicBool::new(false);
+static LARGE_TEXT: AtomicBool = AtomicBool::new(false);
+static ECHO_KEYS: AtomicBool = AtomicBool::new(false);
+static COUNTDOWN_FACTOR: AtomicU32 = AtomicU32::new(1);
+
+/// Enables accessibility mode if configured to be always on or if its hotkey was held at startup
+pub fn init(config: &Accessibility, held_keys: &[Key]) {
+    let held = config.hotkey.map_or(false, |hotkey| held_keys.iter().any(|key| ui::key_matches(key, hotkey)));
+    if !config.enabled && !held {
+        return;
+    }
+    log::info!("accessibility mode enabled");
+    ACTIVE.store(true, Ordering::Relaxed);
+    HIGH_CONTRAST.store(config.high_contrast, Ordering::Relaxed);
+    LARGE_TEXT.store(config.large_text, Ordering::Relaxed);
+    ECHO_KEYS.store(config.echo_keys, Ordering::Relaxed);
+    COUNTDOWN_FACTOR.store(config.countdown_factor.max(1), Ordering::Relaxed);
+}
+
+pub fn active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+pub fn large_text() -> bool {
+    LARGE_TEXT.load(Ordering::Relaxed)
+}
+
+pub fn echo_keys() -> bool {
+    ECHO_KEYS.load(Ordering::Relaxed)
+}
+
+/// stretches countdowns so they are easier to follow
+pub fn countdown(duration: Duration) -> Duration {
+    duration * COUNTDOWN_FACTOR.load(Ordering::Relaxed)
+}
+
+/// applies the high-contrast theme; must be called again after the console got reset
+pub fn apply_theme(st: &SystemTable<Boot>) -> Result {
+    if HIGH_CONTRAST.load(Ordering::Relaxed) {
+        console::set_color(st, Color::White, Color::Black)?;
+        console::clear(st)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,1033 @@
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use opal::{Ace, AdminSession, Authority, Capabilities, OpalDrive, PasswordOrRaw, SecureProtocol, Ssc};
+use uefi::proto::console::text::Key;
+use uefi::table::{Boot, SystemTable};
+use crate::config::{Admin, Config, KeyName, KeyslotSource, Quorum};
+use crate::error::ErrorSource;
+use crate::low_level::nvme_device::{self, RestartableNvmeDevice};
+use crate::raw_console::RawConsole;
+use crate::{console, logging, power, protocol_trace, quorum, smbios, stats, ui, update, util, Cache, Error, Result};
+
+static PRESENT: AtomicBool = AtomicBool::new(false);
+static WIZARD: AtomicBool = AtomicBool::new(false);
+
+/// SMBIOS chassis security status "external interface enabled"
+const CHASSIS_INTERFACE_ENABLED: u8 = 0x05;
+
+/// Establishes physical presence, which gates the setup and recovery menu.
+///
+/// The admin hotkey (or the wizard hotkey, for the setup wizard) must have been held while the greeter started and,
+/// if configured, the chassis must report its external interface as enabled.
+pub fn init(st: &SystemTable<Boot>, config: &Admin, held_keys: &[Key]) {
+    let held = |hotkey: Option<KeyName>| hotkey.map_or(false, |hotkey| held_keys.iter().any(|key| ui::key_matches(key, hotkey)));
+    let (admin, wizard) = (held(config.hotkey), held(config.wizard_hotkey));
+    if !admin && !wizard {
+        return;
+    }
+    if config.require_chassis_unlocked {
+        let status = smbios::find(st, smbios::TYPE_CHASSIS).and_then(|chassis| chassis.byte(0x0c));
+        if status != Some(CHASSIS_INTERFACE_ENABLED) {
+            log::warn!("admin hotkey held, but the chassis reports security status {status:?}; not entering admin mode");
+            return;
+        }
+    }
+    if admin {
+        log::info!("physical presence established, admin mode available");
+        PRESENT.store(true, Ordering::Relaxed);
+    }
+    if wizard {
+        log::info!("physical presence established, starting the setup wizard");
+        WIZARD.store(true, Ordering::Relaxed);
+    }
+}
+
+/// whether the setup wizard hotkey was held at startup
+pub fn wizard_requested() -> bool {
+    WIZARD.load(Ordering::Relaxed)
+}
+
+/// whether the setup and recovery menu may be shown
+pub fn present() -> bool {
+    PRESENT.load(Ordering::Relaxed)
+}
+
+pub fn menu(st: &SystemTable<Boot>, config: &Config) -> Result {
+    assert!(present(), "admin menu entered without physical presence");
+    let _write_access = util::WriteAccess::grant();
+    let options = vec![
+        (true, "Drive overview".to_string()),
+        (true, "Locking range access (ACE editor)".to_string()),
+        (true, "Admin and user authorities".to_string()),
+        (true, "Provisioning audit (authorities, try limits, range access)".to_string()),
+        (true, "Locking range layout (move, resize)".to_string()),
+        (true, "Locking range names".to_string()),
+        (true, "Locking range read/write locking".to_string()),
+        (true, "Quorum keyslot setup".to_string()),
+        (true, "Take ownership (set SID password)".to_string()),
+        (true, "PSID revert (erases everything)".to_string()),
+        (true, "Cryptographic erase of a locking range".to_string()),
+        (true, "Revert the Locking SP (RevertSP)".to_string()),
+        (true, "Install greeter update".to_string()),
+        (true, "Level 0 discovery".to_string()),
+        (true, "Reset the security stack (STACK_RESET, TPER_RESET)".to_string()),
+        (true, "NVMe controller diagnostics".to_string()),
+        (true, "Statistics".to_string()),
+        (true, "Protocol trace".to_string()),
+        (true, "Raw method console (experts)".to_string()),
+        (true, "Back".to_string()),
+    ];
+    loop {
+        console::clear(st)?;
+        match ui::choose(st, &options)? {
+            0 => {
+                let mut overview = Overview { config, lines: Vec::new() };
+                for_each_drive(st, config, None, &mut overview)?;
+                if overview.lines.is_empty() {
+                    overview.lines.push("no OPAL drives found".to_string());
+                }
+                ui::popup(st, "Drive overview", &overview.lines)?;
+            }
+            1 => if let Some(serial) = select_drive(st, config)? {
+                for_each_drive(st, config, Some(&serial), &mut AceEditor)?;
+            },
+            2 => if let Some(serial) = select_drive(st, config)? {
+                for_each_drive(st, config, Some(&serial), &mut Authorities)?;
+            },
+            3 => if let Some(serial) = select_drive(st, config)? {
+                for_each_drive(st, config, Some(&serial), &mut ProvisioningAudit)?;
+            },
+            4 => if let Some(serial) = select_drive(st, config)? {
+                for_each_drive(st, config, Some(&serial), &mut RangeLayout)?;
+            },
+            5 => if let Some(serial) = select_drive(st, config)? {
+                for_each_drive(st, config, Some(&serial), &mut RangeNames)?;
+            },
+            6 => if let Some(serial) = select_drive(st, config)? {
+                for_each_drive(st, config, Some(&serial), &mut LockEnabled)?;
+            },
+            7 => quorum_setup(st, config)?,
+            8 => if let Some(serial) = select_drive(st, config)? {
+                for_each_drive(st, config, Some(&serial), &mut TakeOwnership)?;
+            },
+            9 => if let Some(serial) = select_drive(st, config)? {
+                for_each_drive(st, config, Some(&serial), &mut PsidRevert)?;
+            },
+            10 => if let Some(serial) = select_drive(st, config)? {
+                for_each_drive(st, config, Some(&serial), &mut CryptoErase)?;
+            },
+            11 => if let Some(serial) = select_drive(st, config)? {
+                for_each_drive(st, config, Some(&serial), &mut RevertLockingSp)?;
+            },
+            12 => update::install(st, config)?,
+            13 => if let Some(serial) = select_drive(st, config)? {
+                for_each_drive(st, config, Some(&serial), &mut Discovery)?;
+            },
+            14 => if let Some(serial) = select_drive(st, config)? {
+                for_each_drive(st, config, Some(&serial), &mut SecurityReset)?;
+            },
+            15 => nvme_diagnostics(st)?,
+            16 => stats::view(st)?,
+            17 => protocol_trace::menu(st)?,
+            18 => if let Some(serial) = select_drive(st, config)? {
+                for_each_drive(st, config, Some(&serial), &mut RawConsole)?;
+            },
+            _ => return Ok(()),
+        }
+    }
+}
+
+/// Something to do with an OPAL drive, independent of how it's attached
+pub trait DriveAction {
+    fn run<P: SecureProtocol>(&mut self, st: &SystemTable<Boot>, kind: &str, drive: &mut OpalDrive<P>) -> Result
+    where opal::Error<P::Error>: Into<ErrorSource>;
+}
+
+/// Runs the action on every OPAL drive, or only on the one with the given serial
+pub fn for_each_drive(st: &SystemTable<Boot>, config: &Config, serial: Option<&str>, action: &mut impl DriveAction) -> Result {
+    for (blockio_handle, _, _) in crate::block_devices(st)? {
+        if let Some(nvme) = crate::try_get_nvme_device(st, blockio_handle)? {
+            if serial.map_or(false, |serial| serial != serial_str(nvme.serial_num())) {
+                continue;
+            }
+            match OpalDrive::new(RestartableNvmeDevice::new(&nvme, st, blockio_handle)) {
+                Ok(mut drive) => {
+                    drive.force(config.features.forced());
+                    crate::reset_stack(config, &mut drive);
+                    action.run(st, "NVMe", &mut drive)?
+                }
+                Err(opal::Error::KeyPerIo) => crate::warn_key_per_io(&format!("NVMe {}", serial_str(nvme.serial_num()))),
+                Err(e) => log::debug!("NVMe {}: no OPAL ({e})", serial_str(nvme.serial_num())),
+            }
+        } else if let Some(mut ata) = crate::try_get_ata_device(st, blockio_handle)? {
+            if serial.map_or(false, |serial| serial != serial_str(ata.serial())) {
+                continue;
+            }
+            ata.force(config.features.forced());
+            crate::reset_stack(config, &mut ata);
+            action.run(st, "ATA", &mut ata)?;
+        }
+    }
+    Ok(())
+}
+
+/// Lets the admin pick one of the OPAL drives; returns its serial
+pub fn select_drive(st: &SystemTable<Boot>, config: &Config) -> Result<Option<String>> {
+    struct Serials(Vec<(String, String)>);
+    impl DriveAction for Serials {
+        fn run<P: SecureProtocol>(&mut self, _st: &SystemTable<Boot>, kind: &str, drive: &mut OpalDrive<P>) -> Result
+        where opal::Error<P::Error>: Into<ErrorSource>
+        {
+            self.0.push((kind.to_string(), serial_str(drive.serial())));
+            Ok(())
+        }
+    }
+
+    let mut serials = Serials(Vec::new());
+    for_each_drive(st, config, None, &mut serials)?;
+    let mut options: Vec<_> = serials.0.iter()
+        .map(|(kind, serial)| (true, format!("{kind} {serial}")))
+        .collect();
+    options.push((true, "Back".to_string()));
+    console::clear(st)?;
+    console::write_str(st, "Select drive:\r\n");
+    let index = ui::choose(st, &options)?;
+    Ok(serials.0.get(index).map(|(_, serial)| serial.clone()))
+}
+
+/// Asks for the Admin1 password and opens a Locking SP session with it
+fn authenticate<'d, P: SecureProtocol>(st: &SystemTable<Boot>, drive: &'d mut OpalDrive<P>) -> Result<AdminSession<'d, P>>
+where opal::Error<P::Error>: Into<ErrorSource>
+{
+    console::write_str(st, &format!("Admin1 password for {}: ", serial_str(drive.serial())));
+    let password = ui::password(st)?;
+    drive.admin_session(PasswordOrRaw::Password(password.as_bytes()))
+        .map_err(|e| Error::new(e, "can't authenticate as Admin1"))
+}
+
+struct Overview<'a> {
+    config: &'a Config,
+    lines: Vec<String>,
+}
+
+impl DriveAction for Overview<'_> {
+    fn run<P: SecureProtocol>(&mut self, _st: &SystemTable<Boot>, kind: &str, drive: &mut OpalDrive<P>) -> Result
+    where opal::Error<P::Error>: Into<ErrorSource>
+    {
+        let serial = serial_str(drive.serial());
+        let configured = self.config.partitions.values()
+            .find(|part| part.uuid == serial)
+            .map_or("<not configured>", |part| &part.name);
+        self.lines.push(format!(
+            "{kind} {serial}: {}, features {:?}, partition {configured}",
+            if drive.was_locked() { "locked" } else { "unlocked" },
+            drive.capabilities(),
+        ));
+        self.lines.push(format!(
+            "    {} firmware {}, {}",
+            serial_str(drive.model()), serial_str(drive.firmware_rev()), drive.ssc(),
+        ));
+        if let Ssc::Pyrite { .. } = drive.ssc() {
+            self.lines.push("    no media encryption, locking only restricts access".to_string());
+        }
+        for warning in crate::drive_firmware_warnings(self.config, drive) {
+            self.lines.push(format!("    WARNING: {warning}"));
+        }
+        if let Some(geometry) = drive.geometry() {
+            self.lines.push(format!(
+                "    {} byte blocks, ranges aligned to {} blocks from LBA {}{}",
+                geometry.logical_block_size, geometry.alignment_granularity, geometry.lowest_aligned_lba,
+                if geometry.align_required { " (required)" } else { "" },
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Dumps what the drive advertised in Level 0 discovery, for debugging drives that misbehave
+struct Discovery;
+
+impl DriveAction for Discovery {
+    fn run<P: SecureProtocol>(&mut self, st: &SystemTable<Boot>, kind: &str, drive: &mut OpalDrive<P>) -> Result
+    where opal::Error<P::Error>: Into<ErrorSource>
+    {
+        let title = format!("Level 0 discovery of {kind} {}", serial_str(drive.serial()));
+        let d = drive.discovery();
+        // one feature per line, so it fits on a screen
+        let lines = vec![
+            format!("version {}.{}", d.major_version, d.minor_version),
+            format!("TPer: {:?}", d.tper),
+            format!("Locking: {:?}", d.locking),
+            format!("Geometry: {:?}", d.geometry),
+            format!("Secure messaging: {}", d.secure_messaging),
+            format!("Enterprise: {:?}", d.enterprise),
+            format!("Opal 2: {:?}", d.opal_v2),
+            format!("Pyrite: {:?}", d.pyrite),
+            format!("Single User Mode: {:?}", d.single_user_mode),
+            format!("DataStore: {:?}", d.datastore),
+            format!("Block SID: {:?}", d.block_sid),
+            format!(
+                "in use: {}, ComID {:#06x}{}",
+                drive.ssc(), drive.com_id(), if drive.dynamic_com_id() { " (dynamic)" } else { "" },
+            ),
+        ];
+        ui::popup(st, &title, &lines)
+    }
+}
+
+/// Sends STACK_RESET or TPER_RESET, for drives stuck with sessions nobody closed
+struct SecurityReset;
+
+impl DriveAction for SecurityReset {
+    fn run<P: SecureProtocol>(&mut self, st: &SystemTable<Boot>, kind: &str, drive: &mut OpalDrive<P>) -> Result
+    where opal::Error<P::Error>: Into<ErrorSource>
+    {
+        let serial = serial_str(drive.serial());
+        let options = vec![
+            (true, format!("STACK_RESET of ComID {:#06x} (aborts open sessions)", drive.com_id())),
+            (true, "TPER_RESET (like a power cycle, locks ranges with LockOnReset)".to_string()),
+            (true, "Back".to_string()),
+        ];
+        console::clear(st)?;
+        console::write_str(st, &format!("Reset {kind} drive {serial}:\r\n"));
+        let result = match ui::choose(st, &options)? {
+            0 => drive.stack_reset()
+                .map(|()| format!("sessions on drive {serial} aborted"))
+                .map_err(|e| Error::new(e, format!("STACK_RESET of drive {serial} failed"))),
+            1 => drive.tper_reset()
+                // the drive ignores it unless ProgrammaticResetEnable is set, and doesn't say so
+                .map(|()| format!("TPER_RESET sent to drive {serial}, it only has an effect if ProgrammaticResetEnable is set"))
+                .map_err(|e| Error::new(e, format!("TPER_RESET of drive {serial} failed"))),
+            _ => return Ok(()),
+        };
+        let line = match result {
+            Ok(line) => {
+                log::info!("{line}");
+                line
+            }
+            Err(e) => {
+                log::warn!("{e}");
+                e.to_string()
+            }
+        };
+        ui::popup(st, "Reset the security stack", &[line])
+    }
+}
+
+/// Shows the state of every NVMe controller, including those without a usable drive
+fn nvme_diagnostics(st: &SystemTable<Boot>) -> Result {
+    let controllers = nvme_device::controllers(st);
+    if controllers.is_empty() {
+        return ui::popup(st, "NVMe controller diagnostics", &["no NVMe controllers found".to_string()]);
+    }
+    for (i, &controller) in controllers.iter().enumerate() {
+        let lines = nvme_device::diagnostics(st, controller);
+        for line in &lines {
+            log::debug!("NVMe controller {i}: {line}");
+        }
+        ui::popup(st, &format!("NVMe controller {} of {}", i + 1, controllers.len()), &lines)?;
+    }
+    Ok(())
+}
+
+/// Lists the bounds of all locking ranges, flags those not matching the drive's geometry and moves or resizes them
+struct RangeLayout;
+
+impl DriveAction for RangeLayout {
+    fn run<P: SecureProtocol>(&mut self, st: &SystemTable<Boot>, _kind: &str, drive: &mut OpalDrive<P>) -> Result
+    where opal::Error<P::Error>: Into<ErrorSource>
+    {
+        let geometry = drive.geometry();
+        let (block_size, block_count) = (drive.block_size(), drive.block_count());
+        let size = |blocks: u64| block_size.map_or_else(String::new, |block_size| {
+            format!(" ({})", util::human_size(blocks.saturating_mul(block_size as u64)))
+        });
+        let mut session = authenticate(st, drive)?;
+        let max_ranges = session.max_ranges().map_err(|e| Error::new(e, "can't read number of locking ranges"))?;
+        let names = range_names(&mut session);
+        loop {
+            let mut bounds = Vec::new();
+            for range in 1..=max_ranges {
+                let range_bounds = session.range_bounds(range)
+                    .map_err(|e| Error::new(e, format!("can't read bounds of locking range {range}")))?;
+                bounds.push(range_bounds);
+            }
+            let mut options: Vec<_> = bounds.iter().zip(1..=max_ranges)
+                .map(|(&(start, length), range)| {
+                    let mut line = format!("range {range}{}: LBA {start} + {length}{}", name_suffix(&names, range), size(length));
+                    if length == 0 {
+                        line.push_str(" (unused)");
+                    } else if let Some(Err(e)) = geometry.map(|geometry| geometry.check_range(start, length)) {
+                        line.push_str(&format!(" MISALIGNED: {e}"));
+                    }
+                    (true, line)
+                })
+                .collect();
+            options.push((true, "Back".to_string()));
+            console::clear(st)?;
+            match (block_size, block_count) {
+                (Some(block_size), Some(count)) => console::write_str(st, &format!("blocks of {block_size} bytes, {count} in total{}\r\n", size(count))),
+                (Some(block_size), None) => console::write_str(st, &format!("blocks of {block_size} bytes\r\n")),
+                _ => console::write_str(st, "the block size is unknown, sizes are in blocks only\r\n"),
+            }
+            match geometry {
+                Some(geometry) => console::write_str(st, &format!(
+                    "alignment: {} blocks{} from LBA {}{}\r\n",
+                    geometry.alignment_granularity, size(geometry.alignment_granularity), geometry.lowest_aligned_lba,
+                    if geometry.align_required { ", required by the drive" } else { "" },
+                )),
+                None => console::write_str(st, "the drive doesn't report its geometry, alignment can't be checked\r\n"),
+            }
+            console::write_str(st, "Select a range to move or resize it:\r\n");
+            let selected = ui::choose(st, &options)?;
+            let Some(&(start, length)) = bounds.get(selected) else { return Ok(()) };
+            let range = selected as u8 + 1;
+            // an unused range is offered the space after the last one
+            let (suggested_start, suggested_length) = match length {
+                0 => free_space(&bounds, geometry, block_count),
+                _ => (start, length),
+            };
+
+            console::write_str(st, &format!("\r\nStart LBA of range {range} [{suggested_start}]: "));
+            let Some(new_start) = ui::line_cancelable(st)? else { continue };
+            console::write_str(st, &format!(
+                "\r\nLength in blocks{}, 0 for unused [{suggested_length}{}]: ",
+                if block_size.is_some() { " or with a unit like 20G" } else { "" }, size(suggested_length),
+            ));
+            let Some(new_length) = ui::line_cancelable(st)? else { continue };
+            let parse = |text: &str, suggested: u64| match text.trim() {
+                "" => Ok(suggested),
+                text => parse_blocks(text, block_size),
+            };
+            let (new_start, new_length) = match (parse(&new_start, suggested_start), parse(&new_length, suggested_length)) {
+                (Ok(new_start), Ok(new_length)) => (new_start, new_length),
+                (Err(e), _) | (_, Err(e)) => {
+                    ui::popup(st, "Locking range layout", &[e])?;
+                    continue;
+                }
+            };
+            if (new_start, new_length) == (start, length) {
+                continue;
+            }
+            let mut problems = Vec::new();
+            if new_length != 0 {
+                if let Some(Err(e)) = geometry.map(|geometry| geometry.check_range(new_start, new_length)) {
+                    if geometry.map_or(false, |geometry| geometry.align_required) {
+                        ui::popup(st, "Locking range layout", &[e])?;
+                        continue;
+                    }
+                    problems.push(format!("The range is misaligned, which costs performance: {e}"));
+                }
+                let overlapping = bounds.iter().zip(1..=max_ranges)
+                    .filter(|&(&(_, length), other)| other != range && length != 0)
+                    .find(|&(&(start, length), _)| new_start < start + length && start < new_start + new_length);
+                if let Some((_, other)) = overlapping {
+                    ui::popup(st, "Locking range layout", &[format!("the new bounds overlap range {other}")])?;
+                    continue;
+                }
+            }
+            let mut warning = vec![
+                format!("Range {range} moves from LBA {start} + {length}{} to LBA {new_start} + {new_length}{}.", size(length), size(new_length)),
+                "Data in the blocks that change ranges becomes unreadable, as each range encrypts with its own key.".to_string(),
+            ];
+            warning.append(&mut problems);
+            if !ui::confirm_destructive(st, "Change locking range", &warning, "RESIZE")? {
+                continue;
+            }
+            session.set_range_bounds(range, new_start, new_length)
+                .map_err(|e| Error::new(e, format!("can't change bounds of locking range {range}")))?;
+            log::warn!("locking range {range} moved from LBA {start} + {length} to LBA {new_start} + {new_length}");
+        }
+    }
+}
+
+/// Names locking ranges, e.g. "Windows" or "Data", in the drive's DataStore table
+struct RangeNames;
+
+impl DriveAction for RangeNames {
+    fn run<P: SecureProtocol>(&mut self, st: &SystemTable<Boot>, _kind: &str, drive: &mut OpalDrive<P>) -> Result
+    where opal::Error<P::Error>: Into<ErrorSource>
+    {
+        if !drive.capabilities().contains(Capabilities::DATASTORE) {
+            return ui::popup(st, "Locking range names", &["the drive has no DataStore table to keep names in".to_string()]);
+        }
+        let mut session = authenticate(st, drive)?;
+        let max_ranges = session.max_ranges().map_err(|e| Error::new(e, "can't read number of locking ranges"))?;
+        let mut names = session.range_names().map_err(|e| Error::new(e, "can't read range names"))?;
+        loop {
+            let mut options: Vec<_> = (0..=max_ranges)
+                .map(|range| (true, format!("range {range}{}", name_suffix(&names, range))))
+                .collect();
+            options.push((true, "Back".to_string()));
+            console::clear(st)?;
+            let range = ui::choose(st, &options)?;
+            let Ok(range) = u8::try_from(range) else { return Ok(()) };
+            if range > max_ranges {
+                return Ok(());
+            }
+            console::write_str(st, &format!("\r\nName of range {range} (empty removes it): "));
+            let Some(name) = ui::line_cancelable(st)? else { continue };
+            match name.trim() {
+                "" => names.remove(&range),
+                name => names.insert(range, name.to_string()),
+            };
+            session.set_range_names(&names).map_err(|e| Error::new(e, "can't save range names"))?;
+            log::info!("named locking range {range} `{}`", name.trim());
+        }
+    }
+}
+
+/// Start and length of the space after the last used range, aligned if the drive reports its geometry;
+/// without the drive's capacity the length is 0
+fn free_space(bounds: &[(u64, u64)], geometry: Option<opal::Geometry>, block_count: Option<u64>) -> (u64, u64) {
+    let end = bounds.iter()
+        .filter(|&&(_, length)| length != 0)
+        .map(|&(start, length)| start + length)
+        .max()
+        .unwrap_or(0);
+    let start = geometry.map_or(end, |geometry| geometry.nearest_aligned(end).1);
+    let mut limit = block_count.unwrap_or(start);
+    if let Some(geometry) = geometry {
+        limit = geometry.nearest_aligned(limit).0;
+    }
+    (start, limit.saturating_sub(start))
+}
+
+/// A number of blocks, or of bytes with a K, M, G or T suffix (binary units) that must be whole blocks
+fn parse_blocks(text: &str, block_size: Option<u32>) -> core::result::Result<u64, String> {
+    let text = text.trim();
+    let digits = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let (number, unit) = text.split_at(digits);
+    let number: u64 = number.parse().map_err(|_| format!("`{text}` is not a number"))?;
+    let shift = match unit.trim().to_ascii_uppercase().trim_end_matches("IB").trim_end_matches('B') {
+        "" if unit.is_empty() => return Ok(number),
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(format!("unknown unit `{unit}`, use K, M, G or T")),
+    };
+    let block_size = block_size.ok_or("the block size is unknown, give the length in blocks")? as u64;
+    let bytes = number.checked_mul(1 << shift).ok_or("too large")?;
+    match bytes % block_size {
+        0 => Ok(bytes / block_size),
+        _ => Err(format!("{} is not a whole number of {block_size} byte blocks", util::human_size(bytes))),
+    }
+}
+
+/// Turns read and write locking of single locking ranges on and off
+struct LockEnabled;
+
+impl DriveAction for LockEnabled {
+    fn run<P: SecureProtocol>(&mut self, st: &SystemTable<Boot>, _kind: &str, drive: &mut OpalDrive<P>) -> Result
+    where opal::Error<P::Error>: Into<ErrorSource>
+    {
+        let on_off = |enabled: bool| if enabled { "on" } else { "off" };
+        let mut session = authenticate(st, drive)?;
+        let max_ranges = session.max_ranges().map_err(|e| Error::new(e, "can't read number of locking ranges"))?;
+        let names = range_names(&mut session);
+        loop {
+            let mut states = Vec::new();
+            for range in 0..=max_ranges {
+                let state = session.lock_enabled(range)
+                    .map_err(|e| Error::new(e, format!("can't read locking of range {range}")))?;
+                states.push(state);
+            }
+            let mut options: Vec<_> = states.iter().zip(0..=max_ranges)
+                .map(|(&(read, write), range)| (true, format!(
+                    "range {range}{}: read locking {}, write locking {}",
+                    name_suffix(&names, range), on_off(read), on_off(write),
+                )))
+                .collect();
+            options.push((true, "Back".to_string()));
+            console::clear(st)?;
+            let selected = ui::choose(st, &options)?;
+            let Some(&(read, write)) = states.get(selected) else { return Ok(()) };
+            let range = selected as u8;
+            let toggles = vec![
+                (true, format!("Turn read locking {}", on_off(!read))),
+                (true, format!("Turn write locking {}", on_off(!write))),
+                (true, format!("Turn both {}", on_off(!(read || write)))),
+                (true, "Back".to_string()),
+            ];
+            console::clear(st)?;
+            console::write_str(st, &format!("Range {range}{}:\r\n", name_suffix(&names, range)));
+            let (read, write) = match ui::choose(st, &toggles)? {
+                0 => (!read, write),
+                1 => (read, !write),
+                2 => (!(read || write), !(read || write)),
+                _ => continue,
+            };
+            session.set_lock_enabled(range, read, write)
+                .map_err(|e| Error::new(e, format!("can't change locking of range {range}")))?;
+            log::warn!("locking range {range}: read locking {}, write locking {}", on_off(read), on_off(write));
+        }
+    }
+}
+
+/// Range names for display; none if the drive can't provide them
+fn range_names<P: SecureProtocol>(session: &mut AdminSession<'_, P>) -> BTreeMap<u8, String> {
+    session.range_names().unwrap_or_else(|e| {
+        log::debug!("can't read range names: {e:?}");
+        BTreeMap::new()
+    })
+}
+
+/// ` (name)` if the range has one
+fn name_suffix(names: &BTreeMap<u8, String>, range: u8) -> String {
+    names.get(&range).map_or_else(String::new, |name| format!(" ({name})"))
+}
+
+/// Grants and revokes User authorities access to a locking range by editing its ACEs
+struct AceEditor;
+
+impl DriveAction for AceEditor {
+    fn run<P: SecureProtocol>(&mut self, st: &SystemTable<Boot>, _kind: &str, drive: &mut OpalDrive<P>) -> Result
+    where opal::Error<P::Error>: Into<ErrorSource>
+    {
+        let mut session = authenticate(st, drive)?;
+        console::write_str(st, "Locking range (0 = global): ");
+        let Some(range) = ui::line_cancelable(st)? else { return Ok(()) };
+        let range: u8 = range.trim().parse()
+            .map_err(|_| Error::new_without_source(format!("invalid locking range `{range}`")))?;
+        let name = name_suffix(&range_names(&mut session), range);
+
+        let options = vec![
+            (true, "Grant a user access".to_string()),
+            (true, "Revoke a user's access".to_string()),
+            (true, "Back".to_string()),
+        ];
+        loop {
+            let read = session.locking_ace(range, Ace::ReadLocked).map_err(|e| Error::new(e, "can't read ReadLocked ACE"))?;
+            let write = session.locking_ace(range, Ace::WriteLocked).map_err(|e| Error::new(e, "can't read WriteLocked ACE"))?;
+            let join = |authorities: &[Authority]| authorities.iter().map(|a| a.to_string()).collect::<Vec<_>>().join(" OR ");
+            console::clear(st)?;
+            console::write_str(st, &format!(
+                "Locking range {range}{name}\r\n  may set ReadLocked:  {}\r\n  may set WriteLocked: {}\r\n\r\n",
+                join(&read), join(&write),
+            ));
+            let grant = match ui::choose(st, &options)? {
+                0 => true,
+                1 => false,
+                _ => return Ok(()),
+            };
+            console::write_str(st, "User number: ");
+            let Some(user) = ui::line_cancelable(st)? else { continue };
+            let Ok(user) = user.trim().parse::<u8>() else {
+                ui::popup(st, "ACE editor", &[format!("invalid user number `{}`", user.trim())])?;
+                continue;
+            };
+            let user = Authority::user(user);
+            for (ace, mut authorities) in [(Ace::ReadLocked, read), (Ace::WriteLocked, write)] {
+                match grant {
+                    true if !authorities.contains(&user) => authorities.push(user),
+                    true => continue,
+                    false => authorities.retain(|&a| a != user),
+                }
+                if authorities.is_empty() {
+                    ui::popup(st, "ACE editor", &[format!("refusing to leave {ace:?} of range {range} without any authority")])?;
+                    continue;
+                }
+                session.set_locking_ace(range, ace, &authorities)
+                    .map_err(|e| Error::new(e, "can't update ACE"))?;
+            }
+            log::info!("{} {user} access to locking range {range}", if grant { "granted" } else { "revoked" });
+        }
+    }
+}
+
+/// Enables and disables Admin2..4 and User1..8 and sets their PINs
+struct Authorities;
+
+impl DriveAction for Authorities {
+    fn run<P: SecureProtocol>(&mut self, st: &SystemTable<Boot>, _kind: &str, drive: &mut OpalDrive<P>) -> Result
+    where opal::Error<P::Error>: Into<ErrorSource>
+    {
+        // Opal requires at least 4 admins and 8 users
+        let authorities: Vec<_> = (1..=4).map(Authority::admin).chain((1..=8).map(Authority::user)).collect();
+        let mut session = authenticate(st, drive)?;
+        loop {
+            let mut options = Vec::new();
+            let mut states = Vec::new();
+            for &authority in &authorities {
+                let enabled = session.authority_enabled(authority)
+                    .map_err(|e| Error::new(e, format!("can't read whether {authority} is enabled")))?;
+                options.push((true, format!("{authority}: {}", if enabled { "enabled" } else { "disabled" })));
+                states.push(enabled);
+            }
+            options.push((true, "Back".to_string()));
+            console::clear(st)?;
+            let selected = ui::choose(st, &options)?;
+            let Some(&authority) = authorities.get(selected) else { return Ok(()) };
+            if authority == Authority::admin(1) {
+                ui::popup(st, "Authorities", &["Admin1 is the authority the greeter unlocks with and is not changed here".to_string()])?;
+                continue;
+            }
+
+            let enabled = states[selected];
+            let actions = vec![
+                (true, if enabled { format!("Disable {authority}") } else { format!("Enable {authority}") }),
+                (true, format!("Set PIN of {authority}")),
+                (true, "Bad attempt limit".to_string()),
+                (true, "Back".to_string()),
+            ];
+            console::write_str(st, "\r\n");
+            match ui::choose(st, &actions)? {
+                0 => session.set_authority_enabled(authority, !enabled)
+                    .map_err(|e| Error::new(e, format!("can't change whether {authority} is enabled")))?,
+                1 => if let Some(pin) = new_password(st, &format!("{authority}"))? {
+                    session.set_pin(authority, PasswordOrRaw::Password(pin.as_bytes()))
+                        .map_err(|e| Error::new(e, format!("can't set PIN of {authority}")))?;
+                    log::info!("set PIN of {authority}");
+                },
+                2 => pin_limits(st, &mut session, authority)?,
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Lists the provisioning of the Locking SP without changing it: which authorities are enabled, their
+/// C_PIN try limits, and who may lock and unlock each range
+struct ProvisioningAudit;
+
+impl DriveAction for ProvisioningAudit {
+    fn run<P: SecureProtocol>(&mut self, st: &SystemTable<Boot>, kind: &str, drive: &mut OpalDrive<P>) -> Result
+    where opal::Error<P::Error>: Into<ErrorSource>
+    {
+        let serial = serial_str(drive.serial());
+        let mut session = authenticate(st, drive)?;
+        let mut text = format!("Provisioning of {kind} drive {serial}\n\nAuthorities:\n");
+        // Opal requires at least 4 admins and 8 users
+        for authority in (1..=4).map(Authority::admin).chain((1..=8).map(Authority::user)) {
+            let enabled = match session.authority_enabled(authority) {
+                Ok(true) => "enabled",
+                Ok(false) => "disabled",
+                Err(e) => {
+                    log::debug!("can't read whether {authority} is enabled: {e:?}");
+                    "unknown"
+                }
+            };
+            let limits = match session.pin_limits(authority) {
+                Ok(limits) if limits.try_limit == 0 => format!("tries {}, unlimited", limits.tries),
+                Ok(limits) => format!(
+                    "tries {} of {}{}",
+                    limits.tries, limits.try_limit, if limits.persistence { ", persistent" } else { "" },
+                ),
+                Err(e) => {
+                    log::debug!("can't read C_PIN of {authority}: {e:?}");
+                    "C_PIN not readable".to_string()
+                }
+            };
+            text.push_str(&format!("  {authority}: {enabled}, {limits}\n"));
+        }
+
+        let max_ranges = session.max_ranges().map_err(|e| Error::new(e, "can't read the number of locking ranges"))?;
+        let names = range_names(&mut session);
+        text.push_str("\nLocking ranges, who may set ReadLocked / WriteLocked:\n");
+        for range in 0..=max_ranges {
+            let mut aces = Vec::new();
+            for ace in [Ace::ReadLocked, Ace::WriteLocked].iter() {
+                aces.push(match session.locking_ace(range, *ace) {
+                    Ok(authorities) if authorities.is_empty() => "nobody".to_string(),
+                    Ok(authorities) => authorities.iter().map(Authority::to_string).collect::<Vec<_>>().join(" or "),
+                    Err(e) => {
+                        log::debug!("can't read {ace:?} of range {range}: {e:?}");
+                        "not readable".to_string()
+                    }
+                });
+            }
+            let range_name = match range {
+                0 => "global range".to_string(),
+                range => format!("range {range}"),
+            };
+            text.push_str(&format!("  {range_name}{}: {} / {}\n", name_suffix(&names, range), aces[0], aces[1]));
+        }
+        logging::page(st, &text)
+    }
+}
+
+/// Shows and edits C_PIN's TryLimit and Persistence of the authority
+fn pin_limits<P: SecureProtocol>(st: &SystemTable<Boot>, session: &mut AdminSession<'_, P>, authority: Authority) -> Result
+where opal::Error<P::Error>: Into<ErrorSource>
+{
+    let limits = session.pin_limits(authority)
+        .map_err(|e| Error::new(e, format!("can't read C_PIN of {authority}")))?;
+    console::clear(st)?;
+    console::write_str(st, &format!(
+        "{authority}\r\n  TryLimit:    {}\r\n  Tries:       {}\r\n  Persistence: {}\r\n\r\n\
+        New TryLimit (0 = unlimited, empty keeps it): ",
+        limits.try_limit, limits.tries, limits.persistence,
+    ));
+    let Some(try_limit) = ui::line_cancelable(st)? else { return Ok(()) };
+    let try_limit = match try_limit.trim() {
+        "" => limits.try_limit,
+        limit => limit.parse().map_err(|_| Error::new_without_source(format!("invalid TryLimit `{limit}`")))?,
+    };
+    console::write_str(st, "Keep tries across power cycles? (y/n, empty keeps it): ");
+    let Some(persistence) = ui::line_cancelable(st)? else { return Ok(()) };
+    let persistence = match persistence.trim() {
+        "" => limits.persistence,
+        "y" | "Y" => true,
+        "n" | "N" => false,
+        other => return Err(Error::new_without_source(format!("expected y or n, got `{other}`"))),
+    };
+    session.set_pin_limits(authority, try_limit, persistence)
+        .map_err(|e| Error::new(e, format!("can't set bad attempt limit of {authority}; the drive may not allow changing it")))?;
+    log::info!("{authority}: TryLimit {try_limit}, Persistence {persistence}");
+    Ok(())
+}
+
+/// Lets the admin pick a quorum keyslot and a drive, then sets up the drive for it
+fn quorum_setup(st: &SystemTable<Boot>, config: &Config) -> Result {
+    let quorums: Vec<(&str, &Quorum)> = config.keyslots.values()
+        .filter_map(|keyslot| match &keyslot.source {
+            KeyslotSource::Quorum(quorum) => Some((keyslot.name.as_str(), quorum)),
+            _ => None,
+        })
+        .collect();
+    if quorums.is_empty() {
+        return ui::popup(st, "Quorum keyslot setup", &["no keyslot with a quorum is configured".to_string()]);
+    }
+    let mut options: Vec<_> = quorums.iter()
+        .map(|(name, quorum)| (true, format!("{name}: {} of {}", quorum.threshold, quorum.members.join(", "))))
+        .collect();
+    options.push((true, "Back".to_string()));
+    console::clear(st)?;
+    console::write_str(st, "Select keyslot:\r\n");
+    let Some(&(name, quorum)) = quorums.get(ui::choose(st, &options)?) else { return Ok(()) };
+    let Some(serial) = select_drive(st, config)? else { return Ok(()) };
+    for_each_drive(st, config, Some(&serial), &mut QuorumSetup { config, name, quorum })
+}
+
+/// Replaces Admin1's password with a random key split among the members of a quorum keyslot
+struct QuorumSetup<'a> {
+    config: &'a Config,
+    name: &'a str,
+    quorum: &'a Quorum,
+}
+
+impl DriveAction for QuorumSetup<'_> {
+    fn run<P: SecureProtocol>(&mut self, st: &SystemTable<Boot>, _kind: &str, drive: &mut OpalDrive<P>) -> Result
+    where opal::Error<P::Error>: Into<ErrorSource>
+    {
+        let QuorumSetup { config, name, quorum } = *self;
+        let serial = serial_str(drive.serial());
+        let mut session = authenticate(st, drive)?;
+        let warning = [
+            format!("Admin1's password of drive {serial} will be replaced by a random key."),
+            format!("Afterwards {} of {} are needed to unlock it.", quorum.threshold, quorum.members.join(", ")),
+            format!("Keyslots other than {name} stop working for this drive."),
+        ];
+        if !ui::confirm_destructive(st, "Set up quorum keyslot", &warning, &serial)? {
+            return Ok(());
+        }
+
+        let mut secrets = Vec::new();
+        for member in &quorum.members {
+            let keyslot = config.keyslots.get(member)
+                .ok_or_else(|| Error::new_without_source(format!("keyslot {name}: unknown member keyslot `{member}`")))?;
+            let secret = match keyslot.source {
+                KeyslotSource::Stdin => match new_password(st, member)? {
+                    Some(password) => password.into_bytes(),
+                    None => return Ok(()),
+                },
+                _ => crate::get_password_of_keyslot(st, config, keyslot, Cache::Discard)?,
+            };
+            config.keyslot_buffer.borrow_mut().remove(member);
+            secrets.push(secret);
+        }
+        let res = quorum::create(st, config, name, quorum, &secrets);
+        for secret in &mut secrets {
+            opal::wipe(secret);
+        }
+        let mut key = res?;
+        let res = session.set_pin(Authority::admin(1), PasswordOrRaw::Raw(&key))
+            .map_err(|e| Error::new(e, "can't set PIN of Admin1"));
+        opal::wipe(&mut key);
+        res?;
+        log::info!("drive {serial} now unlocks with quorum keyslot {name}");
+        ui::popup(st, "Quorum keyslot setup", &[
+            format!("drive {serial} now needs {} of {} to unlock", quorum.threshold, quorum.members.join(", ")),
+        ])
+    }
+}
+
+/// Replaces SID's factory PIN, the MSID anyone can read, with a password; the first step of setting up a new drive
+struct TakeOwnership;
+
+impl DriveAction for TakeOwnership {
+    fn run<P: SecureProtocol>(&mut self, st: &SystemTable<Boot>, kind: &str, drive: &mut OpalDrive<P>) -> Result
+    where opal::Error<P::Error>: Into<ErrorSource>
+    {
+        let serial = serial_str(drive.serial());
+        if drive.ssc() == Ssc::Enterprise {
+            return ui::popup(st, "Take ownership", &[format!("{kind} drive {serial} is an Enterprise SSC drive, which has no MSID")]);
+        }
+        if let Some(block_sid) = drive.discovery().block_sid {
+            if block_sid.sid_pin_changed {
+                return ui::popup(st, "Take ownership", &[format!("{kind} drive {serial} already has an owner")]);
+            }
+            if block_sid.sid_blocked {
+                log::warn!("the firmware blocked authenticating as SID on drive {serial}");
+                return ui::popup(st, "Take ownership", &[
+                    format!("the firmware blocked authenticating as SID on {kind} drive {serial}"),
+                    "disable Block SID in the firmware setup or power cycle the drive, then try again".to_string(),
+                ]);
+            }
+        }
+        console::clear(st)?;
+        let Some(password) = new_password(st, "SID")? else { return Ok(()) };
+        match drive.take_ownership(PasswordOrRaw::Password(password.as_bytes())) {
+            Ok(()) => {
+                log::warn!("took ownership of drive {serial}");
+                ui::popup(st, "Take ownership", &[
+                    format!("SID of {kind} drive {serial} now has the new password"),
+                    "activating the Locking SP makes it the Admin1 password as well".to_string(),
+                ])
+            }
+            Err(opal::Error::Opal { source: opal::OpalError::Status { code: opal::StatusCode::NOT_AUTHORIZED }, .. }) => {
+                log::error!("the MSID of drive {serial} was rejected, it already has an owner");
+                ui::popup(st, "Take ownership", &[format!("{kind} drive {serial} already has an owner, SID's password is unchanged")])
+            }
+            Err(e) => Err(Error::new(e, format!("can't take ownership of drive {serial}"))),
+        }
+    }
+}
+
+/// Reverts the drive to factory state with the PSID printed on its label; the recovery when all passwords are lost
+struct PsidRevert;
+
+impl DriveAction for PsidRevert {
+    fn run<P: SecureProtocol>(&mut self, st: &SystemTable<Boot>, kind: &str, drive: &mut OpalDrive<P>) -> Result
+    where opal::Error<P::Error>: Into<ErrorSource>
+    {
+        let serial = serial_str(drive.serial());
+        console::clear(st)?;
+        console::write_str(st, &format!("PSID of {kind} {serial} (32 characters from the drive's label): "));
+        let Some(psid) = ui::line_cancelable(st)? else { return Ok(()) };
+        let psid: String = psid.chars().filter(|c| !c.is_whitespace() && *c != '-').collect::<String>().to_uppercase();
+        if psid.len() != 32 {
+            return ui::popup(st, "PSID revert", &[format!("a PSID has 32 characters, got {}", psid.len())]);
+        }
+        let warning = [
+            format!("ALL DATA on {kind} drive {serial} will be destroyed."),
+            "All passwords, locking ranges and the shadow MBR are reset to factory state.".to_string(),
+            "This can't be undone.".to_string(),
+        ];
+        if !ui::confirm_destructive(st, "PSID revert", &warning, &serial)? || !power::check(st, "PSID revert")? {
+            return Ok(());
+        }
+        match drive.revert_with_psid(psid.as_bytes()) {
+            Ok(()) => {
+                log::warn!("drive {serial} reverted to factory state with its PSID");
+                ui::popup(st, "PSID revert", &[format!("drive {serial} was reverted to factory state")])
+            }
+            Err(opal::Error::Opal { source: opal::OpalError::Status { code: opal::StatusCode::NOT_AUTHORIZED }, .. }) => {
+                log::error!("wrong PSID for drive {serial}");
+                ui::popup(st, "PSID revert", &[format!("the PSID was rejected, drive {serial} is unchanged")])
+            }
+            Err(e) => Err(Error::new(e, format!("can't revert drive {serial}"))),
+        }
+    }
+}
+
+/// Replaces the key of one locking range, destroying its data while the rest of the drive stays as it is
+struct CryptoErase;
+
+impl DriveAction for CryptoErase {
+    fn run<P: SecureProtocol>(&mut self, st: &SystemTable<Boot>, kind: &str, drive: &mut OpalDrive<P>) -> Result
+    where opal::Error<P::Error>: Into<ErrorSource>
+    {
+        let serial = serial_str(drive.serial());
+        let mut session = authenticate(st, drive)?;
+        let max_ranges = session.max_ranges().map_err(|e| Error::new(e, "can't read number of locking ranges"))?;
+        let names = range_names(&mut session);
+        let mut options: Vec<_> = (0..=max_ranges)
+            .map(|range| (true, format!("range {range}{}", name_suffix(&names, range))))
+            .collect();
+        options.push((true, "Back".to_string()));
+        console::clear(st)?;
+        console::write_str(st, "Cryptographically erase:\r\n");
+        let Ok(range) = u8::try_from(ui::choose(st, &options)?) else { return Ok(()) };
+        if range > max_ranges {
+            return Ok(());
+        }
+        let what = match range {
+            0 => "the global range, i.e. everything outside the other locking ranges".to_string(),
+            range => format!("locking range {range}{}", name_suffix(&names, range)),
+        };
+        let warning = [
+            format!("ALL DATA in {what} on {kind} drive {serial} will be destroyed."),
+            "The range gets a new encryption key, the old data can't be recovered.".to_string(),
+            "Passwords and the other ranges are unchanged.".to_string(),
+        ];
+        if !ui::confirm_destructive(st, "Cryptographic erase", &warning, "ERASE")? {
+            return Ok(());
+        }
+        session.crypto_erase(range).map_err(|e| Error::new(e, format!("can't erase locking range {range}")))?;
+        log::warn!("locking range {range} of drive {serial} cryptographically erased");
+        ui::popup(st, "Cryptographic erase", &[format!("{what} was erased")])
+    }
+}
+
+/// Turns locking off by reverting the Locking SP, optionally keeping the data in the global range
+struct RevertLockingSp;
+
+impl DriveAction for RevertLockingSp {
+    fn run<P: SecureProtocol>(&mut self, st: &SystemTable<Boot>, kind: &str, drive: &mut OpalDrive<P>) -> Result
+    where opal::Error<P::Error>: Into<ErrorSource>
+    {
+        let serial = serial_str(drive.serial());
+        let options = vec![
+            (true, "Keep the data in the global range (KeepGlobalRangeKey)".to_string()),
+            (true, "Erase all locking ranges".to_string()),
+            (true, "Back".to_string()),
+        ];
+        console::clear(st)?;
+        console::write_str(st, &format!("Revert the Locking SP of {kind} drive {serial}:\r\n"));
+        let keep_global_range_key = match ui::choose(st, &options)? {
+            0 => true,
+            1 => false,
+            _ => return Ok(()),
+        };
+        let mut warning = vec![
+            "Locking is turned off: Admin and User passwords, locking ranges and the shadow MBR are reset.".to_string(),
+            "The SID password stays, the Locking SP can be activated again later.".to_string(),
+        ];
+        warning.push(match keep_global_range_key {
+            true => "The global range must be unlocked. Its data stays, the data in all other ranges is destroyed.".to_string(),
+            false => format!("ALL DATA on {kind} drive {serial} will be destroyed."),
+        });
+        if !ui::confirm_destructive(st, "Revert the Locking SP", &warning, &serial)?
+            || (!keep_global_range_key && !power::check(st, "reverting the Locking SP")?)
+        {
+            return Ok(());
+        }
+        let session = authenticate(st, drive)?;
+        session.revert_sp(keep_global_range_key).map_err(|e| Error::new(e, format!("can't revert the Locking SP of drive {serial}")))?;
+        log::warn!("Locking SP of drive {serial} reverted, global range key kept: {keep_global_range_key}");
+        ui::popup(st, "Revert the Locking SP", &[format!("locking is off on drive {serial}")])
+    }
+}
+
+/// Asks for a new password twice; `None` if they don't match
+pub fn new_password(st: &SystemTable<Boot>, whom: &str) -> Result<Option<String>> {
+    console::write_str(st, &format!("New password for {whom}: "));
+    let first = ui::password(st)?;
+    console::write_str(st, "Repeat: ");
+    let second = ui::password(st)?;
+    if !opal::constant_time_eq(first.as_bytes(), second.as_bytes()) {
+        ui::popup(st, "Passwords don't match", &[format!("the password of {whom} was not changed")])?;
+        return Ok(None);
+    }
+    Ok(Some(first))
+}
+
+/// identify strings are space-padded ASCII
+pub fn serial_str(serial: &[u8]) -> String {
+    String::from_utf8_lossy(serial).trim().to_string()
+}
@@ -0,0 +1,113 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use opal::{OpalDrive, PasswordOrRaw, SecureProtocol};
+use uefi::Handle;
+use uefi::table::{Boot, SystemTable};
+use crate::config::{Config, Keyslot, KeyslotSource};
+use crate::error::ErrorSource;
+use crate::low_level::nvme_device::RestartableNvmeDevice;
+use crate::{watchdog, Cache, Error, Result};
+
+/// Unlocks drives whose credentials need no interaction, one per step, while the menu waits for keys.
+///
+/// Anything that goes wrong is only logged; the drive is then left for the foreground unlock,
+/// which has the prompts and error handling.
+pub struct BackgroundUnlock<'a> {
+    st: &'a SystemTable<Boot>,
+    config: &'a Config,
+    pending: Vec<Handle>,
+}
+
+impl<'a> BackgroundUnlock<'a> {
+    pub fn new(st: &'a SystemTable<Boot>, config: &'a Config) -> Self {
+        let pending = match config.background_unlock {
+            true => crate::block_devices(st).unwrap_or_default()
+                .into_iter()
+                .map(|(handle, _, _)| handle)
+                .collect(),
+            false => Vec::new(),
+        };
+        BackgroundUnlock { st, config, pending }
+    }
+
+    /// Tries the next drive; returns whether there's anything left to do
+    pub fn step(&mut self) -> bool {
+        let Some(blockio_handle) = self.pending.pop() else { return false };
+        if let Err(e) = self.unlock(blockio_handle) {
+            log::debug!("background unlock: {e}");
+        }
+        !self.pending.is_empty()
+    }
+
+    fn unlock(&self, blockio_handle: Handle) -> Result {
+        let (st, config) = (self.st, self.config);
+        if let Some(nvme) = crate::try_get_nvme_device(st, blockio_handle)? {
+            let Some(keyslot) = self.keyslot(nvme.serial_num()) else { return Ok(()) };
+            let drive = OpalDrive::new(RestartableNvmeDevice::new(&nvme, st, blockio_handle))
+                .map_err(|e| Error::new(e, "open opal"))?;
+            unlock_drive(st, config, drive, keyslot)
+        } else if let Some(ata) = crate::try_get_ata_device(st, blockio_handle)? {
+            let Some(keyslot) = self.keyslot(ata.serial()) else { return Ok(()) };
+            unlock_drive(st, config, ata, keyslot)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// the keyslot of the configured drive with this serial, if it can be used without interaction
+    fn keyslot(&self, serial: &[u8]) -> Option<&'a Keyslot> {
+        let config = self.config;
+        let serial = String::from_utf8_lossy(serial);
+        let partition = config.partitions.values().find(|part| part.uuid == serial.trim())?;
+        let keyslot = config.keyslots.get(partition.keyslot.as_deref()?)?;
+        match needs_interaction(config, keyslot) {
+            true => {
+                log::debug!("background unlock: keyslot {} of `{}` needs interaction", keyslot.name, partition.name);
+                None
+            }
+            false => Some(keyslot),
+        }
+    }
+}
+
+fn unlock_drive<P: SecureProtocol>(st: &SystemTable<Boot>, config: &Config, mut drive: OpalDrive<P>, keyslot: &Keyslot) -> Result
+where opal::Error<P::Error>: Into<ErrorSource>
+{
+    if !drive.was_locked() {
+        return Ok(());
+    }
+    drive.force(config.features.forced());
+    let password = crate::get_password_of_keyslot(st, config, keyslot, Cache::Cached)?;
+    let password_or_raw = match keyslot.source {
+        KeyslotSource::Stdin => PasswordOrRaw::Password(&password),
+        KeyslotSource::File(_) | KeyslotSource::Quorum(_) => PasswordOrRaw::Raw(&password),
+    };
+    let watchdog = config.unlock_watchdog.map(|timeout| watchdog::arm(st, timeout));
+    let res = drive.unlock(password_or_raw);
+    drop(watchdog);
+    res.map_err(|e| Error::new(e, "can't unlock drive in the background"))?;
+    log::info!("unlocked drive for keyslot {} in the background", keyslot.name);
+    Ok(())
+}
+
+/// Whether getting the keyslot's password may prompt, directly or to unlock the partitions holding its keyfile
+fn needs_interaction(config: &Config, keyslot: &Keyslot) -> bool {
+    if config.keyslot_buffer.borrow().contains_key(&keyslot.name) {
+        return false;
+    }
+    match &keyslot.source {
+        KeyslotSource::Stdin | KeyslotSource::Quorum(_) => true,
+        KeyslotSource::File(file) => core::iter::once(&file.partition)
+            .chain(&file.extra_partitions)
+            .any(|partition| partition_needs_interaction(config, partition)),
+    }
+}
+
+fn partition_needs_interaction(config: &Config, name: &str) -> bool {
+    let Some(partition) = config.partitions.get(name) else { return true };
+    let keyslot = partition.keyslot.as_ref()
+        .map_or(false, |keyslot| config.keyslots.get(keyslot).map_or(true, |keyslot| needs_interaction(config, keyslot)));
+    let parent = partition.parent.as_ref()
+        .map_or(false, |parent| partition_needs_interaction(config, parent));
+    keyslot || parent
+}
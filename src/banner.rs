@@ -0,0 +1,70 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use uefi::proto::console::text::{Key, ScanCode};
+use uefi::table::{Boot, SystemTable};
+use uefi::{CString16, Handle};
+use crate::{console, ui, util, Result, Context};
+
+/// Shows the banner file page by page and returns once the last page got acknowledged with Enter,
+/// or right away if the file can't be read
+pub fn show(st: &SystemTable<Boot>, image_handle: Handle, file: &str) -> Result {
+    let path = CString16::try_from(&*file.replace('/', "\\"))
+        .context("banner file path is not valid UTF-16")?;
+    let volume = crate::config::image_volume(image_handle, st)?;
+    let content = match util::read_full_file(st, volume, &path) {
+        Ok(content) => content,
+        // a banner lost from the volume mustn't keep the machine from booting
+        Err(e) => {
+            log::warn!("can't read banner `{file}`, continuing without it: {e}");
+            return Ok(());
+        }
+    };
+    let content = String::from_utf8_lossy(&content);
+
+    let (columns, rows) = console::size(st);
+    let lines = wrap(&content, columns.saturating_sub(1).max(1));
+    // keep two rows for the footer
+    let pages: Vec<&[String]> = lines.chunks(rows.saturating_sub(3).max(1)).collect();
+    let pages = if pages.is_empty() { vec![&[][..]] } else { pages };
+
+    let mut page = 0;
+    loop {
+        console::clear(st)?;
+        let mut output = String::new();
+        for line in pages[page] {
+            output.push_str(line);
+            output.push_str("\r\n");
+        }
+        let last = page + 1 == pages.len();
+        output.push_str(&format!("\r\n[page {}/{}] ", page + 1, pages.len()));
+        match last {
+            false => output.push_str("any key: next page, Up: previous page"),
+            true => output.push_str("Enter: acknowledge, Up: previous page"),
+        }
+        console::write_str(st, &output);
+
+        match ui::key(st)? {
+            Key::Special(ScanCode::UP | ScanCode::PAGE_UP) => page = page.saturating_sub(1),
+            Key::Printable(k) if last && [0xD, 0xA].contains(&u16::from(k)) => break,
+            _ if !last => page += 1,
+            _ => (),
+        }
+    }
+    log::info!("banner `{file}` acknowledged");
+    console::clear(st)?;
+    Ok(())
+}
+
+/// splits the text into lines of at most `width` characters, expanding tabs
+pub fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    for line in text.lines() {
+        let line = line.replace('\t', "    ");
+        let chars: Vec<char> = line.chars().filter(|c| !c.is_control()).collect();
+        if chars.is_empty() {
+            lines.push(String::new());
+        }
+        lines.extend(chars.chunks(width).map(|chunk| chunk.iter().collect::<String>()));
+    }
+    lines
+}
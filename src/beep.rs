@@ -0,0 +1,68 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use crate::util::sleep;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Copy, Clone)]
+pub enum Cue {
+    /// one short high beep
+    ReadyForPassword,
+    /// two low beeps
+    WrongPassword,
+    /// three long low beeps
+    Fatal,
+}
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn cue(cue: Cue) {
+    if !ENABLED.load(Ordering::Relaxed) || crate::safe_mode::active() {
+        return;
+    }
+    log::trace!("beeping {cue:?}");
+    let (hz, millis, count) = match cue {
+        Cue::ReadyForPassword => (1760, 100, 1),
+        Cue::WrongPassword => (440, 150, 2),
+        Cue::Fatal => (220, 600, 3),
+    };
+    for i in 0..count {
+        if i != 0 {
+            sleep(Duration::from_millis(100));
+        }
+        tone(hz, Duration::from_millis(millis));
+    }
+}
+
+/// Plays a tone on the legacy PC speaker via PIT channel 2
+#[cfg(target_arch = "x86_64")]
+fn tone(hz: u32, duration: Duration) {
+    use core::arch::asm;
+
+    unsafe fn outb(port: u16, value: u8) {
+        asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags));
+    }
+    unsafe fn inb(port: u16) -> u8 {
+        let value: u8;
+        asm!("in al, dx", out("al") value, in("dx") port, options(nomem, nostack, preserves_flags));
+        value
+    }
+
+    const PIT_FREQUENCY: u32 = 1_193_182;
+    let divisor = (PIT_FREQUENCY / hz) as u16;
+    unsafe {
+        // channel 2, lobyte/hibyte, square wave
+        outb(0x43, 0xb6);
+        outb(0x42, divisor as u8);
+        outb(0x42, (divisor >> 8) as u8);
+        let gate = inb(0x61);
+        outb(0x61, gate | 0x03);
+        sleep(duration);
+        outb(0x61, gate & !0x03);
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn tone(_hz: u32, _duration: Duration) {}
@@ -0,0 +1,155 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use uefi::proto::media::file::{File as _, FileAttribute, FileInfo, FileMode, FileType};
+use uefi::proto::media::fs::SimpleFileSystem;
+use uefi::table::boot::{AllocateType, MemoryType};
+use uefi::table::runtime::{ResetType, RuntimeServices};
+use uefi::table::{Boot, SystemTable};
+use uefi::{cstr16, CStr16, CString16, Guid, Status};
+use crate::{config, logging, ui, util, Context, Error, Result};
+
+/// where capsule-on-disk updates live by convention
+const DIRECTORY: &CStr16 = cstr16!("\\EFI\\UpdateCapsule");
+const FLAG_PERSIST_ACROSS_RESET: u32 = 0x0001_0000;
+const FLAG_INITIATE_RESET: u32 = 0x0004_0000;
+const HEADER_SIZE: usize = 28;
+
+/// EFI_CAPSULE_BLOCK_DESCRIPTOR; a zero length ends the list
+#[repr(C)]
+struct BlockDescriptor {
+    length: u64,
+    address: u64,
+}
+
+/// leading part of EFI_RUNTIME_SERVICES up to the capsule services, which uefi-rs doesn't wrap
+#[repr(C)]
+struct RawRuntimeServices {
+    _header: [u8; 24],
+    /// GetTime up to and including ResetSystem
+    _before: [usize; 11],
+    update_capsule: unsafe extern "efiapi" fn(headers: *const *const c_void, count: usize, scatter_gather_list: u64) -> Status,
+    query_capsule_capabilities: unsafe extern "efiapi" fn(headers: *const *const c_void, count: usize, max_size: *mut u64, reset_type: *mut u32) -> Status,
+}
+
+/// Names of the capsule files in `\EFI\UpdateCapsule` on the greeter's volume
+pub fn find(st: &SystemTable<Boot>) -> Vec<String> {
+    if st.uefi_revision().major() < 2 {
+        return Vec::new();
+    }
+    match list(st) {
+        Ok(capsules) => capsules,
+        Err(e) => {
+            log::debug!("no firmware capsules: {e}");
+            Vec::new()
+        }
+    }
+}
+
+fn list(st: &SystemTable<Boot>) -> Result<Vec<String>> {
+    let volume = config::image_volume(st.boot_services().image_handle(), st)?;
+    let mut sfs = st.boot_services()
+        .open_protocol_exclusive::<SimpleFileSystem>(volume)
+        .context("can't get SimpleFileSystem of the greeter's volume")?;
+    let mut root = sfs.open_volume().context("can't open the greeter's volume")?;
+    let directory = root.open(DIRECTORY, FileMode::Read, FileAttribute::empty())
+        .context("can't open capsule directory")?;
+    let FileType::Dir(mut directory) = directory.into_type().context("can't get type of capsule directory")? else {
+        return Err(Error::new_without_source(format!("{DIRECTORY} is not a directory")));
+    };
+
+    let mut capsules = Vec::new();
+    loop {
+        let mut buf = vec![0; 1024];
+        let buf = FileInfo::align_buf(&mut buf).unwrap();
+        let Some(entry) = directory.read_entry(buf).context("can't read capsule directory entry")? else { break };
+        if entry.attribute().contains(FileAttribute::DIRECTORY) {
+            continue;
+        }
+        let name = entry.file_name().to_string();
+        let mut header = vec![0; HEADER_SIZE];
+        let path = CString16::try_from(&*format!("{DIRECTORY}\\{name}")).context("capsule name not UTF-16 compatible")?;
+        match util::read_partial_file_to_vec(st, volume, &path, &mut header) {
+            Ok(HEADER_SIZE) if parse_header(&header).is_some() => capsules.push(name),
+            _ => log::debug!("{name} in {DIRECTORY} is not a capsule"),
+        }
+    }
+    capsules.sort();
+    Ok(capsules)
+}
+
+/// guid, flags and image size, if the header is sane
+fn parse_header(data: &[u8]) -> Option<(Guid, u32, usize)> {
+    let data = data.get(..HEADER_SIZE)?;
+    let field = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+    let guid = Guid::from_bytes(data[..16].try_into().unwrap());
+    let (header_size, flags, image_size) = (field(16) as usize, field(20), field(24) as usize);
+    (header_size >= HEADER_SIZE && image_size >= header_size).then_some((guid, flags, image_size))
+}
+
+/// Hands the capsule to the firmware via UpdateCapsule and resets if the firmware applies it across a reset
+pub fn apply(st: &SystemTable<Boot>, name: &str) -> Result {
+    assert!(crate::admin::present(), "firmware update applied without physical presence");
+    let warning = [
+        format!("The firmware update capsule `{name}` will be applied."),
+        "Don't power off the machine while the firmware is being updated.".to_string(),
+    ];
+    if !ui::confirm_destructive(st, "Apply firmware update", &warning, "UPDATE")? {
+        return Ok(());
+    }
+
+    let volume = config::image_volume(st.boot_services().image_handle(), st)?;
+    let path = CString16::try_from(&*format!("{DIRECTORY}\\{name}")).context("capsule name not UTF-16 compatible")?;
+    let data = util::read_full_file(st, volume, &path)?;
+    let Some((guid, flags, image_size)) = parse_header(&data).filter(|&(_, _, size)| size <= data.len()) else {
+        return Err(Error::new_without_source(format!("`{name}` is not a valid capsule")));
+    };
+    log::info!("applying capsule `{name}` for {guid} with flags {flags:#x}");
+
+    // the firmware reads the capsule through physical addresses, possibly after a warm reset
+    let bt = st.boot_services();
+    let pages = (image_size + 4095) / 4096;
+    let capsule = bt.allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, pages)
+        .context("can't allocate memory for the capsule")?;
+    let descriptors = bt.allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, 1)
+        .context("can't allocate memory for the capsule descriptors")?;
+    unsafe {
+        core::ptr::copy_nonoverlapping(data.as_ptr(), capsule as *mut u8, image_size);
+        let descriptors = descriptors as *mut BlockDescriptor;
+        descriptors.write(BlockDescriptor { length: image_size as u64, address: capsule });
+        descriptors.add(1).write(BlockDescriptor { length: 0, address: 0 });
+    }
+
+    let rt = unsafe { &*(st.runtime_services() as *const RuntimeServices as *const RawRuntimeServices) };
+    let headers = [capsule as *const c_void];
+    let mut max_size = 0;
+    let mut reset_type = 0;
+    let status = unsafe { (rt.query_capsule_capabilities)(headers.as_ptr(), 1, &mut max_size, &mut reset_type) };
+    if status.is_error() {
+        let _ = bt.free_pages(capsule, pages);
+        let _ = bt.free_pages(descriptors, 1);
+        return Err(Error::new_from_uefi(status.into(), "the firmware doesn't accept this capsule"));
+    }
+    log::debug!("capsule accepted, up to {max_size} bytes, reset type {reset_type}");
+
+    // processed after the reset, or by a reset the firmware initiates itself
+    let resets = flags & (FLAG_PERSIST_ACROSS_RESET | FLAG_INITIATE_RESET) != 0;
+    if resets {
+        logging::flush();
+    }
+    let status = unsafe { (rt.update_capsule)(headers.as_ptr(), 1, descriptors) };
+    if status.is_error() {
+        let _ = bt.free_pages(capsule, pages);
+        let _ = bt.free_pages(descriptors, 1);
+        return Err(Error::new_from_uefi(status.into(), "the firmware rejected the capsule"));
+    }
+    if flags & FLAG_PERSIST_ACROSS_RESET != 0 {
+        log::info!("capsule `{name}` queued, resetting");
+        st.runtime_services().reset(ResetType(reset_type), Status::SUCCESS, None);
+    }
+
+    let _ = bt.free_pages(capsule, pages);
+    let _ = bt.free_pages(descriptors, 1);
+    log::info!("capsule `{name}` applied");
+    ui::popup(st, "Firmware update", &[format!("The firmware processed `{name}`.")])
+}
@@ -0,0 +1,95 @@
+use alloc::string::{String, ToString};
+use opal::{OpalDrive, SecureProtocol};
+use uefi::proto::loaded_image::LoadedImage;
+use uefi::proto::tcg::v2::Tcg;
+use uefi::table::{Boot, SystemTable};
+use uefi::{Handle, Status};
+use crate::config::Config;
+use crate::low_level::nvme_device::RestartableNvmeDevice;
+use crate::error::ErrorSource;
+use crate::{console, Error, Result};
+
+/// Outcome of checking one subsystem
+enum Outcome {
+    Pass(String),
+    /// not available on this machine, but not needed either
+    Skip(String),
+    Fail(String),
+}
+
+/// whether the greeter was started with `--check`, e.g. from the UEFI shell
+pub fn requested(st: &SystemTable<Boot>, image_handle: Handle) -> bool {
+    let Ok(loaded_image) = st.boot_services().open_protocol_exclusive::<LoadedImage>(image_handle) else { return false };
+    let Ok(options) = loaded_image.load_options_as_cstr16() else { return false };
+    options.to_string().split_whitespace().any(|arg| arg == "--check")
+}
+
+/// Exercises the subsystems needed to boot without touching any drive's locking state,
+/// prints PASS/SKIP/FAIL for each and returns an error status if anything failed
+pub fn run(st: &SystemTable<Boot>, image_handle: Handle) -> Status {
+    let config = crate::config::load(image_handle, st);
+    let mut failed = false;
+    let mut report = |subsystem: &str, outcome: Outcome| {
+        let (verdict, detail) = match outcome {
+            Outcome::Pass(detail) => ("PASS", detail),
+            Outcome::Skip(detail) => ("SKIP", detail),
+            Outcome::Fail(detail) => {
+                failed = true;
+                ("FAIL", detail)
+            }
+        };
+        console::write_str(st, &format!("{verdict} {subsystem}: {detail}\r\n"));
+    };
+
+    report("config", match &config {
+        Ok(config) => Outcome::Pass(format!("{} boot entries, {} partitions", config.boot_entries.len(), config.partitions.len())),
+        Err(e) => Outcome::Fail(e.to_string()),
+    });
+    report("discovery", match &config {
+        Ok(config) => match discovery(st, config) {
+            Ok((0, _)) => Outcome::Skip("no OPAL drives found".to_string()),
+            Ok((drives, locked)) => Outcome::Pass(format!("{drives} OPAL drives, {locked} locked")),
+            Err(e) => Outcome::Fail(e.to_string()),
+        },
+        Err(_) => Outcome::Skip("needs the config".to_string()),
+    });
+    report("tpm", match st.boot_services().get_handle_for_protocol::<Tcg>() {
+        Ok(_) => Outcome::Pass("TCG2 protocol present".to_string()),
+        Err(_) => Outcome::Skip("no TCG2 protocol".to_string()),
+    });
+    report("esp", match crate::find_boot_partitions(st) {
+        Ok(esps) if esps.is_empty() => Outcome::Fail("no EFI system partition found".to_string()),
+        Ok(esps) => Outcome::Pass(format!("{} EFI system partitions", esps.len())),
+        Err(e) => Outcome::Fail(e.to_string()),
+    });
+
+    match failed {
+        true => Status::ABORTED,
+        false => Status::SUCCESS,
+    }
+}
+
+/// number of OPAL drives and how many of them are locked
+fn discovery(st: &SystemTable<Boot>, config: &Config) -> Result<(usize, usize)> {
+    let (mut drives, mut locked) = (0, 0);
+    for (blockio_handle, _, _) in crate::block_devices(st)? {
+        if let Some(nvme) = crate::try_get_nvme_device(st, blockio_handle)? {
+            if let Ok(mut drive) = OpalDrive::new(RestartableNvmeDevice::new(&nvme, st, blockio_handle)) {
+                drive.force(config.features.forced());
+                count_drive(&mut drive, &mut drives, &mut locked)?;
+            }
+        } else if let Some(mut ata) = crate::try_get_ata_device(st, blockio_handle)? {
+            ata.force(config.features.forced());
+            count_drive(&mut ata, &mut drives, &mut locked)?;
+        }
+    }
+    Ok((drives, locked))
+}
+
+fn count_drive<P: SecureProtocol>(drive: &mut OpalDrive<P>, drives: &mut usize, locked: &mut usize) -> Result
+where opal::Error<P::Error>: Into<ErrorSource>
+{
+    *drives += 1;
+    *locked += drive.is_locked().map_err(|e| Error::new(e, "can't get locking state"))? as usize;
+    Ok(())
+}
@@ -0,0 +1,47 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use uefi::table::{Boot, SystemTable};
+
+/// counter value at `init`
+static START: AtomicU64 = AtomicU64::new(0);
+/// counter increments per millisecond; 0 without a counter
+static PER_MS: AtomicU64 = AtomicU64::new(0);
+
+/// how long the counter's rate is measured for at startup
+const CALIBRATION_MS: u64 = 10;
+
+/// Starts the monotonic clock, measuring the counter's rate once against Stall, which the firmware keeps calibrated
+pub fn init(st: &SystemTable<Boot>) {
+    let Some(start) = ticks() else { return };
+    START.store(start, Ordering::Relaxed);
+    st.boot_services().stall(CALIBRATION_MS as usize * 1000);
+    let per_ms = ticks().unwrap_or(start).wrapping_sub(start) / CALIBRATION_MS;
+    PER_MS.store(per_ms, Ordering::Relaxed);
+}
+
+/// Milliseconds since `init`, unlike the RTC neither limited to whole seconds nor wrapping at midnight;
+/// `None` on processors without a counter
+pub fn now_ms() -> Option<u64> {
+    let per_ms = PER_MS.load(Ordering::Relaxed);
+    if per_ms == 0 {
+        return None;
+    }
+    Some(ticks()?.wrapping_sub(START.load(Ordering::Relaxed)) / per_ms)
+}
+
+/// The processor's free-running counter: the TSC on x86_64, the virtual counter on aarch64
+#[cfg(target_arch = "x86_64")]
+pub fn ticks() -> Option<u64> {
+    Some(unsafe { core::arch::x86_64::_rdtsc() })
+}
+
+#[cfg(target_arch = "aarch64")]
+pub fn ticks() -> Option<u64> {
+    let ticks: u64;
+    unsafe { core::arch::asm!("mrs {}, cntvct_el0", out(reg) ticks) };
+    Some(ticks)
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub fn ticks() -> Option<u64> {
+    None
+}
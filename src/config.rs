@@ -11,8 +11,9 @@ use either::Either;
 #[cfg(target_os = "uefi")] use uefi::proto::media::fs::SimpleFileSystem;
 #[cfg(target_os = "uefi")] use crate::error::Context;
 
+/// the volume the greeter itself was loaded from
 #[cfg(target_os = "uefi")]
-pub fn load(image_handle: Handle, st: &SystemTable<Boot>) -> crate::Result<Config> {
+pub fn image_volume(image_handle: Handle, st: &SystemTable<Boot>) -> crate::Result<Handle> {
     let loaded_image = st
         .boot_services()
         .open_protocol_exclusive::<LoadedImage>(image_handle)
@@ -25,15 +26,169 @@ pub fn load(image_handle: Handle, st: &SystemTable<Boot>) -> crate::Result<Confi
         .boot_services()
         .locate_device_path::<SimpleFileSystem>(&mut &*device_path)
         .context("cannot get SimpleFileSystem from DevicePath from LoadedImage")?;
+    Ok(device_handle)
+}
+
+#[cfg(target_os = "uefi")]
+pub fn load(image_handle: Handle, st: &SystemTable<Boot>) -> crate::Result<Config> {
+    let device_handle = image_volume(image_handle, st)?;
     let buf = crate::util::read_full_file(st, device_handle, cstr16!("config.toml"))?;
-    let config: Config = toml::from_slice(&buf)
+    let own: toml::Value = toml::from_slice(&buf)
+        .context("error decoding config file as toml")?;
+    let sources: ConfigSources = match own.get("config_sources") {
+        Some(sources) => sources.clone().try_into().context("error decoding config_sources")?,
+        None => ConfigSources::default(),
+    };
+    let merged = match sources.other_esps {
+        true => load_other_esps(st, device_handle, own, sources.precedence),
+        false => own,
+    };
+    let mut config: Config = merged.try_into()
         .context("error decoding config file as toml")?;
-    log::set_max_level(config.log_level);
+    config.apply_keys();
+    config.check_quorums()?;
     // log::debug!("loaded config = {:#?}", config);
     Ok(config)
 }
 
 
+/// Merges the config.toml files of all other EFI system partitions into the own one, in the order of `precedence`
+#[cfg(target_os = "uefi")]
+fn load_other_esps(st: &SystemTable<Boot>, own_volume: Handle, own: toml::Value, precedence: Precedence) -> toml::Value {
+    use uefi::proto::device_path::text::{AllowShortcuts, DisplayOnly};
+    use uefi::proto::media::block::BlockIO;
+    use uefi::table::boot::{OpenProtocolAttributes, OpenProtocolParams};
+
+    let bt = st.boot_services();
+    let esps = match crate::find_boot_partitions(st) {
+        Ok(esps) => esps,
+        Err(e) => {
+            log::warn!("can't list EFI system partitions for further configs: {e}");
+            return own;
+        }
+    };
+    // (disk order, modification time, config); the own volume always sorts first by disk
+    let mut sources = vec![(String::new(), config_mtime(st, own_volume), own)];
+    for (_, handle) in esps {
+        if handle == own_volume {
+            continue;
+        }
+        // anyone can plug in a stick, so only configs on fixed disks are trusted to add keyslots or partitions
+        let params = OpenProtocolParams { handle, agent: bt.image_handle(), controller: None };
+        let removable = unsafe { bt.open_protocol::<BlockIO>(params, OpenProtocolAttributes::GetProtocol) }
+            .map_or(true, |blockio| blockio.media().is_removable_media());
+        if removable {
+            log::debug!("not merging configs from removable media");
+            continue;
+        }
+        let Ok(buf) = crate::util::read_full_file(st, handle, cstr16!("config.toml")) else { continue };
+        let path = bt.open_protocol_exclusive::<DevicePath>(handle).ok()
+            .and_then(|dp| dp.to_string(bt, DisplayOnly(true), AllowShortcuts(false)).ok().flatten())
+            .map_or_else(|| String::from("<unknown>"), |dp| alloc::format!("{}", &*dp));
+        match toml::from_slice::<toml::Value>(&buf) {
+            Ok(value) => {
+                log::info!("merging config from {path}");
+                // sorts after the own volume's empty path
+                sources.push((alloc::format!("~{path}"), config_mtime(st, handle), value));
+            }
+            Err(e) => log::warn!("ignoring config on {path}: {e}"),
+        }
+    }
+
+    let priority = |value: &toml::Value| value.get("config_priority").and_then(|p| p.as_integer()).unwrap_or(0);
+    match precedence {
+        Precedence::Disk => sources.sort_by(|a, b| a.0.cmp(&b.0)),
+        Precedence::Newest => sources.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0))),
+        Precedence::Priority => sources.sort_by(|a, b| priority(&b.2).cmp(&priority(&a.2)).then_with(|| a.0.cmp(&b.0))),
+    }
+    let mut sources = sources.into_iter().map(|(_, _, value)| value);
+    let mut merged = sources.next().expect("own config is always a source");
+    for lower in sources {
+        merge(&mut merged, lower);
+    }
+    merged
+}
+
+/// modification time of config.toml as (year, month, day, hour, minute, second)
+#[cfg(target_os = "uefi")]
+fn config_mtime(st: &SystemTable<Boot>, volume: Handle) -> Option<(u16, u8, u8, u8, u8, u8)> {
+    use uefi::proto::media::file::{File, FileAttribute, FileInfo, FileMode};
+
+    let mut sfs = st.boot_services().open_protocol_exclusive::<SimpleFileSystem>(volume).ok()?;
+    let mut file = sfs.open_volume().ok()?
+        .open(cstr16!("config.toml"), FileMode::Read, FileAttribute::empty()).ok()?;
+    let info = file.get_boxed_info::<FileInfo>().ok()?;
+    let t = info.modification_time();
+    Some((t.year(), t.month(), t.day(), t.hour(), t.minute(), t.second()))
+}
+
+/// Merges a config with lower precedence into `higher`: tables are merged recursively,
+/// arrays of tables (boot entries, partitions, keyslots, …) gain the entries whose `name` they don't have yet,
+/// and everything else keeps the value of `higher`.
+pub fn merge(higher: &mut toml::Value, lower: toml::Value) {
+    use toml::Value;
+
+    match (higher, lower) {
+        (Value::Table(higher), Value::Table(lower)) => {
+            for (key, value) in lower {
+                match higher.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        higher.insert(key, value);
+                    }
+                }
+            }
+        }
+        (Value::Array(higher), Value::Array(lower)) if higher.iter().chain(&lower).all(Value::is_table) => {
+            for entry in lower {
+                let name = entry.get("name");
+                if name.is_none() || !higher.iter().any(|existing| existing.get("name") == name) {
+                    higher.push(entry);
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Where else configs are read from
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct ConfigSources {
+    /// also read config.toml from all other EFI system partitions
+    pub other_esps: bool,
+    pub precedence: Precedence,
+}
+
+/// Rediscovery of controllers that appear late, e.g. behind Thunderbolt/USB4
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct Hotplug {
+    /// how long to wait for late controllers at startup and after one arrives; 0 disables rediscovery
+    pub settle_ms: u64,
+}
+
+/// Installing a new greeter binary from `\opal-greeter\update.efi`
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct Update {
+    /// expected SHA-256 of the update as hex; without it, the update must pass Secure Boot verification
+    pub sha256: Option<String>,
+}
+
+/// Which config wins when several define the same setting
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Precedence {
+    /// the highest `config_priority`, then by disk
+    Priority,
+    /// the greeter's own volume, then the others ordered by device path
+    #[default]
+    Disk,
+    /// the most recently modified config.toml, then by disk
+    Newest,
+}
+
 #[derive(Debug, serde::Deserialize)]
 pub struct Config {
     #[serde(deserialize_with = "deserialize_keyslots")]
@@ -42,10 +197,598 @@ pub struct Config {
     pub keyslot_buffer: RefCell<BTreeMap<String, Vec<u8>>>,
     #[serde(skip)]
     pub luks_masterkey_buffer: RefCell<BTreeMap<String, luks2::SecretMasterKey>>,
+    #[serde(skip)]
+    pub os_detect_buffer: RefCell<BTreeMap<String, Option<String>>>,
     #[serde(deserialize_with = "deserialize_partitions")]
     pub partitions: BTreeMap<String, Partition>,
     pub boot_entries: Vec<BootEntry>,
+    /// level of the log output on screen
     pub log_level: LevelFilter,
+    /// further log destinations, each with its own level
+    #[serde(default)]
+    pub log_sinks: Vec<LogSink>,
+    pub log_viewer: Option<LogViewer>,
+    pub protocol_trace: Option<ProtocolTrace>,
+    /// keep a watchdog with this timeout in seconds armed while talking to SEDs
+    pub unlock_watchdog: Option<u64>,
+    /// show prompts on and accept keys from every console, not just the firmware's primary one
+    #[serde(default)]
+    pub mirror_consoles: bool,
+    /// audible cues on the PC speaker when ready for a password, on wrong passwords and fatal errors
+    #[serde(default)]
+    pub beep: bool,
+    #[serde(default)]
+    pub accessibility: Accessibility,
+    /// minimum time a failed unlock attempt takes before the prompt is shown again
+    #[serde(default = "default_failed_attempt_latency_ms")]
+    pub failed_attempt_latency_ms: u64,
+    #[serde(default)]
+    pub secure_messaging: SecureMessaging,
+    #[serde(default)]
+    pub admin: Admin,
+    /// key to hold while the greeter starts to boot once from removable media instead of the normal menu
+    pub usb_hotkey: Option<KeyName>,
+    /// key to hold together with the admin hotkey while the greeter starts to offer booting the greeter version
+    /// replaced by the last update
+    pub rollback_hotkey: Option<KeyName>,
+    /// key that saves what's on screen to the greeter's volume, for bug reports
+    pub screenshot_hotkey: Option<KeyName>,
+    /// all hotkeys in one place, replacing those configured next to their features
+    #[serde(default)]
+    pub keys: Keys,
+    /// text file on the greeter's volume that must be acknowledged before the first prompt
+    pub banner_file: Option<String>,
+    #[serde(default)]
+    pub password_echo: PasswordEcho,
+    /// layout used to interpret passwords and other typed text
+    #[serde(default)]
+    pub keymap: Keymap,
+    #[serde(default)]
+    pub features: Features,
+    /// issue a STACK_RESET before opening sessions, for firmware that leaves sessions open on the drives
+    #[serde(default)]
+    pub stack_reset: bool,
+    /// probe a locked drive with discovery commands before unlocking and warn if one takes longer than this
+    pub latency_probe_ms: Option<u64>,
+    /// additions to the embedded table of firmware revisions with known OPAL bugs
+    #[serde(default)]
+    pub firmware_warnings: Vec<FirmwareWarning>,
+    #[serde(default)]
+    pub fatal: Fatal,
+    /// boot the default entry after this many seconds unless a key is pressed
+    pub menu_timeout: Option<u64>,
+    /// show the countdown bar, but wait for Enter instead of counting down
+    #[serde(default)]
+    pub menu_timeout_paused: bool,
+    #[serde(default)]
+    pub no_locked_drives: NoLockedDrives,
+    #[serde(default)]
+    pub on_battery: OnBattery,
+    /// try the MSID before asking for a drive's password, so drives that never got a password unlock silently
+    #[serde(default)]
+    pub try_msid: bool,
+    /// unlock drives whose keyslots need no typed password while the menu is shown
+    #[serde(default)]
+    pub background_unlock: bool,
+    /// only honored in the greeter's own config
+    #[serde(default)]
+    pub config_sources: ConfigSources,
+    /// precedence of this config when merging configs from several ESPs
+    #[serde(default)]
+    pub config_priority: i64,
+    /// sections of the boot menu; entries go into the first group they match, unmatched ones come last
+    #[serde(default)]
+    pub menu_groups: Vec<MenuGroup>,
+    /// order of the entries within each section of the boot menu
+    #[serde(default)]
+    pub sort: Sort,
+    #[serde(default)]
+    pub hotplug: Hotplug,
+    /// people who log in before the menu; the logged-in user decides which entries are shown
+    #[serde(default)]
+    pub users: Vec<User>,
+    /// index into `users` of whoever logged in
+    #[serde(skip)]
+    pub current_user: RefCell<Option<usize>>,
+    #[serde(default)]
+    pub update: Update,
+    /// keep local counters of boots, unlock times and failures per drive in NVRAM, shown in the setup menu
+    #[serde(default)]
+    pub statistics: bool,
+    /// show the machine's model, the number of self-encrypting drives, Secure Boot state and battery presence above password prompts
+    #[serde(default)]
+    pub hardware_summary: bool,
+    /// for boards with very little boot-services memory: smaller log buffers, no OS detection,
+    /// and images on firmware-readable volumes are loaded by device path instead of being read first
+    #[serde(default)]
+    pub low_memory: bool,
+    /// pass security commands to the drivers in pages below 4 GiB, for drivers or DMA protection that can't reach higher
+    #[serde(default)]
+    pub dma_below_4gib: bool,
+}
+
+impl Config {
+    /// Rejects quorum keyslots with quorum keyslots as members, which would ask for shares of shares without end
+    fn check_quorums(&self) -> crate::Result {
+        for keyslot in self.keyslots.values() {
+            let KeyslotSource::Quorum(quorum) = &keyslot.source else { continue };
+            let nested = quorum.members.iter()
+                .find(|member| matches!(self.keyslots.get(*member).map(|member| &member.source), Some(KeyslotSource::Quorum(_))));
+            if let Some(member) = nested {
+                return Err(crate::Error::new_without_source(format!(
+                    "keyslot {}: member `{member}` is a quorum keyslot itself, which isn't supported", keyslot.name,
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the hotkeys from `[keys]` to the features they belong to and warns about keys bound twice
+    pub fn apply_keys(&mut self) {
+        let keys = &self.keys;
+        if let Some(hotkey) = keys.log_viewer {
+            match &mut self.log_viewer {
+                Some(viewer) => viewer.hotkey = hotkey,
+                None => log::warn!("keys.log_viewer is set, but there's no [log_viewer] section"),
+            }
+        }
+        self.screenshot_hotkey = keys.screenshot.or(self.screenshot_hotkey);
+        self.usb_hotkey = keys.usb.or(self.usb_hotkey);
+        self.rollback_hotkey = keys.rollback.or(self.rollback_hotkey);
+        self.admin.hotkey = keys.admin.or(self.admin.hotkey);
+        self.admin.wizard_hotkey = keys.wizard.or(self.admin.wizard_hotkey);
+        self.accessibility.hotkey = keys.accessibility.or(self.accessibility.hotkey);
+
+        // keys held at startup and keys pressed in menus and prompts never compete
+        let mut in_menu: Vec<(&str, KeyName)> = self.keys.info().iter().map(|&key| ("info", key)).collect();
+        in_menu.extend([
+            ("log_viewer", self.log_viewer.as_ref().map(|viewer| viewer.hotkey)),
+            ("screenshot", self.screenshot_hotkey),
+        ].iter().filter_map(|&(name, key)| Some((name, key?))));
+        let startup: Vec<(&str, KeyName)> = [
+            ("usb", self.usb_hotkey),
+            ("rollback", self.rollback_hotkey),
+            ("admin", self.admin.hotkey),
+            ("wizard", self.admin.wizard_hotkey),
+            ("accessibility", self.accessibility.hotkey),
+        ].iter().filter_map(|&(name, key)| Some((name, key?))).collect();
+        for bound in [in_menu, startup] {
+            for (i, (name, key)) in bound.iter().enumerate() {
+                if let Some((other, _)) = bound[..i].iter().find(|(_, other)| other == key) {
+                    log::warn!("{key:?} is bound to both {other} and {name}, only one of them works");
+                }
+            }
+        }
+    }
+
+    /// the logged-in user, if users are configured and someone logged in
+    pub fn user(&self) -> Option<&User> {
+        self.current_user.borrow().map(|i| &self.users[i])
+    }
+
+    /// boot entry indices per menu section, in menu order; the unnamed section holds entries without a group.
+    /// `last_used` are entry names, most recently booted first
+    pub fn grouped_entries(&self, last_used: &[String]) -> Vec<(Option<&str>, Vec<usize>)> {
+        let mut sections: Vec<_> = self.menu_groups.iter()
+            .map(|group| (Some(group.name.as_str()), Vec::new()))
+            .collect();
+        let mut ungrouped = Vec::new();
+        for (i, entry) in self.boot_entries.iter().enumerate() {
+            if !self.user().map_or(true, |user| user.may_boot(entry)) {
+                continue;
+            }
+            match self.menu_groups.iter().position(|group| group.matches(entry)) {
+                Some(group) => sections[group].1.push(i),
+                None => ungrouped.push(i),
+            }
+        }
+        sections.push((None, ungrouped));
+        sections.retain(|(_, entries)| !entries.is_empty());
+        for (_, entries) in &mut sections {
+            self.sort_entries(entries, last_used);
+        }
+        sections
+    }
+
+    /// Sorts boot entry indices by `sort`; ties keep the config order
+    fn sort_entries(&self, entries: &mut [usize], last_used: &[String]) {
+        let entry = |i: usize| &self.boot_entries[i];
+        let recency = |i: usize| last_used.iter().position(|name| *name == entry(i).name).unwrap_or(usize::MAX);
+        match self.sort {
+            Sort::ConfigOrder => entries.sort(),
+            Sort::Title => entries.sort_by(|&a, &b| {
+                case_insensitive(&entry(a).name, &entry(b).name).then(a.cmp(&b))
+            }),
+            Sort::Partition => entries.sort_by(|&a, &b| {
+                entry(a).file.partition.cmp(&entry(b).file.partition)
+                    .then_with(|| case_insensitive(&entry(a).name, &entry(b).name))
+                    .then(a.cmp(&b))
+            }),
+            Sort::LastUsed => entries.sort_by_key(|&i| (recency(i), i)),
+        }
+    }
+}
+
+fn case_insensitive(a: &str, b: &str) -> core::cmp::Ordering {
+    a.chars().flat_map(char::to_lowercase).cmp(b.chars().flat_map(char::to_lowercase))
+}
+
+/// Order of the boot menu entries
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Sort {
+    /// as listed in the config
+    #[default]
+    ConfigOrder,
+    /// alphabetically by name, ignoring case
+    Title,
+    /// by the partition of the entry's image, then by name
+    Partition,
+    /// most recently booted first, entries never booted in config order after them
+    LastUsed,
+}
+
+/// Someone who logs in with their own OPAL User authority
+#[derive(Debug, serde::Deserialize)]
+pub struct User {
+    pub name: String,
+    /// number N of the UserN authority the user unlocks as; it must be in the ACEs of the global range
+    pub authority: u8,
+    /// partition of the drive the user authenticates against
+    pub partition: String,
+    /// keyslot with the user's password
+    pub keyslot: String,
+    /// names of the boot entries the user sees; all of them if empty
+    #[serde(default)]
+    pub boot_entries: Vec<String>,
+    /// whether the user may open the setup and recovery menu
+    #[serde(default)]
+    pub setup: bool,
+}
+
+impl User {
+    pub fn may_boot(&self, entry: &BootEntry) -> bool {
+        self.boot_entries.is_empty() || self.boot_entries.contains(&entry.name)
+    }
+}
+
+/// A named section of the boot menu; an entry matches if any of the rules matches
+#[derive(Debug, serde::Deserialize)]
+pub struct MenuGroup {
+    pub name: String,
+    /// entries booting from one of these partitions
+    #[serde(default)]
+    pub partitions: Vec<String>,
+    /// case-insensitive substrings of the entry's name
+    #[serde(default)]
+    pub names: Vec<String>,
+    /// case-insensitive substrings of the entry's file path
+    #[serde(default)]
+    pub files: Vec<String>,
+}
+
+impl MenuGroup {
+    pub fn matches(&self, entry: &BootEntry) -> bool {
+        let contains = |haystack: &str, needle: &str| haystack.to_lowercase().contains(&needle.to_lowercase());
+        self.partitions.iter().any(|partition| *partition == entry.file.partition)
+            || self.names.iter().any(|name| contains(&entry.name, name))
+            || self.files.iter().any(|file| contains(&entry.file.file, file))
+    }
+}
+
+/// What happens after a fatal error
+#[derive(Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct Fatal {
+    pub reset: ResetKind,
+    /// wait for Enter before resetting; otherwise reset after `delay_secs`
+    pub wait_for_enter: bool,
+    pub delay_secs: u64,
+}
+
+impl Default for Fatal {
+    fn default() -> Self {
+        Fatal {
+            reset: ResetKind::Cold,
+            wait_for_enter: true,
+            delay_secs: 10,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResetKind {
+    #[default]
+    Cold,
+    /// keeps some firmware logs that are useful for debugging
+    Warm,
+    Shutdown,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum LogSink {
+    /// the first serial port
+    Serial { level: LevelFilter },
+    /// a file on the greeter's volume, appended to before handing off and on fatal errors;
+    /// moved to `<path>.old` once it reaches `size_kib`
+    File {
+        level: LevelFilter,
+        path: String,
+        #[serde(default = "default_log_file_size_kib")]
+        size_kib: u64,
+    },
+    /// the last `size` bytes in the non-volatile `OpalGreeterLog` variable
+    Variable {
+        level: LevelFilter,
+        #[serde(default = "default_log_variable_size")]
+        size: usize,
+    },
+}
+
+fn default_log_variable_size() -> usize {
+    4096
+}
+
+fn default_log_file_size_kib() -> u64 {
+    1024
+}
+
+/// Keeps recent log output in memory to be shown by a hotkey in menus
+#[derive(Debug, serde::Deserialize)]
+pub struct LogViewer {
+    pub hotkey: KeyName,
+    #[serde(default = "default_log_viewer_level")]
+    pub level: LevelFilter,
+    #[serde(default = "default_log_viewer_size_kib")]
+    pub size_kib: usize,
+}
+
+fn default_log_viewer_level() -> LevelFilter {
+    LevelFilter::Debug
+}
+
+fn default_log_viewer_size_kib() -> usize {
+    64
+}
+
+/// Keeps recent IF-SEND and IF-RECV payloads in memory, viewable as a hex dump in the setup menu
+#[derive(Debug, serde::Deserialize)]
+pub struct ProtocolTrace {
+    #[serde(default = "default_protocol_trace_size_kib")]
+    pub size_kib: usize,
+}
+
+fn default_protocol_trace_size_kib() -> usize {
+    256
+}
+
+/// What is shown while typing a password
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PasswordEcho {
+    /// a `*` per character
+    #[default]
+    Mask,
+    /// nothing, for serial consoles and screen recordings where even the length must not leak
+    None,
+}
+
+/// What to do before shadow MBR uploads and reverts on a machine that isn't on AC power
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnBattery {
+    /// go ahead once `battery` was typed
+    #[default]
+    Confirm,
+    /// wait for AC power
+    Refuse,
+    /// don't check
+    Ignore,
+}
+
+/// What to do if no locked self-encrypting drive is found at startup
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NoLockedDrives {
+    /// a line above the boot menu
+    #[default]
+    Note,
+    /// a warning that must be acknowledged
+    Warn,
+    /// fail like any other fatal error
+    Abort,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct FirmwareWarning {
+    /// matched anywhere in the drive's model number
+    pub model: String,
+    /// exact firmware revision; all revisions if omitted
+    pub firmware: Option<String>,
+    pub note: String,
+}
+
+/// Optional drive features; each is only used if Level 0 discovery lists it, unless forced
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct Features {
+    pub sum: FeatureOverride,
+    pub datastore: FeatureOverride,
+    pub mbr_shadow: MbrShadowFeature,
+    pub secure_messaging: FeatureOverride,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct FeatureOverride {
+    /// use the feature even if the drive doesn't advertise it
+    pub force: bool,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct MbrShadowFeature {
+    /// use the feature even if the drive doesn't advertise it
+    pub force: bool,
+    /// set MBRControl Enable to this when unlocking, e.g. `false` once a sedutil PBA image is retired; unset leaves it alone
+    pub enable: Option<bool>,
+}
+
+impl Features {
+    pub fn forced(&self) -> opal::Capabilities {
+        let mut forced = opal::Capabilities::empty();
+        forced.set(opal::Capabilities::SINGLE_USER_MODE, self.sum.force);
+        forced.set(opal::Capabilities::DATASTORE, self.datastore.force);
+        forced.set(opal::Capabilities::MBR_SHADOW, self.mbr_shadow.force);
+        forced.set(opal::Capabilities::SECURE_MESSAGING, self.secure_messaging.force);
+        forced
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Keymap {
+    /// guess from the platform language and the SMBIOS BIOS language
+    #[default]
+    Auto,
+    Us,
+    De,
+    Fr,
+}
+
+/// Physical-presence requirements for the setup and recovery menu
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct Admin {
+    /// key to hold while the greeter starts to make the menu available; without one it's unreachable
+    pub hotkey: Option<KeyName>,
+    /// key to hold while the greeter starts to run the wizard that puts a fresh drive into a locked state
+    pub wizard_hotkey: Option<KeyName>,
+    /// additionally require the SMBIOS chassis security status to report its external interface as enabled,
+    /// which some boards tie to a jumper or the intrusion switch
+    pub require_chassis_unlocked: bool,
+    /// leave drives whose SID the firmware blocked out of provisioning instead of failing on them
+    pub skip_blocked_sid: bool,
+}
+
+/// Whether to point out drives that could protect their sessions against sniffing on the bus;
+/// secure messaging isn't implemented, so sessions are always cleartext
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SecureMessaging {
+    /// use cleartext sessions without notice
+    Off,
+    /// warn when a drive supports secure messaging but gets a cleartext session
+    #[default]
+    #[serde(alias = "prefer")]
+    Warn,
+}
+
+fn default_failed_attempt_latency_ms() -> u64 {
+    1000
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct Accessibility {
+    /// always enable accessibility mode, not only when the hotkey is held at startup
+    pub enabled: bool,
+    /// key to hold while the greeter starts to enable accessibility mode
+    pub hotkey: Option<KeyName>,
+    /// white on black instead of the firmware's colors
+    pub high_contrast: bool,
+    /// use the console mode with the fewest columns, resulting in the largest font
+    pub large_text: bool,
+    /// multiplier applied to all countdowns
+    pub countdown_factor: u32,
+    /// repeat the highlighted menu entry on a status line below the menu
+    pub echo_keys: bool,
+}
+
+impl Default for Accessibility {
+    fn default() -> Self {
+        Accessibility {
+            enabled: false,
+            hotkey: None,
+            high_contrast: true,
+            large_text: true,
+            countdown_factor: 3,
+            echo_keys: true,
+        }
+    }
+}
+
+/// Hotkeys, for setups where the defaults clash with a terminal or serial console.
+/// Each one set here replaces the one configured next to its feature, e.g. `usb_hotkey` or `[admin] hotkey`.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct Keys {
+    /// show the details of the selected menu entry; Tab and F1 if empty
+    pub info: Vec<KeyName>,
+    /// open the log viewer; needs a `[log_viewer]` section
+    pub log_viewer: Option<KeyName>,
+    pub screenshot: Option<KeyName>,
+    /// held at startup
+    pub usb: Option<KeyName>,
+    /// held at startup
+    pub rollback: Option<KeyName>,
+    /// held at startup
+    pub admin: Option<KeyName>,
+    /// held at startup
+    pub wizard: Option<KeyName>,
+    /// held at startup
+    pub accessibility: Option<KeyName>,
+}
+
+impl Keys {
+    pub fn info(&self) -> &[KeyName] {
+        match self.info.is_empty() {
+            true => &[KeyName::Tab, KeyName::Function(1)],
+            false => &self.info,
+        }
+    }
+}
+
+/// A key as written in the config, e.g. `"a"`, `"F5"`, `"Tab"` or `"Esc"`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KeyName {
+    Char(char),
+    Function(u8),
+    Tab,
+    Escape,
+    Insert,
+    Delete,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+}
+
+impl<'de> Deserialize<'de> for KeyName {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        let mut chars = name.chars();
+        if let (Some(c), None) = (chars.next(), chars.next()) {
+            return Ok(KeyName::Char(c));
+        }
+        let key = match name.to_ascii_lowercase().as_str() {
+            "tab" => KeyName::Tab,
+            "esc" | "escape" => KeyName::Escape,
+            "ins" | "insert" => KeyName::Insert,
+            "del" | "delete" => KeyName::Delete,
+            "home" => KeyName::Home,
+            "end" => KeyName::End,
+            "pageup" | "pgup" => KeyName::PageUp,
+            "pagedown" | "pgdn" => KeyName::PageDown,
+            f => match f.strip_prefix('f').and_then(|n| n.parse().ok()) {
+                Some(n @ 1..=12) => KeyName::Function(n),
+                _ => return Err(serde::de::Error::custom(format!("unknown key `{name}`"))),
+            }
+        };
+        Ok(key)
+    }
 }
 
 fn deserialize_keyslots<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<String, Keyslot>, D::Error> {
@@ -61,6 +804,8 @@ fn deserialize_partitions<'de, D: Deserializer<'de>>(deserializer: D) -> Result<
 pub struct Keyslot {
     pub name: String,
     pub source: KeyslotSource,
+    /// prompt for typed passwords instead of `Password for keyslot <name>`
+    pub label: Option<String>,
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -69,6 +814,7 @@ pub enum KeyslotSource {
     #[serde(deserialize_with = "deserialize_stdin")]
     Stdin,
     File(File),
+    Quorum(Quorum),
 }
 fn deserialize_stdin<'de, D: Deserializer<'de>>(deserializer: D) -> Result<(), D::Error> {
     #[derive(Deserialize)]
@@ -77,12 +823,24 @@ fn deserialize_stdin<'de, D: Deserializer<'de>>(deserializer: D) -> Result<(), D
     Ok(())
 }
 
+/// A random drive key split so that any `threshold` of the member keyslots can reconstruct it
+#[derive(Debug, serde::Deserialize)]
+pub struct Quorum {
+    pub threshold: u8,
+    /// keyslots of the people or security keys taking part, e.g. each one's password
+    pub members: Vec<String>,
+    /// file on the greeter's volume with the members' shares, written by the setup menu
+    pub shares: String,
+}
+
 #[derive(Debug, serde::Deserialize)]
 pub struct File {
     pub partition: String,
     #[serde(default)]
     pub extra_partitions: Vec<String>,
     pub file: String,
+    /// expected SHA-256 of the file as hex; reading fails on a mismatch
+    pub sha256: Option<String>,
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -91,6 +849,38 @@ pub struct Partition {
     pub parent: Option<String>,
     pub uuid: String,
     pub keyslot: Option<String>,
+    /// for OPAL drives, the locking ranges to unlock (0 being the global range); all with locking enabled if unset
+    pub unlock_ranges: Option<Vec<u8>>,
+    /// for OPAL drives, the authority to unlock as, e.g. `"User1"` for ranges set up to be unlocked by User1
+    #[serde(default = "default_authority", deserialize_with = "deserialize_authority")]
+    pub authority: opal::Authority,
+    /// for OPAL drives whose ACEs require a User together with Admin1
+    pub dual_auth: Option<DualAuth>,
+}
+
+fn default_authority() -> opal::Authority {
+    opal::Authority::admin(1)
+}
+
+/// `AdminN` or `UserN`, ignoring case
+fn deserialize_authority<'de, D: Deserializer<'de>>(deserializer: D) -> Result<opal::Authority, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    let lower = name.to_ascii_lowercase();
+    let parse = |prefix| lower.strip_prefix(prefix).and_then(|n: &str| n.parse::<u8>().ok()).filter(|&n| n != 0);
+    match (parse("admin"), parse("user")) {
+        (Some(n), _) => Ok(opal::Authority::admin(n)),
+        (_, Some(n)) => Ok(opal::Authority::user(n)),
+        _ => Err(serde::de::Error::custom(format!("unknown authority `{name}`, expected e.g. `Admin1` or `User1`"))),
+    }
+}
+
+/// A User credential asked for after the partition's own, for ACEs like `Admin1 AND User1`
+#[derive(Debug, serde::Deserialize)]
+pub struct DualAuth {
+    /// number N of the UserN authority
+    pub user: u8,
+    /// keyslot with the user's password
+    pub keyslot: String,
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -103,6 +893,43 @@ pub struct BootEntry {
     pub options: Option<String>,
     #[serde(default)]
     pub default: bool,
+    /// decorate the name with the OS detected on the entry's partition
+    #[serde(default)]
+    pub detect_os: bool,
+    /// OPAL locking ranges to lock right before booting the entry
+    #[serde(default)]
+    pub lock_ranges: Vec<RangeRef>,
+    /// OPAL locking ranges to unlock right before booting the entry
+    #[serde(default)]
+    pub unlock_ranges: Vec<RangeRef>,
+    /// abort open sessions and lock `lock_ranges` again right before starting the entry, so it can't reuse a session to unlock them
+    #[serde(default)]
+    pub untrusted: bool,
+    #[serde(default)]
+    pub initrd_delivery: InitrdDelivery,
+    /// devicetree installed in place of the firmware's before starting the entry (AArch64 only)
+    pub dtb: Option<File>,
+}
+
+/// How the initramfs is handed to the kernel's EFI stub
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InitrdDelivery {
+    /// placed in memory and passed as `initrdmem=<address>,<length>`
+    #[default]
+    Initrdmem,
+    /// served through the LoadFile2 protocol, which the stub looks for since Linux 5.7
+    LoadFile2,
+    /// as the file named by an `initrd=` option on a volume in memory, for older kernels
+    Legacy,
+}
+
+/// A locking range of the OPAL drive configured as `partition`
+#[derive(Debug, serde::Deserialize)]
+pub struct RangeRef {
+    pub partition: String,
+    /// 0 is the global range
+    pub range: u8,
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -0,0 +1,181 @@
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+use uefi::proto::console::text::{Color, Input, Key, Output};
+use uefi::table::boot::{OpenProtocolAttributes, OpenProtocolParams};
+use uefi::table::{Boot, SystemTable};
+use uefi::{CStr16, Event, Handle};
+use uefi::proto::{unsafe_protocol, Protocol};
+use crate::{Result, Context};
+
+static MIRROR: AtomicBool = AtomicBool::new(false);
+
+/// Also write to / read from every individual console instead of just ConOut / ConIn
+pub fn set_mirror(mirror: bool) {
+    MIRROR.store(mirror, Ordering::Relaxed);
+}
+
+/// installed by the console platform driver on every device the console splitter drives as ConOut
+#[unsafe_protocol("d3b36f2c-d551-11d4-9a46-0090273fc14d")]
+struct ConsoleOutDevice;
+
+/// installed by the console platform driver on every device the console splitter drives as ConIn
+#[unsafe_protocol("d3b36f2b-d551-11d4-9a46-0090273fc14d")]
+struct ConsoleInDevice;
+
+/// Consoles opened for mirroring, kept until the set of handles changes, e.g. by a hotplugged keyboard
+struct Mirrors<P> {
+    handles: Vec<Handle>,
+    consoles: Vec<*mut P>,
+}
+
+struct Global<P>(UnsafeCell<Option<Mirrors<P>>>);
+// UEFI boot services are single-threaded
+unsafe impl<P> Sync for Global<P> {}
+
+static OUTPUTS: Global<Output> = Global(UnsafeCell::new(None));
+static INPUTS: Global<Input> = Global(UnsafeCell::new(None));
+
+/// All consoles of type `P` except for `primary`, which is the one from the system table,
+/// and those with the marker protocol `D`.
+///
+/// With a console splitter in place, the system table's console is the splitter,
+/// so we would otherwise print everything twice to the devices behind it.
+fn mirrors<P: Protocol, D: Protocol>(st: &SystemTable<Boot>, primary: *const P, cache: &'static Global<P>) -> Vec<*mut P> {
+    if !MIRROR.load(Ordering::Relaxed) {
+        return Vec::new();
+    }
+    let bt = st.boot_services();
+    let handles: Vec<Handle> = match bt.find_handles::<P>() {
+        Ok(handles) => handles,
+        Err(e) => {
+            log::warn!("can't list consoles to mirror: {e:?}");
+            return Vec::new();
+        }
+    };
+    let cache = unsafe { &mut *cache.0.get() };
+    if let Some(mirrors) = cache.as_ref().filter(|mirrors| mirrors.handles == handles) {
+        return mirrors.consoles.clone();
+    }
+    let consoles = handles.iter()
+        .filter(|&&handle| {
+            let params = OpenProtocolParams { handle, agent: bt.image_handle(), controller: None };
+            bt.test_protocol::<D>(params).is_err()
+        })
+        .filter_map(|&handle| {
+            let params = OpenProtocolParams { handle, agent: bt.image_handle(), controller: None };
+            let mut console = unsafe { bt.open_protocol::<P>(params, OpenProtocolAttributes::GetProtocol) }.ok()?;
+            let console = &mut *console as *mut P;
+            // stays valid for as long as the handle carries the protocol, which the cache checks on every call
+            Some(console)
+        })
+        .filter(|&console| !core::ptr::eq(console, primary))
+        .collect::<Vec<_>>();
+    *cache = Some(Mirrors { handles, consoles: consoles.clone() });
+    consoles
+}
+
+fn with_outputs(st: &SystemTable<Boot>, mut f: impl FnMut(&mut Output) -> uefi::Result) -> uefi::Result {
+    let mut primary = unsafe { st.unsafe_clone() };
+    let primary = primary.stdout();
+    let res = f(primary);
+    for output in mirrors::<Output, ConsoleOutDevice>(st, primary, &OUTPUTS) {
+        // mirrors are best-effort; only the primary console's errors matter
+        let _ = f(unsafe { &mut *output });
+    }
+    res
+}
+
+pub fn write_str(st: &SystemTable<Boot>, s: &str) {
+    crate::screenshot::record(s);
+    let _ = with_outputs(st, |output| {
+        output.write_str(s).unwrap();
+        Ok(())
+    });
+}
+
+pub fn output_string(st: &SystemTable<Boot>, s: &CStr16) -> Result {
+    crate::screenshot::record(&s.to_string());
+    with_outputs(st, |output| output.output_string(s)).context("can't output string")
+}
+
+pub fn set_cursor_position(st: &SystemTable<Boot>, column: usize, row: usize) -> Result {
+    crate::screenshot::record_cursor(column, row);
+    with_outputs(st, |output| output.set_cursor_position(column, row))
+        .context("can't set cursor position")
+}
+
+/// cursor position of the primary console, which all mirrors follow
+pub fn cursor_position(st: &SystemTable<Boot>) -> (usize, usize) {
+    let mut st = unsafe { st.unsafe_clone() };
+    st.stdout().cursor_position()
+}
+
+/// whether the primary console currently shows its cursor
+pub fn cursor_visible(st: &SystemTable<Boot>) -> bool {
+    let mut st = unsafe { st.unsafe_clone() };
+    st.stdout().cursor_visible()
+}
+
+/// Shows or hides the cursor; best-effort as not all consoles can hide it
+pub fn enable_cursor(st: &SystemTable<Boot>, visible: bool) {
+    if let Err(e) = with_outputs(st, |output| output.enable_cursor(visible)) {
+        log::trace!("can't set cursor visibility to {visible}: {e:?}");
+    }
+}
+
+/// columns and rows of the primary console's current mode
+pub fn size(st: &SystemTable<Boot>) -> (usize, usize) {
+    let mut st = unsafe { st.unsafe_clone() };
+    st.stdout().current_mode().ok().flatten().map_or((80, 25), |mode| (mode.columns(), mode.rows()))
+}
+
+pub fn set_color(st: &SystemTable<Boot>, foreground: Color, background: Color) -> Result {
+    with_outputs(st, |output| output.set_color(foreground, background))
+        .context("can't set console color")
+}
+
+pub fn clear(st: &SystemTable<Boot>) -> Result {
+    crate::screenshot::record_clear(st);
+    with_outputs(st, |output| output.clear()).context("can't clear screen")
+}
+
+/// Reads a key from any console without waiting
+pub fn read_key(st: &SystemTable<Boot>) -> Result<Option<Key>> {
+    let mut con = unsafe { st.unsafe_clone() };
+    if let Some(key) = con.stdin().read_key().context("can't read key")? {
+        return Ok(Some(key));
+    }
+    let primary = con.stdin() as *const Input;
+    for input in mirrors::<Input, ConsoleInDevice>(st, primary, &INPUTS) {
+        if let Ok(Some(key)) = unsafe { &mut *input }.read_key() {
+            return Ok(Some(key));
+        }
+    }
+    Ok(None)
+}
+
+/// Waits for a keypress on any console
+pub fn wait_for_key(st: &SystemTable<Boot>) -> Result<Key> {
+    let mut con = unsafe { st.unsafe_clone() };
+    let primary = con.stdin() as *const Input;
+    let inputs = mirrors::<Input, ConsoleInDevice>(st, primary, &INPUTS);
+    let mut events: Vec<Event> = core::iter::once(unsafe { con.stdin().wait_for_key_event().unsafe_clone() })
+        .chain(inputs.iter().map(|&input| unsafe { (*input).wait_for_key_event().unsafe_clone() }))
+        .collect();
+
+    loop {
+        let index = st.boot_services()
+            .wait_for_event(&mut events)
+            .context("error waiting for key event")?;
+        let key = match index {
+            0 => con.stdin().read_key().context("error reading key")?,
+            i => unsafe { &mut *inputs[i - 1] }.read_key().context("error reading key of mirrored console")?,
+        };
+        if let Some(key) = key {
+            return Ok(key)
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use alloc::string::String;
+use core::fmt::Write as _;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use uefi::proto::device_path::DevicePath;
+use uefi::proto::device_path::text::{AllowShortcuts, DisplayOnly};
+use uefi::proto::media::block::BlockIO;
+use uefi::table::runtime::ResetType;
+use uefi::table::{Boot, SystemTable};
+use uefi::{CString16, Status};
+use crate::{console, logging, util, Context, Result};
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Writes a crash dump to the greeter's volume, then resets
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let st = unsafe { uefi_services::system_table().as_ref() };
+    if PANICKING.swap(true, Ordering::Relaxed) {
+        // panicked while dumping, don't try again
+        st.runtime_services().reset(ResetType::COLD, Status::ABORTED, None);
+    }
+    console::write_str(st, &format!("\r\npanic: {info}\r\n"));
+    match write_dump(st, info) {
+        Ok(path) => console::write_str(st, &format!("crash dump written to {path}\r\n")),
+        Err(e) => console::write_str(st, &format!("can't write crash dump: {e}\r\n")),
+    }
+    logging::flush();
+    console::write_str(st, "Resetting in 10s\r\n");
+    util::sleep(Duration::from_secs(10));
+    st.runtime_services().reset(ResetType::COLD, Status::ABORTED, None)
+}
+
+fn write_dump(st: &SystemTable<Boot>, info: &PanicInfo) -> Result<String> {
+    let mut dump = String::new();
+    let _ = writeln!(dump, "panic: {info}");
+    let _ = writeln!(dump, "boot session: {}", logging::boot_id());
+    let _ = writeln!(dump, "\nrecent log:");
+    dump.push_str(&logging::recent());
+    let _ = writeln!(dump, "\ndevices:");
+    inventory(st, &mut dump);
+
+    let timestamp = match st.runtime_services().get_time() {
+        Ok(t) => format!("{:04}{:02}{:02}-{:02}{:02}{:02}", t.year(), t.month(), t.day(), t.hour(), t.minute(), t.second()),
+        Err(_) => String::from("unknown"),
+    };
+    let path = format!("\\opal-greeter\\crash-{timestamp}.txt");
+    let volume = crate::config::image_volume(st.boot_services().image_handle(), st)?;
+    let _write_access = util::WriteAccess::grant();
+    util::create_greeter_dir(st, volume)?;
+    let path16 = CString16::try_from(path.as_str()).context("crash dump path is not valid UTF-16")?;
+    util::write_full_file(st, volume, &path16, dump.as_bytes())?;
+    Ok(path)
+}
+
+/// BlockIO devices with their paths, as far as the firmware can still tell us
+fn inventory(st: &SystemTable<Boot>, dump: &mut String) {
+    let bt = st.boot_services();
+    let Ok(handles) = bt.find_handles::<BlockIO>() else {
+        dump.push_str("<can't list BlockIO handles>\n");
+        return;
+    };
+    for handle in handles {
+        let path = bt.open_protocol_exclusive::<DevicePath>(handle).ok()
+            .and_then(|dp| dp.to_string(bt, DisplayOnly(true), AllowShortcuts(false)).ok().flatten())
+            .map_or_else(|| String::from("<unprintable device path>"), |dp| format!("{}", &*dp));
+        match bt.open_protocol_exclusive::<BlockIO>(handle) {
+            Ok(blockio) => {
+                let media = blockio.media();
+                let _ = writeln!(
+                    dump, "{path}: {} blocks of {} bytes{}{}",
+                    media.last_block() + 1, media.block_size(),
+                    if media.is_logical_partition() { ", partition" } else { "" },
+                    if media.is_removable_media() { ", removable" } else { "" },
+                );
+            }
+            Err(_) => {
+                let _ = writeln!(dump, "{path}: <BlockIO in use>");
+            }
+        }
+    }
+}
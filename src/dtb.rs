@@ -0,0 +1,61 @@
+use core::ffi::c_void;
+use uefi::table::boot::{AllocateType, MemoryType};
+use uefi::table::{Boot, SystemTable};
+use uefi::{guid, Guid};
+use crate::config::{Config, File};
+use crate::{Error, Result, Context};
+
+const DEVICE_TREE_GUID: Guid = guid!("b1b621d5-f19c-41a5-830b-d9152c69aae0");
+const FDT_MAGIC: u32 = 0xd00dfeed;
+
+/// A devicetree installed in the configuration table in place of the firmware's
+pub struct Installed {
+    previous: Option<*const c_void>,
+    pages: (u64, usize),
+}
+
+/// Loads the entry's `.dtb` and installs it as the system's devicetree, for boards whose firmware one is inadequate
+pub fn install(st: &SystemTable<Boot>, config: &Config, file: &File) -> Result<Installed> {
+    if !cfg!(target_arch = "aarch64") {
+        return Err(Error::new_without_source(format!("can't load devicetree `{}`, devicetrees are only supported on AArch64", file.file)));
+    }
+    let dtb = crate::resolve_and_read_file(st, config, file)?;
+    let header = |offset: usize| dtb.get(offset..offset + 4).map(|b| u32::from_be_bytes(b.try_into().unwrap()));
+    if header(0) != Some(FDT_MAGIC) {
+        return Err(Error::new_without_source(format!("`{}` is not a flattened devicetree", file.file)));
+    }
+    let Some(total_size) = header(4).map(|size| size as usize).filter(|&size| size <= dtb.len()) else {
+        return Err(Error::new_without_source(format!("`{}` is truncated", file.file)));
+    };
+
+    let bt = st.boot_services();
+    // the kernel keeps using the devicetree after exit_boot_services, like the firmware's own
+    let num_pages = (total_size + 4095) / 4096;
+    let addr = bt.allocate_pages(AllocateType::AnyPages, MemoryType::ACPI_RECLAIM, num_pages)
+        .context("can't allocate memory for the devicetree")?;
+    let buffer = unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, total_size) };
+    buffer.copy_from_slice(&dtb[..total_size]);
+
+    let previous = st.config_table().iter().find(|entry| entry.guid == DEVICE_TREE_GUID).map(|entry| entry.address);
+    if let Err(e) = unsafe { bt.install_configuration_table(&DEVICE_TREE_GUID, addr as *const c_void) } {
+        let _ = bt.free_pages(addr, num_pages);
+        return Err(Error::new_from_uefi(e, "can't install the devicetree"));
+    }
+    log::info!("installed devicetree `{}` ({total_size} bytes)", file.file);
+    Ok(Installed { previous, pages: (addr, num_pages) })
+}
+
+impl Installed {
+    /// Puts the firmware's devicetree back, for when the image returned
+    pub fn restore(self, st: &SystemTable<Boot>) {
+        let bt = st.boot_services();
+        let previous = self.previous.unwrap_or(core::ptr::null());
+        // a null table removes the entry again if the firmware had none
+        match unsafe { bt.install_configuration_table(&DEVICE_TREE_GUID, previous) } {
+            Ok(()) => {
+                let _ = bt.free_pages(self.pages.0, self.pages.1);
+            }
+            Err(e) => log::warn!("can't restore the firmware's devicetree: {e:?}"),
+        }
+    }
+}
@@ -0,0 +1,30 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::config::FirmwareWarning;
+
+/// part of the model number, the affected firmware revisions and what's wrong with them
+const KNOWN_BAD: &[(&str, &[&str], &str)] = &[
+    // "Self-encrypting deception", Meijer & van Gastel, 2018; Crucial fixed it in MU03, MU05 and M0CR040
+    ("MX100", &["MU01", "MU02"], "the encryption key may not depend on the password; update the SSD firmware"),
+    ("MX200", &["MU01", "MU02", "MU03", "MU04"], "the encryption key may not depend on the password; update the SSD firmware"),
+    ("MX300", &["M0CR011", "M0CR021", "M0CR031"], "the encryption key may not depend on the password; update the SSD firmware"),
+    // Samsung released no fix for these, only advised software encryption
+    ("Samsung SSD 840 EVO", &["EXT0AB0Q", "EXT0BB0Q", "EXT0BB6Q", "EXT0CB6Q", "EXT0DB6Q"], "hardware encryption has known weaknesses; prefer software encryption"),
+    ("Samsung SSD 850 EVO", &["EMT01B6Q", "EMT02B6Q"], "hardware encryption has known weaknesses; prefer software encryption"),
+];
+
+/// Warnings for the drive from the embedded table and the config's `firmware_warnings`
+pub fn warnings(model: &str, firmware: &str, extra: &[FirmwareWarning]) -> Vec<String> {
+    let matches = |part: &str, revision: Option<&str>| {
+        model.contains(part) && revision.map_or(true, |revision| revision == firmware)
+    };
+    let embedded = KNOWN_BAD.iter()
+        .filter(|(part, revisions, _)| model.contains(part) && revisions.contains(&firmware))
+        .map(|(_, _, note)| *note);
+    let configured = extra.iter()
+        .filter(|warning| matches(&warning.model, warning.firmware.as_deref()))
+        .map(|warning| warning.note.as_str());
+    configured.chain(embedded)
+        .map(|note| format!("{model} firmware {firmware}: {note}"))
+        .collect()
+}
@@ -0,0 +1,68 @@
+use core::ffi::c_void;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+use core::time::Duration;
+use uefi::{Event, Identify};
+use uefi::table::boot::{BootServices, EventType, SearchType, Tpl};
+use uefi::table::{Boot, SystemTable};
+use crate::config::Hotplug;
+use crate::low_level::nvme_passthru::NvmExpressPassthru;
+use crate::util;
+
+/// signaled whenever an NVMe controller's pass-through protocol gets installed
+static ARRIVAL: AtomicPtr<c_void> = AtomicPtr::new(null_mut());
+static SETTLE_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Gives controllers that show up late, like NVMe drives in Thunderbolt/USB4 enclosures, time to appear
+/// and watches for further ones, so discovery can be repeated when they arrive
+pub fn init(st: &SystemTable<Boot>, config: &Hotplug) {
+    if config.settle_ms == 0 {
+        return;
+    }
+    SETTLE_MS.store(config.settle_ms, Ordering::Relaxed);
+    let bt = st.boot_services();
+    let before = nvme_controllers(bt);
+    settle(bt);
+    log::debug!("NVMe controllers: {before} before settling, {} after", nvme_controllers(bt));
+
+    let event = match unsafe { bt.create_event(EventType::empty(), Tpl::CALLBACK, None, None) } {
+        Ok(event) => event,
+        Err(e) => {
+            log::warn!("can't create event for NVMe controller arrival: {e:?}");
+            return;
+        }
+    };
+    match bt.register_protocol_notify(&NvmExpressPassthru::GUID, event) {
+        Ok((event, _)) => ARRIVAL.store(event.as_ptr(), Ordering::Relaxed),
+        Err(e) => log::warn!("can't watch for NVMe controller arrival: {e:?}"),
+    }
+}
+
+/// Connects newly arrived controllers so their drives show up; call before enumerating drives
+pub fn poll(st: &SystemTable<Boot>) {
+    let Some(event) = (unsafe { Event::from_ptr(ARRIVAL.load(Ordering::Relaxed)) }) else { return };
+    let bt = st.boot_services();
+    if bt.check_event(event).unwrap_or(false) {
+        log::info!("NVMe controller arrived, re-running discovery");
+        settle(bt);
+    }
+}
+
+/// Waits the settle delay and connects all drivers to all controllers, twice,
+/// as tunnelled PCIe only appears once the enclosure's controller is connected
+fn settle(bt: &BootServices) {
+    connect_all(bt);
+    util::sleep(Duration::from_millis(SETTLE_MS.load(Ordering::Relaxed)));
+    connect_all(bt);
+}
+
+fn connect_all(bt: &BootServices) {
+    let Ok(handles) = bt.locate_handle_buffer(SearchType::AllHandles) else { return };
+    for &handle in handles.iter() {
+        let _ = bt.connect_controller(handle, None, None, true);
+    }
+}
+
+fn nvme_controllers(bt: &BootServices) -> usize {
+    bt.find_handles::<NvmExpressPassthru>().map_or(0, |handles| handles.len())
+}
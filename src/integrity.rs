@@ -0,0 +1,83 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use sha2::{Digest, Sha256};
+use uefi::cstr16;
+use uefi::proto::loaded_image::LoadedImage;
+use uefi::table::runtime::VariableVendor;
+use uefi::table::{Boot, SystemTable};
+use crate::util::nvram;
+use crate::{console, pe};
+
+/// Tamper evidence for when Secure Boot doesn't verify the greeter: hashes the greeter's own loaded image and
+/// compares it against `OpalGreeterImageHash`, which is only trusted if it's a time-based authenticated variable.
+///
+/// That only means later updates need the key that signed its first write. Whoever creates it first picks that key,
+/// and it's not checked against any known signer, so the variable has to be provisioned before the machine is deployed;
+/// one an attacker created first passes the check.
+///
+/// Only read-only, non-discardable sections are hashed, with relocated addresses normalized to RVAs,
+/// so the hash doesn't depend on where the image was loaded. It's logged so the variable can be provisioned.
+pub fn check(st: &SystemTable<Boot>) {
+    if secure_boot(st) {
+        log::debug!("Secure Boot is on, skipping the image self-check");
+        return;
+    }
+    let Some(hash) = image_hash(st) else {
+        log::warn!("can't hash the greeter's own image");
+        return;
+    };
+    let hash: String = hash.iter().map(|b| format!("{b:02x}")).collect();
+    log::info!("greeter image hash: {hash}");
+
+    let Some(expected) = nvram::read_authenticated(st, cstr16!("OpalGreeterImageHash")) else {
+        log::debug!("no expected image hash provisioned");
+        return;
+    };
+    let expected: String = expected.iter().map(|b| format!("{b:02x}")).collect();
+    if expected != hash {
+        log::warn!("greeter image hash mismatch: expected {expected}, got {hash}");
+        console::write_str(st, "Warning: this greeter doesn't match the provisioned image hash, it may have been tampered with.\r\n");
+    }
+}
+
+/// whether the firmware verifies images with Secure Boot
+pub fn secure_boot(st: &SystemTable<Boot>) -> bool {
+    let mut buf = [0; 1];
+    st.runtime_services()
+        .get_variable(cstr16!("SecureBoot"), &VariableVendor::GLOBAL_VARIABLE, &mut buf)
+        .map_or(false, |(value, _)| value == [1])
+}
+
+fn image_hash(st: &SystemTable<Boot>) -> Option<[u8; 32]> {
+    let bt = st.boot_services();
+    let loaded_image = bt.open_protocol_exclusive::<LoadedImage>(bt.image_handle()).ok()?;
+    let (base, size) = loaded_image.info();
+    let image = unsafe { core::slice::from_raw_parts(base as *const u8, size as usize) };
+    let base = base as u64;
+
+    let relocations = pe::base_relocations(image).unwrap_or_default();
+    let mut hash = Sha256::new();
+    for section in pe::sections(image)? {
+        if section.characteristics & (pe::SCN_MEM_WRITE | pe::SCN_MEM_DISCARDABLE) != 0 {
+            continue;
+        }
+        let start = section.rva as usize;
+        let mut data: Vec<u8> = image.get(start..start.checked_add(section.size as usize)?)?.to_vec();
+        for &(rva, kind) in &relocations {
+            let Some(offset) = (rva as usize).checked_sub(start) else { continue };
+            match kind {
+                pe::REL_BASED_DIR64 => if let Some(value) = data.get_mut(offset..offset + 8) {
+                    let address = u64::from_le_bytes(value.try_into().unwrap());
+                    value.copy_from_slice(&address.wrapping_sub(base).to_le_bytes());
+                },
+                pe::REL_BASED_HIGHLOW => if let Some(value) = data.get_mut(offset..offset + 4) {
+                    let address = u32::from_le_bytes(value.try_into().unwrap());
+                    value.copy_from_slice(&address.wrapping_sub(base as u32).to_le_bytes());
+                },
+                _ => (),
+            }
+        }
+        hash.update(&data);
+    }
+    Some(hash.finalize().into())
+}
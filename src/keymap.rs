@@ -0,0 +1,123 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU8, Ordering};
+use uefi::cstr16;
+use uefi::table::boot::{OpenProtocolAttributes, OpenProtocolParams};
+use uefi::table::runtime::VariableVendor;
+use uefi::table::{Boot, SystemTable};
+use crate::config::Keymap;
+use crate::low_level::hii_database::HiiDatabase;
+use crate::smbios;
+
+static ACTIVE: AtomicU8 = AtomicU8::new(Keymap::Us as u8);
+
+/// BIOS language information
+const SMBIOS_TYPE_LANGUAGE: u8 = 13;
+
+/// characters the firmware reports for a US layout and what the same key produces in the layout
+const DE: &[(char, char)] = &[
+    ('y', 'z'), ('z', 'y'), ('Y', 'Z'), ('Z', 'Y'),
+    ('-', 'ß'), ('_', '?'), ('=', '´'), ('+', '`'),
+    ('[', 'ü'), ('{', 'Ü'), (']', '+'), ('}', '*'),
+    (';', 'ö'), (':', 'Ö'), ('\'', 'ä'), ('"', 'Ä'),
+    ('\\', '#'), ('|', '\''), ('`', '^'), ('~', '°'),
+    ('/', '-'), ('?', '_'), ('<', ';'), ('>', ':'),
+    ('@', '"'), ('#', '§'), ('^', '&'), ('&', '/'),
+    ('*', '('), ('(', ')'), (')', '='),
+];
+const FR: &[(char, char)] = &[
+    ('q', 'a'), ('a', 'q'), ('Q', 'A'), ('A', 'Q'),
+    ('w', 'z'), ('z', 'w'), ('W', 'Z'), ('Z', 'W'),
+    (';', 'm'), (':', 'M'), ('m', ','), ('M', '?'),
+    ('1', '&'), ('2', 'é'), ('3', '"'), ('4', '\''), ('5', '('),
+    ('6', '-'), ('7', 'è'), ('8', '_'), ('9', 'ç'), ('0', 'à'),
+    ('!', '1'), ('@', '2'), ('#', '3'), ('$', '4'), ('%', '5'),
+    ('^', '6'), ('&', '7'), ('*', '8'), ('(', '9'), (')', '0'),
+    ('-', ')'), ('_', '°'), ('[', '^'), ('{', '¨'), (']', '$'), ('}', '£'),
+    ('\'', 'ù'), ('"', '%'), ('\\', '*'), ('|', 'µ'), ('`', '²'),
+    (',', ';'), ('<', '.'), ('.', ':'), ('>', '/'), ('/', '!'), ('?', '§'),
+];
+
+/// Selects the keymap: the configured one, or one guessed from the platform language and SMBIOS.
+///
+/// The tables assume the firmware reports the characters of a US layout, so nothing is translated
+/// when it already applies another one, which would otherwise be remapped a second time.
+pub fn init(st: &SystemTable<Boot>, keymap: Keymap) {
+    let keymap = match keymap {
+        Keymap::Auto => detect(st),
+        keymap => keymap,
+    };
+    let keymap = match firmware_layout(st) {
+        Some(language) if !language.eq_ignore_ascii_case("en-US") => {
+            if keymap != Keymap::Us {
+                log::info!("the firmware already applies the {language} keyboard layout, not translating to {keymap:?}");
+            }
+            Keymap::Us
+        }
+        _ => keymap,
+    };
+    log::info!("using keymap {keymap:?}");
+    ACTIVE.store(keymap as u8, Ordering::Relaxed);
+}
+
+/// translates a character as reported by the firmware into the active layout
+pub fn translate(c: char) -> char {
+    let table = match ACTIVE.load(Ordering::Relaxed) {
+        k if k == Keymap::De as u8 => DE,
+        k if k == Keymap::Fr as u8 => FR,
+        _ => return c,
+    };
+    table.iter().find(|(from, _)| *from == c).map_or(c, |(_, to)| *to)
+}
+
+/// Language of the keyboard layout the firmware applies, e.g. `de-DE`;
+/// `None` if it doesn't report one, in which case its drivers pass on US characters
+fn firmware_layout(st: &SystemTable<Boot>) -> Option<String> {
+    let bt = st.boot_services();
+    let handle = bt.get_handle_for_protocol::<HiiDatabase>().ok()?;
+    let params = OpenProtocolParams { handle, agent: bt.image_handle(), controller: None };
+    let hii = unsafe { bt.open_protocol::<HiiDatabase>(params, OpenProtocolAttributes::GetProtocol) }.ok()?;
+    match hii.keyboard_layout_language() {
+        Ok(language) => {
+            log::debug!("firmware keyboard layout: {language:?}");
+            language
+        }
+        Err(e) => {
+            log::debug!("can't get the firmware's keyboard layout: {e:?}");
+            None
+        }
+    }
+}
+
+fn detect(st: &SystemTable<Boot>) -> Keymap {
+    let mut hints = Vec::new();
+    // e.g. `en-US`
+    let mut buf = [0u8; 16];
+    if let Ok((lang, _)) = st.runtime_services().get_variable(cstr16!("PlatformLang"), &VariableVendor::GLOBAL_VARIABLE, &mut buf) {
+        hints.push(String::from_utf8_lossy(lang).trim_end_matches('\0').into());
+    }
+    // current language, e.g. `de|DE|iso8859-1` or `deDE`
+    if let Some(language) = smbios::find(st, SMBIOS_TYPE_LANGUAGE) {
+        if let Some(current) = language.string(0x15) {
+            hints.push(String::from(current));
+        }
+    }
+    log::debug!("keymap hints: {hints:?}");
+    hints.iter()
+        .find_map(|hint| from_locale(hint))
+        .unwrap_or(Keymap::Us)
+}
+
+/// maps the country of a locale like `de-AT`, `fr|FR|iso8859-1` or `frCA` to its usual layout
+fn from_locale(locale: &str) -> Option<Keymap> {
+    let letters: String = locale.chars().filter(|c| c.is_ascii_alphabetic()).take(4).collect();
+    if letters.len() < 4 {
+        return None;
+    }
+    let country = letters[2..4].to_ascii_uppercase();
+    match &*country {
+        "DE" | "AT" | "CH" | "LI" => Some(Keymap::De),
+        "FR" | "BE" => Some(Keymap::Fr),
+        _ => Some(Keymap::Us),
+    }
+}
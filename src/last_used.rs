@@ -0,0 +1,32 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use uefi::cstr16;
+use uefi::table::{Boot, SystemTable};
+use crate::config::{Config, Sort};
+use crate::util::nvram;
+
+/// entries remembered; older ones sort like entries never booted
+const MAX_ENTRIES: usize = 32;
+
+/// Names of the booted entries, most recent first; only kept with `sort = "last-used"`
+pub fn load(st: &SystemTable<Boot>, config: &Config) -> Vec<String> {
+    if config.sort != Sort::LastUsed {
+        return Vec::new();
+    }
+    let Some(data) = nvram::read(st, cstr16!("OpalGreeterLastUsed")) else { return Vec::new() };
+    String::from_utf8_lossy(&data).lines().map(ToString::to_string).collect()
+}
+
+/// Moves the entry to the front right before it's booted
+pub fn record(st: &SystemTable<Boot>, config: &Config, name: &str) {
+    if config.sort != Sort::LastUsed {
+        return;
+    }
+    let mut names = load(st, config);
+    names.retain(|other| other != name);
+    names.insert(0, name.to_string());
+    names.truncate(MAX_ENTRIES);
+    if let Err(e) = nvram::write(st, cstr16!("OpalGreeterLastUsed"), names.join("\n").as_bytes()) {
+        log::warn!("can't remember the booted entry: {e}");
+    }
+}
@@ -0,0 +1,37 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use crate::config::{BootEntry, Config};
+
+struct Global(UnsafeCell<Vec<String>>);
+// UEFI boot services are single-threaded
+unsafe impl Sync for Global {}
+
+/// serials of the drives that were locked at startup and weren't unlocked since
+static LOCKED: Global = Global(UnsafeCell::new(Vec::new()));
+
+fn locked() -> &'static mut Vec<String> {
+    unsafe { &mut *LOCKED.0.get() }
+}
+
+pub fn record(serial: &str) {
+    if !locked().iter().any(|s| s == serial) {
+        locked().push(serial.into());
+    }
+}
+
+pub fn unlocked(serial: &str) {
+    locked().retain(|s| s != serial);
+}
+
+/// Whether the entry's partition, or one it's nested in, is on a drive that is still locked.
+/// Such entries are shown with a note instead of a detected OS, which can't be read yet
+pub fn entry_locked(config: &Config, entry: &BootEntry) -> bool {
+    !locked().is_empty() && partition_locked(config, &entry.file.partition)
+}
+
+fn partition_locked(config: &Config, name: &str) -> bool {
+    let Some(partition) = config.partitions.get(name) else { return false };
+    locked().iter().any(|serial| *serial == partition.uuid)
+        || partition.parent.as_ref().map_or(false, |parent| partition_locked(config, parent))
+}
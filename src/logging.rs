@@ -0,0 +1,358 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::fmt::Write as _;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use log::{LevelFilter, Log, Metadata, Record};
+use uefi::proto::console::serial::Serial;
+use uefi::proto::console::text::{Key, ScanCode};
+use uefi::table::boot::{OpenProtocolAttributes, OpenProtocolParams, ScopedProtocol};
+use uefi::table::{Boot, SystemTable};
+use uefi::{cstr16, CString16, Handle};
+use crate::config::{Config, KeyName, LogSink};
+use crate::{clock, console, low_memory, ui, util, Context, Result};
+
+/// A destination for log lines; each has its own level in the registry
+trait Sink {
+    fn write(&mut self, line: &str);
+    /// persists what was buffered; called before handing off and before fatal resets
+    fn flush(&mut self) {}
+    /// whether lines start with the boot session ID, which only clutters the screen
+    fn tagged(&self) -> bool {
+        true
+    }
+}
+
+struct Global<T>(UnsafeCell<T>);
+// UEFI boot services are single-threaded
+unsafe impl<T> Sync for Global<T> {}
+
+static SINKS: Global<Vec<(LevelFilter, Box<dyn Sink>)>> = Global(UnsafeCell::new(Vec::new()));
+/// recent output for the log viewer
+static RECENT: Global<VecDeque<u8>> = Global(UnsafeCell::new(VecDeque::new()));
+static VIEWER_HOTKEY: Global<Option<KeyName>> = Global(UnsafeCell::new(None));
+/// drops records logged while a sink is writing, e.g. by the console mirroring
+static BUSY: AtomicBool = AtomicBool::new(false);
+/// random per boot, so lines of several boots in one log file or variable can be told apart
+static BOOT_ID: AtomicU32 = AtomicU32::new(0);
+/// RTC time of day in milliseconds when logging started, `NO_RTC` if it couldn't be read
+static START_OF_DAY_MS: AtomicU64 = AtomicU64::new(NO_RTC);
+const NO_RTC: u64 = u64::MAX;
+/// numbers lines on processors without a counter
+static LINES: AtomicU64 = AtomicU64::new(0);
+static LOGGER: Logger = Logger;
+const DEFAULT_RECENT_SIZE: usize = 16 * 1024;
+
+/// Runs `f` on the registered sinks; `f` must not log or reach the sinks again, which `BUSY` guards in `Logger`
+fn with_sinks<R>(f: impl FnOnce(&mut Vec<(LevelFilter, Box<dyn Sink>)>) -> R) -> R {
+    f(unsafe { &mut *SINKS.0.get() })
+}
+
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // a sink logging while it writes must not reach the sinks again
+        !BUSY.load(Ordering::Relaxed) && with_sinks(|sinks| sinks.iter().any(|(level, _)| metadata.level() <= *level))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) || BUSY.swap(true, Ordering::Acquire) {
+            return;
+        }
+        let mut line = String::new();
+        let _ = write!(
+            line, "{} [{:>5}]: {}@{:03}: {}\r\n",
+            timestamp(), record.level(), record.file().unwrap_or("<unknown>"), record.line().unwrap_or(0), record.args(),
+        );
+        let tagged = format!("{} {line}", boot_id());
+        with_sinks(|sinks| {
+            for (level, sink) in sinks {
+                if record.level() <= *level {
+                    sink.write(if sink.tagged() { &tagged } else { &line });
+                }
+            }
+        });
+        BUSY.store(false, Ordering::Release);
+    }
+
+    fn flush(&self) {
+        if BUSY.swap(true, Ordering::Acquire) {
+            return;
+        }
+        with_sinks(|sinks| {
+            for (_, sink) in sinks {
+                sink.flush();
+            }
+        });
+        BUSY.store(false, Ordering::Release);
+    }
+}
+
+/// Time of day with milliseconds: the RTC read once at startup plus the processor's counter since.
+/// Without a working RTC lines carry milliseconds since startup, without a counter they are at least numbered
+fn timestamp() -> String {
+    let Some(elapsed) = clock::now_ms() else {
+        return format!("#{}", LINES.fetch_add(1, Ordering::Relaxed));
+    };
+    match START_OF_DAY_MS.load(Ordering::Relaxed) {
+        NO_RTC => format!("+{elapsed}ms"),
+        start => {
+            let ms = (start + elapsed) % (24 * 60 * 60 * 1000);
+            format!("{:02}:{:02}:{:02}.{:03}", ms / 3_600_000, ms / 60_000 % 60, ms / 1000 % 60, ms % 1000)
+        }
+    }
+}
+
+/// The boot session ID as 8 hex digits
+pub fn boot_id() -> String {
+    format!("{:08x}", BOOT_ID.load(Ordering::Relaxed))
+}
+
+/// Installs the logger with only the screen and the recent output, until the config is loaded
+pub fn init() {
+    let mut id = [0; 4];
+    crate::rng::fill(&mut id);
+    BOOT_ID.store(u32::from_be_bytes(id), Ordering::Relaxed);
+    if let Ok(t) = system_table().runtime_services().get_time() {
+        let seconds = (u64::from(t.hour()) * 60 + u64::from(t.minute())) * 60 + u64::from(t.second());
+        START_OF_DAY_MS.store(seconds * 1000 + u64::from(t.nanosecond()) / 1_000_000, Ordering::Relaxed);
+    }
+    with_sinks(|sinks| {
+        sinks.push((LevelFilter::Info, Box::new(Screen)));
+        sinks.push((LevelFilter::Info, Box::new(Recent { size: DEFAULT_RECENT_SIZE })));
+    });
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(LevelFilter::Info);
+    }
+}
+
+/// Sets the screen's level to `log_level` and adds the configured sinks
+pub fn configure(st: &SystemTable<Boot>, image_handle: Handle, config: &Config) {
+    let mut configured: Vec<(LevelFilter, Box<dyn Sink>)> = vec![(config.log_level, Box::new(Screen))];
+    for sink in &config.log_sinks {
+        match sink {
+            LogSink::Serial { level } => match SerialPort::open() {
+                Some(serial) => configured.push((*level, Box::new(serial))),
+                None => log::warn!("can't log to the serial port, there is none"),
+            },
+            LogSink::File { level, path, size_kib } => match LogFile::new(st, image_handle, path, *size_kib * 1024) {
+                Ok(file) => configured.push((*level, Box::new(file))),
+                Err(e) => log::warn!("can't log to file `{path}`: {e}"),
+            },
+            LogSink::Variable { level, size } => configured.push((*level, Box::new(RingBuffer::new(low_memory::log_buffer_size(*size))))),
+        }
+    }
+    // crash dumps include the recent output even without the viewer
+    match &config.log_viewer {
+        Some(viewer) => {
+            configured.push((viewer.level, Box::new(Recent { size: low_memory::log_buffer_size(viewer.size_kib * 1024) })));
+            unsafe { *VIEWER_HOTKEY.0.get() = Some(viewer.hotkey) };
+        }
+        None => configured.push((LevelFilter::Info, Box::new(Recent { size: low_memory::log_buffer_size(DEFAULT_RECENT_SIZE) }))),
+    }
+    let max = configured.iter().map(|(level, _)| *level).max().unwrap_or(LevelFilter::Off);
+    with_sinks(|sinks| *sinks = configured);
+    log::set_max_level(max);
+}
+
+/// Persists buffered sinks
+pub fn flush() {
+    log::logger().flush();
+}
+
+fn system_table() -> &'static SystemTable<Boot> {
+    unsafe { uefi_services::system_table().as_ref() }
+}
+
+struct Screen;
+
+impl Sink for Screen {
+    fn write(&mut self, line: &str) {
+        console::write_str(system_table(), line);
+    }
+
+    fn tagged(&self) -> bool {
+        false
+    }
+}
+
+/// The first serial port, written directly so the screen stays clean.
+///
+/// Opened once and shared with the terminal driver instead of exclusively, which would disconnect a serial console
+struct SerialPort(ScopedProtocol<'static, Serial>);
+
+impl SerialPort {
+    fn open() -> Option<SerialPort> {
+        let bt = system_table().boot_services();
+        let handle = bt.get_handle_for_protocol::<Serial>().ok()?;
+        let params = OpenProtocolParams { handle, agent: bt.image_handle(), controller: None };
+        unsafe { bt.open_protocol::<Serial>(params, OpenProtocolAttributes::GetProtocol) }.ok().map(SerialPort)
+    }
+}
+
+impl Sink for SerialPort {
+    fn write(&mut self, line: &str) {
+        let _ = self.0.write(line.as_bytes());
+    }
+}
+
+/// A file on the greeter's volume that log lines are appended to, moved to `<path>.old` once it reached its size limit
+struct LogFile {
+    volume: Handle,
+    path: CString16,
+    /// lines not written yet
+    buffer: Vec<u8>,
+    /// buffered lines are written once there are more, so low-memory mode holds little without losing any
+    limit: usize,
+    /// size in bytes at which the file is moved aside
+    size: u64,
+}
+
+impl LogFile {
+    fn new(st: &SystemTable<Boot>, image_handle: Handle, path: &str, size: u64) -> Result<LogFile> {
+        let volume = crate::config::image_volume(image_handle, st)?;
+        let path = CString16::try_from(path.replace('/', "\\").as_str())
+            .context("log file path is not valid UTF-16")?;
+        Ok(LogFile { volume, path, buffer: Vec::new(), limit: low_memory::log_buffer_size(usize::MAX), size })
+    }
+
+    /// Replaces `<path>.old` with the full log file, so the next lines start a new one
+    fn rotate(&self) -> Result {
+        let path = self.path.to_string();
+        let old = CString16::try_from(format!("{path}.old").as_str()).context("log file path is not valid UTF-16")?;
+        let name = path.rsplit('\\').next().unwrap_or(&path);
+        let new_name = CString16::try_from(format!("{name}.old").as_str()).context("log file path is not valid UTF-16")?;
+        util::delete_file(system_table(), self.volume, &old)?;
+        util::rename_file(system_table(), self.volume, &self.path, &new_name)
+    }
+}
+
+impl Sink for LogFile {
+    fn write(&mut self, line: &str) {
+        self.buffer.extend_from_slice(line.as_bytes());
+        if self.buffer.len() > self.limit {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        let _write_access = util::WriteAccess::grant();
+        match util::append_file(system_table(), self.volume, &self.path, &self.buffer) {
+            Ok(written) => {
+                self.buffer.clear();
+                if written >= self.size {
+                    if let Err(e) = self.rotate() {
+                        console::write_str(system_table(), &format!("can't move full log file aside: {e}\r\n"));
+                    }
+                }
+            }
+            Err(e) => console::write_str(system_table(), &format!("can't write log file: {e}\r\n")),
+        }
+    }
+}
+
+/// The last `size` bytes of log output, for the log viewer
+struct Recent {
+    size: usize,
+}
+
+impl Sink for Recent {
+    fn write(&mut self, line: &str) {
+        let recent = unsafe { &mut *RECENT.0.get() };
+        recent.extend(line.as_bytes());
+        let excess = recent.len().saturating_sub(self.size);
+        recent.drain(..excess);
+    }
+
+    // only ever holds this boot's lines, and crash dumps name the ID once
+    fn tagged(&self) -> bool {
+        false
+    }
+}
+
+/// the recent log output
+pub fn recent() -> String {
+    let recent: Vec<u8> = unsafe { &*RECENT.0.get() }.iter().copied().collect();
+    String::from_utf8_lossy(&recent).into_owned()
+}
+
+/// whether the key opens the log viewer
+pub fn is_viewer_hotkey(key: &Key) -> bool {
+    unsafe { *VIEWER_HOTKEY.0.get() }.map_or(false, |hotkey| ui::key_matches(key, hotkey))
+}
+
+/// Scrollable view of the recent log output, starting at the end
+pub fn view(st: &SystemTable<Boot>) -> Result {
+    page(st, &recent())
+}
+
+/// Scrollable view of long text, starting at the end
+pub fn page(st: &SystemTable<Boot>, text: &str) -> Result {
+    let (columns, rows) = console::size(st);
+    let lines = crate::banner::wrap(text, columns.saturating_sub(1).max(1));
+    // keep two rows for the footer
+    let height = rows.saturating_sub(3).max(1);
+    let last = lines.len().saturating_sub(height);
+    let mut top = last;
+    loop {
+        console::clear(st)?;
+        let mut output = String::new();
+        for line in lines.iter().skip(top).take(height) {
+            output.push_str(line);
+            output.push_str("\r\n");
+        }
+        output.push_str(&format!(
+            "\r\n[lines {}-{} of {}] Up/Down/PageUp/PageDown/Home/End: scroll, Esc: close",
+            (top + 1).min(lines.len()), (top + height).min(lines.len()), lines.len(),
+        ));
+        console::write_str(st, &output);
+
+        match ui::key(st)? {
+            Key::Special(ScanCode::UP) => top = top.saturating_sub(1),
+            Key::Special(ScanCode::DOWN) => top = (top + 1).min(last),
+            Key::Special(ScanCode::PAGE_UP) => top = top.saturating_sub(height),
+            Key::Special(ScanCode::PAGE_DOWN) => top = (top + height).min(last),
+            Key::Special(ScanCode::HOME) => top = 0,
+            Key::Special(ScanCode::END) => top = last,
+            Key::Special(ScanCode::ESCAPE) => break,
+            Key::Printable(k) if [0xD, 0xA].contains(&u16::from(k)) => break,
+            key if is_viewer_hotkey(&key) => break,
+            _ => (),
+        }
+    }
+    console::clear(st)?;
+    Ok(())
+}
+
+/// The last `size` bytes of log output, kept in a non-volatile variable across reboots
+struct RingBuffer {
+    size: usize,
+    buffer: VecDeque<u8>,
+}
+
+impl RingBuffer {
+    fn new(size: usize) -> RingBuffer {
+        RingBuffer { size, buffer: VecDeque::with_capacity(size) }
+    }
+}
+
+impl Sink for RingBuffer {
+    fn write(&mut self, line: &str) {
+        self.buffer.extend(line.as_bytes());
+        let excess = self.buffer.len().saturating_sub(self.size);
+        self.buffer.drain(..excess);
+    }
+
+    fn flush(&mut self) {
+        let data: Vec<u8> = self.buffer.iter().copied().collect();
+        if let Err(e) = util::nvram::write(system_table(), cstr16!("OpalGreeterLog"), &data) {
+            console::write_str(system_table(), &format!("can't write log variable: {e}\r\n"));
+        }
+    }
+}
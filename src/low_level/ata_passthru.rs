@@ -1,6 +1,6 @@
-use alloc::boxed::Box;
 use alloc::string::String;
 use bitflags::bitflags;
+use core::time::Duration;
 use opal::SecureProtocol;
 use uefi::table::{SystemTable, Boot};
 use uefi::table::boot::ScopedProtocol;
@@ -11,13 +11,16 @@ use uefi_raw::Status;
 
 use crate::error::Error;
 use crate::low_level::nvme_device::UefiError;
-use crate::util::{alloc_init_aligned, alloc_aligned_t};
+use crate::low_level::dma::DmaBuffer;
+use crate::util::alloc_aligned_t;
 
 pub struct AtaProtocol<'a> {
     passthru: ScopedProtocol<'a, AtaPassthru>,
     port: u16,
     port_multiplier_port: u16,
     serial: [u8; 20],
+    model: [u8; 40],
+    firmware: [u8; 8],
 
     st: &'a SystemTable<Boot>,
     handle: Handle,
@@ -31,13 +34,15 @@ impl<'a> AtaProtocol<'a> {
         }
         log::info!("port={port} pmp={port_multiplier_port}");
         //let (port, port_multiplier_port) = passthru.find_first_dev().map_err(|e| Error::new_from_uefi(e, "find first dev"))?;
-        let serial = passthru.get_serial_num(port, port_multiplier_port).map_err(|e| Error::new_from_uefi(e, "get serial num"))?;
+        let (serial, model, firmware) = passthru.get_identity(port, port_multiplier_port).map_err(|e| Error::new_from_uefi(e, "get serial num"))?;
         log::info!("serial = {}", String::from_utf8_lossy(&serial));
         Ok(Self {
             passthru,
             port,
             port_multiplier_port,
             serial,
+            model,
+            firmware,
             st,
             handle,
         })
@@ -47,6 +52,7 @@ impl<'a> SecureProtocol for AtaProtocol<'a> {
     type Error = UefiError;
 
     unsafe fn secure_send(&mut self, protocol: u8, cmd_id: u16, data: &mut [u8]) -> Result<(), Self::Error> {
+        crate::protocol_trace::record("IF-SEND", protocol, cmd_id, data);
         self.passthru.do_io(self.port, self.port_multiplier_port, IoMode::Send { protocol, cmd_id, data }).map_err(|error| UefiError { error })?;
         Ok(())
     }
@@ -57,9 +63,10 @@ impl<'a> SecureProtocol for AtaProtocol<'a> {
         cmd_id: u16,
         buffer: &mut [u8],
     ) -> Result<(), Self::Error> {
-        let data = self.passthru.do_io(self.port, self.port_multiplier_port, IoMode::Recv { protocol, cmd_id }).map_err(|error| UefiError { error })?;
+        let data = self.passthru.do_io(self.port, self.port_multiplier_port, IoMode::Recv { protocol, cmd_id, len: buffer.len() }).map_err(|error| UefiError { error })?;
         let s = core::cmp::min(data.len(), buffer.len());
         buffer[..s].copy_from_slice(&data[..s]);
+        crate::protocol_trace::record("IF-RECV", protocol, cmd_id, &buffer[..s]);
         Ok(())
     }
 
@@ -80,6 +87,22 @@ impl<'a> SecureProtocol for AtaProtocol<'a> {
     fn serial_num(&self) -> &[u8] {
         &self.serial
     }
+
+    fn model_num(&self) -> &[u8] {
+        &self.model
+    }
+
+    fn firmware_rev(&self) -> &[u8] {
+        &self.firmware
+    }
+
+    fn fill_random(&mut self, buf: &mut [u8]) {
+        crate::rng::fill(buf)
+    }
+
+    fn wait(&mut self, duration: Duration) {
+        crate::util::sleep(duration)
+    }
 }
 
 #[unsafe_protocol("1d3de7f0-0807-424f-aa69-11a54e19a46f")]
@@ -135,9 +158,12 @@ pub enum AtaCommand {
 }
 
 #[derive(Clone, Copy)]
+const SECTOR_SIZE: usize = 512;
+
 enum IoMode<'a> {
     Identify,
-    Recv { protocol: u8, cmd_id: u16 },
+    /// receives `len` bytes, rounded up to whole sectors
+    Recv { protocol: u8, cmd_id: u16, len: usize },
     Send {
         protocol: u8,
         cmd_id: u16,
@@ -147,9 +173,10 @@ enum IoMode<'a> {
 
 impl AtaPassthru {
     // https://edk2.groups.io/g/devel/message/22393
-    unsafe fn do_io(&self, port: u16, port_multiplier_port: u16, mode: IoMode) -> uefi::Result<Box<[u8]>> {
+    unsafe fn do_io(&self, port: u16, port_multiplier_port: u16, mode: IoMode) -> uefi::Result<DmaBuffer> {
+        crate::watchdog::pet();
         let align = (*self.mode).io_align as usize;
-        let asb = alloc_aligned_t(AtaStatusBlock::default(), align);
+        let asb = alloc_aligned_t(AtaStatusBlock::default(), align)?;
 
         let command = match mode {
             IoMode::Identify => AtaCommand::Identify,
@@ -163,7 +190,7 @@ impl AtaPassthru {
         };
         match mode {
             IoMode::Identify => (),
-            IoMode::Recv { protocol, cmd_id } | IoMode::Send { protocol, cmd_id, .. } => {
+            IoMode::Recv { protocol, cmd_id, .. } | IoMode::Send { protocol, cmd_id, .. } => {
                 acb.features = protocol;
                 /*
                 acb.cylinder_high = cmd_id as u8;
@@ -180,12 +207,30 @@ impl AtaPassthru {
             IoMode::Send { .. } => AtaPassthruProtocol::PioDataOut,
         };
 
-        let mut return_data = alloc_init_aligned(2048, align);
+        // TRUSTED RECEIVE and SEND transfer whole sectors, their count goes into Count and LBA (7:0)
+        let sectors = |len: usize| ((len + SECTOR_SIZE - 1) / SECTOR_SIZE).max(1);
+        let in_len = match mode {
+            IoMode::Identify => 2048,
+            IoMode::Recv { len, .. } => sectors(len) * SECTOR_SIZE,
+            // nothing comes back, the buffer only keeps the pointer valid
+            IoMode::Send { .. } => SECTOR_SIZE,
+        };
+        let transfer_sectors = match mode {
+            IoMode::Identify => None,
+            IoMode::Recv { len, .. } => Some(sectors(len)),
+            IoMode::Send { data, .. } => Some(sectors(data.len())),
+        };
+        if let Some(transfer_sectors) = transfer_sectors {
+            acb.sector_count = transfer_sectors as u8;
+            acb.sector_number = (transfer_sectors >> 8) as u8;
+        }
+
+        let mut return_data = DmaBuffer::new(in_len, align)?;
         let mut packet = CommandPacket {
             protocol,
             length: AtaPassthruLength::BYTES | AtaPassthruLength::SECTOR_COUNT,
             in_data_buffer: return_data.as_mut_ptr(),
-            in_transfer_length: return_data.len() as u32,
+            in_transfer_length: if let IoMode::Send { .. } = mode { 0 } else { return_data.len() as u32 },
             out_data_buffer: core::ptr::null_mut(),
             out_transfer_length: 0,
             timeout: 3 * 10000000,
@@ -195,8 +240,8 @@ impl AtaPassthru {
 
         let _out_buf = match mode {
             IoMode::Send { data, .. } => {
-                let rounded_len = ((data.len() + 512 - 1) / 512) * 512;
-                let mut out_buf = alloc_init_aligned(rounded_len, align);
+                let rounded_len = sectors(data.len()) * SECTOR_SIZE;
+                let mut out_buf = DmaBuffer::new(rounded_len, align)?;
                 out_buf[..data.len()].copy_from_slice(data);
                 packet.out_data_buffer = out_buf.as_mut_ptr();
                 packet.out_transfer_length = rounded_len as u32;
@@ -219,7 +264,8 @@ impl AtaPassthru {
         }
     }
 
-    pub fn get_serial_num(&self, port: u16, port_multiplier_port: u16) -> uefi::Result<[u8; 20]> {
+    /// serial number, model number and firmware revision from IDENTIFY DEVICE
+    pub fn get_identity(&self, port: u16, port_multiplier_port: u16) -> uefi::Result<([u8; 20], [u8; 40], [u8; 8])> {
         unsafe {
             let identify_data = self.do_io(port, port_multiplier_port, IoMode::Identify)?;
 
@@ -250,8 +296,12 @@ impl AtaPassthru {
             */
             let mut serial = identify.serial_num;
             byteswap(&mut serial);
+            let mut model = identify.model_num;
+            byteswap(&mut model);
+            let mut firmware = identify.firmware_rev;
+            byteswap(&mut firmware);
 
-            Ok(serial)
+            Ok((serial, model, firmware))
         }
     }
 }
@@ -0,0 +1,91 @@
+use alloc::boxed::Box;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use uefi::table::boot::{AllocateType, MemoryType};
+
+static BELOW_4GIB: AtomicBool = AtomicBool::new(false);
+
+/// highest address a bounced buffer may reach
+const LIMIT: u64 = 0xFFFF_FFFF;
+const PAGE_SIZE: usize = 4096;
+
+/// Makes passthru transfers go through pages below 4 GiB, for drivers and IOMMU setups that can't DMA above
+pub fn init(below_4gib: bool) {
+    BELOW_4GIB.store(below_4gib, Ordering::Relaxed);
+    if below_4gib {
+        log::info!("passthru buffers are kept below 4 GiB");
+    }
+}
+
+pub fn active() -> bool {
+    BELOW_4GIB.load(Ordering::Relaxed)
+}
+
+/// A zeroed transfer buffer: whole pages below 4 GiB if configured, pool memory otherwise.
+/// The pages come from AllocatePages, so drivers mapping them through PCI I/O get page-aligned memory
+/// their mapping never has to bounce again
+pub enum DmaBuffer {
+    Pool(Box<[u8]>),
+    Pages { address: u64, pages: usize, len: usize },
+}
+
+impl DmaBuffer {
+    pub fn new(len: usize, align: usize) -> uefi::Result<Self> {
+        if active() && align <= PAGE_SIZE {
+            let bt = unsafe { uefi_services::system_table().as_ref() }.boot_services();
+            let pages = (len + PAGE_SIZE - 1) / PAGE_SIZE;
+            match bt.allocate_pages(AllocateType::MaxAddress(LIMIT), MemoryType::BOOT_SERVICES_DATA, pages.max(1)) {
+                Ok(address) => {
+                    unsafe { core::ptr::write_bytes(address as *mut u8, 0, len) };
+                    return Ok(DmaBuffer::Pages { address, pages: pages.max(1), len });
+                }
+                // the pool still works on most machines, so it's better than failing the command
+                Err(e) => log::warn!("no memory below 4 GiB for a {len} byte transfer, using the pool: {e:?}"),
+            }
+        }
+        unsafe { crate::util::alloc_init_aligned(len, align) }.map(DmaBuffer::Pool)
+    }
+
+    /// Runs a transfer on a bounce copy of `data` if buffers must stay below 4 GiB and `data` doesn't,
+    /// copying what the drive wrote back afterwards
+    pub fn bounce<R>(data: &mut [u8], align: usize, transfer: impl FnOnce(&mut [u8]) -> uefi::Result<R>) -> uefi::Result<R> {
+        let end = data.as_ptr() as u64 + data.len() as u64;
+        if !active() || end <= LIMIT + 1 {
+            return transfer(data);
+        }
+        let mut buffer = DmaBuffer::new(data.len(), align)?;
+        buffer.copy_from_slice(data);
+        let result = transfer(&mut buffer);
+        data.copy_from_slice(&buffer);
+        result
+    }
+}
+
+impl Deref for DmaBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            DmaBuffer::Pool(buffer) => buffer,
+            DmaBuffer::Pages { address, len, .. } => unsafe { core::slice::from_raw_parts(*address as *const u8, *len) },
+        }
+    }
+}
+
+impl DerefMut for DmaBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            DmaBuffer::Pool(buffer) => buffer,
+            DmaBuffer::Pages { address, len, .. } => unsafe { core::slice::from_raw_parts_mut(*address as *mut u8, *len) },
+        }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        if let DmaBuffer::Pages { address, pages, .. } = *self {
+            let bt = unsafe { uefi_services::system_table().as_ref() }.boot_services();
+            let _ = bt.free_pages(address, pages);
+        }
+    }
+}
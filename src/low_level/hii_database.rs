@@ -0,0 +1,61 @@
+use alloc::string::String;
+use core::ffi::c_void;
+use core::ptr;
+use uefi::proto::unsafe_protocol;
+use uefi::{Guid, StatusExt};
+use uefi_raw::Status;
+
+/// Just enough of EFI_HII_DATABASE_PROTOCOL to read the keyboard layout the firmware applies
+#[unsafe_protocol("ef9fc172-a1b2-4693-b327-6d32fc416042")]
+#[repr(C)]
+pub struct HiiDatabase {
+    _new_package_list: *const c_void,
+    _remove_package_list: *const c_void,
+    _update_package_list: *const c_void,
+    _list_package_lists: *const c_void,
+    _export_package_lists: *const c_void,
+    _register_package_notify: *const c_void,
+    _unregister_package_notify: *const c_void,
+    _find_keyboard_layouts: *const c_void,
+    get_keyboard_layout: unsafe extern "efiapi" fn(
+        this: &HiiDatabase,
+        key_guid: *const Guid,
+        length: &mut u16,
+        layout: *mut u8,
+    ) -> Status,
+    // the remaining functions aren't used
+}
+
+/// offset of LayoutDescriptorStringOffset in the packed EFI_HII_KEYBOARD_LAYOUT
+const DESCRIPTOR_STRING_OFFSET: usize = 18;
+
+impl HiiDatabase {
+    /// Language of the current keyboard layout, e.g. `en-US` or `de-DE`; `None` if none is set
+    pub fn keyboard_layout_language(&self) -> uefi::Result<Option<String>> {
+        let mut length = 0u16;
+        // a null GUID asks for the current layout
+        let status = unsafe { (self.get_keyboard_layout)(self, ptr::null(), &mut length, ptr::null_mut()) };
+        if status == Status::NOT_FOUND || status.is_success() {
+            return Ok(None);
+        }
+        if status != Status::BUFFER_TOO_SMALL {
+            return Err(status.into());
+        }
+        let mut layout = vec![0u8; length as usize];
+        unsafe { (self.get_keyboard_layout)(self, ptr::null(), &mut length, layout.as_mut_ptr()) }.to_result()?;
+        Ok(language(&layout))
+    }
+}
+
+/// The language of the layout's first description; they're `<language> <description>` in UTF-16,
+/// after a count of them, at LayoutDescriptorStringOffset
+fn language(layout: &[u8]) -> Option<String> {
+    let offset = layout.get(DESCRIPTOR_STRING_OFFSET..DESCRIPTOR_STRING_OFFSET + 4)?;
+    let offset = u32::from_le_bytes([offset[0], offset[1], offset[2], offset[3]]) as usize;
+    let strings = layout.get(offset.checked_add(2)?..)?;
+    let language = strings.chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|&c| c != 0 && c != u16::from(b' '));
+    let language: String = char::decode_utf16(language).map(|c| c.unwrap_or('?')).collect();
+    (!language.is_empty()).then_some(language)
+}
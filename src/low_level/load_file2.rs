@@ -0,0 +1,100 @@
+use alloc::boxed::Box;
+use core::ffi::c_void;
+use uefi::proto::device_path::{DevicePath, FfiDevicePath};
+use uefi::table::boot::BootServices;
+use uefi::{guid, Guid, Handle, Identify, Status};
+
+const LOAD_FILE2_GUID: Guid = guid!("4006c0c1-fcb3-403e-996d-4a6c8724e06d");
+
+/// Vendor media device path with LINUX_EFI_INITRD_MEDIA_GUID, where the EFI stub looks for its initrd
+#[repr(C, packed)]
+struct InitrdDevicePath {
+    vendor_type: u8,
+    vendor_subtype: u8,
+    vendor_length: [u8; 2],
+    guid: [u8; 16],
+    end: [u8; 4],
+}
+
+static INITRD_DEVICE_PATH: InitrdDevicePath = InitrdDevicePath {
+    vendor_type: 4,
+    vendor_subtype: 3,
+    vendor_length: 20u16.to_le_bytes(),
+    guid: guid!("5568e427-68fc-4f3d-ac74-ca555231cc68").to_bytes(),
+    end: [0x7f, 0xff, 4, 0],
+};
+
+/// EFI_LOAD_FILE2_PROTOCOL handing out one buffer, followed by that buffer for the callback
+#[repr(C)]
+struct LoadFile2 {
+    load_file: unsafe extern "efiapi" fn(
+        this: *mut LoadFile2,
+        file_path: *const FfiDevicePath,
+        boot_policy: u8,
+        buffer_size: *mut usize,
+        buffer: *mut c_void,
+    ) -> Status,
+    data: *const u8,
+    len: usize,
+}
+
+unsafe extern "efiapi" fn load_file(
+    this: *mut LoadFile2,
+    _file_path: *const FfiDevicePath,
+    boot_policy: u8,
+    buffer_size: *mut usize,
+    buffer: *mut c_void,
+) -> Status {
+    // LoadFile2 never loads boot options
+    if this.is_null() || buffer_size.is_null() || boot_policy != 0 {
+        return Status::INVALID_PARAMETER;
+    }
+    let this = &*this;
+    if buffer.is_null() || *buffer_size < this.len {
+        *buffer_size = this.len;
+        return Status::BUFFER_TOO_SMALL;
+    }
+    core::ptr::copy_nonoverlapping(this.data, buffer as *mut u8, this.len);
+    *buffer_size = this.len;
+    Status::SUCCESS
+}
+
+/// An initrd served to the Linux EFI stub (5.7+) through LoadFile2; uninstall it once the image returned
+pub struct InitrdLoadFile2 {
+    handle: Handle,
+    protocol: *mut LoadFile2,
+}
+
+impl InitrdLoadFile2 {
+    /// `data` must stay valid until `uninstall`
+    pub unsafe fn install(bt: &BootServices, data: &[u8]) -> uefi::Result<InitrdLoadFile2> {
+        let protocol = Box::into_raw(Box::new(LoadFile2 { load_file, data: data.as_ptr(), len: data.len() }));
+        let device_path = &INITRD_DEVICE_PATH as *const InitrdDevicePath as *mut c_void;
+        let handle = match bt.install_protocol_interface(None, &DevicePath::GUID, device_path) {
+            Ok(handle) => handle,
+            Err(e) => {
+                drop(Box::from_raw(protocol));
+                return Err(e);
+            }
+        };
+        if let Err(e) = bt.install_protocol_interface(Some(handle), &LOAD_FILE2_GUID, protocol as *mut c_void) {
+            let _ = bt.uninstall_protocol_interface(handle, &DevicePath::GUID, device_path);
+            drop(Box::from_raw(protocol));
+            return Err(e);
+        }
+        Ok(InitrdLoadFile2 { handle, protocol })
+    }
+
+    pub fn uninstall(self, bt: &BootServices) {
+        let device_path = &INITRD_DEVICE_PATH as *const InitrdDevicePath as *mut c_void;
+        unsafe {
+            let res = bt.uninstall_protocol_interface(self.handle, &LOAD_FILE2_GUID, self.protocol as *mut c_void)
+                .and_then(|()| bt.uninstall_protocol_interface(self.handle, &DevicePath::GUID, device_path));
+            match res {
+                // nobody can call into it anymore
+                Ok(()) => drop(Box::from_raw(self.protocol)),
+                Err(e) => log::warn!("can't uninstall initrd LoadFile2: {e:?}"),
+            }
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use uefi::proto::media::file::FileInfo;
+use uefi::proto::media::fs::SimpleFileSystem;
+use uefi::table::boot::BootServices;
+use uefi::{Guid, Handle, Identify, Status};
+
+const FILE_PROTOCOL_REVISION: u64 = 0x0001_0000;
+const FILE_DIRECTORY: u64 = 0x10;
+const FILE_READ_ONLY: u64 = 0x01;
+const FILE_MODE_READ: u64 = 0x01;
+/// null-terminated UCS-2 name of the file, for FileInfo
+const FILE_NAME: &[u16] = &[b'i' as u16, b'n' as u16, b'i' as u16, b't' as u16, b'r' as u16, b'd' as u16, 0];
+/// FileInfo up to, but excluding the file name
+const FILE_INFO_HEADER_SIZE: usize = 80;
+
+/// A read-only volume with a single file in its root, served from memory.
+///
+/// Used for the legacy `initrd=` option of the Linux EFI stub, which reads its initrd from the
+/// SimpleFileSystem of the device handle in its LoadedImage.
+pub struct MemoryFs {
+    handle: Handle,
+    protocol: *mut SimpleFileSystemProtocol,
+}
+
+#[repr(C)]
+struct SimpleFileSystemProtocol {
+    revision: u64,
+    open_volume: unsafe extern "efiapi" fn(this: *mut SimpleFileSystemProtocol, root: *mut *mut FileProtocol) -> Status,
+    data: *const u8,
+    len: usize,
+}
+
+/// EFI_FILE_PROTOCOL revision 1, followed by our state
+#[repr(C)]
+struct FileProtocol {
+    revision: u64,
+    open: unsafe extern "efiapi" fn(this: *mut FileProtocol, new: *mut *mut FileProtocol, name: *const u16, mode: u64, attributes: u64) -> Status,
+    close: unsafe extern "efiapi" fn(this: *mut FileProtocol) -> Status,
+    delete: unsafe extern "efiapi" fn(this: *mut FileProtocol) -> Status,
+    read: unsafe extern "efiapi" fn(this: *mut FileProtocol, size: *mut usize, buffer: *mut c_void) -> Status,
+    write: unsafe extern "efiapi" fn(this: *mut FileProtocol, size: *mut usize, buffer: *const c_void) -> Status,
+    get_position: unsafe extern "efiapi" fn(this: *mut FileProtocol, position: *mut u64) -> Status,
+    set_position: unsafe extern "efiapi" fn(this: *mut FileProtocol, position: u64) -> Status,
+    get_info: unsafe extern "efiapi" fn(this: *mut FileProtocol, kind: *const Guid, size: *mut usize, buffer: *mut c_void) -> Status,
+    set_info: unsafe extern "efiapi" fn(this: *mut FileProtocol, kind: *const Guid, size: usize, buffer: *const c_void) -> Status,
+    flush: unsafe extern "efiapi" fn(this: *mut FileProtocol) -> Status,
+    fs: *const SimpleFileSystemProtocol,
+    is_root: bool,
+    position: u64,
+}
+
+impl MemoryFs {
+    /// `data` must stay valid until `uninstall`
+    pub unsafe fn install(bt: &BootServices, data: &[u8]) -> uefi::Result<MemoryFs> {
+        let protocol = Box::into_raw(Box::new(SimpleFileSystemProtocol {
+            revision: FILE_PROTOCOL_REVISION,
+            open_volume,
+            data: data.as_ptr(),
+            len: data.len(),
+        }));
+        match bt.install_protocol_interface(None, &SimpleFileSystem::GUID, protocol as *mut c_void) {
+            Ok(handle) => Ok(MemoryFs { handle, protocol }),
+            Err(e) => {
+                drop(Box::from_raw(protocol));
+                Err(e)
+            }
+        }
+    }
+
+    pub fn handle(&self) -> Handle {
+        self.handle
+    }
+
+    pub fn uninstall(self, bt: &BootServices) {
+        unsafe {
+            match bt.uninstall_protocol_interface(self.handle, &SimpleFileSystem::GUID, self.protocol as *mut c_void) {
+                // file handles still open leak their state, but can't reach the volume anymore
+                Ok(()) => drop(Box::from_raw(self.protocol)),
+                Err(e) => log::warn!("can't uninstall in-memory file system: {e:?}"),
+            }
+        }
+    }
+}
+
+fn new_file(fs: *const SimpleFileSystemProtocol, is_root: bool) -> *mut FileProtocol {
+    Box::into_raw(Box::new(FileProtocol {
+        revision: FILE_PROTOCOL_REVISION,
+        open,
+        close,
+        delete,
+        read,
+        write,
+        get_position,
+        set_position,
+        get_info,
+        set_info,
+        flush,
+        fs,
+        is_root,
+        position: 0,
+    }))
+}
+
+unsafe extern "efiapi" fn open_volume(this: *mut SimpleFileSystemProtocol, root: *mut *mut FileProtocol) -> Status {
+    if this.is_null() || root.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    *root = new_file(this, true);
+    Status::SUCCESS
+}
+
+/// every name opens the one file, as the stub only ever asks for the file named in `initrd=`
+unsafe extern "efiapi" fn open(this: *mut FileProtocol, new: *mut *mut FileProtocol, name: *const u16, mode: u64, _attributes: u64) -> Status {
+    if this.is_null() || new.is_null() || name.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    if mode != FILE_MODE_READ {
+        return Status::WRITE_PROTECTED;
+    }
+    *new = new_file((*this).fs, false);
+    Status::SUCCESS
+}
+
+unsafe extern "efiapi" fn close(this: *mut FileProtocol) -> Status {
+    if !this.is_null() {
+        drop(Box::from_raw(this));
+    }
+    Status::SUCCESS
+}
+
+unsafe extern "efiapi" fn delete(this: *mut FileProtocol) -> Status {
+    close(this);
+    Status::WARN_DELETE_FAILURE
+}
+
+unsafe extern "efiapi" fn read(this: *mut FileProtocol, size: *mut usize, buffer: *mut c_void) -> Status {
+    if this.is_null() || size.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    let file = &mut *this;
+    if file.is_root {
+        // no directory entries besides the implicit file
+        *size = 0;
+        return Status::SUCCESS;
+    }
+    let fs = &*file.fs;
+    let remaining = fs.len.saturating_sub(file.position as usize);
+    let count = remaining.min(*size);
+    if count != 0 {
+        if buffer.is_null() {
+            return Status::INVALID_PARAMETER;
+        }
+        core::ptr::copy_nonoverlapping(fs.data.add(file.position as usize), buffer as *mut u8, count);
+    }
+    file.position += count as u64;
+    *size = count;
+    Status::SUCCESS
+}
+
+unsafe extern "efiapi" fn write(_this: *mut FileProtocol, _size: *mut usize, _buffer: *const c_void) -> Status {
+    Status::WRITE_PROTECTED
+}
+
+unsafe extern "efiapi" fn get_position(this: *mut FileProtocol, position: *mut u64) -> Status {
+    if this.is_null() || position.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    *position = (*this).position;
+    Status::SUCCESS
+}
+
+unsafe extern "efiapi" fn set_position(this: *mut FileProtocol, position: u64) -> Status {
+    if this.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    let file = &mut *this;
+    // u64::MAX seeks to the end
+    file.position = position.min((*file.fs).len as u64);
+    Status::SUCCESS
+}
+
+unsafe extern "efiapi" fn get_info(this: *mut FileProtocol, kind: *const Guid, size: *mut usize, buffer: *mut c_void) -> Status {
+    if this.is_null() || kind.is_null() || size.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    if *kind != FileInfo::GUID {
+        return Status::UNSUPPORTED;
+    }
+    let file = &*this;
+    let len = if file.is_root { 0 } else { (*file.fs).len as u64 };
+    let name: &[u16] = if file.is_root { &[0] } else { FILE_NAME };
+    let needed = FILE_INFO_HEADER_SIZE + name.len() * 2;
+    if buffer.is_null() || *size < needed {
+        *size = needed;
+        return Status::BUFFER_TOO_SMALL;
+    }
+    let mut info = Vec::with_capacity(needed);
+    info.extend_from_slice(&(needed as u64).to_le_bytes());
+    info.extend_from_slice(&len.to_le_bytes());
+    info.extend_from_slice(&len.to_le_bytes());
+    // creation, last access and modification time
+    info.extend_from_slice(&[0; 48]);
+    let attributes = FILE_READ_ONLY | if file.is_root { FILE_DIRECTORY } else { 0 };
+    info.extend_from_slice(&attributes.to_le_bytes());
+    for c in name {
+        info.extend_from_slice(&c.to_le_bytes());
+    }
+    core::ptr::copy_nonoverlapping(info.as_ptr(), buffer as *mut u8, needed);
+    *size = needed;
+    Status::SUCCESS
+}
+
+unsafe extern "efiapi" fn set_info(_this: *mut FileProtocol, _kind: *const Guid, _size: usize, _buffer: *const c_void) -> Status {
+    Status::WRITE_PROTECTED
+}
+
+unsafe extern "efiapi" fn flush(_this: *mut FileProtocol) -> Status {
+    Status::SUCCESS
+}
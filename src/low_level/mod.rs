@@ -1,3 +1,8 @@
 pub mod nvme_device;
 pub mod nvme_passthru;
+pub mod pci_io;
+pub mod hii_database;
+pub mod dma;
 pub mod ata_passthru;
+pub mod load_file2;
+pub mod memory_fs;
@@ -1,17 +1,80 @@
+use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 use snafu::Snafu;
+use uefi::table::boot::{OpenProtocolAttributes, OpenProtocolParams};
 use uefi::table::{SystemTable, Boot};
-use core::mem::MaybeUninit;
+use core::{mem::MaybeUninit, time::Duration};
 
 use uefi::{Status, StatusExt, Handle};
 
+use crate::low_level::dma::DmaBuffer;
 use crate::low_level::nvme_passthru::{self, Command, CommandPacket, NvmExpressPassthru, QueueType, SendTarget};
+use crate::low_level::pci_io::PciIo;
 use opal::SecureProtocol;
 
 pub struct NvmeDevice {
     passthru: *mut NvmExpressPassthru,
     align: usize,
     serial_num: Vec<u8>,
+    model_num: Vec<u8>,
+    firmware_rev: Vec<u8>,
+    /// warning and critical composite temperature thresholds in Kelvin, 0 if not reported
+    temperature_thresholds: (u16, u16),
+    lba_format: Option<LbaFormat>,
+}
+
+/// The active LBA format of the first namespace, from Identify Namespace
+#[derive(Debug, Copy, Clone)]
+pub struct LbaFormat {
+    /// bytes of data per logical block
+    pub block_size: u32,
+    /// bytes of metadata per logical block
+    pub metadata_size: u16,
+    /// namespace size in logical blocks
+    pub blocks: u64,
+    /// index of the format in use
+    pub index: u8,
+    /// number of formats the namespace supports
+    pub count: u8,
+}
+
+impl core::fmt::Display for LbaFormat {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let kind = match self.block_size {
+            512 => " (512n or 512e)",
+            4096 => " (4Kn)",
+            _ => "",
+        };
+        write!(f, "LBA format {} of {}: {} byte blocks{kind}", self.index, self.count, self.block_size)?;
+        if self.metadata_size != 0 {
+            write!(f, " + {} bytes metadata", self.metadata_size)?;
+        }
+        let bytes = self.blocks.saturating_mul(self.block_size as u64);
+        write!(f, ", {} blocks ({})", self.blocks, crate::util::human_size(bytes))
+    }
+}
+
+/// Composite temperature from the SMART / Health Information log page
+#[derive(Debug, Copy, Clone)]
+pub struct Temperature {
+    pub kelvin: u16,
+    /// WCTEMP from Identify Controller, 0 if not reported
+    pub warning_kelvin: u16,
+    /// CCTEMP from Identify Controller, 0 if not reported
+    pub critical_kelvin: u16,
+    /// the controller's critical warning flag for temperature
+    pub over_threshold: bool,
+}
+
+impl Temperature {
+    pub fn celsius(kelvin: u16) -> i32 {
+        kelvin as i32 - 273
+    }
+
+    /// whether it's above or close to the warning threshold
+    pub fn is_hot(&self, margin_kelvin: u16) -> bool {
+        self.over_threshold || (self.warning_kelvin != 0 && self.kelvin + margin_kelvin >= self.warning_kelvin)
+    }
 }
 
 pub struct RestartableNvmeDevice<'a> {
@@ -22,24 +85,193 @@ pub struct RestartableNvmeDevice<'a> {
 
 impl NvmeDevice {
     pub unsafe fn new(passthru: *mut NvmExpressPassthru) -> uefi::Result<NvmeDevice> {
-        let serial_num = recv_serial_num(passthru)?;
+        let (serial_num, model_num, firmware_rev, temperature_thresholds) = recv_identity(passthru)?;
         let align = unsafe { &mut *passthru }.mode().io_align as _;
+        let lba_format = identify_namespace(passthru)
+            .map_err(|e| log::debug!("can't identify the namespace: {e:?}"))
+            .ok();
         Ok(Self {
             passthru,
             align,
             serial_num,
+            model_num,
+            firmware_rev,
+            temperature_thresholds,
+            lba_format,
+        })
+    }
+
+    pub fn lba_format(&self) -> Option<LbaFormat> {
+        self.lba_format
+    }
+
+    /// reads the SMART / Health Information log page
+    pub fn temperature(&self) -> uefi::Result<Temperature> {
+        let passthru = unsafe { &mut *self.passthru };
+        let mut data = unsafe { crate::util::alloc_uninit_aligned(512, self.align) }?;
+        // Get Log Page, log identifier 0x02, 128 dwords, controller-wide
+        let command = Command::new(0x02)
+            .ns(unsafe { nvme_passthru::NamespaceId::new(0xFFFF_FFFF) })
+            .cdw_10(127 << 16 | 0x02);
+        let mut packet = CommandPacket::new(
+            nvme_passthru::NVME_GENERIC_TIMEOUT,
+            Some(&mut data),
+            None,
+            QueueType::ADMIN,
+            &command,
+        );
+        unsafe { passthru.send(SendTarget::Controller, &mut packet) }?;
+
+        let log = unsafe { core::slice::from_raw_parts(data.as_ptr() as *const u8, 3) };
+        let (warning_kelvin, critical_kelvin) = self.temperature_thresholds;
+        Ok(Temperature {
+            kelvin: u16::from_le_bytes([log[1], log[2]]),
+            warning_kelvin,
+            critical_kelvin,
+            over_threshold: log[0] & 0x02 != 0,
         })
     }
 
     pub fn serial_num(&self) -> &[u8] {
         &self.serial_num
     }
+
+    pub fn model_num(&self) -> &[u8] {
+        &self.model_num
+    }
+
+    pub fn firmware_rev(&self) -> &[u8] {
+        &self.firmware_rev
+    }
+}
+
+/// Handles of all NVMe controllers with a passthru, whether or not they have a usable drive
+pub fn controllers(st: &SystemTable<Boot>) -> Vec<Handle> {
+    st.boot_services().find_handles::<NvmExpressPassthru>().unwrap_or_default()
+}
+
+/// What the passthru, Identify Controller and the controller's registers say, for debugging controllers the
+/// passthru fails on; each part that can't be read is reported as such
+pub fn diagnostics(st: &SystemTable<Boot>, controller: Handle) -> Vec<String> {
+    let bt = st.boot_services();
+    let mut lines = Vec::new();
+    let params = || OpenProtocolParams { handle: controller, agent: bt.image_handle(), controller: None };
+    // the NVMe driver has both protocols open, opening them exclusively would disconnect it
+    match unsafe { bt.open_protocol::<NvmExpressPassthru>(params(), OpenProtocolAttributes::GetProtocol) } {
+        Ok(mut passthru) => {
+            let mode = passthru.mode();
+            lines.push(format!("passthru: NVMe {}, {:?}, I/O alignment {}", mode.version, mode.attributes, mode.io_align));
+            match unsafe { NvmeDevice::new(&mut *passthru) } {
+                Ok(dev) => {
+                    lines.push(format!(
+                        "Identify Controller: {} {}, firmware {}",
+                        String::from_utf8_lossy(dev.model_num()).trim(),
+                        String::from_utf8_lossy(dev.serial_num()).trim(),
+                        String::from_utf8_lossy(dev.firmware_rev()).trim(),
+                    ));
+                    match dev.lba_format() {
+                        Some(format) => lines.push(format!("namespace: {format}")),
+                        None => lines.push("Identify Namespace failed".to_string()),
+                    }
+                }
+                Err(e) => lines.push(format!("Identify Controller failed: {:?}", e.status())),
+            }
+            match queue_counts(&mut passthru) {
+                Ok((submission, completion)) => lines.push(format!("I/O queues allocated: {submission} submission, {completion} completion")),
+                Err(e) => lines.push(format!("Get Features (Number of Queues) failed: {:?}", e.status())),
+            }
+        }
+        Err(e) => lines.push(format!("can't open the NVMe passthru: {:?}", e.status())),
+    }
+    match unsafe { bt.open_protocol::<PciIo>(params(), OpenProtocolAttributes::GetProtocol) } {
+        Ok(pci) => if let Err(e) = registers(&pci, &mut lines) {
+            lines.push(format!("can't read the controller registers: {:?}", e.status()));
+        },
+        Err(e) => lines.push(format!("no PCI I/O on the controller: {:?}", e.status())),
+    }
+    lines
+}
+
+/// I/O submission and completion queues the controller allocated, from Get Features (Number of Queues)
+fn queue_counts(passthru: &mut NvmExpressPassthru) -> uefi::Result<(u32, u32)> {
+    let command = Command::new(0x0A).cdw_10(0x07);
+    let mut packet = CommandPacket::new(nvme_passthru::NVME_GENERIC_TIMEOUT, None, None, QueueType::ADMIN, &command);
+    let completion = unsafe { passthru.send(SendTarget::Controller, &mut packet) }?;
+    // both are 0's based
+    Ok(((completion.dw_0 & 0xFFFF) + 1, (completion.dw_0 >> 16) + 1))
 }
 
-fn recv_serial_num(passthru: *mut NvmExpressPassthru) -> uefi::Result<Vec<u8>> {
+/// Decodes CAP, VS, CC, CSTS and AQA from BAR 0
+fn registers(pci: &PciIo, lines: &mut Vec<String>) -> uefi::Result {
+    let bits = |value: u64, low: u32, count: u32| (value >> low) & ((1 << count) - 1);
+    let cap = pci.read_mem_u64(0, 0x00)?;
+    let vs = pci.read_mem_u32(0, 0x08)? as u64;
+    let cc = pci.read_mem_u32(0, 0x14)? as u64;
+    let csts = pci.read_mem_u32(0, 0x1C)? as u64;
+    let aqa = pci.read_mem_u32(0, 0x24)? as u64;
+    lines.push(format!(
+        "CAP {cap:#018X}: queues up to {} entries{}, doorbell stride {} bytes, timeout {} ms, pages {}-{} KiB",
+        bits(cap, 0, 16) + 1,
+        if bits(cap, 16, 1) == 1 { " (contiguous)" } else { "" },
+        4u64 << bits(cap, 32, 4),
+        bits(cap, 24, 8) * 500,
+        4u64 << bits(cap, 48, 4),
+        4u64 << bits(cap, 52, 4),
+    ));
+    lines.push(format!("VS {}.{}.{}", bits(vs, 16, 16), bits(vs, 8, 8), bits(vs, 0, 8)));
+    lines.push(format!(
+        "CC {cc:#010X}: {}, pages {} KiB, I/O SQ entries {} bytes, CQ entries {} bytes",
+        if bits(cc, 0, 1) == 1 { "enabled" } else { "disabled" },
+        4u64 << bits(cc, 7, 4),
+        1u64 << bits(cc, 16, 4),
+        1u64 << bits(cc, 20, 4),
+    ));
+    lines.push(format!(
+        "CSTS {csts:#010X}: {}{}, shutdown status {}",
+        if bits(csts, 0, 1) == 1 { "ready" } else { "not ready" },
+        if bits(csts, 1, 1) == 1 { ", FATAL STATUS" } else { "" },
+        bits(csts, 2, 2),
+    ));
+    lines.push(format!("AQA {aqa:#010X}: admin SQ {} entries, admin CQ {} entries", bits(aqa, 0, 12) + 1, bits(aqa, 16, 12) + 1));
+    Ok(())
+}
+
+/// the active LBA format and size of the first namespace
+fn identify_namespace(passthru: *mut NvmExpressPassthru) -> uefi::Result<LbaFormat> {
+    let passthru = unsafe { &mut *passthru };
+    let namespace = passthru.first_namespace()?;
+    let mut data =
+        unsafe { crate::util::alloc_uninit_aligned(4096, passthru.mode().io_align as usize) }?;
+    let command = Command::new(0x06).ns(namespace).cdw_10(0);
+    let mut packet = CommandPacket::new(
+        nvme_passthru::NVME_GENERIC_TIMEOUT,
+        Some(&mut data),
+        None,
+        QueueType::ADMIN,
+        &command,
+    );
+    unsafe { passthru.send(SendTarget::Controller, &mut packet) }?;
+
+    let identify = unsafe { core::slice::from_raw_parts(data.as_ptr() as *const u8, 384) };
+    // FLBAS bits 3:0, extended by bits 6:5 for namespaces with more than 16 formats
+    let index = (identify[26] & 0x0F) | (identify[26] >> 5 & 0x03) << 4;
+    let format = &identify[128 + 4 * index as usize..][..4];
+    Ok(LbaFormat {
+        block_size: 1u32.checked_shl(format[2] as u32).unwrap_or(0),
+        metadata_size: u16::from_le_bytes([format[0], format[1]]),
+        blocks: u64::from_le_bytes(identify[0..8].try_into().unwrap()),
+        index,
+        // NLBAF is 0's based
+        count: identify[25] + 1,
+    })
+}
+
+/// serial number, model number, firmware revision and temperature thresholds from Identify Controller
+#[allow(clippy::type_complexity)]
+fn recv_identity(passthru: *mut NvmExpressPassthru) -> uefi::Result<(Vec<u8>, Vec<u8>, Vec<u8>, (u16, u16))> {
     let passthru = unsafe { &mut *passthru };
     let mut data =
-        unsafe { crate::util::alloc_uninit_aligned(4096, passthru.mode().io_align as usize) };
+        unsafe { crate::util::alloc_uninit_aligned(4096, passthru.mode().io_align as usize) }?;
     let command = Command::new(0x06).cdw_10(1);
     let mut packet = CommandPacket::new(
         nvme_passthru::NVME_GENERIC_TIMEOUT,
@@ -51,9 +283,13 @@ fn recv_serial_num(passthru: *mut NvmExpressPassthru) -> uefi::Result<Vec<u8>> {
 
     unsafe { passthru.send(SendTarget::Controller, &mut packet) }?;
 
-    let serial_num = unsafe { core::slice::from_raw_parts(data.as_ptr().offset(4) as *const u8, 20) };
+    let identify = unsafe { core::slice::from_raw_parts(data.as_ptr() as *const u8, 270) };
     //let serial_num = unsafe { MaybeUninit::slice_assume_init_ref(&data[4..24]) };
-    Ok(serial_num.to_vec())
+    let thresholds = (
+        u16::from_le_bytes([identify[266], identify[267]]),
+        u16::from_le_bytes([identify[268], identify[269]]),
+    );
+    Ok((identify[4..24].to_vec(), identify[24..64].to_vec(), identify[64..72].to_vec(), thresholds))
 }
 
 #[repr(u8)]
@@ -69,10 +305,14 @@ unsafe fn secure_protocol(
     com_id: u16,
     buffer: &mut [MaybeUninit<u8>],
 ) -> uefi::Result {
+    crate::watchdog::pet();
     let command = Command::new(direction as u8)
         .cdw_10((protocol as u32) << 24 | (com_id as u32) << 8)
         .cdw_11(buffer.len() as u32);
 
+    // EFI_NVM_EXPRESS_PASS_THRU takes one transfer buffer and has no scatter-gather list: the driver maps it
+    // and builds PRP1, PRP2 or a PRP list for it, so a buffer made of scattered pages can't be handed over.
+    // Transfers are at most a few KiB, which the pool serves even when free memory is fragmented
     let mut packet = CommandPacket::new(
         nvme_passthru::NVME_GENERIC_TIMEOUT,
         Some(buffer),
@@ -99,13 +339,14 @@ impl<'a> SecureProtocol for RestartableNvmeDevice<'a> {
     type Error = UefiError;
 
     unsafe fn secure_send(&mut self, protocol: u8, com_id: u16, data: &mut [u8]) -> Result<(), UefiError> {
-        secure_protocol(
+        crate::protocol_trace::record("IF-SEND", protocol, com_id, data);
+        DmaBuffer::bounce(data, self.dev.align, |data| secure_protocol(
             self.dev.passthru,
             Direction::Send,
             protocol,
             com_id,
             core::slice::from_raw_parts_mut(data.as_mut_ptr() as _, data.len()),
-        ).map_err(|error| UefiError { error })
+        )).map_err(|error| UefiError { error })
     }
 
     unsafe fn secure_recv(
@@ -114,13 +355,15 @@ impl<'a> SecureProtocol for RestartableNvmeDevice<'a> {
         com_id: u16,
         buffer: &mut [u8],
     ) -> Result<(), UefiError> {
-        secure_protocol(
+        DmaBuffer::bounce(buffer, self.dev.align, |buffer| secure_protocol(
             self.dev.passthru,
             Direction::Recv,
             protocol,
             com_id,
             core::slice::from_raw_parts_mut(buffer.as_mut_ptr() as _, buffer.len()),
-        ).map_err(|error| UefiError { error })
+        )).map_err(|error| UefiError { error })?;
+        crate::protocol_trace::record("IF-RECV", protocol, com_id, buffer);
+        Ok(())
     }
 
     fn align(&self) -> usize {
@@ -131,6 +374,30 @@ impl<'a> SecureProtocol for RestartableNvmeDevice<'a> {
         &self.dev.serial_num
     }
 
+    fn model_num(&self) -> &[u8] {
+        &self.dev.model_num
+    }
+
+    fn firmware_rev(&self) -> &[u8] {
+        &self.dev.firmware_rev
+    }
+
+    fn block_size(&self) -> Option<u32> {
+        self.dev.lba_format.map(|format| format.block_size)
+    }
+
+    fn block_count(&self) -> Option<u64> {
+        self.dev.lba_format.map(|format| format.blocks)
+    }
+
+    fn fill_random(&mut self, buf: &mut [u8]) {
+        crate::rng::fill(buf)
+    }
+
+    fn wait(&mut self, duration: Duration) {
+        crate::util::sleep(duration)
+    }
+
     fn reconnect_controller(&mut self) -> Result<(), Self::Error> {
         self.st.boot_services()
             .disconnect_controller(self.handle, None, None)
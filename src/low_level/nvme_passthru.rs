@@ -81,7 +81,10 @@ impl NvmExpressPassthru {
         let mut result = Vec::new();
         loop {
             let status = unsafe { (self.get_next_namespace)(self, &mut namespace_id) };
-            if status.is_success() {
+            if status.is_success() && result.last() == Some(&NamespaceId(namespace_id)) {
+                // a driver that doesn't advance would keep this going forever
+                break Err(Status::DEVICE_ERROR.into());
+            } else if status.is_success() {
                 result.push(NamespaceId(namespace_id));
             } else if status == Status::NOT_FOUND {
                 break Status::SUCCESS.to_result_with_val(|| result);
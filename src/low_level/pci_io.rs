@@ -0,0 +1,48 @@
+use core::ffi::c_void;
+use uefi::proto::unsafe_protocol;
+use uefi::StatusExt;
+use uefi_raw::Status;
+
+/// Just enough of EFI_PCI_IO_PROTOCOL to read device registers through a BAR
+#[unsafe_protocol("4cf5b200-68b8-4ca5-9eec-b23e3f50029a")]
+#[repr(C)]
+pub struct PciIo {
+    _poll_mem: *const c_void,
+    _poll_io: *const c_void,
+    mem: Access,
+    _io: Access,
+    _pci: Access,
+    // the remaining functions aren't used
+}
+
+#[repr(C)]
+struct Access {
+    read: unsafe extern "efiapi" fn(
+        this: &PciIo,
+        width: u32,
+        bar_index: u8,
+        offset: u64,
+        count: usize,
+        buffer: *mut c_void,
+    ) -> Status,
+    _write: *const c_void,
+}
+
+/// EfiPciIoWidthUint32
+const WIDTH_U32: u32 = 2;
+
+impl PciIo {
+    /// A 32 bit register in the memory space of `bar`
+    pub fn read_mem_u32(&self, bar: u8, offset: u64) -> uefi::Result<u32> {
+        let mut value = 0u32;
+        unsafe { (self.mem.read)(self, WIDTH_U32, bar, offset, 1, &mut value as *mut u32 as _) }
+            .to_result_with_val(|| value)
+    }
+
+    /// A 64 bit register as two 32 bit reads, which every bridge supports
+    pub fn read_mem_u64(&self, bar: u8, offset: u64) -> uefi::Result<u64> {
+        let low = self.read_mem_u32(bar, offset)?;
+        let high = self.read_mem_u32(bar, offset + 4)?;
+        Ok((high as u64) << 32 | low as u64)
+    }
+}
@@ -0,0 +1,27 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+static LOW_MEMORY: AtomicBool = AtomicBool::new(false);
+
+/// log buffers held in memory are capped to this many bytes
+pub const LOG_BUFFER_LIMIT: usize = 4 * 1024;
+
+/// Enables the low-memory code paths, for embedded boards with little boot-services memory
+pub fn init(enabled: bool) {
+    LOW_MEMORY.store(enabled, Ordering::Relaxed);
+    if enabled {
+        log::info!("low-memory mode");
+    }
+}
+
+/// whether to avoid buffering whole files and other memory-hungry conveniences
+pub fn active() -> bool {
+    LOW_MEMORY.load(Ordering::Relaxed)
+}
+
+/// `size` capped to the log buffer limit in low-memory mode
+pub fn log_buffer_size(size: usize) -> usize {
+    match active() {
+        true => size.min(LOG_BUFFER_LIMIT),
+        false => size,
+    }
+}
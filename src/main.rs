@@ -10,6 +10,7 @@ extern crate alloc;
 extern crate rlibc;
 
 use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 use either::Either;
@@ -17,8 +18,9 @@ use low_level::ata_passthru::{AtaPassthru, AtaProtocol};
 use opal::PasswordOrRaw;
 use uefi::proto::device_path::text::{DisplayOnly, AllowShortcuts};
 use uefi::table::boot::{AllocateType, LoadImageSource, MemoryType, OpenProtocolParams, OpenProtocolAttributes};
+use core::sync::atomic::{AtomicBool, Ordering};
 use core::time::Duration;
-use core::{convert::TryFrom, fmt::Write, slice};
+use core::{convert::TryFrom, slice};
 use acid_io::{IoSliceMut, Read};
 use bootsector::{ReadGPT, ReadMBR, SectorSize};
 use ext4::SuperBlock;
@@ -44,13 +46,15 @@ use uefi::proto::media::partition::GptPartitionEntry;
 use uuid::Uuid;
 use low_level::nvme_device::NvmeDevice;
 use low_level::nvme_passthru::*;
+use crate::low_level::load_file2::InitrdLoadFile2;
+use crate::low_level::memory_fs::MemoryFs;
 use crate::low_level::nvme_device::RestartableNvmeDevice;
 use crate::{
     config::Config,
     error::{Error, Result, Context},
     util::sleep,
 };
-use crate::config::{AdditionalInitrdFile, BootEntry, File, Initrd, Keyslot, KeyslotSource, Partition};
+use crate::config::{AdditionalInitrdFile, BootEntry, DualAuth, File, Initrd, InitrdDelivery, Keyslot, KeyslotSource, NoLockedDrives, Partition, RangeRef, ResetKind, SecureMessaging};
 use crate::error::ErrorSource;
 use crate::io::{BlockIoReader, PartialReader, OptimizedSeek, ReadSeek, IgnoreWriteWrapper};
 
@@ -60,28 +64,140 @@ pub mod util;
 pub mod low_level;
 mod ui;
 mod io;
+mod console;
+mod beep;
+mod accessibility;
+mod rng;
+mod clock;
+mod os_detect;
+mod pe;
+mod safe_mode;
+mod watchdog;
+mod smbios;
+mod admin;
+mod banner;
+mod keymap;
+mod firmware;
+mod removable;
+mod background;
+mod logging;
+mod crash;
+mod check;
+mod hotplug;
+mod users;
+mod dtb;
+mod capsule;
+mod update;
+mod stats;
+mod quorum;
+mod integrity;
+mod relock;
+mod provision;
+mod low_memory;
+mod wizard;
+mod screenshot;
+mod password;
+mod last_used;
+mod summary;
+mod power;
+mod raw_console;
+mod protocol_trace;
+mod locked_drives;
 
 #[entry]
 fn main(image_handle: Handle, mut st: SystemTable<Boot>) -> Status {
     if uefi_services::init(&mut st).is_err() {
-        log::error!("Failed to initialize UEFI services");
+        console::write_str(&st, "Failed to initialize UEFI services\r\n");
     }
-
-
-    let exit = |st: &SystemTable<Boot>| {
-        let _ = ui::line(st);
+    clock::init(&st);
+    logging::init();
+    if check::requested(&st, image_handle) {
+        return check::run(&st, image_handle);
+    }
+    safe_mode::init(&st);
+
+    let exit = |st: &SystemTable<Boot>, fatal: &config::Fatal| {
+        logging::flush();
+        beep::cue(beep::Cue::Fatal);
+        let reset = match fatal.reset {
+            ResetKind::Cold => ResetType::COLD,
+            ResetKind::Warm => ResetType::WARM,
+            ResetKind::Shutdown => ResetType::SHUTDOWN,
+        };
+        if fatal.wait_for_enter {
+            console::write_str(st, &format!("Press Enter to {}\r\n", if reset == ResetType::SHUTDOWN { "shut down" } else { "reset" }));
+            let _ = ui::line(st);
+        } else {
+            console::write_str(st, &format!("Resetting in {}s\r\n", fatal.delay_secs));
+            sleep(Duration::from_secs(fatal.delay_secs));
+        }
         st.runtime_services()
-        .reset(ResetType::COLD, Status::SUCCESS, None)
+        .reset(reset, Status::SUCCESS, None)
     };
 
     let config: Config = match config::load(image_handle, &mut st) {
         Ok(config) => config,
         Err(err) => {
             log::error!("Error loading config: {err}");
-            return exit(&mut st);
+            return exit(&mut st, &config::Fatal::default());
         }
     };
+    low_memory::init(config.low_memory);
+    low_level::dma::init(config.dma_below_4gib);
+    logging::configure(&st, image_handle, &config);
+    protocol_trace::init(config.protocol_trace.as_ref());
     log::trace!("loaded config");
+    integrity::check(&st);
+    console::set_mirror(config.mirror_consoles);
+    beep::set_enabled(config.beep);
+    ui::set_password_echo(config.password_echo);
+    keymap::init(&st, config.keymap);
+    let held_keys = ui::held_keys(&st).unwrap_or_default();
+    accessibility::init(&config.accessibility, &held_keys);
+    admin::init(&st, &config.admin, &held_keys);
+    update::init(config.rollback_hotkey, &held_keys);
+    screenshot::init(config.screenshot_hotkey);
+    ui::init(config.keys.info());
+    hotplug::init(&st, &config.hotplug);
+    stats::init(&st, config.statistics);
+    summary::init(&st, config.hardware_summary);
+    power::init(config.on_battery);
+    if let Some((medium, name)) = provision::find(&st) {
+        let res = config_stdout(&st).context("can't configure stdout")
+            .and_then(|()| accessibility::apply_theme(&st))
+            .and_then(|()| provision::run(&st, &config, medium, &name));
+        if let Err(err) = res {
+            log::error!("Error provisioning from {name}: {err}");
+        }
+    }
+    if admin::wizard_requested() {
+        let res = config_stdout(&st).context("can't configure stdout")
+            .and_then(|()| accessibility::apply_theme(&st))
+            .and_then(|()| wizard::run(&st, &config));
+        if let Err(err) = res {
+            log::error!("Error in the setup wizard: {err}");
+            let _ = ui::popup(&st, "Setup wizard", &[format!("setup failed: {err}")]);
+        }
+    }
+    let usb_override = config.usb_hotkey
+        .map_or(false, |hotkey| held_keys.iter().any(|key| ui::key_matches(key, hotkey)));
+    if let Some(banner_file) = &config.banner_file {
+        let res = config_stdout(&st).context("can't configure stdout")
+            .and_then(|()| accessibility::apply_theme(&st))
+            .and_then(|()| banner::show(&st, image_handle, banner_file));
+        if let Err(err) = res {
+            log::error!("Error showing banner: {err}");
+            return exit(&mut st, &config.fatal);
+        }
+    }
+    if usb_override {
+        let res = config_stdout(&st).context("can't configure stdout")
+            .and_then(|()| accessibility::apply_theme(&st))
+            .and_then(|()| removable::menu(&st, image_handle));
+        if let Err(err) = res {
+            log::error!("Error booting from removable media: {err}");
+        }
+    }
     loop {
         match run(image_handle, &mut st, &config) {
            Ok(()) => (),
@@ -92,12 +208,15 @@ fn main(image_handle: Handle, mut st: SystemTable<Boot>) -> Status {
        }
     }
 
-    exit(&mut st)
+    exit(&mut st, &config.fatal)
 }
 
+static MENU_SHOWN: AtomicBool = AtomicBool::new(false);
+
 fn run(image_handle: Handle, st: &SystemTable<Boot>, config: &Config) -> Result {
     // set size of console
     config_stdout(st).context("can't configure stdout")?;
+    accessibility::apply_theme(st)?;
     log::trace!("configured stdout");
 
     // disable watchdog
@@ -105,18 +224,96 @@ fn run(image_handle: Handle, st: &SystemTable<Boot>, config: &Config) -> Result
         .context("error disabling 5min reboot watchdog")?;
     log::trace!("disabled watchdog");
 
-    let mut options: Vec<_> = config.boot_entries.iter().map(|e| (true, e.name.clone())).collect();
+    let first_menu = !MENU_SHOWN.swap(true, Ordering::Relaxed);
+    if first_menu {
+        check_locked_drives(st, config)?;
+    }
+    users::login(st, config)?;
+
+    // boot entry index of each menu line, `None` for headers and separators
+    let mut options = Vec::new();
+    let mut entries = Vec::new();
+    let sections = config.grouped_entries(&last_used::load(st, config));
+    for (section, (name, indices)) in sections.iter().enumerate() {
+        if section != 0 {
+            options.push(ui::separator());
+            entries.push(None);
+        }
+        if let Some(name) = name {
+            options.push(ui::header(name));
+            entries.push(None);
+        } else if section != 0 {
+            options.push(ui::header("Other"));
+            entries.push(None);
+        }
+        for &i in indices {
+            options.push((true, os_detect::entry_title(st, config, &config.boot_entries[i])));
+            entries.push(Some(i));
+        }
+    }
+    if !config.menu_groups.is_empty() && !sections.is_empty() {
+        options.push(ui::separator());
+    }
+    let unlock_index = options.len();
     options.push((true, "Unlock configured opal drives".to_string()));
+    // optional entries are `None` when not shown, so their index can't match the entry after them
+    let mut optional = |shown: bool, name: &str| shown.then(|| {
+        options.push((true, name.to_string()));
+        options.len() - 1
+    });
+    let password_index = optional(password::available(config), "Change drive password");
+    let setup_index = optional(admin::present() && config.user().map_or(true, |user| user.setup), "Setup and recovery");
+    let rollback_index = optional(update::rollback_offered(), "Previous greeter version");
+    // flashing firmware needs the same physical presence as the other setup actions
+    let capsules = if safe_mode::active() || !admin::present() { Vec::new() } else { capsule::find(st) };
+    let capsule_index = options.len();
+    if !capsules.is_empty() {
+        options.push(ui::header("Firmware updates"));
+        options.extend(capsules.iter().map(|name| (true, name.clone())));
+    }
     log::trace!("created chooser-options");
-    let selected = ui::choose(st, &options)?;
-    let boot_entry_len = config.boot_entries.len();
+    let mut selected = entries.iter()
+        .position(|entry| entry.map_or(false, |i| config.boot_entries[i].default))
+        .unwrap_or(0);
+    // only count down on the first menu after startup, not after returning from a submenu
+    let mut countdown = config.menu_timeout
+        .filter(|_| first_menu)
+        .map(|secs| ui::Countdown {
+            duration: accessibility::countdown(Duration::from_secs(secs)),
+            paused: config.menu_timeout_paused,
+        });
+    let mut background = background::BackgroundUnlock::new(st, config);
+    loop {
+        match ui::menu(st, &options, selected, countdown.take(), &mut || background.step())? {
+            (i, ui::MenuAction::Select | ui::MenuAction::Timeout) => {
+                selected = i;
+                break;
+            }
+            (i, ui::MenuAction::Info) => {
+                if let Some(&Some(entry)) = entries.get(i) {
+                    let lines = entry_details(st, config, &config.boot_entries[entry])?;
+                    ui::popup(st, &options[i].1, &lines)?;
+                }
+                selected = i;
+            }
+            (i, ui::MenuAction::ShowLog) => {
+                logging::view(st)?;
+                selected = i;
+            }
+            (i, ui::MenuAction::Cancel) => selected = i,
+        }
+    }
 
     match selected {
-        i if i < boot_entry_len => {
-            let boot_entry = &config.boot_entries[selected];
+        i if i < unlock_index => {
+            let boot_entry = &config.boot_entries[entries[i].expect("headers are not selectable")];
             handle_boot_entry(st, image_handle, config, boot_entry)?;
         },
-        i if i == boot_entry_len => handle_unlock_configured_opal_drives(st, config)?,
+        i if i == unlock_index => handle_unlock_configured_opal_drives(st, config)?,
+        i if Some(i) == password_index => password::change(st, config)?,
+        i if i > capsule_index => capsule::apply(st, &capsules[i - capsule_index - 1])?,
+        i if Some(i) == rollback_index => update::rollback(st, image_handle)?,
+        i if Some(i) == setup_index => admin::menu(st, config)?,
         i => unreachable!("unknown boot entry selection {}", i),
     }
 
@@ -177,7 +374,19 @@ fn try_get_ata_device(st: &SystemTable<Boot>, blockio_handle: Handle) -> Result<
                 .context("error creating AtaPassthru handle")?;
 
             let proto = AtaProtocol::try_make(nvme, locate_path, st, blockio_handle)?;
-            let opal = opal::OpalDrive::new(proto).map_err(|e| Error::new(e, "error opening opal"))?;
+            let opal = match opal::OpalDrive::new(proto) {
+                Ok(opal) => opal,
+                Err(opal::Error::KeyPerIo) => {
+                    warn_key_per_io(&path_before_locate);
+                    return Ok(None);
+                }
+                // plain SATA disks without a usable TPer are just not self-encrypting
+                Err(e @ (opal::Error::Unsupported | opal::Error::IncompatibleVersion)) => {
+                    log::debug!("{path_before_locate} is not an OPAL drive: {e}");
+                    return Ok(None);
+                }
+                Err(e) => return Err(Error::new(e, "error opening opal")),
+            };
 
             Ok(Some(opal))
         },
@@ -186,6 +395,68 @@ fn try_get_ata_device(st: &SystemTable<Boot>, blockio_handle: Handle) -> Result<
 }
 
 
+/// Key Per I/O drives encrypt each command with a key the host injects, so there are no locking ranges for the greeter to unlock
+fn warn_key_per_io(drive: &str) {
+    log::warn!("{drive} implements the Key Per I/O SSC, which isn't managed by OPAL range locking; skipping it");
+    log::warn!("its keys are provided by the OS per command, remove it from the partitions in config.toml");
+}
+
+/// Block SID is what firmware normally does before booting an OS, but it makes taking ownership fail,
+/// so it's only worth a warning when the setup menu is available
+fn warn_sid_blocked<P: opal::SecureProtocol>(drive: &mut opal::OpalDrive<P>) {
+    if drive.sid_blocked() && admin::present() {
+        let serial = String::from_utf8_lossy(drive.serial()).trim().to_string();
+        log::warn!("the firmware blocked authenticating as SID on drive {serial} until the next power cycle, taking ownership will fail");
+    }
+}
+
+/// Remembers a locked drive, so menu entries on it are marked until it's unlocked
+fn record_locked<P: opal::SecureProtocol>(drive: &mut opal::OpalDrive<P>) -> bool {
+    if drive.was_locked() {
+        locked_drives::record(String::from_utf8_lossy(drive.serial()).trim());
+    }
+    drive.was_locked()
+}
+
+/// Lets the greeter act as a plain boot manager on machines without locked SEDs, as configured
+fn check_locked_drives(st: &SystemTable<Boot>, config: &Config) -> Result {
+    let (mut found, mut locked) = (0, 0);
+    for (blockio_handle, _, _) in block_devices(st)? {
+        if let Some(nvme) = try_get_nvme_device(st, blockio_handle)? {
+            if let Ok(mut drive) = opal::OpalDrive::new(RestartableNvmeDevice::new(&nvme, st, blockio_handle)) {
+                found += 1;
+                locked += record_locked(&mut drive) as usize;
+                warn_sid_blocked(&mut drive);
+            }
+        } else if let Some(mut ata) = try_get_ata_device(st, blockio_handle)? {
+            found += 1;
+            locked += record_locked(&mut ata) as usize;
+            warn_sid_blocked(&mut ata);
+        }
+    }
+    summary::set_drives(found);
+    log::debug!("{locked} locked self-encrypting drives at startup");
+    if locked != 0 {
+        return Ok(());
+    }
+    let message = "No locked self-encrypting drives found";
+    match config.no_locked_drives {
+        NoLockedDrives::Note => {
+            log::info!("{message}");
+            console::write_str(st, &format!("{message}\r\n\r\n"));
+        }
+        NoLockedDrives::Warn => {
+            log::warn!("{message}");
+            ui::popup(st, "Warning", &[
+                message.to_string(),
+                "Drives that should be locked may have been reset or replaced.".to_string(),
+            ])?;
+        }
+        NoLockedDrives::Abort => return Err(Error::new_without_source(message)),
+    }
+    Ok(())
+}
+
 fn handle_unlock_configured_opal_drives(st: &SystemTable<Boot>, config: &Config) -> Result<()> {
     for (i, (blockio_handle, start_lba, end_lba)) in block_devices(st)?.into_iter().enumerate() {
         log::debug!("probing blockio #{i} {start_lba:#x} - {end_lba:#x}");
@@ -218,7 +489,18 @@ fn handle_unlock_configured_opal_drives(st: &SystemTable<Boot>, config: &Config)
         let keyslot = partition.keyslot.as_deref().unwrap();
         let keyslot = &config.keyslots[keyslot];
         match dev {
-            Either::Left(nvme) => unlock_opal(st, opal::OpalDrive::new(RestartableNvmeDevice::new(&nvme, st, blockio_handle)).map_err(|e| Error::new(e, "open opal"))?, config, keyslot)?,
+            Either::Left(nvme) => {
+                warn_if_hot(st, &nvme);
+                let drive = match opal::OpalDrive::new(RestartableNvmeDevice::new(&nvme, st, blockio_handle)) {
+                    Ok(drive) => drive,
+                    Err(opal::Error::KeyPerIo) => {
+                        warn_key_per_io(&partition.uuid);
+                        continue;
+                    }
+                    Err(e) => return Err(Error::new(e, "open opal")),
+                };
+                unlock_opal(st, drive, config, keyslot)?
+            }
             Either::Right(ata) => unlock_opal(st, ata, config, keyslot)?,
         }
     }
@@ -273,33 +555,60 @@ fn find_random_esp_path(st: &SystemTable<Boot>) -> Result<Option<Box<DevicePath>
 }
 
 fn handle_boot_entry(st: &SystemTable<Boot>, image_handle: Handle, config: &Config, boot_entry: &BootEntry) -> Result<()> {
-    let BootEntry { name, file: efi_file, initrd, additional_initrd_files, options, default } = boot_entry;
+    let BootEntry { name, file: efi_file, initrd, additional_initrd_files, options, default, .. } = boot_entry;
 
     for part in &efi_file.extra_partitions {
         let partitions = [&config.partitions[part]];
         let _ = find_read_file(st, config, &partitions, &efi_file.file);
     }
 
-    let efi_image = resolve_and_read_file(st, config, efi_file)?;
-    if efi_image.get(0..2) != Some(&[0x4d, 0x5a]) {
-        return Err(Error::new_without_source("image is not a valid PeCoff"));
-    }
-
-    let initramfs_addr = if initrd.is_some() || additional_initrd_files.is_some() {
-        Some(construct_initramfs(st, config, initrd, additional_initrd_files)?)
-    } else {
-        None
+    let firmware_volume = if low_memory::active() { firmware_volume(st, config, efi_file) } else { None };
+    let efi_image = match &firmware_volume {
+        // the firmware reads the image itself, only the headers are checked here
+        Some((volume, path)) => {
+            let mut headers = vec![0; 4096];
+            let len = util::read_partial_file_to_vec(st, *volume, path, &mut headers)?;
+            headers.truncate(len);
+            headers
+        }
+        None => resolve_and_read_file(st, config, efi_file)?,
     };
-
-    let dev_path = find_random_esp_path(st)?;
+    if let Err(problem) = pe::check(&efi_image) {
+        return Err(Error::new_without_source(format!("`{}` {problem}", efi_file.file)));
+    }
+    log::debug!("`{}` is {}signed", efi_file.file, if pe::is_signed(&efi_image) { "" } else { "not " });
 
     // LoadedImage
 
-    let file_path = dev_path.as_ref().map(|x| x.as_ref());
+    let load_path = image_device_path(st, firmware_volume.as_ref())?;
+    let source = match (&firmware_volume, &load_path) {
+        (Some(_), Some(device_path)) => {
+            log::debug!("loading `{}` by device path", efi_file.file);
+            LoadImageSource::FromDevicePath { device_path, from_boot_manager: false }
+        }
+        _ => LoadImageSource::FromBuffer { file_path: load_path.as_deref(), buffer: &efi_image },
+    };
     let loaded_image_handle = st
         .boot_services()
-        .load_image(image_handle, LoadImageSource::FromBuffer { file_path, buffer: &efi_image })
-        .context("can't get handle to new LoadedImage-to-boot")?;
+        .load_image(image_handle, source)
+        .map_err(|e| match e.status() {
+            Status::SECURITY_VIOLATION if !pe::is_signed(&efi_image) => Error::new_from_uefi(e, "Secure Boot rejected the image, it isn't signed"),
+            Status::SECURITY_VIOLATION => Error::new_from_uefi(e, "Secure Boot rejected the image's signature"),
+            _ => Error::new_from_uefi(e, "can't get handle to new LoadedImage-to-boot"),
+        })?;
+    // the firmware made its own copy, so the image and the initramfs don't have to fit into memory at once
+    drop(efi_image);
+    let initramfs = if initrd.is_some() || additional_initrd_files.is_some() {
+        match construct_initramfs(st, config, initrd, additional_initrd_files) {
+            Ok(initramfs) => Some(initramfs),
+            Err(e) => {
+                unload_image(st, loaded_image_handle);
+                return Err(e);
+            }
+        }
+    } else {
+        None
+    };
     let mut loaded_image = st
         .boot_services()
         .open_protocol_exclusive::<LoadedImage>(loaded_image_handle)
@@ -308,8 +617,32 @@ fn handle_boot_entry(st: &SystemTable<Boot>, image_handle: Handle, config: &Conf
     // chain-load efistub
 
     let mut options = options.clone().unwrap_or_default();
-    if let Some((initramfs_addr, len)) = initramfs_addr {
-        options.push_str(&format!(" initrdmem={initramfs_addr},{len}"));
+    // LoadFile2 or legacy `initrd=` provider, must outlive the image and is removed once it returns
+    let mut initrd_provider = None;
+    if let Some(initramfs) = &initramfs {
+        let bt = st.boot_services();
+        let provider = match boot_entry.initrd_delivery {
+            InitrdDelivery::Initrdmem => {
+                let (initramfs_addr, len) = place_initramfs(st, initramfs)?;
+                options.push_str(&format!(" initrdmem={initramfs_addr},{len}"));
+                Ok(None)
+            }
+            InitrdDelivery::LoadFile2 => unsafe { InitrdLoadFile2::install(bt, initramfs) }.map(|lf2| Some(Either::Left(lf2))),
+            InitrdDelivery::Legacy => unsafe { MemoryFs::install(bt, initramfs) }.map(|fs| {
+                // the stub opens `initrd=` on the volume it was loaded from
+                unsafe { set_device_handle(&mut loaded_image, fs.handle()) };
+                options.push_str(" initrd=\\initrd");
+                Some(Either::Right(fs))
+            }),
+        };
+        match provider {
+            Ok(provider) => initrd_provider = provider,
+            Err(e) => {
+                drop(loaded_image);
+                unload_image(st, loaded_image_handle);
+                return Err(Error::new_from_uefi(e, "can't provide the initrd to the image"));
+            }
+        }
     }
     log::debug!("passing options: `{options}`");
     let options = CString16::try_from(&*options)
@@ -325,14 +658,182 @@ fn handle_boot_entry(st: &SystemTable<Boot>, image_handle: Handle, config: &Conf
         }
     }
 
-    st.boot_services()
-        .start_image(loaded_image_handle)
-        .context("error booting loaded bootimage")?;
+    drop(loaded_image);
+    let dtb = apply_range_policy(st, config, boot_entry)
+        .and_then(|()| relock::relock(st, config, boot_entry))
+        .and_then(|()| boot_entry.dtb.as_ref().map(|file| dtb::install(st, config, file)).transpose());
+    let dtb = match dtb {
+        Ok(dtb) => dtb,
+        Err(e) => {
+            unload_image(st, loaded_image_handle);
+            remove_initrd_provider(st, initrd_provider);
+            return Err(e);
+        }
+    };
 
-    Ok(())
+    last_used::record(st, config, name);
+    let res = start_loaded_image(st, loaded_image_handle, name);
+    remove_initrd_provider(st, initrd_provider);
+    if let Some(dtb) = dtb {
+        dtb.restore(st);
+    }
+    res
+}
+
+fn remove_initrd_provider(st: &SystemTable<Boot>, provider: Option<Either<InitrdLoadFile2, MemoryFs>>) {
+    match provider {
+        Some(Either::Left(lf2)) => lf2.uninstall(st.boot_services()),
+        Some(Either::Right(fs)) => fs.uninstall(st.boot_services()),
+        None => (),
+    }
+}
+
+/// Points the image's LoadedImage at another device; uefi-rs has no setter for `DeviceHandle`
+unsafe fn set_device_handle(loaded_image: &mut LoadedImage, device: Handle) {
+    // leading fields of EFI_LOADED_IMAGE_PROTOCOL
+    #[repr(C)]
+    struct Header {
+        revision: u32,
+        parent_handle: Handle,
+        system_table: *const core::ffi::c_void,
+        device_handle: Handle,
+    }
+    (*(loaded_image as *mut LoadedImage as *mut Header)).device_handle = device;
+}
+
+/// Hands off to the image. If it fails to start or returns, it's unloaded and the exit status is shown,
+/// so the menu can be shown again instead of taking the fatal error path.
+fn start_loaded_image(st: &SystemTable<Boot>, loaded_image_handle: Handle, name: &str) -> Result {
+    logging::flush();
+    let res = st.boot_services().start_image(loaded_image_handle);
+    let status = match res {
+        Ok(()) => Status::SUCCESS,
+        Err(e) => e.status(),
+    };
+    log::warn!("`{name}` returned with {status:?}");
+    unload_image(st, loaded_image_handle);
+    ui::popup(st, &format!("`{name}` returned"), &[
+        format!("exit status: {status:?} ({:#x})", status.0),
+    ])
+}
+
+fn unload_image(st: &SystemTable<Boot>, loaded_image_handle: Handle) {
+    // applications that called Exit() are already unloaded by the firmware
+    if let Err(e) = st.boot_services().unload_image(loaded_image_handle) {
+        log::debug!("can't unload image: {e:?}");
+    }
+}
+
+/// Locks and unlocks the ranges the entry asks for, so e.g. one OS can't see the other's data
+fn apply_range_policy(st: &SystemTable<Boot>, config: &Config, boot_entry: &BootEntry) -> Result {
+    let mut policy: BTreeMap<&str, Vec<(u8, opal::LockingState)>> = BTreeMap::new();
+    let states = [(&boot_entry.unlock_ranges, opal::LockingState::ReadWrite), (&boot_entry.lock_ranges, opal::LockingState::Locked)];
+    for (ranges, state) in states {
+        for RangeRef { partition, range } in ranges {
+            policy.entry(partition.as_str()).or_default().push((*range, state));
+        }
+    }
+
+    for (blockio_handle, _, _) in block_devices(st)? {
+        if policy.is_empty() {
+            return Ok(());
+        }
+        if let Some(nvme) = try_get_nvme_device(st, blockio_handle)? {
+            let serial = core::str::from_utf8(nvme.serial_num())
+                .context("can't convert nvme serial number to UTF8")?
+                .trim();
+            if let Some(partition) = config.partitions.values().find(|part| part.uuid == serial && policy.contains_key(part.name.as_str())) {
+                let drive = opal::OpalDrive::new(RestartableNvmeDevice::new(&nvme, st, blockio_handle))
+                    .map_err(|e| Error::new(e, "open opal"))?;
+                set_range_states(st, config, drive, partition, &policy.remove(partition.name.as_str()).unwrap())?;
+            }
+        } else if let Some(mut ata) = try_get_ata_device(st, blockio_handle)? {
+            let serial = core::str::from_utf8(ata.serial())
+                .context("can't convert ATA serial number to UTF8")?
+                .trim();
+            if let Some(partition) = config.partitions.values().find(|part| part.uuid == serial && policy.contains_key(part.name.as_str())) {
+                set_range_states(st, config, ata, partition, &policy.remove(partition.name.as_str()).unwrap())?;
+            }
+        }
+    }
+
+    match policy.keys().next() {
+        Some(partition) => Err(Error::new_without_source(format!("no OPAL drive found for partition `{partition}` to apply the entry's range policy to"))),
+        None => Ok(()),
+    }
+}
+
+fn set_range_states<P: opal::SecureProtocol>(st: &SystemTable<Boot>, config: &Config, mut drive: opal::OpalDrive<P>, partition: &Partition, ranges: &[(u8, opal::LockingState)]) -> Result
+where opal::Error<P::Error>: Into<ErrorSource>
+{
+    let keyslot = match &partition.keyslot {
+        Some(name) => &config.keyslots[name],
+        None => return Err(Error::new_without_source(format!("no keyslot defined for partition `{}`", partition.name))),
+    };
+    drive.force(config.features.forced());
+    reset_stack(config, &mut drive);
+    let password = get_password_of_keyslot(st, config, keyslot, Cache::Cached)?;
+    let password_or_raw = match keyslot.source {
+        KeyslotSource::Stdin => PasswordOrRaw::Password(&password),
+        KeyslotSource::File(_) | KeyslotSource::Quorum(_) => PasswordOrRaw::Raw(&password),
+    };
+    log::info!("{}: applying range policy {ranges:?}", partition.name);
+    drive.set_range_states(password_or_raw, ranges)
+        .map_err(|e| Error::new(e, "error applying range policy"))
 }
 
-fn construct_initramfs(st: &SystemTable<Boot>, config: &Config, initrd: &Option<Initrd>, additional_initrd_files: &Option<Vec<AdditionalInitrdFile>>) -> Result<(u64, usize)> {
+fn entry_details(st: &SystemTable<Boot>, config: &Config, boot_entry: &BootEntry) -> Result<Vec<String>> {
+    let BootEntry { file: efi_file, initrd, additional_initrd_files, options, .. } = boot_entry;
+    let mut lines = Vec::new();
+
+    let mut chain = Vec::new();
+    let mut current = Some(&efi_file.partition);
+    while let Some(name) = current {
+        let partition = &config.partitions[name];
+        chain.push(format!("{} ({})", partition.name, partition.uuid));
+        current = partition.parent.as_ref();
+    }
+    chain.reverse();
+    lines.push(format!("path:       {}", efi_file.file));
+    lines.push(format!("partitions: {}", chain.join(" -> ")));
+
+    let firmware_volume = if low_memory::active() { firmware_volume(st, config, efi_file) } else { None };
+    match image_device_path(st, firmware_volume.as_ref()) {
+        Ok(Some(dp)) => match dp.to_string(st.boot_services(), DisplayOnly(true), AllowShortcuts(false)) {
+            Ok(Some(dp)) => lines.push(format!("load path:  {dp}")),
+            _ => lines.push("load path:  <unprintable device path>".to_string()),
+        },
+        Ok(None) => lines.push("load path:  <none>".to_string()),
+        Err(e) => lines.push(format!("load path:  <error: {e}>")),
+    }
+
+    match resolve_and_read_file(st, config, efi_file) {
+        Ok(image) => {
+            use sha1::Digest;
+            let hash: String = sha1::Sha1::digest(&image).iter().map(|b| format!("{b:02x}")).collect();
+            let arch = match pe::machine(&image) {
+                Some(machine) => format!("{} ({machine:#06x})", pe::machine_name(machine)),
+                None if pe::is_mz(&image) => "<invalid PE header>".to_string(),
+                None => "<not a PE/COFF image>".to_string(),
+            };
+            lines.push(format!("size:       {} bytes", image.len()));
+            lines.push(format!("sha1:       {hash}"));
+            lines.push(format!("arch:       {arch}"));
+        }
+        Err(e) => lines.push(format!("file:       <can't read: {e}>")),
+    }
+
+    lines.push(format!("options:    {}", options.as_deref().unwrap_or("<none>")));
+    for initrd in initrd.iter().flat_map(|initrd| initrd.iter()) {
+        lines.push(format!("initrd:     {} on {}", initrd.file, initrd.partition));
+    }
+    for AdditionalInitrdFile { source, target_file } in additional_initrd_files.iter().flatten() {
+        lines.push(format!("extra file: {} on {} as {target_file}", source.file, source.partition));
+    }
+    Ok(lines)
+}
+
+fn construct_initramfs(st: &SystemTable<Boot>, config: &Config, initrd: &Option<Initrd>, additional_initrd_files: &Option<Vec<AdditionalInitrdFile>>) -> Result<Vec<u8>> {
     let mut initramfs = Initramfs::new();
 
     for initrd in initrd.iter().flat_map(|initrd| initrd.iter()) {
@@ -351,7 +852,12 @@ fn construct_initramfs(st: &SystemTable<Boot>, config: &Config, initrd: &Option<
 
     let mut serialized = Vec::new();
     initramfs.write(&mut serialized);
+    log::debug!("initramfs loaded");
+    Ok(serialized)
+}
 
+/// copies the initramfs to where the kernel's `initrdmem=` can pick it up, returning its address and length
+fn place_initramfs(st: &SystemTable<Boot>, serialized: &[u8]) -> Result<(u64, usize)> {
     let num_pages = (serialized.len() + 4095) / 4096;
     // Allocate initramfs in RUNTIME_SERVICES_DATA such that it is available after the EFISTUB calls exit_boot_services.
     // After reallocating the RAMDISK, the kernel frees our memory in `reserve_initrd` via `memblock_phys_free`.
@@ -359,8 +865,7 @@ fn construct_initramfs(st: &SystemTable<Boot>, config: &Config, initrd: &Option<
         .allocate_pages(AllocateType::AnyPages, MemoryType::RUNTIME_SERVICES_DATA, num_pages)
         .context("can't align memory for initramfs")?;
     let buffer = unsafe { slice::from_raw_parts_mut(initramfs_addr as *mut u8, num_pages * 4096) };
-    buffer[..serialized.len()].copy_from_slice(&serialized);
-    log::debug!("initramfs loaded");
+    buffer[..serialized.len()].copy_from_slice(serialized);
     Ok((initramfs_addr, serialized.len()))
 }
 
@@ -378,12 +883,66 @@ fn resolve_and_read_file(st: &SystemTable<Boot>, config: &Config, file: &File) -
     }
 
     partitions.reverse();
-    let res = find_read_file(st, config, &partitions, &file.file);
+    let res = find_read_file(st, config, &partitions, &file.file)?;
     log::info!("fetched `{}` from `{}`", file.file, file.partition);
-    res
+    if let Some(expected) = &file.sha256 {
+        verify_sha256(&res, expected)
+            .map_err(|problem| Error::new_without_source(format!("`{}` from `{}`: {problem}", file.file, file.partition)))?;
+        log::debug!("sha256 of `{}` matches", file.file);
+    }
+    Ok(res)
+}
+
+/// The device path the firmware gets for an entry's image: the file itself if it loads the image from
+/// `firmware_volume`, otherwise the ESP the buffered image is attributed to
+fn image_device_path(st: &SystemTable<Boot>, firmware_volume: Option<&(Handle, CString16)>) -> Result<Option<Box<DevicePath>>> {
+    match firmware_volume {
+        Some((volume, path)) => util::file_device_path(st, *volume, path).map(Some),
+        None => find_random_esp_path(st),
+    }
+}
+
+/// The volume and path of `file` if it's on a plain FAT partition the firmware mounted itself,
+/// so the firmware can load it by device path without the greeter buffering it.
+/// Files in LUKS, LVM or ext4 are only readable by the greeter, and hashed files must be read anyway
+fn firmware_volume(st: &SystemTable<Boot>, config: &Config, file: &File) -> Option<(Handle, CString16)> {
+    let partition = &config.partitions[&file.partition];
+    if partition.parent.is_some() || partition.keyslot.is_some() || file.sha256.is_some() {
+        return None;
+    }
+    let path = CString16::try_from(file.file.replace('/', "\\").as_str()).ok()?;
+    let volumes = st.boot_services().find_handles::<SimpleFileSystem>().ok()?;
+    let volume = volumes.into_iter()
+        .find(|&volume| fat_volume_id(st, volume).as_deref() == Some(partition.uuid.as_str()))?;
+    Some((volume, path))
+}
+
+/// The FAT volume ID from the boot sector, formatted like partition UUIDs in the config
+fn fat_volume_id(st: &SystemTable<Boot>, volume: Handle) -> Option<String> {
+    let bt = st.boot_services();
+    let params = OpenProtocolParams { handle: volume, agent: bt.image_handle(), controller: None };
+    let blockio = unsafe { bt.open_protocol::<BlockIO>(params, OpenProtocolAttributes::GetProtocol) }.ok()?;
+    let media = blockio.media();
+    let mut sector = unsafe { util::alloc_init_aligned(media.block_size() as usize, media.io_align().max(1) as usize) }.ok()?;
+    blockio.read_blocks(media.media_id(), 0, &mut sector).ok()?;
+    // FAT32 has a larger BPB, which moves the volume ID back
+    let offset = if sector.get(0x52..0x57) == Some(&b"FAT32"[..]) { 0x43 } else { 0x27 };
+    let id = u32::from_le_bytes(sector.get(offset..offset + 4)?.try_into().ok()?);
+    Some(format!("{:X}-{:X}", id >> 16, id as u16))
+}
+
+/// Catches bit-rot and tampering of files on unlocked drives
+fn verify_sha256(data: &[u8], expected: &str) -> core::result::Result<(), String> {
+    use sha2::{Digest, Sha256};
+    let actual: String = Sha256::digest(data).iter().map(|b| format!("{b:02x}")).collect();
+    if !actual.eq_ignore_ascii_case(expected.trim()) {
+        return Err(format!("sha256 mismatch, expected {expected}, got {actual}"));
+    }
+    Ok(())
 }
 
 fn block_devices(st: &SystemTable<Boot>) -> Result<Vec<(Handle, Lba, Lba)>> {
+    hotplug::poll(st);
     Ok(st.boot_services().find_handles::<BlockIO>()
         .context("error getting list of BlockIO Handles")?
         .into_iter()
@@ -421,6 +980,19 @@ fn try_get_nvme_device(st: &SystemTable<Boot>, blockio_handle: Handle) -> Result
     }
 }
 
+/// Aborts sessions left open on the drive if configured; drives without STACK_RESET are used as they are
+fn reset_stack<P: opal::SecureProtocol>(config: &Config, drive: &mut opal::OpalDrive<P>)
+where opal::Error<P::Error>: Into<ErrorSource>
+{
+    if !config.stack_reset {
+        return;
+    }
+    if let Err(e) = drive.stack_reset() {
+        let serial = String::from_utf8_lossy(drive.serial()).trim().to_string();
+        log::warn!("{}", Error::new(e, format!("STACK_RESET of drive {serial} failed")));
+    }
+}
+
 /// returns if it was already unlocked
 fn unlock_opal<P: opal::SecureProtocol>(st: &SystemTable<Boot>, mut secure_device: opal::OpalDrive<P>, config: &Config, keyslot: &Keyslot) -> Result<()>
 where opal::Error<P::Error>: Into<ErrorSource>
@@ -428,35 +1000,258 @@ where opal::Error<P::Error>: Into<ErrorSource>
     if !secure_device.was_locked() {
         return Ok(());
     }
+    secure_device.force(config.features.forced());
+    reset_stack(config, &mut secure_device);
+    for warning in drive_firmware_warnings(config, &mut secure_device) {
+        log::warn!("{warning}");
+        console::write_str(st, &format!("Warning: {warning}\r\n"));
+    }
+    if let Some(threshold) = config.latency_probe_ms {
+        probe_latency(st, &mut secure_device, Duration::from_millis(threshold));
+    }
 
+    // secure messaging sessions aren't implemented, so every session is cleartext
+    match (config.secure_messaging, secure_device.supports_secure_messaging()) {
+        (SecureMessaging::Off, _) => (),
+        (SecureMessaging::Warn, true) => log::warn!("drive supports secure messaging, but it's not implemented; using a cleartext session"),
+        (SecureMessaging::Warn, false) => log::debug!("drive doesn't support secure messaging, using a cleartext session"),
+    }
+
+    let serial = String::from_utf8_lossy(secure_device.serial()).trim().to_string();
+    if secure_device.single_user_mode() {
+        log::info!("drive {serial} has locking ranges in Single User Mode, unlocking each as the User owning it");
+    }
+    let authority = config.partitions.values()
+        .find(|part| part.uuid == serial)
+        .map_or(opal::Authority::admin(1), |part| part.authority);
+    authenticate(st, &mut secure_device, config, keyslot, authority)
+}
+
+/// Unlocks the global range as `authority` with the keyslot's password, prompting again on wrong passwords.
+/// Unlike `unlock_opal`, this also authenticates against drives that are already unlocked.
+fn authenticate<P: opal::SecureProtocol>(st: &SystemTable<Boot>, secure_device: &mut opal::OpalDrive<P>, config: &Config, keyslot: &Keyslot, authority: opal::Authority) -> Result
+where opal::Error<P::Error>: Into<ErrorSource>
+{
+    secure_device.mbr_enable(config.features.mbr_shadow.enable);
+    let serial = String::from_utf8_lossy(secure_device.serial()).trim().to_string();
+    let partition = config.partitions.values().find(|part| part.uuid == serial);
+    secure_device.unlock_only(partition.and_then(|part| part.unlock_ranges.clone()));
+    if let (Some(dual_auth), false) = (partition.and_then(|part| part.dual_auth.as_ref()), authority.is_user()) {
+        return authenticate_dual(st, secure_device, config, keyslot, authority, dual_auth, &serial);
+    }
+    if try_msid(config, secure_device, keyslot) {
+        match secure_device.unlock_with_msid(authority) {
+            Ok(unlocked) => {
+                log::info!("drive {serial} still has the factory default password");
+                stats::record_unlock(st, &serial, None);
+                report_unlocked(st, &serial, &unlocked, secure_device.mbr_done_refused());
+                return Ok(());
+            }
+            Err(opal::Error::Opal { source: opal::OpalError::Status { code: opal::StatusCode::NOT_AUTHORIZED }, .. }) => {
+                log::debug!("drive {serial} rejected the MSID, asking for its password");
+            }
+            Err(e) => log::warn!("can't try the MSID on drive {serial}: {e}"),
+        }
+    }
     let mut cached = Cache::Cached;
     loop {
         let password = get_password_of_keyslot(st, config, keyslot, cached)?;
-        let password_or_raw = match keyslot.source {
-            KeyslotSource::Stdin => PasswordOrRaw::Password(&password),
-            KeyslotSource::File(_) => PasswordOrRaw::Raw(&password),
-        };
-        match secure_device.unlock(password_or_raw) {
-            Ok(()) => break,
-            Err(opal::Error::Opal { source: opal::OpalError::Status { code: opal::StatusCode::NOT_AUTHORIZED }, .. }) => {
-                log::error!("Invalid Password, try again!");
-            }
-            Err(opal::Error::Opal { source: opal::OpalError::Status { code: opal::StatusCode::AUTHORITY_LOCKED_OUT }, .. }) => {
-                let mut st = unsafe { st.unsafe_clone() };
-                st.stdout()
-                    .write_str("Too many bad tries, SED locked out, resetting in 10s..")
-                    .unwrap();
-                sleep(Duration::from_secs(10));
-                st.runtime_services()
-                    .reset(ResetType::COLD, Status::WARN_RESET_REQUIRED, None);
+        // pad failed attempts to a uniform latency so the response time doesn't leak anything
+        let deadline = util::Deadline::after(Duration::from_millis(config.failed_attempt_latency_ms));
+        let watchdog = config.unlock_watchdog.map(|timeout| watchdog::arm(st, timeout));
+        let started = clock::now_ms();
+        let res = secure_device.unlock_as(authority, credential(keyslot, &password));
+        let elapsed = started.zip(clock::now_ms()).map(|(started, ended)| ended.saturating_sub(started));
+        drop(watchdog);
+        match res {
+            Ok(unlocked) => {
+                stats::record_unlock(st, &serial, elapsed);
+                report_unlocked(st, &serial, &unlocked, secure_device.mbr_done_refused());
+                break;
             }
-            Err(e) => return Err(Error::new(e, "efi error trying to unlock device")),
+            Err(e) => failed_attempt::<P>(st, &serial, authority, e, deadline)?,
         }
         cached = Cache::Discard;
     }
     Ok(())
 }
 
+/// Whether to try the factory default password before asking: always with `try_msid`, otherwise only when
+/// the drive reports that SID's PIN still is the MSID, as Admin1 starts out with SID's PIN.
+/// A rejected MSID costs one of the authority's tries, so it's not tried once a password is at hand,
+/// nor for key files and quorum keys, which are available without asking anyway.
+fn try_msid<P: opal::SecureProtocol>(config: &Config, secure_device: &opal::OpalDrive<P>, keyslot: &Keyslot) -> bool {
+    if !matches!(keyslot.source, KeyslotSource::Stdin) || config.keyslot_buffer.borrow().contains_key(&keyslot.name) {
+        return false;
+    }
+    config.try_msid || secure_device.discovery().block_sid.map_or(false, |block_sid| !block_sid.sid_pin_changed)
+}
+
+/// Two-stage unlock for drives whose ACEs require a User together with `authority`, e.g. `Admin1 AND User1`.
+/// Each credential is asked for and retried on its own, so mistyping the second one doesn't cost the first.
+fn authenticate_dual<P: opal::SecureProtocol>(st: &SystemTable<Boot>, secure_device: &mut opal::OpalDrive<P>, config: &Config, keyslot: &Keyslot, authority: opal::Authority, dual_auth: &DualAuth, serial: &str) -> Result
+where opal::Error<P::Error>: Into<ErrorSource>
+{
+    let user = opal::Authority::user(dual_auth.user);
+    let user_keyslot = config.keyslots.get(&dual_auth.keyslot)
+        .ok_or_else(|| Error::new_without_source(format!("unknown keyslot `{}` for {user} of drive {serial}", dual_auth.keyslot)))?;
+    console::write_str(st, &format!("Drive {serial} needs two credentials, first {authority}, then {user}\r\n"));
+    // armed only around the drive's commands, the prompts in between may take as long as the user needs
+    let arm = || config.unlock_watchdog.map(|timeout| watchdog::arm(st, timeout));
+
+    let mut cached = Cache::Cached;
+    let mut pending = loop {
+        let password = get_password_of_keyslot(st, config, keyslot, cached)?;
+        let deadline = util::Deadline::after(Duration::from_millis(config.failed_attempt_latency_ms));
+        let watchdog = arm();
+        let res = secure_device.begin_unlock(authority, credential(keyslot, &password));
+        drop(watchdog);
+        match res {
+            Ok(pending) => break pending,
+            Err(e) => failed_attempt::<P>(st, serial, authority, e, deadline)?,
+        }
+        cached = Cache::Discard;
+    };
+    let mut cached = Cache::Cached;
+    loop {
+        let password = get_password_of_keyslot(st, config, user_keyslot, cached)?;
+        let deadline = util::Deadline::after(Duration::from_millis(config.failed_attempt_latency_ms));
+        let watchdog = arm();
+        let res = pending.authenticate(user, credential(user_keyslot, &password));
+        drop(watchdog);
+        match res {
+            Ok(()) => break,
+            Err(e) => failed_attempt::<P>(st, serial, user, e, deadline)?,
+        }
+        cached = Cache::Discard;
+    }
+    let watchdog = arm();
+    let res = pending.finish();
+    drop(watchdog);
+    match res {
+        Ok(unlocked) => {
+            stats::record_unlock(st, serial, None);
+            report_unlocked(st, serial, &unlocked, secure_device.mbr_done_refused());
+            Ok(())
+        }
+        Err(e) => {
+            stats::record_failure(st, serial, stats::Failure::Error);
+            Err(Error::new(e, "efi error trying to unlock device"))
+        }
+    }
+}
+
+/// Tells which ranges were unlocked, by the names stored on the drive where it has them,
+/// and if the drive still shows its shadow MBR because MBRDone couldn't be set
+fn report_unlocked(st: &SystemTable<Boot>, serial: &str, unlocked: &[opal::UnlockedRange], mbr_done_refused: bool) {
+    let ranges: Vec<String> = unlocked.iter().map(|unlocked| match (&unlocked.name, unlocked.range) {
+        (Some(name), range) => format!("{name} (range {range})"),
+        (None, 0) => "global range".to_string(),
+        (None, range) => format!("range {range}"),
+    }).collect();
+    console::write_str(st, &format!("Drive {serial}: unlocked {}\r\n", ranges.join(", ")));
+    log::debug!("drive {serial}: unlocked ranges {}", ranges.join(", "));
+    if mbr_done_refused {
+        log::warn!("drive {serial}: MBRDone wasn't set, the shadow MBR still shows");
+        console::write_str(st, &format!("Drive {serial}: this user may not set MBRDone, so the pre-boot image still hides the start of the drive until an Admin unlocks it\r\n"));
+    }
+    locked_drives::unlocked(serial);
+}
+
+/// How the keyslot's secret is sent: typed passwords are derived first, key files and quorum keys are used as they are
+fn credential<'a>(keyslot: &Keyslot, password: &'a [u8]) -> PasswordOrRaw<'a> {
+    match keyslot.source {
+        KeyslotSource::Stdin => PasswordOrRaw::Password(password),
+        KeyslotSource::File(_) | KeyslotSource::Quorum(_) => PasswordOrRaw::Raw(password),
+    }
+}
+
+/// Returns to ask for `authority`'s password again if it was wrong, once `deadline` passed, and resets when the drive locked it out
+fn failed_attempt<P: opal::SecureProtocol>(st: &SystemTable<Boot>, serial: &str, authority: opal::Authority, e: opal::Error<P::Error>, deadline: util::Deadline) -> Result
+where opal::Error<P::Error>: Into<ErrorSource>
+{
+    match e {
+        opal::Error::Opal { source: opal::OpalError::Status { code: opal::StatusCode::NOT_AUTHORIZED }, .. } => {
+            stats::record_failure(st, serial, stats::Failure::WrongPassword);
+            deadline.wait();
+            log::error!("Invalid Password for {authority}, try again!");
+            beep::cue(beep::Cue::WrongPassword);
+            Ok(())
+        }
+        opal::Error::Opal { source: opal::OpalError::Status { code: opal::StatusCode::AUTHORITY_LOCKED_OUT }, .. } => {
+            console::write_str(st, "Too many bad tries, SED locked out, resetting in 10s..");
+            beep::cue(beep::Cue::Fatal);
+            sleep(Duration::from_secs(10));
+            st.runtime_services()
+                .reset(ResetType::COLD, Status::WARN_RESET_REQUIRED, None);
+        }
+        e => {
+            stats::record_failure(st, serial, stats::Failure::Error);
+            Err(Error::new(e, "efi error trying to unlock device"))
+        }
+    }
+}
+
+/// Warns about drives at or near their temperature threshold, where thermal throttling or
+/// a thermal shutdown in the middle of unlocking or a shadow MBR upload becomes likely
+fn warn_if_hot(st: &SystemTable<Boot>, nvme: &NvmeDevice) {
+    /// warn this many degrees before the warning threshold already
+    const MARGIN_KELVIN: u16 = 5;
+    let serial = String::from_utf8_lossy(nvme.serial_num()).trim().to_string();
+    let temperature = match nvme.temperature() {
+        Ok(temperature) => temperature,
+        Err(e) => {
+            log::debug!("drive {serial}: can't read SMART log: {e:?}");
+            return;
+        }
+    };
+    let celsius = low_level::nvme_device::Temperature::celsius;
+    // 0 K means the drive doesn't report that threshold
+    let threshold = |kelvin: u16| match kelvin {
+        0 => "unknown".to_string(),
+        kelvin => format!("{} C", celsius(kelvin)),
+    };
+    log::info!(
+        "drive {serial}: {} C (warning at {}, critical at {})",
+        celsius(temperature.kelvin), threshold(temperature.warning_kelvin), threshold(temperature.critical_kelvin),
+    );
+    if temperature.is_hot(MARGIN_KELVIN) {
+        console::write_str(st, &format!(
+            "Warning: drive {serial} is at {} C, its warning threshold is {}. Consider letting it cool down first.\r\n",
+            celsius(temperature.kelvin), threshold(temperature.warning_kelvin),
+        ));
+    }
+}
+
+fn drive_firmware_warnings<P: opal::SecureProtocol>(config: &Config, secure_device: &mut opal::OpalDrive<P>) -> Vec<String> {
+    let model = String::from_utf8_lossy(secure_device.model()).trim().to_string();
+    let firmware = String::from_utf8_lossy(secure_device.firmware_rev()).trim().to_string();
+    firmware::warnings(&model, &firmware, &config.firmware_warnings)
+}
+
+/// Warns if the drive answers benign commands pathologically slowly, which usually predicts a failing unlock
+fn probe_latency<P: opal::SecureProtocol>(st: &SystemTable<Boot>, secure_device: &mut opal::OpalDrive<P>, threshold: Duration) {
+    const PROBES: usize = 3;
+    let mut slow = 0;
+    for _ in 0..PROBES {
+        let deadline = util::Deadline::after(threshold);
+        if let Err(e) = secure_device.is_locked() {
+            log::warn!("latency probe failed: {e}");
+            slow += 1;
+        } else if deadline.expired() {
+            slow += 1;
+        }
+    }
+    let serial = String::from_utf8_lossy(secure_device.serial()).trim().to_string();
+    log::debug!("drive {serial}: {slow} of {PROBES} latency probes slower than {threshold:?}");
+    if slow != 0 {
+        console::write_str(st, &format!(
+            "Warning: drive {serial} responded slowly to {slow} of {PROBES} probes (> {threshold:?}).\r\n\
+            If unlocking times out, the drive is more likely the problem than the password.\r\n",
+        ));
+    }
+}
+
 fn find_read_file(st: &SystemTable<Boot>, config: &Config, mut partitions: &[&Partition], file: &str) -> Result<Vec<u8>> {
     for (i, (blockio_handle, start_lba, end_lba)) in block_devices(st)?.into_iter().enumerate() {
         log::debug!("probing blockio #{i} {start_lba:#x} - {end_lba:#x}");
@@ -473,7 +1268,15 @@ fn find_read_file(st: &SystemTable<Boot>, config: &Config, mut partitions: &[&Pa
                 if partitions[0].keyslot.is_some() {
                     let keyslot = partitions[0].keyslot.as_deref().unwrap();
                     let keyslot = &config.keyslots[keyslot];
-                    let secure_device = opal::OpalDrive::new(RestartableNvmeDevice::new(&nvme, st, blockio_handle)).unwrap();
+                    warn_if_hot(st, &nvme);
+                    let secure_device = match opal::OpalDrive::new(RestartableNvmeDevice::new(&nvme, st, blockio_handle)) {
+                        Ok(drive) => drive,
+                        Err(opal::Error::KeyPerIo) => {
+                            warn_key_per_io(serial);
+                            return Err(Error::new_without_source(format!("partition `{}` is on a Key Per I/O drive, which can't be unlocked here", partitions[0].name)));
+                        }
+                        Err(e) => return Err(Error::new(e, "open opal")),
+                    };
                     unlock_opal(st, secure_device, config, keyslot)?;
                 }
                 partitions = &partitions[1..];
@@ -572,7 +1375,10 @@ fn find_read_file_internal(st: &SystemTable<Boot>, reader: &mut dyn ReadSeek, co
                         let password = get_password_of_keyslot(st, config, keyslot, cached)?;
                         match LuksDevice::from_device(&mut *reader, &password, 512) {
                             Ok(luks) => break luks,
-                            Err(LuksError::InvalidPassword) => log::error!("Invalid Password, try again!"),
+                            Err(LuksError::InvalidPassword) => {
+                                log::error!("Invalid Password, try again!");
+                                beep::cue(beep::Cue::WrongPassword);
+                            }
                             Err(e) => return Err(e).context("error opening luks2 with password"),
                         }
                         reader.rewind().context("can't rewind reader after luks2 invalid password")?;
@@ -684,30 +1490,49 @@ fn get_password_of_keyslot(st: &SystemTable<Boot>, config: &Config, keyslot: &Ke
 
     let password = match &keyslot.source {
         KeyslotSource::Stdin => {
-            let mut st = unsafe { st.unsafe_clone() };
-            st.stdout().write_str(&format!("Password for keyslot {}: ", keyslot.name)).unwrap();
-            ui::password(&st)?.into_bytes()
+            summary::show(st);
+            match &keyslot.label {
+                Some(label) => console::write_str(st, &format!("{label}: ")),
+                None => console::write_str(st, &format!("Password for keyslot {}: ", keyslot.name)),
+            }
+            beep::cue(beep::Cue::ReadyForPassword);
+            ui::password(st)?.into_bytes()
         },
         KeyslotSource::File(file) => {
             resolve_and_read_file(st, config, file)?
         }
+        KeyslotSource::Quorum(quorum) => quorum::combine(st, config, &keyslot.name, quorum)?,
     };
     config.keyslot_buffer.borrow_mut().insert(keyslot.name.clone(), password.clone());
     Ok(password)
 }
 
-fn config_stdout(st: &SystemTable<Boot>) -> uefi::Result {
-    let mut st = unsafe { st.unsafe_clone() };
+fn config_stdout(system_table: &SystemTable<Boot>) -> uefi::Result {
+    let mut st = unsafe { system_table.unsafe_clone() };
     st.stdout().reset(false)?;
     st.stdout().clear()?;
 
-    if let Some(mode) = st.stdout().modes().max_by_key(|m| {
-        m.rows() * m.columns()
-    // if let Some(mode) = st.stdout().modes().min_by_key(|m| {
-    //     (m.rows() as i32 * m.columns() as i32 - 200*64).abs()
-    }) {
+    if safe_mode::active() {
+        log::warn!("safe mode: keeping current console mode");
+        return Ok(());
+    }
+
+    let mode = if accessibility::large_text() {
+        // fewer cells on the same screen means larger glyphs
+        st.stdout().modes().min_by_key(|m| m.rows() * m.columns())
+    } else {
+        st.stdout().modes().max_by_key(|m| {
+            m.rows() * m.columns()
+        // if let Some(mode) = st.stdout().modes().min_by_key(|m| {
+        //     (m.rows() as i32 * m.columns() as i32 - 200*64).abs()
+        })
+    };
+    if let Some(mode) = mode {
         log::trace!("selected {mode:?}");
-        st.stdout().set_mode(mode)?;
+        if st.stdout().current_mode()?.map_or(true, |current| current.index() != mode.index()) {
+            let _risky = safe_mode::Risky::begin(system_table, "console mode switch");
+            st.stdout().set_mode(mode)?;
+        }
     };
 
     Ok(())
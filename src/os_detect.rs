@@ -0,0 +1,175 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use uefi::table::{Boot, SystemTable};
+use crate::config::{BootEntry, Config, File};
+
+/// Known `\EFI\<vendor>` directories and the OS they usually belong to.
+const EFI_VENDORS: &[(&str, &str)] = &[
+    ("microsoft", "Windows"),
+    ("ubuntu", "Ubuntu"),
+    ("debian", "Debian"),
+    ("fedora", "Fedora"),
+    ("centos", "CentOS"),
+    ("redhat", "Red Hat Enterprise Linux"),
+    ("opensuse", "openSUSE"),
+    ("sles", "SUSE Linux Enterprise"),
+    ("arch", "Arch Linux"),
+    ("manjaro", "Manjaro"),
+    ("gentoo", "Gentoo"),
+    ("nixos", "NixOS"),
+    ("pop", "Pop!_OS"),
+    ("systemd", "systemd-boot"),
+    ("refind", "rEFInd"),
+];
+
+/// Returns the boot entry's name decorated with the detected OS if `detect_os` is set.
+///
+/// Results are cached in the config as detection may need to read (and thus unlock) partitions;
+/// entries on partitions whose key isn't known yet are skipped rather than prompting.
+/// Low-memory mode skips it, as it buffers whole files and keeps the results.
+pub fn entry_title(st: &SystemTable<Boot>, config: &Config, entry: &BootEntry) -> String {
+    // nothing can be detected before unlocking, and a cached miss would outlive the unlock
+    if crate::locked_drives::entry_locked(config, entry) {
+        return format!("{} (locked, unlock to boot)", entry.name);
+    }
+    if !entry.detect_os || crate::safe_mode::active() || crate::low_memory::active() {
+        return entry.name.clone();
+    }
+    // building the menu mustn't prompt; not cached, so it's detected once the key is known
+    if needs_key(config, entry) {
+        return entry.name.clone();
+    }
+    if let Some(os) = config.os_detect_buffer.borrow().get(&entry.name) {
+        return decorate(&entry.name, os.as_deref());
+    }
+    let os = {
+        let _risky = crate::safe_mode::Risky::begin(st, "OS detection");
+        detect(st, config, entry)
+    };
+    log::debug!("detected os of boot entry `{}`: {os:?}", entry.name);
+    let title = decorate(&entry.name, os.as_deref());
+    config.os_detect_buffer.borrow_mut().insert(entry.name.clone(), os);
+    title
+}
+
+/// whether a partition the entry lives on needs a key that isn't known yet
+fn needs_key(config: &Config, entry: &BootEntry) -> bool {
+    let mut current = Some(&entry.file.partition);
+    while let Some(name) = current {
+        let partition = &config.partitions[name];
+        if let Some(keyslot) = &partition.keyslot {
+            if !config.keyslot_buffer.borrow().contains_key(keyslot) {
+                return true;
+            }
+        }
+        current = partition.parent.as_ref();
+    }
+    false
+}
+
+fn decorate(name: &str, os: Option<&str>) -> String {
+    match os {
+        Some(os) if !name.contains(os) => format!("{name} ({os})"),
+        _ => name.to_string(),
+    }
+}
+
+fn detect(st: &SystemTable<Boot>, config: &Config, entry: &BootEntry) -> Option<String> {
+    let components: Vec<String> = entry.file.file
+        .split(['/', '\\'])
+        .filter(|c| !c.is_empty())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+
+    // Windows Boot Manager is only usable together with its BCD store
+    if components.ends_with(&["efi".into(), "microsoft".into(), "boot".into(), "bootmgfw.efi".into()]) {
+        let bcd = sibling(&entry.file.file, "BCD");
+        return match read(st, config, entry, &bcd) {
+            Some(_) => Some("Windows".to_string()),
+            None => Some("Windows, BCD missing".to_string()),
+        };
+    }
+
+    if let Some(os) = ["/etc/os-release", "/usr/lib/os-release"].iter()
+        .find_map(|path| read(st, config, entry, path))
+        .and_then(|content| parse_os_release(&content))
+    {
+        return Some(os);
+    }
+
+    if let Some(title) = loader_default_title(st, config, entry) {
+        return Some(title);
+    }
+
+    let vendor = components.iter()
+        .position(|c| c == "efi")
+        .and_then(|i| components.get(i + 1))?;
+    EFI_VENDORS.iter()
+        .find(|(dir, _)| dir == vendor)
+        .map(|(_, os)| os.to_string())
+}
+
+/// title of the systemd-boot default entry if it's not a glob
+fn loader_default_title(st: &SystemTable<Boot>, config: &Config, entry: &BootEntry) -> Option<String> {
+    let loader_conf = read(st, config, entry, "/loader/loader.conf")?;
+    let default = loader_conf.lines()
+        .filter_map(|line| line.trim().strip_prefix("default"))
+        .map(str::trim)
+        .last()?;
+    if default.contains(['*', '?', '[']) {
+        return None;
+    }
+    let default = default.strip_suffix(".conf").unwrap_or(default);
+    let conf = read(st, config, entry, &format!("/loader/entries/{default}.conf"))?;
+    let title = conf.lines()
+        .filter_map(|line| line.trim().strip_prefix("title"))
+        .map(str::trim)
+        .next()?;
+    let version = conf.lines()
+        .filter_map(|line| line.trim().strip_prefix("version"))
+        .map(str::trim)
+        .next();
+    match version {
+        Some(version) => Some(format!("{title} {version}")),
+        None => Some(title.to_string()),
+    }
+}
+
+fn parse_os_release(content: &str) -> Option<String> {
+    let value = |key: &str| content.lines()
+        .filter_map(|line| line.trim().strip_prefix(key)?.strip_prefix('='))
+        .map(|value| value.trim().trim_matches(['"', '\'']).to_string())
+        .find(|value| !value.is_empty());
+
+    if let Some(pretty) = value("PRETTY_NAME") {
+        return Some(pretty);
+    }
+    let name = value("NAME")?;
+    match value("VERSION_ID") {
+        Some(version) => Some(format!("{name} {version}")),
+        None => Some(name),
+    }
+}
+
+fn sibling(path: &str, name: &str) -> String {
+    match path.rfind(['/', '\\']) {
+        Some(i) => format!("{}{name}", &path[..=i]),
+        None => name.to_string(),
+    }
+}
+
+fn read(st: &SystemTable<Boot>, config: &Config, entry: &BootEntry, path: &str) -> Option<String> {
+    let file = File {
+        partition: entry.file.partition.clone(),
+        extra_partitions: Vec::new(),
+        file: path.to_string(),
+        sha256: None,
+    };
+    match crate::resolve_and_read_file(st, config, &file) {
+        Ok(content) => Some(String::from_utf8_lossy(&content).into_owned()),
+        Err(e) => {
+            log::trace!("os detection: can't read `{path}`: {e}");
+            None
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use opal::{Authority, OpalDrive, PasswordOrRaw, SecureProtocol};
+use uefi::table::{Boot, SystemTable};
+use crate::admin::{self, DriveAction};
+use crate::config::{Config, Keyslot, KeyslotSource};
+use crate::error::ErrorSource;
+use crate::{console, ui, Error, Result};
+
+/// A drive whose password can be changed here: the partition's serial, the authority and the keyslot it unlocks with
+struct Target<'c> {
+    serial: &'c str,
+    authority: Authority,
+    keyslot: &'c Keyslot,
+}
+
+/// The drives whose credential is a typed password: the logged-in user's, or the unlock authority's of every configured OPAL partition
+fn targets(config: &Config) -> Vec<Target<'_>> {
+    let typed = |keyslot: &Keyslot| matches!(keyslot.source, KeyslotSource::Stdin);
+    if let Some(user) = config.user() {
+        return config.partitions.get(&user.partition)
+            .zip(config.keyslots.get(&user.keyslot))
+            .filter(|(_, keyslot)| typed(keyslot))
+            .map(|(partition, keyslot)| Target { serial: &partition.uuid, authority: Authority::user(user.authority), keyslot })
+            .into_iter()
+            .collect();
+    }
+    config.partitions.values()
+        .filter(|part| part.parent.is_none())
+        .filter_map(|part| Some((part, config.keyslots.get(part.keyslot.as_deref()?)?)))
+        .filter(|(_, keyslot)| typed(keyslot))
+        .map(|(part, keyslot)| Target { serial: &part.uuid, authority: part.authority, keyslot })
+        .collect()
+}
+
+/// whether the boot menu offers changing a password
+pub fn available(config: &Config) -> bool {
+    !targets(config).is_empty()
+}
+
+/// Changes the password of the chosen drives, or all of them, after the drive verified the current one
+pub fn change(st: &SystemTable<Boot>, config: &Config) -> Result {
+    let targets = targets(config);
+    let mut options: Vec<_> = targets.iter()
+        .map(|target| (true, format!("{} of drive {}", target.authority, target.serial)))
+        .collect();
+    if targets.len() > 1 {
+        options.push((true, "All of them".to_string()));
+    }
+    options.push((true, "Back".to_string()));
+    console::clear(st)?;
+    console::write_str(st, "Change the password of:\r\n");
+    let selected: Vec<&Target> = match ui::choose(st, &options)? {
+        i if i < targets.len() => vec![&targets[i]],
+        i if i == targets.len() && targets.len() > 1 => targets.iter().collect(),
+        _ => return Ok(()),
+    };
+
+    console::clear(st)?;
+    console::write_str(st, "Current password: ");
+    let old = ui::password(st)?;
+    let Some(new) = admin::new_password(st, "the selected drives")? else { return Ok(()) };
+    let mut change = Change { old: &old, new: &new, target: None, results: Vec::new() };
+    for target in selected {
+        change.target = Some(target.authority);
+        let before = change.results.len();
+        if let Err(e) = admin::for_each_drive(st, config, Some(target.serial), &mut change) {
+            log::error!("changing the password of drive {} failed: {e}", target.serial);
+            change.results.push(format!("{}: failed, {e}", target.serial));
+        }
+        if change.results.len() == before {
+            change.results.push(format!("{}: no OPAL drive with this serial", target.serial));
+        }
+        // the cached password is the old one now
+        config.keyslot_buffer.borrow_mut().remove(&target.keyslot.name);
+    }
+    ui::popup(st, "Change password", &change.results)
+}
+
+struct Change<'a> {
+    old: &'a str,
+    new: &'a str,
+    target: Option<Authority>,
+    results: Vec<String>,
+}
+
+impl DriveAction for Change<'_> {
+    fn run<P: SecureProtocol>(&mut self, _st: &SystemTable<Boot>, _kind: &str, drive: &mut OpalDrive<P>) -> Result
+    where opal::Error<P::Error>: Into<ErrorSource>
+    {
+        let serial = admin::serial_str(drive.serial());
+        let authority = self.target.expect("authority is set before each drive");
+        let res = drive.change_password(
+            authority,
+            PasswordOrRaw::Password(self.old.as_bytes()),
+            PasswordOrRaw::Password(self.new.as_bytes()),
+        );
+        match res {
+            Ok(()) => {
+                log::warn!("changed the password of {authority} on drive {serial}");
+                self.results.push(format!("{serial}: password of {authority} changed"));
+                Ok(())
+            }
+            Err(opal::Error::Opal { source: opal::OpalError::Status { code: opal::StatusCode::NOT_AUTHORIZED }, .. }) => {
+                log::error!("wrong current password for {authority} on drive {serial}");
+                self.results.push(format!("{serial}: the current password was wrong, nothing was changed"));
+                Ok(())
+            }
+            Err(e) => Err(Error::new(e, format!("can't change the password of {authority}"))),
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Offset of the `e_lfanew` field within the MZ header pointing to the PE header
+const E_LFANEW: usize = 0x3c;
+/// Offsets within the optional header
+const SUBSYSTEM: usize = 68;
+const PE32_DATA_DIRECTORIES: usize = 96;
+const PE32_PLUS_DATA_DIRECTORIES: usize = 112;
+/// index of the certificate table in the data directories
+const SECURITY_DIRECTORY: usize = 4;
+const BASE_RELOCATION_DIRECTORY: usize = 5;
+const SECTION_HEADER_SIZE: usize = 40;
+pub const SCN_MEM_DISCARDABLE: u32 = 0x0200_0000;
+pub const SCN_MEM_WRITE: u32 = 0x8000_0000;
+pub const REL_BASED_HIGHLOW: u8 = 3;
+pub const REL_BASED_DIR64: u8 = 10;
+
+const SUBSYSTEM_EFI_APPLICATION: u16 = 10;
+
+#[cfg(target_arch = "x86_64")]
+pub const NATIVE_MACHINE: u16 = 0x8664;
+#[cfg(target_arch = "x86")]
+pub const NATIVE_MACHINE: u16 = 0x014c;
+#[cfg(target_arch = "aarch64")]
+pub const NATIVE_MACHINE: u16 = 0xaa64;
+#[cfg(target_arch = "arm")]
+pub const NATIVE_MACHINE: u16 = 0x01c2;
+#[cfg(target_arch = "riscv32")]
+pub const NATIVE_MACHINE: u16 = 0x5032;
+#[cfg(target_arch = "riscv64")]
+pub const NATIVE_MACHINE: u16 = 0x5064;
+#[cfg(target_arch = "loongarch64")]
+pub const NATIVE_MACHINE: u16 = 0x6264;
+#[cfg(not(any(
+    target_arch = "x86_64", target_arch = "x86", target_arch = "aarch64", target_arch = "arm",
+    target_arch = "riscv32", target_arch = "riscv64", target_arch = "loongarch64",
+)))]
+compile_error!("no PE machine type known for this architecture");
+
+pub fn is_mz(image: &[u8]) -> bool {
+    image.get(0..2) == Some(&[0x4d, 0x5a])
+}
+
+fn u16_at(image: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(image.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn u32_at(image: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(image.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+/// offset of the `PE\0\0` signature, if the image has one
+fn pe_offset(image: &[u8]) -> Option<usize> {
+    if !is_mz(image) {
+        return None;
+    }
+    let pe_offset = u32_at(image, E_LFANEW)? as usize;
+    if image.get(pe_offset..pe_offset.checked_add(4)?)? != b"PE\0\0" {
+        return None;
+    }
+    Some(pe_offset)
+}
+
+/// COFF `Machine` field of the PE header, if the image has one
+pub fn machine(image: &[u8]) -> Option<u16> {
+    u16_at(image, pe_offset(image)? + 4)
+}
+
+/// offset of the optional header and whether it's PE32+
+fn optional_header(image: &[u8]) -> Option<(usize, bool)> {
+    let pe_offset = pe_offset(image)?;
+    let size = u16_at(image, pe_offset + 20)? as usize;
+    let offset = pe_offset + 24;
+    image.get(offset..offset + size)?;
+    match u16_at(image, offset)? {
+        0x10b => Some((offset, false)),
+        0x20b => Some((offset, true)),
+        _ => None,
+    }
+}
+
+/// `Subsystem` field of the optional header
+pub fn subsystem(image: &[u8]) -> Option<u16> {
+    let (offset, _) = optional_header(image)?;
+    u16_at(image, offset + SUBSYSTEM)
+}
+
+/// (address, size) of a data directory; the address is a file offset for the certificate table and an RVA otherwise
+fn data_directory(image: &[u8], index: usize) -> Option<(u32, u32)> {
+    let (offset, pe32_plus) = optional_header(image)?;
+    let directories = offset + if pe32_plus { PE32_PLUS_DATA_DIRECTORIES } else { PE32_DATA_DIRECTORIES };
+    let entry = directories + index * 8;
+    Some((u32_at(image, entry)?, u32_at(image, entry + 4)?))
+}
+
+/// Whether the image carries an Authenticode certificate table
+pub fn is_signed(image: &[u8]) -> bool {
+    data_directory(image, SECURITY_DIRECTORY).map_or(false, |(_, size)| size != 0)
+}
+
+/// An entry of the section table
+pub struct Section {
+    pub rva: u32,
+    pub size: u32,
+    pub characteristics: u32,
+}
+
+/// the section table of an image
+pub fn sections(image: &[u8]) -> Option<Vec<Section>> {
+    let pe_offset = pe_offset(image)?;
+    let count = u16_at(image, pe_offset + 6)? as usize;
+    let table = pe_offset + 24 + u16_at(image, pe_offset + 20)? as usize;
+    (0..count)
+        .map(|i| {
+            let header = table + i * SECTION_HEADER_SIZE;
+            Some(Section {
+                size: u32_at(image, header + 8)?,
+                rva: u32_at(image, header + 12)?,
+                characteristics: u32_at(image, header + 36)?,
+            })
+        })
+        .collect()
+}
+
+/// (RVA, type) of each base relocation of an image as laid out in memory, i.e. with sections at their RVAs
+pub fn base_relocations(image: &[u8]) -> Option<Vec<(u32, u8)>> {
+    let (rva, size) = data_directory(image, BASE_RELOCATION_DIRECTORY)?;
+    let blocks = image.get(rva as usize..rva.checked_add(size)? as usize)?;
+    let mut relocations = Vec::new();
+    let mut offset = 0;
+    while offset + 8 <= blocks.len() {
+        let page = u32_at(blocks, offset)?;
+        let block_size = u32_at(blocks, offset + 4)? as usize;
+        if block_size < 8 {
+            return None;
+        }
+        for entry in (offset + 8..(offset + block_size).min(blocks.len())).step_by(2) {
+            let entry = u16_at(blocks, entry)?;
+            relocations.push((page + (entry & 0xfff) as u32, (entry >> 12) as u8));
+        }
+        offset += block_size;
+    }
+    Some(relocations)
+}
+
+pub fn subsystem_name(subsystem: u16) -> &'static str {
+    match subsystem {
+        1 => "native",
+        2 => "Windows GUI",
+        3 => "Windows console",
+        10 => "EFI application",
+        11 => "EFI boot service driver",
+        12 => "EFI runtime driver",
+        13 => "EFI ROM",
+        _ => "unknown",
+    }
+}
+
+/// Checks that the image is an EFI application for this machine, describing what's wrong otherwise
+pub fn check(image: &[u8]) -> Result<(), String> {
+    if !is_mz(image) {
+        return Err(String::from("is not a PE/COFF image: no MZ signature"));
+    }
+    let Some(machine) = machine(image) else {
+        return Err(String::from("has a corrupted PE header: PE signature missing or out of bounds"));
+    };
+    if machine != NATIVE_MACHINE {
+        return Err(format!(
+            "is built for {} ({machine:#06x}), but this machine is {}",
+            machine_name(machine), machine_name(NATIVE_MACHINE),
+        ));
+    }
+    let Some(subsystem) = subsystem(image) else {
+        return Err(String::from("has a corrupted PE header: optional header missing, truncated or of unknown format"));
+    };
+    if subsystem != SUBSYSTEM_EFI_APPLICATION {
+        return Err(format!("is a {} ({subsystem}), not an EFI application", subsystem_name(subsystem)));
+    }
+    Ok(())
+}
+
+pub fn machine_name(machine: u16) -> &'static str {
+    match machine {
+        0x014c => "x86",
+        0x8664 => "x86_64",
+        0x01c2 | 0x01c4 => "arm",
+        0xaa64 => "aarch64",
+        0x5032 => "riscv32",
+        0x5064 => "riscv64",
+        0x6264 => "loongarch64",
+        0x0ebc => "EBC",
+        _ => "unknown",
+    }
+}
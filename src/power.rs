@@ -0,0 +1,84 @@
+use alloc::string::ToString;
+use core::cell::UnsafeCell;
+use uefi::table::{Boot, SystemTable};
+use crate::config::OnBattery;
+use crate::{console, smbios, ui, Result};
+
+/// Power Supply Characteristics of a System Power Supply structure
+const TYPE_POWER_SUPPLY: u8 = 39;
+const SUPPLY_PRESENT: u16 = 1 << 1;
+const SUPPLY_UNPLUGGED: u16 = 1 << 2;
+
+struct Global(UnsafeCell<OnBattery>);
+// UEFI boot services are single-threaded
+unsafe impl Sync for Global {}
+
+static POLICY: Global = Global(UnsafeCell::new(OnBattery::Confirm));
+
+pub fn init(policy: OnBattery) {
+    unsafe { *POLICY.0.get() = policy };
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Source {
+    Ac,
+    Battery,
+    Unknown,
+}
+
+/// Where the machine draws power from.
+///
+/// UEFI has no battery interface and ACPI's battery status needs an AML interpreter, so this relies on
+/// the SMBIOS power supply structures some firmware keeps current. Machines without a battery are always on AC.
+fn source(st: &SystemTable<Boot>) -> Source {
+    let structures = smbios::structures(st);
+    if !structures.iter().any(|s| s.kind == smbios::TYPE_BATTERY) {
+        return Source::Ac;
+    }
+    let supplies: alloc::vec::Vec<u16> = structures.iter()
+        .filter(|s| s.kind == TYPE_POWER_SUPPLY)
+        .filter_map(|s| Some(u16::from_le_bytes([s.byte(0x0e)?, s.byte(0x0f)?])))
+        .filter(|characteristics| characteristics & SUPPLY_PRESENT != 0)
+        .collect();
+    match supplies.is_empty() {
+        true => Source::Unknown,
+        false if supplies.iter().any(|characteristics| characteristics & SUPPLY_UNPLUGGED == 0) => Source::Ac,
+        false => Source::Battery,
+    }
+}
+
+/// Makes sure a long write to a drive, like a shadow MBR upload or a revert, isn't cut short by a dying battery.
+///
+/// Returns whether to go ahead: on AC power right away, otherwise as `on_battery` says, asking again after AC was connected.
+pub fn check(st: &SystemTable<Boot>, action: &str) -> Result<bool> {
+    let policy = unsafe { *POLICY.0.get() };
+    loop {
+        let source = source(st);
+        log::debug!("power source before {action}: {source:?}");
+        if source == Source::Ac || policy == OnBattery::Ignore {
+            return Ok(true);
+        }
+        let state = match source {
+            Source::Battery => "The machine runs on battery.",
+            _ => "The machine has a battery and the firmware doesn't tell whether AC power is connected.",
+        };
+        console::clear(st)?;
+        console::write_str(st, &format!("!!! {action} !!!\r\n\r\n{state}\r\n"));
+        console::write_str(st, "If it runs out midway, the drive may be left unusable.\r\n\r\n");
+        match policy {
+            OnBattery::Refuse => console::write_str(st, "Connect AC power and press Enter to check again, Escape cancels: "),
+            _ => console::write_str(st, "Connect AC power and press Enter to check again, type `battery` to go ahead anyway, Escape cancels: "),
+        }
+        match ui::line_cancelable(st)?.map(|line| line.trim().to_string()) {
+            None => {
+                log::info!("{action} cancelled, not on AC power");
+                return Ok(false);
+            }
+            Some(line) if line == "battery" && policy == OnBattery::Confirm => {
+                log::warn!("{action} confirmed without AC power");
+                return Ok(true);
+            }
+            Some(_) => (),
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use alloc::collections::VecDeque;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::fmt::Write as _;
+use core::ops::Range;
+use uefi::table::{Boot, SystemTable};
+use uefi::CString16;
+use crate::config::ProtocolTrace;
+use crate::{console, logging, ui, util, Context, Result};
+
+/// One IF-SEND or IF-RECV payload
+struct Record {
+    direction: &'static str,
+    protocol: u8,
+    com_id: u16,
+    data: Vec<u8>,
+}
+
+/// The most recent payloads, at most `size` bytes of them
+struct Trace {
+    size: usize,
+    used: usize,
+    /// numbers records across evictions, so gaps show
+    next: usize,
+    records: VecDeque<(usize, Record)>,
+}
+
+struct Global(UnsafeCell<Option<Trace>>);
+// UEFI boot services are single-threaded
+unsafe impl Sync for Global {}
+
+static TRACE: Global = Global(UnsafeCell::new(None));
+
+fn trace() -> &'static mut Option<Trace> {
+    unsafe { &mut *TRACE.0.get() }
+}
+
+/// Starts recording if configured
+pub fn init(config: Option<&ProtocolTrace>) {
+    *trace() = config.map(|config| Trace { size: config.size_kib * 1024, used: 0, next: 1, records: VecDeque::new() });
+}
+
+pub fn enabled() -> bool {
+    trace().is_some()
+}
+
+/// Keeps a payload that was sent, or received into `data`; IF-RECV buffers are cut to the length the drive reported.
+/// Credentials in sent ComPackets are blanked out first, see `redact`
+pub fn record(direction: &'static str, protocol: u8, com_id: u16, data: &[u8]) {
+    let Some(trace) = trace() else { return };
+    let mut data = data[..payload_len(protocol, com_id, data)].to_vec();
+    if direction == "IF-SEND" && protocol == 1 {
+        redact(&mut data);
+    }
+    trace.used += data.len();
+    let record = Record { direction, protocol, com_id, data };
+    trace.records.push_back((trace.next, record));
+    trace.next += 1;
+    while trace.used > trace.size {
+        match trace.records.pop_front() {
+            Some((_, old)) => trace.used -= old.data.len(),
+            None => break,
+        }
+    }
+}
+
+/// ComPacket, Packet and Subpacket headers in front of the tokens
+const HEADERS_LEN: usize = 56;
+/// filled into redacted bytes, so they show as `*` in the dump
+const REDACTED: u8 = b'*';
+
+const STARTSESSION: [u8; 8] = [0, 0, 0, 0, 0, 0, 0xFF, 0x02];
+const AUTHENTICATE: [u8; 8] = [0, 0, 0, 6, 0, 0, 0, 0x1C];
+const EAUTHENTICATE: [u8; 8] = [0, 0, 0, 6, 0, 0, 0, 0x0C];
+/// rows of the C_PIN table start with this
+const C_PIN: [u8; 4] = [0, 0, 0, 0x0B];
+
+/// A token of the stream: an atom with the range of its data, or a control token like StartName
+enum Token {
+    Atom { bytes: bool, data: Range<usize> },
+    Control(u8),
+}
+
+/// The tokens of a ComPacket with a single Subpacket, as far as they can be decoded
+fn tokens(data: &[u8]) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut i = HEADERS_LEN;
+    while let Some(&b) = data.get(i) {
+        // header length, whether it's a byte string, data length
+        let (header, bytes, len) = match b {
+            0x00..=0x7F => (1, false, 0),
+            0x80..=0xBF => (1, b & 0x20 != 0, usize::from(b & 0x0F)),
+            0xC0..=0xDF => match data.get(i + 1) {
+                Some(&low) => (2, b & 0x10 != 0, usize::from(b & 0x07) << 8 | usize::from(low)),
+                None => break,
+            },
+            0xE0..=0xE3 => match data.get(i + 1..i + 4) {
+                Some(len) => (4, b & 0x02 != 0, usize::from(len[0]) << 16 | usize::from(len[1]) << 8 | usize::from(len[2])),
+                None => break,
+            },
+            _ => {
+                tokens.push(Token::Control(b));
+                i += 1;
+                continue;
+            }
+        };
+        let start = i + header;
+        let end = (start + len).min(data.len());
+        // tiny atoms carry their value in the header byte
+        let range = if header == 1 && b < 0x80 { i..i + 1 } else { start..end };
+        tokens.push(Token::Atom { bytes, data: range });
+        i = end.max(i + 1);
+    }
+    tokens
+}
+
+/// Blanks out what would let anyone reading the trace unlock the drive: the HostChallenge of StartSession,
+/// the proof of Authenticate, which are the credentials as the drive accepts them, and the values Set in C_PIN rows.
+/// Lengths stay, so the dump still shows the structure
+fn redact(data: &mut [u8]) {
+    let tokens = tokens(data);
+    let atom = |i: usize| match tokens.get(i) {
+        Some(Token::Atom { data: range, .. }) => Some(range.clone()),
+        _ => None,
+    };
+    // Call, invoking UID, method UID
+    let Some(call) = tokens.iter().position(|token| matches!(token, Token::Control(0xF8))) else { return };
+    let (Some(invoking), Some(method)) = (atom(call + 1), atom(call + 2)) else { return };
+    let method = &data[method];
+    let pin = data[invoking].starts_with(&C_PIN);
+    let challenge = [&STARTSESSION[..], &AUTHENTICATE, &EAUTHENTICATE].contains(&method);
+    if !pin && !challenge {
+        return;
+    }
+    let mut secrets = Vec::new();
+    for window in tokens[call..].windows(3) {
+        let [Token::Control(0xF2), Token::Atom { data: name, .. }, Token::Atom { bytes: true, data: value }] = window else { continue };
+        // HostChallenge is parameter 0 in Opal, named `Challenge` in Enterprise
+        let name = &data[name.clone()];
+        if pin || name == &[0x00][..] || name == &b"Challenge"[..] {
+            secrets.push(value.clone());
+        }
+    }
+    for range in secrets {
+        data[range].fill(REDACTED);
+    }
+}
+
+/// Length of the payload in a buffer of protocol 1, a ComPacket or Level 0 discovery
+fn payload_len(protocol: u8, com_id: u16, data: &[u8]) -> usize {
+    let be32 = |offset: usize| data.get(offset..offset + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize);
+    let len = match (protocol, com_id) {
+        // Level 0 discovery shares protocol 1 with the ComPackets, but has its own ComID
+        (1, 1) => be32(0).map(|len| 4 + len),
+        (1, _) => be32(16).map(|len| 20 + len),
+        _ => None,
+    };
+    len.map_or(data.len(), |len| len.min(data.len()))
+}
+
+/// The recorded payloads as a hex dump, 16 bytes per line with their ASCII
+fn dump() -> String {
+    let mut out = String::new();
+    let Some(trace) = trace() else { return out };
+    for (number, record) in &trace.records {
+        let _ = write!(
+            out, "#{number} {} protocol {:#04X} ComID {:#06X}, {} bytes\r\n",
+            record.direction, record.protocol, record.com_id, record.data.len(),
+        );
+        for (i, chunk) in record.data.chunks(16).enumerate() {
+            let _ = write!(out, "{:04X} ", i * 16);
+            for b in chunk {
+                let _ = write!(out, " {b:02X}");
+            }
+            out.push_str(&"   ".repeat(16 - chunk.len()));
+            out.push_str("  ");
+            out.extend(chunk.iter().map(|&b| if (0x20..0x7F).contains(&b) { b as char } else { '.' }));
+            out.push_str("\r\n");
+        }
+    }
+    out
+}
+
+/// View, save or clear the recorded payloads
+pub fn menu(st: &SystemTable<Boot>) -> Result {
+    if !enabled() {
+        return ui::popup(st, "Protocol trace", &["protocol tracing is off, enable it with [protocol_trace] in the config".to_string()]);
+    }
+    let options = vec![
+        (true, "View hex dump".to_string()),
+        (true, "Save to the greeter's volume".to_string()),
+        (true, "Clear".to_string()),
+        (true, "Back".to_string()),
+    ];
+    loop {
+        console::clear(st)?;
+        console::write_str(st, "Payloads contain password hashes, share them with care.\r\n\r\n");
+        match ui::choose(st, &options)? {
+            0 => logging::page(st, &dump())?,
+            1 => {
+                let lines = match save(st) {
+                    Ok(path) => vec![format!("saved to {path}")],
+                    Err(e) => vec![format!("can't save the trace: {e}")],
+                };
+                ui::popup(st, "Protocol trace", &lines)?;
+            }
+            2 => if let Some(trace) = trace() {
+                trace.records.clear();
+                trace.used = 0;
+            },
+            _ => return Ok(()),
+        }
+    }
+}
+
+fn save(st: &SystemTable<Boot>) -> Result<String> {
+    let name = match st.runtime_services().get_time() {
+        Ok(time) => format!(
+            "\\opal-trace-{:04}{:02}{:02}-{:02}{:02}{:02}.txt",
+            time.year(), time.month(), time.day(), time.hour(), time.minute(), time.second(),
+        ),
+        Err(_) => "\\opal-trace.txt".to_string(),
+    };
+    let path = CString16::try_from(&*name).context("trace path is not valid UTF-16")?;
+    let volume = crate::config::image_volume(st.boot_services().image_handle(), st)?;
+    let _write_access = util::WriteAccess::grant();
+    util::write_full_file(st, volume, &path, dump().as_bytes())?;
+    log::info!("protocol trace saved to {name}");
+    Ok(name)
+}
@@ -0,0 +1,251 @@
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use opal::{Ace, Authority, OpalDrive, PasswordOrRaw, SecureProtocol, Ssc};
+use uefi::table::{Boot, SystemTable};
+use uefi::{cstr16, CStr16, CString16, Handle};
+use crate::admin::{self, DriveAction};
+use crate::config::Config;
+use crate::error::ErrorSource;
+use crate::{console, power, removable, ui, util, Context, Error, Result};
+
+/// looked for in the root of removable media
+const MANIFEST: &CStr16 = cstr16!("\\provision.toml");
+
+/// Setup applied unattended to every drive still in factory state, for enrolling many machines alike
+#[derive(Debug, serde::Deserialize)]
+struct Manifest {
+    /// becomes the password of SID and Admin1
+    admin_password: String,
+    /// only drives with these serials; all if empty
+    #[serde(default)]
+    serials: Vec<String>,
+    /// enable locking of the global range
+    #[serde(default = "default_lock_global")]
+    lock_global: bool,
+    #[serde(default)]
+    ranges: Vec<Range>,
+    #[serde(default)]
+    users: Vec<User>,
+    /// pre-boot image on the same medium to write to the shadow MBR, which is then enabled
+    pba: Option<String>,
+    /// leave the manifest on the medium after provisioning instead of wiping it, to enroll further machines
+    #[serde(default)]
+    keep: bool,
+}
+
+fn default_lock_global() -> bool {
+    true
+}
+
+/// A locking range to set up with locking enabled
+#[derive(Debug, serde::Deserialize)]
+struct Range {
+    range: u8,
+    start: u64,
+    length: u64,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct User {
+    user: u8,
+    password: String,
+    /// locking ranges the user may lock and unlock besides the Admins
+    #[serde(default)]
+    ranges: Vec<u8>,
+}
+
+/// The removable medium with a provisioning manifest, if one is inserted
+pub fn find(st: &SystemTable<Boot>) -> Option<(Handle, String)> {
+    match removable::media_with(st, MANIFEST) {
+        Ok(media) => media.into_iter().next(),
+        Err(e) => {
+            log::warn!("can't look for {MANIFEST} on removable media: {e}");
+            None
+        }
+    }
+}
+
+/// Takes ownership of the drives in factory state and sets them up as the manifest on `medium` says,
+/// after the operator confirmed it once for all of them
+pub fn run(st: &SystemTable<Boot>, config: &Config, medium: Handle, name: &str) -> Result {
+    let manifest: Manifest = toml::from_slice(&util::read_full_file(st, medium, MANIFEST)?)
+        .context(format!("error decoding {MANIFEST} as toml"))?;
+    let image = match &manifest.pba {
+        Some(pba) => {
+            let path = format!("\\{}", pba.trim_start_matches(['\\', '/']).replace('/', "\\"));
+            let path = CString16::try_from(&*path).context("pre-boot image path is not valid UTF-16")?;
+            Some(util::read_full_file(st, medium, &path)?)
+        }
+        None => None,
+    };
+
+    let mut drives = Drives { found: Vec::new(), skip_blocked_sid: config.admin.skip_blocked_sid };
+    admin::for_each_drive(st, config, None, &mut drives)?;
+    drives.found.retain(|(_, serial)| manifest.serials.is_empty() || manifest.serials.contains(serial));
+    if drives.found.is_empty() {
+        log::info!("found {MANIFEST} on {name}, but no drive it applies to");
+        return Ok(());
+    }
+
+    let mut warning = vec![format!("{MANIFEST} on {name} takes ownership of these drives and sets them up:")];
+    warning.extend(drives.found.iter().map(|(kind, serial)| format!("  {kind} {serial}")));
+    warning.push("Drives that already have an owner are skipped.".to_string());
+    if !ui::confirm_destructive(st, "Provision drives", &warning, "PROVISION")? {
+        return Ok(());
+    }
+    if image.is_some() && !power::check(st, "Provision drives")? {
+        return Ok(());
+    }
+
+    let mut provision = Provision { manifest: &manifest, image: image.as_deref(), results: Vec::new(), provisioned: 0 };
+    let mut failed = false;
+    for (_, serial) in &drives.found {
+        if let Err(e) = admin::for_each_drive(st, config, Some(serial), &mut provision) {
+            log::error!("provisioning drive {serial} failed: {e}");
+            provision.results.push(format!("{serial}: failed, {e}"));
+            failed = true;
+        }
+    }
+
+    // the initial passwords shouldn't travel onward with the medium once they did their job
+    if !failed && provision.provisioned != 0 && !manifest.keep {
+        let _write_access = util::WriteAccess::grant();
+        match util::wipe_file(st, medium, MANIFEST) {
+            Ok(()) => {
+                log::info!("wiped {MANIFEST} from {name}");
+                provision.results.push(format!("{MANIFEST} was wiped from {name}"));
+            }
+            Err(e) => {
+                log::error!("can't wipe {MANIFEST} from {name}: {e}");
+                provision.results.push(format!("WARNING: {MANIFEST} could not be wiped, remove it by hand ({e})"));
+            }
+        }
+    }
+    ui::popup(st, "Provision drives", &provision.results)
+}
+
+struct Drives {
+    found: Vec<(String, String)>,
+    skip_blocked_sid: bool,
+}
+
+impl DriveAction for Drives {
+    fn run<P: SecureProtocol>(&mut self, _st: &SystemTable<Boot>, kind: &str, drive: &mut OpalDrive<P>) -> Result
+    where opal::Error<P::Error>: Into<ErrorSource>
+    {
+        let serial = admin::serial_str(drive.serial());
+        if drive.ssc() == Ssc::Enterprise {
+            log::info!("not provisioning Enterprise SSC drive {serial}");
+        } else if drive.sid_blocked() && self.skip_blocked_sid {
+            log::warn!("not provisioning drive {serial}, the firmware blocked authenticating as SID");
+        } else {
+            self.found.push((kind.to_string(), serial));
+        }
+        Ok(())
+    }
+}
+
+struct Provision<'m> {
+    manifest: &'m Manifest,
+    image: Option<&'m [u8]>,
+    results: Vec<String>,
+    /// drives set up, not counting those skipped
+    provisioned: usize,
+}
+
+impl DriveAction for Provision<'_> {
+    fn run<P: SecureProtocol>(&mut self, st: &SystemTable<Boot>, _kind: &str, drive: &mut OpalDrive<P>) -> Result
+    where opal::Error<P::Error>: Into<ErrorSource>
+    {
+        let serial = admin::serial_str(drive.serial());
+        let manifest = self.manifest;
+        let setup = Setup {
+            admin_password: manifest.admin_password.as_bytes(),
+            lock_global: manifest.lock_global,
+            ranges: manifest.ranges.iter().map(|range| (range.range, range.start, range.length)).collect(),
+            users: manifest.users.iter().map(|user| (user.user, user.password.as_bytes(), user.ranges.clone())).collect(),
+            image: self.image,
+        };
+        if !set_up(st, &serial, drive, &setup)? {
+            log::info!("drive {serial} already has an owner, not provisioning it");
+            self.results.push(format!("{serial}: already has an owner, skipped"));
+            return Ok(());
+        }
+
+        log::warn!("drive {serial} was provisioned from {MANIFEST}");
+        self.results.push(format!("{serial}: provisioned"));
+        self.provisioned += 1;
+        Ok(())
+    }
+}
+
+/// What `set_up` makes of a drive in factory state
+pub struct Setup<'a> {
+    /// becomes the password of SID and Admin1
+    pub admin_password: &'a [u8],
+    /// enable locking of the global range
+    pub lock_global: bool,
+    /// (range, start, length) of further locking ranges to set up with locking enabled
+    pub ranges: Vec<(u8, u64, u64)>,
+    /// (user, password, locking ranges the user may lock and unlock besides the Admins)
+    pub users: Vec<(u8, &'a [u8], Vec<u8>)>,
+    /// pre-boot image to write to the shadow MBR, which is then enabled
+    pub image: Option<&'a [u8]>,
+}
+
+/// Takes ownership of a drive, activates the Locking SP and applies `setup` as Admin1;
+/// `false` if the drive rejected its MSID, i.e. already has an owner and was left alone
+pub fn set_up<P: SecureProtocol>(st: &SystemTable<Boot>, serial: &str, drive: &mut OpalDrive<P>, setup: &Setup) -> Result<bool>
+where opal::Error<P::Error>: Into<ErrorSource>
+{
+    let pwd = || PasswordOrRaw::Password(setup.admin_password);
+    console::write_str(st, &format!("\r\n{serial}: taking ownership\r\n"));
+    match drive.take_ownership(pwd()) {
+        Ok(()) => (),
+        Err(opal::Error::Opal { source: opal::OpalError::Status { code: opal::StatusCode::NOT_AUTHORIZED }, .. }) => return Ok(false),
+        Err(e) => return Err(Error::new(e, "can't take ownership")),
+    }
+    console::write_str(st, &format!("{serial}: activating the Locking SP\r\n"));
+    drive.activate_locking_sp(pwd()).map_err(|e| Error::new(e, "can't activate the Locking SP"))?;
+    let mut session = drive.admin_session(pwd()).map_err(|e| Error::new(e, "can't authenticate as Admin1"))?;
+
+    console::write_str(st, &format!("{serial}: configuring locking ranges and users\r\n"));
+    session.configure_range(0, None, setup.lock_global)
+        .map_err(|e| Error::new(e, "can't configure the global range"))?;
+    for &(range, start, length) in &setup.ranges {
+        session.configure_range(range, Some((start, length)), true)
+            .map_err(|e| Error::new(e, format!("can't configure locking range {range}")))?;
+    }
+    let mut aces: BTreeMap<u8, Vec<Authority>> = BTreeMap::new();
+    for (user, password, ranges) in &setup.users {
+        let authority = Authority::user(*user);
+        session.set_pin(authority, PasswordOrRaw::Password(password))
+            .map_err(|e| Error::new(e, format!("can't set the password of {authority}")))?;
+        session.set_authority_enabled(authority, true)
+            .map_err(|e| Error::new(e, format!("can't enable {authority}")))?;
+        for &range in ranges {
+            aces.entry(range).or_insert_with(|| vec![Authority::ADMINS]).push(authority);
+        }
+    }
+    for (range, authorities) in &aces {
+        for ace in [Ace::ReadLocked, Ace::WriteLocked] {
+            session.set_locking_ace(*range, ace, authorities)
+                .map_err(|e| Error::new(e, format!("can't set the {ace:?} ACE of locking range {range}")))?;
+        }
+    }
+
+    if let Some(image) = setup.image {
+        let mut shown = None;
+        session.write_shadow_mbr(image, &mut |written| {
+            let percent = written * 100 / image.len();
+            if shown != Some(percent) {
+                shown = Some(percent);
+                console::write_str(st, &format!("\r{serial}: writing the pre-boot image, {percent}%"));
+            }
+        }).map_err(|e| Error::new(e, "can't write the pre-boot image"))?;
+        console::write_str(st, "\r\n");
+        session.set_mbr_enable(true).map_err(|e| Error::new(e, "can't enable the shadow MBR"))?;
+    }
+    Ok(true)
+}
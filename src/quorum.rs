@@ -0,0 +1,260 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write as _;
+use sha2::{Digest, Sha256};
+use uefi::CString16;
+use uefi::table::{Boot, SystemTable};
+use crate::config::{Config, Quorum};
+use crate::{beep, console, rng, ui, util, Cache, Context, Error, Result};
+
+/// length of the drive key and thus of each share
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+
+/// One member's Shamir share of the drive key, stored masked with a key stretched from the member's credential
+struct Share {
+    member: String,
+    salt: [u8; SALT_LEN],
+    masked: [u8; KEY_LEN],
+    /// hash of the unmasked share keyed with the stretched credential, to tell a wrong credential from a wrong share
+    /// without making the file an unstretched password oracle
+    check: [u8; KEY_LEN],
+}
+
+/// Collects credentials of `threshold` members and reconstructs the drive key from their shares
+pub fn combine(st: &SystemTable<Boot>, config: &Config, name: &str, quorum: &Quorum) -> Result<Vec<u8>> {
+    validate(config, name, quorum)?;
+    let shares = read_shares(st, quorum)?;
+    // members without a share can't take part, so they aren't offered
+    let with_share: Vec<usize> = (0..quorum.members.len())
+        .filter(|&i| {
+            let member = &quorum.members[i];
+            let found = shares.iter().any(|share| share.member == *member);
+            if !found {
+                log::error!("no share for `{member}` in {}", quorum.shares);
+            }
+            found
+        })
+        .collect();
+    // (x coordinate, share) of each member who authenticated
+    let mut collected: Vec<(u8, [u8; KEY_LEN])> = Vec::new();
+    while collected.len() < quorum.threshold as usize {
+        let remaining: Vec<usize> = with_share.iter().copied()
+            .filter(|&i| !collected.iter().any(|&(x, _)| x as usize == i + 1))
+            .collect();
+        if remaining.len() < quorum.threshold as usize - collected.len() {
+            return Err(Error::new_without_source(format!(
+                "keyslot {name} needs {} more credentials, but only {} members with a share in {} are left",
+                quorum.threshold as usize - collected.len(), remaining.len(), quorum.shares,
+            )));
+        }
+        console::clear(st)?;
+        let mut options = vec![ui::header(&format!(
+            "Keyslot {name}: {} of {} credentials given, who's next? (Escape cancels)", collected.len(), quorum.threshold,
+        ))];
+        options.extend(remaining.iter().map(|&i| (true, quorum.members[i].clone())));
+        let Some(chosen) = ui::choose_cancelable(st, &options)? else {
+            for (_, share) in &mut collected {
+                opal::wipe(share);
+            }
+            return Err(Error::new_without_source(format!("unlocking keyslot {name} was cancelled")));
+        };
+        // skip the header
+        let i = remaining[chosen - 1];
+        let member = &quorum.members[i];
+        let share = shares.iter().find(|share| share.member == *member).expect("members without a share are not offered");
+        let mut secret = crate::get_password_of_keyslot(st, config, &config.keyslots[member], Cache::Discard)?;
+        // members' credentials are only useful together, don't keep them around
+        if let Some(mut cached) = config.keyslot_buffer.borrow_mut().remove(member) {
+            opal::wipe(&mut cached);
+        }
+        let (mut mask, mut check_key) = derive(member, &secret, &share.salt);
+        opal::wipe(&mut secret);
+        let mut candidate = share.masked;
+        xor(&mut candidate, &mask);
+        opal::wipe(&mut mask);
+        let matches = opal::constant_time_eq(&check(&check_key, &candidate), &share.check);
+        opal::wipe(&mut check_key);
+        if !matches {
+            opal::wipe(&mut candidate);
+            log::error!("wrong credential for `{member}`");
+            beep::cue(beep::Cue::WrongPassword);
+            util::sleep(core::time::Duration::from_secs(1));
+            continue;
+        }
+        collected.push((i as u8 + 1, candidate));
+    }
+
+    let key = interpolate(&collected);
+    for (_, share) in &mut collected {
+        opal::wipe(share);
+    }
+    Ok(key)
+}
+
+/// Generates a new random drive key, splits it among the members and writes their masked shares.
+/// `secrets` holds each member's credential in the order of `quorum.members`.
+pub fn create(st: &SystemTable<Boot>, config: &Config, name: &str, quorum: &Quorum, secrets: &[Vec<u8>]) -> Result<Vec<u8>> {
+    validate(config, name, quorum)?;
+    assert_eq!(secrets.len(), quorum.members.len());
+    let mut key = vec![0; KEY_LEN];
+    rng::fill(&mut key);
+    // coefficients of one polynomial per key byte, the constant term being the key byte
+    let mut coefficients = vec![0; KEY_LEN * (quorum.threshold as usize - 1)];
+    rng::fill(&mut coefficients);
+
+    let mut file = String::new();
+    for (i, (member, secret)) in quorum.members.iter().zip(secrets).enumerate() {
+        let x = i as u8 + 1;
+        let mut share = [0; KEY_LEN];
+        for (byte, share_byte) in share.iter_mut().enumerate() {
+            // Horner's method, highest coefficient first
+            let mut y = 0;
+            for coefficient in coefficients.iter().skip(byte).step_by(KEY_LEN).rev() {
+                y = gf_mul(y, x) ^ coefficient;
+            }
+            *share_byte = gf_mul(y, x) ^ key[byte];
+        }
+        let mut salt = [0; SALT_LEN];
+        rng::fill(&mut salt);
+        let (mut mask, mut check_key) = derive(member, secret, &salt);
+        let share_check = check(&check_key, &share);
+        let mut masked = share;
+        xor(&mut masked, &mask);
+        opal::wipe(&mut share);
+        opal::wipe(&mut mask);
+        opal::wipe(&mut check_key);
+        let _ = writeln!(file, "{member} {} {} {}", hex(&salt), hex(&masked), hex(&share_check));
+    }
+    opal::wipe(&mut coefficients);
+
+    let volume = crate::config::image_volume(st.boot_services().image_handle(), st)?;
+    util::write_full_file(st, volume, &shares_path(quorum)?, file.as_bytes())?;
+    log::info!("wrote {} quorum shares of keyslot {name} to {}", quorum.members.len(), quorum.shares);
+    Ok(key)
+}
+
+fn validate(config: &Config, name: &str, quorum: &Quorum) -> Result {
+    let n = quorum.members.len();
+    if quorum.threshold < 2 || quorum.threshold as usize > n || n > 255 {
+        return Err(Error::new_without_source(format!(
+            "keyslot {name}: the quorum threshold must be between 2 and the number of members ({n})",
+        )));
+    }
+    for member in &quorum.members {
+        if !config.keyslots.contains_key(member) {
+            return Err(Error::new_without_source(format!("keyslot {name}: unknown member keyslot `{member}`")));
+        }
+    }
+    Ok(())
+}
+
+fn shares_path(quorum: &Quorum) -> Result<CString16> {
+    CString16::try_from(quorum.shares.replace('/', "\\").as_str())
+        .context("quorum shares path is not valid UTF-16")
+}
+
+fn read_shares(st: &SystemTable<Boot>, quorum: &Quorum) -> Result<Vec<Share>> {
+    let volume = crate::config::image_volume(st.boot_services().image_handle(), st)?;
+    let content = util::read_full_file(st, volume, &shares_path(quorum)?)?;
+    let content = core::str::from_utf8(&content).context("quorum shares file is not UTF-8")?;
+    content.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let invalid = || Error::new_without_source(format!("invalid line in {}: `{line}`", quorum.shares));
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match *fields.as_slice() {
+                [member, salt, masked, check] => Ok(Share {
+                    member: member.into(),
+                    salt: unhex(salt).ok_or_else(invalid)?,
+                    masked: unhex(masked).ok_or_else(invalid)?,
+                    check: unhex(check).ok_or_else(invalid)?,
+                }),
+                [_, _, _] => Err(Error::new_without_source(format!(
+                    "{} holds unsalted shares of an older version, set up the quorum keyslot again", quorum.shares,
+                ))),
+                _ => Err(invalid()),
+            }
+        })
+        .collect()
+}
+
+/// The member's credential stretched with the PBKDF2 of drive passwords and the share's salt,
+/// as the mask of the share and the key of its check value
+fn derive(member: &str, secret: &[u8], salt: &[u8; SALT_LEN]) -> ([u8; KEY_LEN], [u8; KEY_LEN]) {
+    let mut salted = b"opal-greeter quorum share\0".to_vec();
+    salted.extend_from_slice(member.as_bytes());
+    salted.push(0);
+    salted.extend_from_slice(salt);
+    let mut key = [0; KEY_LEN];
+    opal::pbkdf2(secret, &salted, &mut key);
+    let mask = Sha256::new().chain_update(b"mask").chain_update(key).finalize().into();
+    let check_key = Sha256::new().chain_update(b"check").chain_update(key).finalize().into();
+    opal::wipe(&mut key);
+    (mask, check_key)
+}
+
+fn check(check_key: &[u8; KEY_LEN], share: &[u8; KEY_LEN]) -> [u8; KEY_LEN] {
+    Sha256::new().chain_update(check_key).chain_update(share).finalize().into()
+}
+
+/// Lagrange interpolation at x = 0
+fn interpolate(shares: &[(u8, [u8; KEY_LEN])]) -> Vec<u8> {
+    let mut key = vec![0; KEY_LEN];
+    for &(xi, yi) in shares {
+        // basis polynomial of xi at 0: product of xj / (xj - xi), subtraction being XOR
+        let mut basis = 1;
+        for &(xj, _) in shares {
+            if xj != xi {
+                basis = gf_mul(basis, gf_mul(xj, gf_inv(xj ^ xi)));
+            }
+        }
+        for (byte, y) in key.iter_mut().zip(yi) {
+            *byte ^= gf_mul(basis, y);
+        }
+    }
+    key
+}
+
+/// multiplication in GF(2^8) with the AES polynomial
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        a = (a << 1) ^ if a & 0x80 != 0 { 0x1b } else { 0 };
+        b >>= 1;
+    }
+    product
+}
+
+/// a^254 = a^-1
+fn gf_inv(a: u8) -> u8 {
+    let mut result = 1;
+    for _ in 0..254 {
+        result = gf_mul(result, a);
+    }
+    result
+}
+
+fn xor(target: &mut [u8; KEY_LEN], mask: &[u8; KEY_LEN]) {
+    for (t, m) in target.iter_mut().zip(mask) {
+        *t ^= m;
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn unhex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    let mut bytes = [0; N];
+    if hex.len() != N * 2 {
+        return None;
+    }
+    for (byte, pair) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(core::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(bytes)
+}
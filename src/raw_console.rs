@@ -0,0 +1,209 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use opal::{Authority, OpalDrive, PasswordOrRaw, SecureProtocol, Value, KNOWN_METHODS, KNOWN_OBJECTS, KNOWN_SPS};
+use uefi::table::{Boot, SystemTable};
+use crate::admin::{serial_str, DriveAction};
+use crate::error::ErrorSource;
+use crate::{console, ui, Error, Result};
+
+/// Authorities offered for the session, the rest are entered as UIDs
+const AUTHORITIES: &[(&str, Authority)] = &[
+    ("Anybody (no password)", Authority::ANYBODY),
+    ("SID", Authority([0, 0, 0, 9, 0, 0, 0, 6])),
+    ("Admin1", Authority([0, 0, 0, 9, 0, 1, 0, 1])),
+    ("User1", Authority([0, 0, 0, 9, 0, 3, 0, 1])),
+];
+
+/// Sends method calls composed by hand in one session and shows the decoded responses
+pub struct RawConsole;
+
+impl DriveAction for RawConsole {
+    fn run<P: SecureProtocol>(&mut self, st: &SystemTable<Boot>, _kind: &str, drive: &mut OpalDrive<P>) -> Result
+    where opal::Error<P::Error>: Into<ErrorSource>
+    {
+        let serial = serial_str(drive.serial());
+        let warning = [
+            "Method calls are sent as typed, without any checks.".to_string(),
+            "Set, GenKey, Revert and Erase can lock you out or destroy all data.".to_string(),
+        ];
+        if !ui::confirm_destructive(st, "Raw method console", &warning, "EXPERT")? {
+            return Ok(());
+        }
+        console::clear(st)?;
+        console::write_str(st, "Session to:\r\n");
+        let Some(sp) = pick_uid(st, KNOWN_SPS)? else { return Ok(()) };
+        console::clear(st)?;
+        console::write_str(st, "Authenticate as:\r\n");
+        let named: Vec<_> = AUTHORITIES.iter().map(|&(name, authority)| (name, authority.0)).collect();
+        let Some(authority) = pick_uid(st, &named)? else { return Ok(()) };
+        let authority = Authority(authority);
+        let password = match authority {
+            Authority::ANYBODY => None,
+            _ => {
+                console::write_str(st, &format!("\r\nPassword of {authority} on {serial}: "));
+                Some(ui::password(st)?)
+            }
+        };
+        let mut session = drive.raw_session(sp, authority, password.as_deref().map(|p| PasswordOrRaw::Password(p.as_bytes())))
+            .map_err(|e| Error::new(e, format!("can't open a session as {authority}")))?;
+        log::warn!("opened a raw session as {authority} on drive {serial}");
+
+        loop {
+            console::clear(st)?;
+            console::write_str(st, &format!("Raw session as {authority} on {serial}, invoke on:\r\n"));
+            let Some(object) = pick_uid(st, KNOWN_OBJECTS)? else { return Ok(()) };
+            console::clear(st)?;
+            console::write_str(st, &format!("Method to invoke on {}:\r\n", uid_str(object)));
+            let Some(method) = pick_uid(st, KNOWN_METHODS)? else { continue };
+            console::write_str(st, "\r\nArguments, e.g. [ 0 = 3, 1 = 3 ] 5 0x1F \"text\" h:00AA true\r\n> ");
+            let Some(args) = ui::line_cancelable(st)? else { continue };
+            let args = match parse_args(&args) {
+                Ok(args) => args,
+                Err(e) => {
+                    ui::popup(st, "Invalid arguments", &[e])?;
+                    continue;
+                }
+            };
+            let call = format!("{}.{}{}", uid_str(object), uid_str(method), Value::List(args.clone()));
+            log::info!("raw call {call}");
+            let lines = match session.call(object, method, &args) {
+                Ok(values) => core::iter::once(call).chain(values.iter().map(Value::to_string)).collect(),
+                Err(e) => {
+                    let e = Error::new(e, "the call failed");
+                    log::warn!("{e}");
+                    vec![call, e.to_string()]
+                }
+            };
+            ui::popup(st, "Response", &lines)?;
+        }
+    }
+}
+
+/// Picks one of `known` or a UID typed in hex; `None` for Back
+fn pick_uid(st: &SystemTable<Boot>, known: &[(&str, [u8; 8])]) -> Result<Option<[u8; 8]>> {
+    let mut options: Vec<_> = known.iter()
+        .map(|&(name, uid)| (true, format!("{name} ({})", uid_str(uid))))
+        .collect();
+    options.push((true, "Enter UID".to_string()));
+    options.push((true, "Back".to_string()));
+    let index = ui::choose(st, &options)?;
+    if let Some(&(_, uid)) = known.get(index) {
+        return Ok(Some(uid));
+    }
+    if index > known.len() {
+        return Ok(None);
+    }
+    loop {
+        console::write_str(st, "\r\nUID (16 hex digits): ");
+        let Some(text) = ui::line_cancelable(st)? else { return Ok(None) };
+        let digits: String = text.chars().filter(|c| !matches!(c, ' ' | ':' | '_')).collect();
+        let digits = digits.strip_prefix("0x").unwrap_or(&digits);
+        match u64::from_str_radix(digits, 16) {
+            Ok(uid) if digits.len() <= 16 => return Ok(Some(uid.to_be_bytes())),
+            _ => console::write_str(st, "not a UID"),
+        }
+    }
+}
+
+fn uid_str(uid: [u8; 8]) -> String {
+    format!("{:016X}", u64::from_be_bytes(uid))
+}
+
+/// Parses space or comma separated arguments: numbers (`5`, `0x1F`, `-3`), `true`/`false`, byte strings
+/// (`"text"`, `h:00AA`), lists in `[ ]` and named values `name = value`
+fn parse_args(text: &str) -> core::result::Result<Vec<Value>, String> {
+    Parser { text: text.as_bytes(), pos: 0 }.values(None)
+}
+
+struct Parser<'t> {
+    text: &'t [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.text.get(self.pos).copied()
+    }
+
+    fn skip(&mut self, separators: &[u8]) {
+        while self.peek().map_or(false, |b| separators.contains(&b)) {
+            self.pos += 1;
+        }
+    }
+
+    /// values up to `end`, or the end of the text
+    fn values(&mut self, end: Option<u8>) -> core::result::Result<Vec<Value>, String> {
+        let mut values = Vec::new();
+        loop {
+            self.skip(b" \t,");
+            match self.peek() {
+                None if end.is_none() => return Ok(values),
+                None => return Err("missing `]`".to_string()),
+                b if b == end => {
+                    self.pos += 1;
+                    return Ok(values);
+                }
+                Some(_) => values.push(self.named()?),
+            }
+        }
+    }
+
+    fn named(&mut self) -> core::result::Result<Value, String> {
+        let name = self.value()?;
+        self.skip(b" \t");
+        if self.peek() != Some(b'=') {
+            return Ok(name);
+        }
+        self.pos += 1;
+        self.skip(b" \t");
+        let value = self.value()?;
+        Ok(Value::Named(name.into(), value.into()))
+    }
+
+    fn value(&mut self) -> core::result::Result<Value, String> {
+        match self.peek() {
+            Some(b'[') => {
+                self.pos += 1;
+                Ok(Value::List(self.values(Some(b']'))?))
+            }
+            Some(b'"') => {
+                let start = self.pos + 1;
+                let len = self.text[start..].iter().position(|&b| b == b'"').ok_or("missing closing `\"`")?;
+                self.pos = start + len + 1;
+                Ok(Value::Bytes(self.text[start..start + len].to_vec()))
+            }
+            _ => {
+                let start = self.pos;
+                while self.peek().map_or(false, |b| !b" \t,[]=\"".contains(&b)) {
+                    self.pos += 1;
+                }
+                let word = core::str::from_utf8(&self.text[start..self.pos]).map_err(|_| "invalid text")?;
+                word_value(word, start)
+            }
+        }
+    }
+}
+
+fn word_value(word: &str, pos: usize) -> core::result::Result<Value, String> {
+    let invalid = || match word {
+        "" => format!("unexpected character at position {}", pos + 1),
+        word => format!("`{word}` is not a number, true/false, \"text\" or h:hex"),
+    };
+    if let Some(hex) = word.strip_prefix("h:") {
+        if hex.len() % 2 != 0 {
+            return Err(format!("`{word}` has an odd number of hex digits"));
+        }
+        return hex.as_bytes().chunks(2)
+            .map(|pair| core::str::from_utf8(pair).ok().and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+            .collect::<Option<Vec<u8>>>()
+            .map(Value::Bytes)
+            .ok_or_else(invalid);
+    }
+    match word {
+        "true" => Ok(Value::Uint(1)),
+        "false" => Ok(Value::Uint(0)),
+        _ if word.starts_with("0x") => u64::from_str_radix(&word[2..], 16).map(Value::Uint).map_err(|_| invalid()),
+        _ if word.starts_with('-') => word.parse().map(Value::Int).map_err(|_| invalid()),
+        _ => word.parse().map(Value::Uint).map_err(|_| invalid()),
+    }
+}
@@ -0,0 +1,72 @@
+use alloc::vec::Vec;
+use opal::{LockingState, OpalDrive, PasswordOrRaw, SecureProtocol};
+use uefi::table::{Boot, SystemTable};
+use crate::config::{BootEntry, Config, KeyslotSource, Partition};
+use crate::error::ErrorSource;
+use crate::low_level::nvme_device::RestartableNvmeDevice;
+use crate::{Cache, Error, Result};
+
+/// For entries flagged untrusted, locks their `lock_ranges` once more as the last thing before the handoff,
+/// after aborting all sessions on the drives, so the image can't take over a session still open from the firmware or us.
+///
+/// This can't wait for ExitBootServices: its notification functions may not allocate, wait or do I/O,
+/// and a locking session needs all of them.
+pub fn relock(st: &SystemTable<Boot>, config: &Config, boot_entry: &BootEntry) -> Result {
+    if !boot_entry.untrusted || boot_entry.lock_ranges.is_empty() {
+        return Ok(());
+    }
+    let mut relocked = 0;
+    for (blockio_handle, _, _) in crate::block_devices(st)? {
+        if let Some(nvme) = crate::try_get_nvme_device(st, blockio_handle)? {
+            let Some((partition, ranges)) = ranges_of(config, boot_entry, nvme.serial_num()) else { continue };
+            let drive = OpalDrive::new(RestartableNvmeDevice::new(&nvme, st, blockio_handle))
+                .map_err(|e| Error::new(e, "open opal"))?;
+            relock_drive(st, config, drive, partition, &ranges)?;
+        } else if let Some(mut ata) = crate::try_get_ata_device(st, blockio_handle)? {
+            let serial = ata.serial().to_vec();
+            let Some((partition, ranges)) = ranges_of(config, boot_entry, &serial) else { continue };
+            relock_drive(st, config, ata, partition, &ranges)?;
+        } else {
+            continue;
+        }
+        relocked += 1;
+    }
+    if relocked == 0 {
+        return Err(Error::new_without_source(format!("no OPAL drive found to relock for `{}`", boot_entry.name)));
+    }
+    log::info!("relocked {relocked} drives of untrusted `{}`", boot_entry.name);
+    Ok(())
+}
+
+/// the partition configured for the drive with this serial and the entry's ranges on it
+fn ranges_of<'a>(config: &'a Config, boot_entry: &BootEntry, serial: &[u8]) -> Option<(&'a Partition, Vec<(u8, LockingState)>)> {
+    let serial = core::str::from_utf8(serial).ok()?.trim();
+    let partition = config.partitions.values().find(|part| part.uuid == serial)?;
+    let ranges: Vec<_> = boot_entry.lock_ranges.iter()
+        .filter(|range| range.partition == partition.name)
+        .map(|range| (range.range, LockingState::Locked))
+        .collect();
+    (!ranges.is_empty()).then_some((partition, ranges))
+}
+
+fn relock_drive<P: SecureProtocol>(st: &SystemTable<Boot>, config: &Config, mut drive: OpalDrive<P>, partition: &Partition, ranges: &[(u8, LockingState)]) -> Result
+where opal::Error<P::Error>: Into<ErrorSource>
+{
+    let keyslot = match &partition.keyslot {
+        Some(name) => &config.keyslots[name],
+        None => return Err(Error::new_without_source(format!("no keyslot defined for partition `{}`", partition.name))),
+    };
+    drive.force(config.features.forced());
+    if let Err(e) = drive.stack_reset() {
+        log::warn!("{}: can't abort open sessions before relocking: {e:?}", partition.name);
+    }
+    let mut password = crate::get_password_of_keyslot(st, config, keyslot, Cache::Cached)?;
+    let password_or_raw = match keyslot.source {
+        KeyslotSource::Stdin => PasswordOrRaw::Password(&password),
+        KeyslotSource::File(_) | KeyslotSource::Quorum(_) => PasswordOrRaw::Raw(&password),
+    };
+    let res = drive.set_range_states(password_or_raw, ranges)
+        .map_err(|e| Error::new(e, format!("{}: can't relock {ranges:?}", partition.name)));
+    opal::wipe(&mut password);
+    res
+}
@@ -0,0 +1,67 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use uefi::proto::device_path::DevicePath;
+use uefi::proto::device_path::text::{AllowShortcuts, DisplayOnly};
+use uefi::proto::media::block::BlockIO;
+use uefi::proto::media::fs::SimpleFileSystem;
+use uefi::table::boot::LoadImageSource;
+use uefi::table::{Boot, SystemTable};
+use uefi::{cstr16, CStr16, Handle};
+use crate::{ui, util, console, Result, Context};
+
+#[cfg(target_arch = "x86_64")]
+const FALLBACK_LOADER: &CStr16 = cstr16!("\\EFI\\BOOT\\BOOTX64.EFI");
+#[cfg(target_arch = "aarch64")]
+const FALLBACK_LOADER: &CStr16 = cstr16!("\\EFI\\BOOT\\BOOTAA64.EFI");
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const FALLBACK_LOADER: &CStr16 = cstr16!("\\EFI\\BOOT\\BOOTIA32.EFI");
+
+/// Filesystems on removable media which carry `file`, with a printable name
+pub fn media_with(st: &SystemTable<Boot>, file: &CStr16) -> Result<Vec<(Handle, String)>> {
+    let bt = st.boot_services();
+    let mut media = Vec::new();
+    for handle in bt.find_handles::<SimpleFileSystem>().context("can't list filesystems")? {
+        let removable = bt.open_protocol_exclusive::<BlockIO>(handle)
+            .map_or(false, |blockio| blockio.media().is_removable_media());
+        if !removable {
+            continue;
+        }
+        let mut header = vec![0; 2];
+        if util::read_partial_file_to_vec(st, handle, file, &mut header).is_err() {
+            continue;
+        }
+        let name = bt.open_protocol_exclusive::<DevicePath>(handle).ok()
+            .and_then(|dp| dp.to_string(bt, DisplayOnly(true), AllowShortcuts(true)).ok().flatten())
+            .map_or_else(|| "<removable medium>".to_string(), |dp| dp.to_string());
+        media.push((handle, name));
+    }
+    Ok(media)
+}
+
+/// Shows only the removable media to boot once from; returns if the user wants the normal menu instead
+pub fn menu(st: &SystemTable<Boot>, image_handle: Handle) -> Result {
+    let media = media_with(st, FALLBACK_LOADER)?;
+    console::write_str(st, "Boot once from removable media:\r\n");
+    let mut options: Vec<_> = media.iter()
+        .map(|(_, name)| (true, format!("{FALLBACK_LOADER} on {name}")))
+        .collect();
+    options.push((true, "Continue to the normal menu".to_string()));
+    let index = ui::choose(st, &options)?;
+    let Some((handle, name)) = media.get(index) else { return Ok(()) };
+
+    log::info!("booting {FALLBACK_LOADER} from removable medium {name}");
+    let loaded_image_handle = if crate::low_memory::active() {
+        let file_path = util::file_device_path(st, *handle, FALLBACK_LOADER)?;
+        st.boot_services()
+            .load_image(image_handle, LoadImageSource::FromDevicePath { device_path: &file_path, from_boot_manager: false })
+            .context("can't load fallback loader from removable medium")?
+    } else {
+        let image = util::read_full_file(st, *handle, FALLBACK_LOADER)?;
+        let device_path = st.boot_services().open_protocol_exclusive::<DevicePath>(*handle)
+            .context("can't get DevicePath of removable medium")?;
+        st.boot_services()
+            .load_image(image_handle, LoadImageSource::FromBuffer { file_path: Some(&*device_path), buffer: &image })
+            .context("can't load fallback loader from removable medium")?
+    };
+    crate::start_loaded_image(st, loaded_image_handle, &format!("{FALLBACK_LOADER} on {name}"))
+}
@@ -0,0 +1,51 @@
+use sha1::{Digest, Sha1};
+use uefi::proto::rng::Rng;
+use uefi::table::boot::BootServices;
+
+/// Fills `buf` with random bytes from EFI_RNG_PROTOCOL.
+///
+/// Falls back to a hash DRBG seeded from timer jitter if the firmware has no RNG.
+pub fn fill(buf: &mut [u8]) {
+    let bt = unsafe { uefi_services::system_table().as_ref() }.boot_services();
+    match fill_efi(bt, buf) {
+        Ok(()) => (),
+        Err(e) => {
+            log::debug!("EFI_RNG_PROTOCOL unavailable ({e:?}), falling back to jitter DRBG");
+            fill_jitter(bt, buf);
+        }
+    }
+}
+
+fn fill_efi(bt: &BootServices, buf: &mut [u8]) -> uefi::Result {
+    let handle = bt.get_handle_for_protocol::<Rng>()?;
+    let mut rng = bt.open_protocol_exclusive::<Rng>(handle)?;
+    rng.get_rng(None, buf)
+}
+
+fn fill_jitter(bt: &BootServices, buf: &mut [u8]) {
+    let mut seed = Sha1::new();
+    for i in 0..256 {
+        let before = timestamp();
+        bt.stall(1 + i % 3);
+        let after = timestamp();
+        seed.update(after.wrapping_sub(before).to_le_bytes());
+        seed.update(after.to_le_bytes());
+    }
+    let seed = seed.finalize();
+
+    for (counter, chunk) in buf.chunks_mut(20).enumerate() {
+        let block = Sha1::new()
+            .chain_update(seed)
+            .chain_update((counter as u64).to_le_bytes())
+            .finalize();
+        chunk.copy_from_slice(&block[..chunk.len()]);
+    }
+}
+
+/// the processor's counter, or the RTC where there is none
+fn timestamp() -> u64 {
+    crate::clock::ticks().unwrap_or_else(|| {
+        let rt = unsafe { uefi_services::system_table().as_ref() }.runtime_services();
+        rt.get_time().map_or(0, |time| time.nanosecond() as u64 ^ (time.second() as u64) << 32)
+    })
+}
@@ -0,0 +1,48 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use uefi::{cstr16, CStr16};
+use uefi::table::{Boot, SystemTable};
+use crate::util::nvram;
+
+static SAFE_MODE: AtomicBool = AtomicBool::new(false);
+
+const SENTINEL: &CStr16 = cstr16!("OpalGreeterRunning");
+
+/// Checks whether the previous run crashed during a risky phase.
+///
+/// The sentinel only exists while a [`Risky`] phase runs, so it isn't written on every boot
+/// and powering off at a prompt doesn't count as a crash. If it's present at startup,
+/// we skip all non-essential features in this run and clear it, so the next run tries them again.
+pub fn init(st: &SystemTable<Boot>) {
+    if nvram::read(st, SENTINEL).is_some() {
+        log::warn!("previous run crashed during a risky phase, starting in safe mode");
+        SAFE_MODE.store(true, Ordering::Relaxed);
+        nvram::delete(st, SENTINEL);
+    }
+}
+
+/// A phase that may crash or hang on broken firmware, e.g. switching console modes
+pub struct Risky<'a> {
+    st: &'a SystemTable<Boot>,
+}
+
+impl<'a> Risky<'a> {
+    /// Sets the sentinel until the returned guard is dropped
+    pub fn begin(st: &'a SystemTable<Boot>, what: &str) -> Self {
+        log::trace!("entering risky phase: {what}");
+        if let Err(e) = nvram::write(st, SENTINEL, &[1]) {
+            log::warn!("can't set safe mode sentinel: {e}");
+        }
+        Risky { st }
+    }
+}
+
+impl Drop for Risky<'_> {
+    fn drop(&mut self) {
+        nvram::delete(self.st, SENTINEL);
+    }
+}
+
+/// whether non-essential features (console mode switching, OS detection, …) must be skipped
+pub fn active() -> bool {
+    SAFE_MODE.load(Ordering::Relaxed)
+}
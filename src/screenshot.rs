@@ -0,0 +1,193 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use uefi::proto::console::gop::{BltOp, BltPixel, BltRegion, GraphicsOutput};
+use uefi::proto::console::text::Key;
+use uefi::table::boot::{OpenProtocolAttributes, OpenProtocolParams};
+use uefi::table::{Boot, SystemTable};
+use uefi::CString16;
+use crate::config::KeyName;
+use crate::{console, ui, util, Context, Error, Result};
+
+/// Hotkey and a copy of the text screen, for consoles without a framebuffer
+struct State {
+    hotkey: Option<KeyName>,
+    /// text lines as written since the last clear
+    lines: Vec<Vec<char>>,
+    row: usize,
+    column: usize,
+    rows: usize,
+    /// while a password is read, nothing is captured and typed characters aren't recorded
+    suspended: bool,
+}
+
+struct Screenshots(UnsafeCell<State>);
+// UEFI boot services are single-threaded
+unsafe impl Sync for Screenshots {}
+
+static STATE: Screenshots = Screenshots(UnsafeCell::new(State { hotkey: None, lines: Vec::new(), row: 0, column: 0, rows: 25, suspended: false }));
+
+fn state() -> &'static mut State {
+    unsafe { &mut *STATE.0.get() }
+}
+
+/// Enables the hotkey; the text screen is only recorded with one configured
+pub fn init(hotkey: Option<KeyName>) {
+    state().hotkey = hotkey;
+}
+
+/// Suspends capturing and recording until dropped, so passwords and their echo never end up in a screenshot
+pub struct Suspend(bool);
+
+impl Suspend {
+    pub fn begin() -> Suspend {
+        let state = state();
+        Suspend(core::mem::replace(&mut state.suspended, true))
+    }
+}
+
+impl Drop for Suspend {
+    fn drop(&mut self) {
+        state().suspended = self.0;
+    }
+}
+
+pub fn is_hotkey(key: &Key) -> bool {
+    state().hotkey.map_or(false, |hotkey| ui::key_matches(key, hotkey))
+}
+
+/// Captures the screen to the greeter's volume; failures are only logged, as the hotkey works in every prompt
+pub fn capture(st: &SystemTable<Boot>) {
+    if state().suspended {
+        log::warn!("no screenshots while a password is read");
+        return;
+    }
+    match save(st) {
+        Ok(path) => log::info!("screenshot saved to {path}"),
+        Err(e) => log::warn!("can't save screenshot: {e}"),
+    }
+}
+
+fn save(st: &SystemTable<Boot>) -> Result<String> {
+    let (extension, data) = match framebuffer(st) {
+        Some(res) => ("bmp", res?),
+        None => ("txt", text().into_bytes()),
+    };
+    let name = match st.runtime_services().get_time() {
+        Ok(time) => format!(
+            "\\screenshot-{:04}{:02}{:02}-{:02}{:02}{:02}.{extension}",
+            time.year(), time.month(), time.day(), time.hour(), time.minute(), time.second(),
+        ),
+        Err(_) => format!("\\screenshot.{extension}"),
+    };
+    let path = CString16::try_from(&*name).context("screenshot path is not valid UTF-16")?;
+    let volume = crate::config::image_volume(st.boot_services().image_handle(), st)?;
+    let _write_access = util::WriteAccess::grant();
+    util::write_full_file(st, volume, &path, &data)?;
+    Ok(name)
+}
+
+/// The framebuffer as BMP, if there's a graphics console
+fn framebuffer(st: &SystemTable<Boot>) -> Option<Result<Vec<u8>>> {
+    let bt = st.boot_services();
+    let handle = bt.get_handle_for_protocol::<GraphicsOutput>().ok()?;
+    // shared with the console driver, opening it exclusively would disconnect it
+    let params = OpenProtocolParams { handle, agent: bt.image_handle(), controller: None };
+    let mut gop = unsafe { bt.open_protocol::<GraphicsOutput>(params, OpenProtocolAttributes::GetProtocol) }.ok()?;
+    let (width, height) = gop.current_mode_info().resolution();
+    let mut pixels = vec![BltPixel::new(0, 0, 0); width * height];
+    let res = gop.blt(BltOp::VideoToBltBuffer {
+        buffer: &mut pixels,
+        src: (0, 0),
+        dest: BltRegion::Full,
+        dims: (width, height),
+    });
+    Some(match res {
+        Ok(()) => Ok(bmp(width, height, &pixels)),
+        Err(e) => Err(Error::new_from_uefi(e, "can't read the framebuffer")),
+    })
+}
+
+/// 24 bit uncompressed bitmap, rows bottom-up and padded to 4 bytes
+fn bmp(width: usize, height: usize, pixels: &[BltPixel]) -> Vec<u8> {
+    let stride = (width * 3 + 3) & !3;
+    let size = 54 + stride * height;
+    let mut data = Vec::with_capacity(size);
+    data.extend_from_slice(b"BM");
+    data.extend_from_slice(&(size as u32).to_le_bytes());
+    data.extend_from_slice(&[0; 4]);
+    data.extend_from_slice(&54u32.to_le_bytes());
+    // BITMAPINFOHEADER
+    data.extend_from_slice(&40u32.to_le_bytes());
+    data.extend_from_slice(&(width as i32).to_le_bytes());
+    data.extend_from_slice(&(height as i32).to_le_bytes());
+    data.extend_from_slice(&1u16.to_le_bytes());
+    data.extend_from_slice(&24u16.to_le_bytes());
+    data.extend_from_slice(&[0; 24]);
+    for row in pixels.chunks(width.max(1)).rev() {
+        for pixel in row {
+            data.extend_from_slice(&[pixel.blue, pixel.green, pixel.red]);
+        }
+        data.resize(data.len() + stride - width * 3, 0);
+    }
+    data
+}
+
+fn text() -> String {
+    let mut text = String::new();
+    for line in &state().lines {
+        text.extend(line.iter());
+        text.push_str("\r\n");
+    }
+    text
+}
+
+/// Mirrors text written to the console, so it can be saved without a framebuffer
+pub fn record(s: &str) {
+    let state = state();
+    if state.hotkey.is_none() {
+        return;
+    }
+    for c in s.chars() {
+        match c {
+            '\r' => state.column = 0,
+            '\n' => {
+                state.row += 1;
+                if state.row >= state.rows {
+                    // the console scrolled
+                    if !state.lines.is_empty() {
+                        state.lines.remove(0);
+                    }
+                    state.row = state.rows - 1;
+                }
+            }
+            // keep the layout, but not what was typed
+            _ if state.suspended => state.column += 1,
+            c => {
+                if state.lines.len() <= state.row {
+                    state.lines.resize(state.row + 1, Vec::new());
+                }
+                let line = &mut state.lines[state.row];
+                if line.len() <= state.column {
+                    line.resize(state.column + 1, ' ');
+                }
+                line[state.column] = c;
+                state.column += 1;
+            }
+        }
+    }
+}
+
+pub fn record_clear(st: &SystemTable<Boot>) {
+    let state = state();
+    state.lines.clear();
+    state.row = 0;
+    state.column = 0;
+    state.rows = console::size(st).1.max(1);
+}
+
+pub fn record_cursor(column: usize, row: usize) {
+    let state = state();
+    state.column = column;
+    state.row = row.min(state.rows - 1);
+}
@@ -0,0 +1,84 @@
+use alloc::vec::Vec;
+use core::slice;
+use uefi::table::cfg::{SMBIOS3_GUID, SMBIOS_GUID};
+use uefi::table::{Boot, SystemTable};
+
+pub const TYPE_SYSTEM: u8 = 1;
+pub const TYPE_CHASSIS: u8 = 3;
+pub const TYPE_BATTERY: u8 = 22;
+pub const TYPE_END: u8 = 127;
+
+/// A single SMBIOS structure: its formatted area and the strings following it
+pub struct Structure {
+    pub kind: u8,
+    pub data: &'static [u8],
+    strings: Vec<&'static [u8]>,
+}
+
+impl Structure {
+    pub fn byte(&self, offset: usize) -> Option<u8> {
+        self.data.get(offset).copied()
+    }
+
+    /// Resolves the string referenced by the string number at `offset`
+    pub fn string(&self, offset: usize) -> Option<&'static str> {
+        let index = usize::from(self.byte(offset)?).checked_sub(1)?;
+        let s = core::str::from_utf8(self.strings.get(index)?).ok()?.trim();
+        (!s.is_empty()).then_some(s)
+    }
+}
+
+/// The SMBIOS structure table published by the firmware, preferring the 64-bit entry point
+fn table(st: &SystemTable<Boot>) -> Option<&'static [u8]> {
+    let entry = |guid| st.config_table().iter().find(|entry| entry.guid == guid).map(|entry| entry.address as *const u8);
+    unsafe {
+        if let Some(ep) = entry(SMBIOS3_GUID) {
+            let ep = slice::from_raw_parts(ep, 0x18);
+            if &ep[..5] == b"_SM3_" {
+                let len = u32::from_le_bytes(ep[0x0c..0x10].try_into().unwrap()) as usize;
+                let addr = u64::from_le_bytes(ep[0x10..0x18].try_into().unwrap()) as usize;
+                return Some(slice::from_raw_parts(addr as *const u8, len));
+            }
+        }
+        if let Some(ep) = entry(SMBIOS_GUID) {
+            let ep = slice::from_raw_parts(ep, 0x1f);
+            if &ep[..4] == b"_SM_" {
+                let len = u16::from_le_bytes(ep[0x16..0x18].try_into().unwrap()) as usize;
+                let addr = u32::from_le_bytes(ep[0x18..0x1c].try_into().unwrap()) as usize;
+                return Some(slice::from_raw_parts(addr as *const u8, len));
+            }
+        }
+    }
+    None
+}
+
+/// All structures of the SMBIOS table; empty if there is none
+pub fn structures(st: &SystemTable<Boot>) -> Vec<Structure> {
+    let Some(mut table) = table(st) else {
+        log::debug!("firmware doesn't publish an SMBIOS table");
+        return Vec::new();
+    };
+    let mut structures = Vec::new();
+    while table.len() >= 4 {
+        let kind = table[0];
+        let len = usize::from(table[1]);
+        if len < 4 || len > table.len() {
+            log::warn!("malformed SMBIOS structure of type {kind}");
+            break;
+        }
+        let data = &table[..len];
+        // the string-set is terminated by a double NUL
+        let Some(end) = table[len..].windows(2).position(|w| w == [0, 0]) else { break };
+        let strings = table[len..len + end].split(|&b| b == 0).filter(|s| !s.is_empty()).collect();
+        structures.push(Structure { kind, data, strings });
+        table = &table[len + end + 2..];
+        if kind == TYPE_END {
+            break;
+        }
+    }
+    structures
+}
+
+pub fn find(st: &SystemTable<Boot>, kind: u8) -> Option<Structure> {
+    structures(st).into_iter().find(|s| s.kind == kind)
+}
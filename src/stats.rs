@@ -0,0 +1,125 @@
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use uefi::cstr16;
+use uefi::table::{Boot, SystemTable};
+use crate::util::nvram;
+use crate::{ui, Result};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Local counters, never sent anywhere; kept in a variable as `boots <n>` and
+/// `drive <unlocks> <timed unlocks> <unlock ms> <wrong passwords> <errors> <serial>` lines
+#[derive(Default)]
+struct Stats {
+    boots: u64,
+    drives: BTreeMap<String, Drive>,
+}
+
+#[derive(Default)]
+struct Drive {
+    unlocks: u64,
+    /// unlocks with a usable clock, which `unlock_ms` sums up
+    timed_unlocks: u64,
+    unlock_ms: u64,
+    wrong_passwords: u64,
+    errors: u64,
+}
+
+pub enum Failure {
+    WrongPassword,
+    /// the drive or its controller failed the command
+    Error,
+}
+
+/// Enables the counters as configured and counts this boot
+pub fn init(st: &SystemTable<Boot>, enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+    update(st, |stats| stats.boots += 1);
+}
+
+/// Counts a successful unlock that took `elapsed_ms` on the drive, if it could be timed
+pub fn record_unlock(st: &SystemTable<Boot>, serial: &str, elapsed_ms: Option<u64>) {
+    update(st, |stats| {
+        let drive = stats.drives.entry(serial.to_string()).or_default();
+        drive.unlocks += 1;
+        if let Some(elapsed_ms) = elapsed_ms {
+            drive.timed_unlocks += 1;
+            drive.unlock_ms += elapsed_ms;
+        }
+    });
+}
+
+pub fn record_failure(st: &SystemTable<Boot>, serial: &str, failure: Failure) {
+    update(st, |stats| {
+        let drive = stats.drives.entry(serial.to_string()).or_default();
+        match failure {
+            Failure::WrongPassword => drive.wrong_passwords += 1,
+            Failure::Error => drive.errors += 1,
+        }
+    });
+}
+
+/// Shows the counters, e.g. to spot a drive getting slower or a keyboard dropping keys
+pub fn view(st: &SystemTable<Boot>) -> Result {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return ui::popup(st, "Statistics", &["statistics are disabled, set `statistics = true` to collect them".to_string()]);
+    }
+    let stats = load(st);
+    let mut lines = vec![format!("{} boots", stats.boots)];
+    for (serial, drive) in &stats.drives {
+        let average = match drive.timed_unlocks {
+            0 => "unknown".to_string(),
+            timed => format!("{} ms", drive.unlock_ms / timed),
+        };
+        lines.push(format!(
+            "{serial}: {} unlocks, {average} on average, {} wrong passwords, {} errors",
+            drive.unlocks, drive.wrong_passwords, drive.errors,
+        ));
+    }
+    ui::popup(st, "Statistics", &lines)
+}
+
+fn update(st: &SystemTable<Boot>, f: impl FnOnce(&mut Stats)) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut stats = load(st);
+    f(&mut stats);
+    if let Err(e) = nvram::write(st, cstr16!("OpalGreeterStats"), store(&stats).as_bytes()) {
+        log::warn!("can't save statistics: {e}");
+    }
+}
+
+fn load(st: &SystemTable<Boot>) -> Stats {
+    let mut stats = Stats::default();
+    let Some(data) = nvram::read(st, cstr16!("OpalGreeterStats")) else { return stats };
+    for line in String::from_utf8_lossy(&data).lines() {
+        let mut fields = line.splitn(7, ' ');
+        match fields.next() {
+            Some("boots") => stats.boots = fields.next().and_then(|n| n.parse().ok()).unwrap_or(0),
+            Some("drive") => {
+                let numbers: Vec<u64> = fields.by_ref().take(5).filter_map(|n| n.parse().ok()).collect();
+                let (Ok([unlocks, timed_unlocks, unlock_ms, wrong_passwords, errors]), Some(serial)) = (<[u64; 5]>::try_from(numbers), fields.next()) else {
+                    log::debug!("ignoring malformed statistics line `{line}`");
+                    continue;
+                };
+                stats.drives.insert(serial.to_string(), Drive { unlocks, timed_unlocks, unlock_ms, wrong_passwords, errors });
+            }
+            _ => log::debug!("ignoring malformed statistics line `{line}`"),
+        }
+    }
+    stats
+}
+
+fn store(stats: &Stats) -> String {
+    let mut data = format!("boots {}\n", stats.boots);
+    for (serial, drive) in &stats.drives {
+        data.push_str(&format!(
+            "drive {} {} {} {} {} {serial}\n",
+            drive.unlocks, drive.timed_unlocks, drive.unlock_ms, drive.wrong_passwords, drive.errors,
+        ));
+    }
+    data
+}
@@ -0,0 +1,60 @@
+use alloc::string::{String, ToString};
+use core::cell::UnsafeCell;
+use uefi::table::{Boot, SystemTable};
+use crate::{console, integrity, smbios};
+
+/// What the status line above password prompts shows
+struct Summary {
+    model: Option<String>,
+    secure_boot: bool,
+    battery: bool,
+    /// self-encrypting drives found at startup, unknown until they were probed
+    drives: Option<usize>,
+}
+
+struct Global(UnsafeCell<Option<Summary>>);
+// UEFI boot services are single-threaded
+unsafe impl Sync for Global {}
+
+static SUMMARY: Global = Global(UnsafeCell::new(None));
+
+fn summary() -> &'static mut Option<Summary> {
+    unsafe { &mut *SUMMARY.0.get() }
+}
+
+/// Collects the machine's model, Secure Boot state and whether it has a battery; the line stays off unless enabled
+pub fn init(st: &SystemTable<Boot>, enabled: bool) {
+    if !enabled {
+        return;
+    }
+    let model = smbios::find(st, smbios::TYPE_SYSTEM).and_then(|system| {
+        match (system.string(0x04), system.string(0x05)) {
+            (Some(vendor), Some(product)) if product.starts_with(vendor) => Some(product.to_string()),
+            (Some(vendor), Some(product)) => Some(format!("{vendor} {product}")),
+            (vendor, product) => vendor.or(product).map(ToString::to_string),
+        }
+    });
+    let battery = smbios::find(st, smbios::TYPE_BATTERY).is_some();
+    *summary() = Some(Summary { model, secure_boot: integrity::secure_boot(st), battery, drives: None });
+}
+
+/// Records how many self-encrypting drives were found
+pub fn set_drives(count: usize) {
+    if let Some(summary) = summary() {
+        summary.drives = Some(count);
+    }
+}
+
+/// Writes the status line, so users see they're on the right machine before typing a password
+pub fn show(st: &SystemTable<Boot>) {
+    let Some(summary) = summary() else { return };
+    let mut line = summary.model.clone().unwrap_or_else(|| "unknown machine".to_string());
+    match summary.drives {
+        Some(1) => line.push_str(" | 1 self-encrypting drive"),
+        Some(n) => line.push_str(&format!(" | {n} self-encrypting drives")),
+        None => (),
+    }
+    line.push_str(if summary.secure_boot { " | Secure Boot on" } else { " | Secure Boot off" });
+    line.push_str(if summary.battery { " | battery" } else { " | no battery" });
+    console::write_str(st, &format!("{line}\r\n"));
+}
@@ -1,15 +1,105 @@
 use alloc::string::String;
 use alloc::vec::Vec;
-use core::fmt::Write;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, Ordering};
 use core::time::Duration;
 use uefi::proto::console::text::{Key, ScanCode};
 use uefi::table::{Boot, SystemTable};
 use uefi::{CStr16, Status};
 use uefi::table::runtime::ResetType;
-use crate::{Result, Context, util};
+use crate::{Result, Context, util, console, accessibility, keymap};
+use crate::config::{KeyName, PasswordEcho};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MenuAction {
+    /// Enter
+    Select,
+    /// one of the info hotkeys, Tab or F1 unless configured
+    Info,
+    /// the countdown ran out
+    Timeout,
+    /// the log viewer hotkey
+    ShowLog,
+    /// Escape
+    Cancel,
+}
+
+/// Automatic selection of the initial option, shown as a bar below the menu
+#[derive(Debug, Copy, Clone)]
+pub struct Countdown {
+    pub duration: Duration,
+    /// wait for Enter instead of counting down
+    pub paused: bool,
+}
+
+const COUNTDOWN_BAR_WIDTH: usize = 40;
+
+struct InfoKeys(UnsafeCell<Vec<KeyName>>);
+// UEFI boot services are single-threaded
+unsafe impl Sync for InfoKeys {}
+
+static INFO_KEYS: InfoKeys = InfoKeys(UnsafeCell::new(Vec::new()));
+
+/// Sets the keys that show the details of the selected menu entry
+pub fn init(info: &[KeyName]) {
+    unsafe { *INFO_KEYS.0.get() = info.to_vec() };
+}
+
+fn info_keys() -> &'static [KeyName] {
+    unsafe { &*INFO_KEYS.0.get() }
+}
 
 /// options is a Vec<(selectable, String)>; returns the chosen index within the options-vec
 pub fn choose(st: &SystemTable<Boot>, options: &Vec<(bool, String)>) -> Result<usize> {
+    let mut chosen = 0;
+    loop {
+        match menu(st, options, chosen, None, &mut || false)? {
+            (index, MenuAction::Select) => return Ok(index),
+            (index, MenuAction::ShowLog) => {
+                crate::logging::view(st)?;
+                chosen = index;
+            }
+            (index, _) => chosen = index,
+        }
+    }
+}
+
+/// Like `choose`, but Escape cancels
+pub fn choose_cancelable(st: &SystemTable<Boot>, options: &Vec<(bool, String)>) -> Result<Option<usize>> {
+    let mut chosen = 0;
+    loop {
+        match menu(st, options, chosen, None, &mut || false)? {
+            (index, MenuAction::Select) => return Ok(Some(index)),
+            (_, MenuAction::Cancel) => return Ok(None),
+            (index, MenuAction::ShowLog) => {
+                crate::logging::view(st)?;
+                chosen = index;
+            }
+            (index, _) => chosen = index,
+        }
+    }
+}
+
+/// A non-selectable section title for `choose` and `menu`
+pub fn header(title: &str) -> (bool, String) {
+    (false, format!("-- {title} --"))
+}
+
+/// A non-selectable empty line between sections
+pub fn separator() -> (bool, String) {
+    (false, String::new())
+}
+
+/// Like `choose`, but starts at `initial` and also returns on hotkeys with the action they map to.
+/// The countdown is cancelled by any key, which is then handled as usual.
+/// `idle` is called while waiting for keys until it returns that there's nothing left to do.
+pub fn menu(
+    st: &SystemTable<Boot>,
+    options: &Vec<(bool, String)>,
+    initial: usize,
+    countdown: Option<Countdown>,
+    idle: &mut dyn FnMut() -> bool,
+) -> Result<(usize, MenuAction)> {
     consume_old_keypresses(st)?;
 
     fn next_selectable<T>(current: usize, options: &[(bool, T)], rev: bool) -> usize {
@@ -32,67 +122,209 @@ pub fn choose(st: &SystemTable<Boot>, options: &Vec<(bool, String)>) -> Result<u
     }
 
     assert!(!options.is_empty());
-    let mut chosen = match options[0].0 {
-        true => 0,
-        false => next_selectable(0, options, false),
+    let mut chosen = match options.get(initial) {
+        Some((true, _)) => initial,
+        _ => next_selectable(initial.min(options.len() - 1), options, false),
     };
     // initialize menu output; only update the `>` later on
     let output: String = options.iter()
         .flat_map(|(_, option)| ["  ", option, "\r\n"])
         .collect();
 
-    let mut st = unsafe { st.unsafe_clone() };
-    st.stdout().write_str(&output).unwrap();
+    console::write_str(st, &output);
 
-    let next_row = st.stdout().cursor_position().1;
+    let next_row = console::cursor_position(st).1;
+    let first_row = next_row - options.len();
+    let mut previous = chosen;
+    let mut countdown = countdown;
+    let mut pending = None;
 
     loop {
         // reset old `>`
-        let row = st.stdout().cursor_position().1;
-        st.stdout().set_cursor_position(0, row)
+        console::set_cursor_position(st, 0, first_row + previous)
             .context("can't set cursor position to overwrite old `>`")?;
-        st.stdout().write_char(' ').unwrap();
+        console::write_str(st, " ");
         // write `>`
-        st.stdout().set_cursor_position(0, next_row - options.len() + chosen)
+        console::set_cursor_position(st, 0, first_row + chosen)
             .context("can't set cursor position to write `>`")?;
-        st.stdout().write_char('>').unwrap();
+        console::write_str(st, ">");
+        previous = chosen;
+        if accessibility::echo_keys() {
+            console::set_cursor_position(st, 0, next_row)?;
+            console::write_str(st, &format!("selected: {:<1$}", options[chosen].1, 60));
+        }
 
-        loop {
-            match key(&st)? {
+        if let Some(countdown) = countdown.take() {
+            let bar_row = next_row + accessibility::echo_keys() as usize;
+            match run_countdown(st, bar_row, countdown, idle)? {
+                Some(key) => pending = Some(key),
+                None => {
+                    console::set_cursor_position(st, 0, bar_row).context("can't reset cursor position")?;
+                    return Ok((chosen, MenuAction::Timeout))
+                }
+            }
+        }
+
+        let action = loop {
+            let key = match pending.take() {
+                Some(key) => key,
+                None => key_or_idle(st, idle)?,
+            };
+            match key {
                 Key::Special(ScanCode::DOWN) => {
                     chosen = next_selectable(chosen, options, false);
-                    break;
+                    break None;
                 },
                 Key::Special(ScanCode::UP) => {
                     chosen = next_selectable(chosen, options, true);
-                    break;
+                    break None;
                 },
                 // enter
-                Key::Printable(k) if [0xD, 0xA].contains(&u16::from(k)) => {
-                    // reset cursor position
-                    st.stdout().set_cursor_position(0, next_row).context("can't reset cursor position")?;
-                    return Ok(chosen)
-                },
+                Key::Printable(k) if [0xD, 0xA].contains(&u16::from(k)) => break Some(MenuAction::Select),
+                key if info_keys().iter().any(|&info| key_matches(&key, info)) => break Some(MenuAction::Info),
+                key if crate::logging::is_viewer_hotkey(&key) => break Some(MenuAction::ShowLog),
+                Key::Special(ScanCode::ESCAPE) => break Some(MenuAction::Cancel),
                 _ => (),
             }
+        };
+        if let Some(action) = action {
+            // reset cursor position
+            let end_row = next_row + accessibility::echo_keys() as usize;
+            console::set_cursor_position(st, 0, end_row).context("can't reset cursor position")?;
+            return Ok((chosen, action))
+        }
+    }
+}
+
+/// Draws a shrinking bar on `row` until the countdown ran out; returns the key that cancelled it instead
+fn run_countdown(st: &SystemTable<Boot>, row: usize, countdown: Countdown, idle: &mut dyn FnMut() -> bool) -> Result<Option<Key>> {
+    let total = countdown.duration.as_secs().max(1);
+    let mut remaining = total;
+    let blank = " ".repeat(COUNTDOWN_BAR_WIDTH + 40);
+    let clear = |st: &SystemTable<Boot>| -> Result {
+        console::set_cursor_position(st, 0, row)?;
+        console::write_str(st, &blank);
+        Ok(())
+    };
+    loop {
+        let filled = (COUNTDOWN_BAR_WIDTH as u64 * remaining / total) as usize;
+        let status = match countdown.paused {
+            true => String::from("paused, press Enter to boot"),
+            false => format!("booting in {remaining}s, press any key to stop"),
+        };
+        console::set_cursor_position(st, 0, row)?;
+        console::write_str(st, &format!("[{}{}] {status}", "#".repeat(filled), "-".repeat(COUNTDOWN_BAR_WIDTH - filled)));
+
+        if countdown.paused {
+            let key = key_or_idle(st, idle)?;
+            clear(st)?;
+            return Ok(Some(key));
+        }
+        let second = util::Deadline::after(Duration::from_secs(1));
+        while !second.expired() {
+            if let Some(key) = console::read_key(st)? {
+                clear(st)?;
+                return Ok(Some(key));
+            }
+            if !idle() {
+                util::Deadline::after(Duration::from_millis(50)).wait();
+            }
+        }
+        remaining -= 1;
+        if remaining == 0 {
+            clear(st)?;
+            return Ok(None);
         }
     }
 }
 
+/// Clears the screen, shows the title and lines and waits for any key
+pub fn popup(st: &SystemTable<Boot>, title: &str, lines: &[String]) -> Result {
+    console::clear(st).context("can't clear screen for popup")?;
+    let mut output = format!("{title}\r\n\r\n");
+    for line in lines {
+        output.push_str(line);
+        output.push_str("\r\n");
+    }
+    output.push_str("\r\nPress any key to continue");
+    console::write_str(st, &output);
+    consume_old_keypresses(st)?;
+    key(st)?;
+    console::clear(st).context("can't clear screen after popup")?;
+    Ok(())
+}
+
+/// What pressing Escape while reading a line does
+#[derive(Debug, Copy, Clone)]
+enum Escape {
+    Shutdown,
+    Cancel,
+}
+
+/// What is shown for each typed character
+#[derive(Debug, Copy, Clone)]
+enum Echo {
+    Plain,
+    Mask(char),
+    /// nothing at all, not even the length
+    Off,
+}
+
+static PASSWORD_ECHO_OFF: AtomicBool = AtomicBool::new(false);
+
+pub fn set_password_echo(echo: PasswordEcho) {
+    PASSWORD_ECHO_OFF.store(echo == PasswordEcho::None, Ordering::Relaxed);
+}
+
+/// Reads a password with the cursor hidden, restoring the cursor afterwards
 pub fn password(st: &SystemTable<Boot>) -> Result<String> {
     consume_old_keypresses(st)?;
-    read(st, Some('*'))
+    let echo = match PASSWORD_ECHO_OFF.load(Ordering::Relaxed) {
+        true => Echo::Off,
+        false => Echo::Mask('*'),
+    };
+    let cursor = console::cursor_visible(st);
+    console::enable_cursor(st, false);
+    let _suspend = crate::screenshot::Suspend::begin();
+    let res = read(st, echo, Escape::Shutdown).map(Option::unwrap);
+    console::enable_cursor(st, cursor);
+    res
 }
 pub fn line(st: &SystemTable<Boot>) -> Result<String> {
     consume_old_keypresses(st)?;
-    read(st, None)
+    read(st, Echo::Plain, Escape::Shutdown).map(Option::unwrap)
+}
+/// Like `line`, but Escape cancels instead of shutting down and empty input is allowed
+pub fn line_cancelable(st: &SystemTable<Boot>) -> Result<Option<String>> {
+    consume_old_keypresses(st)?;
+    read(st, Echo::Plain, Escape::Cancel)
+}
+
+/// Gate for destructive actions: shows the warning and requires typing `phrase`,
+/// e.g. the drive serial or `ERASE`, to proceed. Returns whether the user confirmed.
+pub fn confirm_destructive(st: &SystemTable<Boot>, action: &str, warning: &[String], phrase: &str) -> Result<bool> {
+    console::clear(st)?;
+    let mut output = format!("!!! {action} !!!\r\n\r\n");
+    for line in warning {
+        output.push_str(line);
+        output.push_str("\r\n");
+    }
+    output.push_str(&format!("\r\nType `{phrase}` to proceed, anything else or Escape cancels: "));
+    console::write_str(st, &output);
+    let confirmed = match line_cancelable(st)? {
+        Some(input) => input.trim() == phrase,
+        None => false,
+    };
+    if !confirmed {
+        log::info!("{action} cancelled");
+    }
+    Ok(confirmed)
 }
 fn consume_old_keypresses(st: &SystemTable<Boot>) -> Result<()> {
-    let mut st = unsafe { st.unsafe_clone() };
     // yield to let UEFI queue all stale key events
     util::sleep(Duration::from_millis(10));
     loop {
-        match st.stdin().read_key().context("can't read key to consume old stale events")? {
+        match console::read_key(st).context("can't read key to consume old stale events")? {
             Some(key) => log::trace!("consumed stale key: {key:?}"),
             None => break,
         }
@@ -100,56 +332,108 @@ fn consume_old_keypresses(st: &SystemTable<Boot>) -> Result<()> {
 
     Ok(())
 }
+/// Keys already queued when the greeter started, i.e. held down during startup
+pub fn held_keys(st: &SystemTable<Boot>) -> Result<Vec<Key>> {
+    // yield to let UEFI queue the key repeats
+    util::sleep(Duration::from_millis(10));
+    let mut keys = Vec::new();
+    while let Some(key) = console::read_key(st).context("can't read key held at startup")? {
+        log::trace!("key held at startup: {key:?}");
+        keys.push(key);
+    }
+    Ok(keys)
+}
+
+pub fn key_matches(key: &Key, name: KeyName) -> bool {
+    match (*key, name) {
+        (Key::Printable(k), KeyName::Char(c)) => char::from(k).eq_ignore_ascii_case(&c),
+        (Key::Printable(k), KeyName::Tab) => u16::from(k) == 0x9,
+        (Key::Special(code), KeyName::Function(n)) => {
+            let codes = [
+                ScanCode::FUNCTION_1, ScanCode::FUNCTION_2, ScanCode::FUNCTION_3, ScanCode::FUNCTION_4,
+                ScanCode::FUNCTION_5, ScanCode::FUNCTION_6, ScanCode::FUNCTION_7, ScanCode::FUNCTION_8,
+                ScanCode::FUNCTION_9, ScanCode::FUNCTION_10, ScanCode::FUNCTION_11, ScanCode::FUNCTION_12,
+            ];
+            codes.get(usize::from(n) - 1) == Some(&code)
+        }
+        (Key::Special(code), KeyName::Escape) => code == ScanCode::ESCAPE,
+        (Key::Special(code), KeyName::Insert) => code == ScanCode::INSERT,
+        (Key::Special(code), KeyName::Delete) => code == ScanCode::DELETE,
+        (Key::Special(code), KeyName::Home) => code == ScanCode::HOME,
+        (Key::Special(code), KeyName::End) => code == ScanCode::END,
+        (Key::Special(code), KeyName::PageUp) => code == ScanCode::PAGE_UP,
+        (Key::Special(code), KeyName::PageDown) => code == ScanCode::PAGE_DOWN,
+        _ => false,
+    }
+}
+
+/// Waits for a key; the screenshot hotkey is handled here, so it works in every prompt
 pub fn key(st: &SystemTable<Boot>) -> Result<Key> {
-    let mut st = unsafe { st.unsafe_clone() };
-    let mut wait_for_key = [unsafe { st.stdin().wait_for_key_event().unsafe_clone() }];
+    loop {
+        let key = console::wait_for_key(st)?;
+        if !crate::screenshot::is_hotkey(&key) {
+            return Ok(key);
+        }
+        crate::screenshot::capture(st);
+    }
+}
 
+/// Like `key`, but calls `idle` between polls for as long as it has work left
+fn key_or_idle(st: &SystemTable<Boot>, idle: &mut dyn FnMut() -> bool) -> Result<Key> {
     loop {
-        st.boot_services()
-            .wait_for_event(&mut wait_for_key)
-            .context("error waiting for key event")?;
-        if let Some(key) = st.stdin().read_key().context("error reading key")? {
-            return Ok(key)
+        match console::read_key(st)? {
+            Some(key) if crate::screenshot::is_hotkey(&key) => crate::screenshot::capture(st),
+            Some(key) => return Ok(key),
+            None => (),
+        }
+        if !idle() {
+            return key(st);
         }
     }
 }
-fn read(st: &SystemTable<Boot>, replacement_char: Option<char>) -> Result<String> {
+fn read(st: &SystemTable<Boot>, echo: Echo, escape: Escape) -> Result<Option<String>> {
     let mut data = String::with_capacity(32);
     loop {
         match key(st)? {
-            // cr / lf
-            Key::Printable(k) if [0xD, 0xA].contains(&u16::from(k)) && !data.is_empty() => {
+            // cr / lf; only cancelable prompts accept empty input
+            Key::Printable(k) if [0xD, 0xA].contains(&u16::from(k)) && (!data.is_empty() || matches!(escape, Escape::Cancel)) => {
                 write_char(st, 0x0D)?;
                 write_char(st, 0x0A)?;
-                break Ok(data);
+                break Ok(Some(data));
             }
             // backspace
             Key::Printable(k) if u16::from(k) == 0x8 => {
-                if data.pop().is_some() {
+                if data.pop().is_some() && !matches!(echo, Echo::Off) {
                     write_char(st, 0x08)?;
                 }
             }
             Key::Printable(k) => {
-                match replacement_char {
-                    Some(c) => write_char(st, c as u16)?,
-                    None => write_char(st, u16::from(k))?,
+                let c = keymap::translate(k.into());
+                match echo {
+                    Echo::Plain => write_char(st, c as u16)?,
+                    Echo::Mask(mask) => write_char(st, mask as u16)?,
+                    Echo::Off => (),
                 }
-                data.push(k.into());
-            }
-            Key::Special(ScanCode::ESCAPE) => {
-                st.runtime_services()
-                    .reset(ResetType::SHUTDOWN, Status::SUCCESS, None)
+                data.push(c);
             }
+            Key::Special(ScanCode::ESCAPE) => match escape {
+                Escape::Shutdown => st
+                    .runtime_services()
+                    .reset(ResetType::SHUTDOWN, Status::SUCCESS, None),
+                Escape::Cancel => {
+                    write_char(st, 0x0D)?;
+                    write_char(st, 0x0A)?;
+                    break Ok(None);
+                }
+            },
             _ => {}
         }
     }
 }
 
 fn write_char(st: &SystemTable<Boot>, ch: u16) -> Result {
-    let mut st = unsafe { st.unsafe_clone() };
     let str = &[ch, 0];
-    st.stdout()
-        .output_string(unsafe { CStr16::from_u16_with_nul_unchecked(str) })
-        .context("")
+    let str = unsafe { CStr16::from_u16_with_nul_unchecked(str) };
+    console::output_string(st, str).context("")
 }
 
@@ -0,0 +1,156 @@
+use alloc::string::{String, ToString};
+use core::sync::atomic::{AtomicBool, Ordering};
+use sha2::{Digest, Sha256};
+use uefi::proto::console::text::Key;
+use uefi::proto::device_path::DevicePath;
+use uefi::proto::device_path::text::{AllowShortcuts, DisplayOnly};
+use uefi::proto::loaded_image::LoadedImage;
+use uefi::table::boot::LoadImageSource;
+use uefi::table::{Boot, SystemTable};
+use uefi::{cstr16, CStr16, CString16, Handle};
+use crate::config::{Config, KeyName};
+use crate::{integrity, pe, ui, util, Context, Error, Result};
+
+/// where a new greeter binary is dropped for installation
+const UPDATE: &CStr16 = cstr16!("\\opal-greeter\\update.efi");
+/// the replaced binary, next to the installed one
+const PREVIOUS: &CStr16 = cstr16!("greeter-prev.efi");
+
+static ROLLBACK: AtomicBool = AtomicBool::new(false);
+
+/// Offers booting the previous greeter version in the menu if the rollback hotkey is held at startup
+pub fn init(hotkey: Option<KeyName>, held_keys: &[Key]) {
+    let held = hotkey.map_or(false, |hotkey| held_keys.iter().any(|key| ui::key_matches(key, hotkey)));
+    ROLLBACK.store(held, Ordering::Relaxed);
+}
+
+/// whether the hidden menu entry for the previous greeter version is shown;
+/// a downgrade may bring back fixed bugs, so it needs the same physical presence as the setup menu
+pub fn rollback_offered() -> bool {
+    ROLLBACK.load(Ordering::Relaxed) && crate::admin::present()
+}
+
+/// Chainloads the greeter version replaced by the last update, so a broken release never locks anyone out
+pub fn rollback(st: &SystemTable<Boot>, image_handle: Handle) -> Result {
+    assert!(rollback_offered(), "rollback without physical presence");
+    let installed = installed_path(st)?;
+    let (directory, _) = installed.rsplit_once('\\').unwrap_or(("", &installed));
+    let previous = CString16::try_from(&*format!("{directory}\\{PREVIOUS}")).context("greeter path is not valid UTF-16")?;
+    let volume = crate::config::image_volume(image_handle, st)?;
+    if crate::low_memory::active() {
+        // the firmware reads it itself, there's no need to hold a copy
+        if util::read_partial_file_to_vec(st, volume, &previous, &mut vec![0; 2]).is_err() {
+            return ui::popup(st, "Previous greeter version", &[format!("{previous} doesn't exist, no update was installed yet")]);
+        }
+        log::warn!("rolling back to {previous}");
+        let file_path = util::file_device_path(st, volume, &previous)?;
+        let loaded_image_handle = st.boot_services()
+            .load_image(image_handle, LoadImageSource::FromDevicePath { device_path: &file_path, from_boot_manager: false })
+            .context("can't load the previous greeter version")?;
+        return crate::start_loaded_image(st, loaded_image_handle, &previous.to_string());
+    }
+    let Ok(image) = util::read_full_file(st, volume, &previous) else {
+        return ui::popup(st, "Previous greeter version", &[format!("{previous} doesn't exist, no update was installed yet")]);
+    };
+    log::warn!("rolling back to {previous}");
+    let device_path = st.boot_services().open_protocol_exclusive::<DevicePath>(volume)
+        .context("can't get DevicePath of the greeter's volume")?;
+    let loaded_image_handle = st.boot_services()
+        .load_image(image_handle, LoadImageSource::FromBuffer { file_path: Some(&*device_path), buffer: &image })
+        .context("can't load the previous greeter version")?;
+    drop(device_path);
+    crate::start_loaded_image(st, loaded_image_handle, &previous.to_string())
+}
+
+/// Installs `\opal-greeter\update.efi` over the running greeter.
+///
+/// The update must match `update.sha256`, or, without one, pass the firmware's Secure Boot verification.
+/// It's written next to the installed binary first and then renamed into place;
+/// the previous binary is kept as `greeter-prev.efi` in the same directory for rolling back.
+pub fn install(st: &SystemTable<Boot>, config: &Config) -> Result {
+    let volume = crate::config::image_volume(st.boot_services().image_handle(), st)?;
+    let Ok(update) = util::read_full_file(st, volume, UPDATE) else {
+        return ui::popup(st, "Greeter update", &[format!("no update found at {UPDATE}")]);
+    };
+    if let Err(problem) = pe::check(&update) {
+        return Err(Error::new_without_source(format!("{UPDATE} {problem}")));
+    }
+    verify(st, config, &update)?;
+
+    let installed = installed_path(st)?;
+    let (directory, name) = installed.rsplit_once('\\').unwrap_or(("", &installed));
+    let warning = [
+        format!("{installed} will be replaced by {UPDATE} ({}).", hex(&Sha256::digest(&update))),
+        format!("The current greeter is kept as {directory}\\{PREVIOUS}."),
+    ];
+    if !ui::confirm_destructive(st, "Install greeter update", &warning, "UPDATE")? {
+        return Ok(());
+    }
+
+    let path = |suffix: &str| CString16::try_from(&*format!("{installed}{suffix}")).context("greeter path is not valid UTF-16");
+    let name16 = |suffix: &str| CString16::try_from(&*format!("{name}{suffix}")).context("greeter name is not valid UTF-16");
+    let (installed16, new) = (path("")?, path(".new")?);
+    let previous = CString16::try_from(&*format!("{directory}\\{PREVIOUS}")).context("greeter path is not valid UTF-16")?;
+
+    // write and read back the new binary before touching the installed one
+    util::write_full_file(st, volume, &new, &update)?;
+    if util::read_full_file(st, volume, &new)? != update {
+        util::delete_file(st, volume, &new)?;
+        return Err(Error::new_without_source(format!("{installed}.new doesn't read back as written")));
+    }
+    util::delete_file(st, volume, &previous)?;
+    util::rename_file(st, volume, &installed16, PREVIOUS)?;
+    if let Err(e) = util::rename_file(st, volume, &new, &name16("")?) {
+        // put the previous binary back so the machine still boots
+        util::rename_file(st, volume, &previous, &name16("")?)?;
+        return Err(e);
+    }
+    util::delete_file(st, volume, UPDATE)?;
+
+    log::info!("installed greeter update {} to {installed}, previous version kept as {previous}", hex(&Sha256::digest(&update)));
+    ui::popup(st, "Greeter update", &[
+        format!("{installed} was updated, it takes effect on the next boot"),
+        format!("the previous version is kept as {previous}"),
+    ])
+}
+
+fn verify(st: &SystemTable<Boot>, config: &Config, update: &[u8]) -> Result {
+    if let Some(expected) = &config.update.sha256 {
+        return crate::verify_sha256(update, expected)
+            .map_err(|problem| Error::new_without_source(format!("{UPDATE}: {problem}")));
+    }
+    if !integrity::secure_boot(st) {
+        return Err(Error::new_without_source("neither `update.sha256` is configured nor Secure Boot on, refusing an unverified update"));
+    }
+    // loading verifies the signature against db and dbx without running anything
+    let bt = st.boot_services();
+    let handle = bt.load_image(bt.image_handle(), LoadImageSource::FromBuffer { buffer: update, file_path: None })
+        .map_err(|e| Error::new_from_uefi(e, "Secure Boot rejected the update"))?;
+    if let Err(e) = bt.unload_image(handle) {
+        log::warn!("can't unload the verified update image: {e:?}");
+    }
+    Ok(())
+}
+
+/// the running greeter's path on its volume
+fn installed_path(st: &SystemTable<Boot>) -> Result<String> {
+    let bt = st.boot_services();
+    let loaded_image = bt.open_protocol_exclusive::<LoadedImage>(bt.image_handle())
+        .context("cannot get LoadedImage")?;
+    let path = loaded_image.file_path()
+        .ok_or_else(|| Error::new_without_source("the greeter wasn't loaded from a file"))?
+        .to_string(bt, DisplayOnly(false), AllowShortcuts(false))
+        .map_err(|e| Error::new_without_source(format!("dtos: {e}")))?
+        .ok_or_else(|| Error::new_without_source("can't convert the greeter's path to text"))?
+        .to_string();
+    // only the file path node, some firmware prefixes the device
+    let path = path.rsplit_once(')').map_or(&*path, |(_, file)| file).trim_start_matches('/');
+    if !path.starts_with('\\') {
+        return Err(Error::new_without_source(format!("unexpected greeter path `{path}`")));
+    }
+    Ok(path.to_string())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
@@ -0,0 +1,52 @@
+use alloc::string::String;
+use opal::{Authority, OpalDrive};
+use uefi::table::{Boot, SystemTable};
+use crate::config::{Config, Partition};
+use crate::low_level::nvme_device::RestartableNvmeDevice;
+use crate::{ui, Error, Result};
+
+/// Asks who is booting and authenticates them as their own User authority on their drive,
+/// which decides the boot entries shown afterwards. Does nothing without users or once someone logged in.
+pub fn login(st: &SystemTable<Boot>, config: &Config) -> Result {
+    if config.users.is_empty() || config.current_user.borrow().is_some() {
+        return Ok(());
+    }
+    let mut options = vec![ui::header("Who is booting?")];
+    options.extend(config.users.iter().map(|user| (true, user.name.clone())));
+    // skip the header
+    let index = ui::choose(st, &options)? - 1;
+    let user = &config.users[index];
+    let partition = config.partitions.get(&user.partition)
+        .ok_or_else(|| Error::new_without_source(format!("unknown partition `{}` of user `{}`", user.partition, user.name)))?;
+    let keyslot = config.keyslots.get(&user.keyslot)
+        .ok_or_else(|| Error::new_without_source(format!("unknown keyslot `{}` of user `{}`", user.keyslot, user.name)))?;
+    let authority = Authority::user(user.authority);
+
+    for (blockio_handle, _, _) in crate::block_devices(st)? {
+        if let Some(nvme) = crate::try_get_nvme_device(st, blockio_handle)? {
+            if !is_drive_of(nvme.serial_num(), partition) {
+                continue;
+            }
+            let mut drive = OpalDrive::new(RestartableNvmeDevice::new(&nvme, st, blockio_handle))
+                .map_err(|e| Error::new(e, "open opal"))?;
+            drive.force(config.features.forced());
+            crate::authenticate(st, &mut drive, config, keyslot, authority)?;
+        } else if let Some(mut ata) = crate::try_get_ata_device(st, blockio_handle)? {
+            if !is_drive_of(ata.serial(), partition) {
+                continue;
+            }
+            ata.force(config.features.forced());
+            crate::authenticate(st, &mut ata, config, keyslot, authority)?;
+        } else {
+            continue;
+        }
+        log::info!("logged in as `{}` ({authority})", user.name);
+        *config.current_user.borrow_mut() = Some(index);
+        return Ok(());
+    }
+    Err(Error::new_without_source(format!("no OPAL drive found for partition `{}` of user `{}`", partition.name, user.name)))
+}
+
+fn is_drive_of(serial: &[u8], partition: &Partition) -> bool {
+    String::from_utf8_lossy(serial).trim() == partition.uuid
+}
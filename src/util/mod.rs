@@ -0,0 +1,393 @@
+use alloc::{alloc::alloc, boxed::Box};
+use alloc::vec::Vec;
+use core::{alloc::Layout, mem::MaybeUninit, time::Duration};
+use core::sync::atomic::{AtomicBool, Ordering};
+use uefi::{cstr16, CStr16, Event, Handle, Status, guid};
+use uefi::proto::media::file::{File, FileAttribute, FileInfo, FileMode, FileType};
+use uefi::proto::device_path::DevicePath;
+use uefi::proto::device_path::build::{self, DevicePathBuilder};
+use uefi::proto::media::fs::SimpleFileSystem;
+use uefi::table::{Boot, SystemTable};
+use uefi::table::boot::{EventType, TimerTrigger, Tpl};
+use uefi::table::runtime::VariableVendor;
+use crate::{Error, Result, Context};
+
+pub mod nvram;
+
+/// vendor GUID of all UEFI variables owned by the greeter
+pub const VENDOR: VariableVendor = VariableVendor(guid!("5e4c3a7d-2f1b-4c8e-9a6d-0b7f3e21c9a4"));
+
+pub fn sleep(duration: Duration) {
+    // duration.as_nanos() works with u128 which is unsupported on some devices lol
+    // let nanos = (duration.as_nanos() / 100) as u64;
+    let nanos = duration.as_secs() * 1_000_000_000 + duration.subsec_nanos() as u64;
+
+    // untie the sleep function from the system table
+    // so that it can be used in the opal lib
+    let bt = unsafe { uefi_services::system_table().as_ref() }.boot_services();
+
+    let res = unsafe { bt.create_event(EventType::TIMER, Tpl::APPLICATION, None, None) };
+    let event = match res {
+        Ok(event) => event,
+        Err(err) if err.status() == Status::INVALID_PARAMETER => {
+            log::error!("Mainboard doesn't support Timer-Event -> falling back to stall");
+            bt.stall((nanos / 1000) as usize);
+            return;
+        }
+        Err(e) => Err(e).unwrap(),
+    };
+    bt.set_timer(&event, TimerTrigger::Periodic(nanos / 100)).unwrap();
+
+    bt.wait_for_event(&mut [event]).unwrap();
+}
+
+/// A point in time, backed by a one-shot timer event
+pub struct Deadline(Option<Event>);
+
+impl Deadline {
+    pub fn after(duration: Duration) -> Deadline {
+        let nanos = duration.as_secs() * 1_000_000_000 + duration.subsec_nanos() as u64;
+        let bt = unsafe { uefi_services::system_table().as_ref() }.boot_services();
+        let event = match unsafe { bt.create_event(EventType::TIMER, Tpl::APPLICATION, None, None) } {
+            Ok(event) => event,
+            Err(e) => {
+                log::warn!("can't create timer event for deadline: {e:?}");
+                return Deadline(None);
+            }
+        };
+        match bt.set_timer(&event, TimerTrigger::Relative(nanos / 100)) {
+            Ok(()) => Deadline(Some(event)),
+            Err(e) => {
+                log::warn!("can't set timer for deadline: {e:?}");
+                let _ = bt.close_event(event);
+                Deadline(None)
+            }
+        }
+    }
+
+    /// Deadlines without a timer are always expired
+    pub fn expired(&self) -> bool {
+        let bt = unsafe { uefi_services::system_table().as_ref() }.boot_services();
+        match &self.0 {
+            Some(event) => bt.check_event(unsafe { event.unsafe_clone() }).unwrap_or(true),
+            None => true,
+        }
+    }
+
+    /// Waits until the deadline is reached
+    pub fn wait(mut self) {
+        let bt = unsafe { uefi_services::system_table().as_ref() }.boot_services();
+        if let Some(event) = self.0.take() {
+            let mut events = [event];
+            let _ = bt.wait_for_event(&mut events);
+            let [event] = events;
+            let _ = bt.close_event(event);
+        }
+    }
+}
+
+impl Drop for Deadline {
+    fn drop(&mut self) {
+        if let Some(event) = self.0.take() {
+            let bt = unsafe { uefi_services::system_table().as_ref() }.boot_services();
+            let _ = bt.close_event(event);
+        }
+    }
+}
+
+pub unsafe fn alloc_init_aligned(len: usize, align: usize) -> uefi::Result<Box<[u8]>> {
+    let ptr = alloc_checked(Layout::from_size_align(len, align).unwrap())? as _;
+    core::ptr::write_bytes(ptr, 0, len);
+    Ok(Box::from_raw(core::slice::from_raw_parts_mut(ptr, len)))
+}
+
+pub unsafe fn alloc_aligned_t<T>(t: T, align: usize) -> uefi::Result<Box<T>> {
+    let ptr = alloc_checked(Layout::from_size_align(core::mem::size_of::<T>(), align).unwrap())? as _;
+    core::ptr::write(ptr, t);
+    Ok(Box::from_raw(ptr))
+}
+
+pub unsafe fn alloc_uninit_aligned(len: usize, align: usize) -> uefi::Result<Box<[MaybeUninit<u8>]>> {
+    let ptr = alloc_checked(Layout::from_size_align(len, align).unwrap())? as _;
+    Ok(Box::from_raw(core::slice::from_raw_parts_mut(ptr, len)))
+}
+
+/// `alloc` that doesn't hand out null when the pool is exhausted, which would become a DMA to address 0.
+/// Fails the transfer with OUT_OF_RESOURCES instead, so the caller can report the drive and move on
+unsafe fn alloc_checked(layout: Layout) -> uefi::Result<*mut u8> {
+    let ptr = alloc(layout);
+    if ptr.is_null() {
+        log::warn!("out of memory for a {} byte transfer buffer", layout.size());
+        return Err(Status::OUT_OF_RESOURCES.into());
+    }
+    Ok(ptr)
+}
+
+pub fn read_full_file(
+    st: &SystemTable<Boot>,
+    device: Handle,
+    file: &CStr16,
+) -> Result<Vec<u8>> {
+    let mut vec = Vec::new();
+    read_full_file_to_vec(st, device, file, &mut vec)?;
+    Ok(vec)
+}
+/// resizes the vector to fit the whole file
+pub fn read_full_file_to_vec(
+    st: &SystemTable<Boot>,
+    device: Handle,
+    file: &CStr16,
+    vec: &mut Vec<u8>,
+) -> Result<()> {
+    read_to_vec(st, device, file, vec, true).map(|_| ())
+}
+
+/// reads into the existing vector-length; does not resize the vector
+pub fn read_partial_file_to_vec(
+    st: &SystemTable<Boot>,
+    device: Handle,
+    file: &CStr16,
+    vec: &mut Vec<u8>,
+) -> Result<usize> {
+    read_to_vec(st, device, file, vec, false)
+}
+
+static WRITABLE: AtomicBool = AtomicBool::new(false);
+
+/// Permission to write to the greeter's volumes while held.
+///
+/// Files are only opened read-only during routine boots, so no stray code path can corrupt the ESP;
+/// setup screens and the log and crash dump writers take one explicitly.
+pub struct WriteAccess(bool);
+
+impl WriteAccess {
+    pub fn grant() -> WriteAccess {
+        WriteAccess(WRITABLE.swap(true, Ordering::Relaxed))
+    }
+}
+
+impl Drop for WriteAccess {
+    fn drop(&mut self) {
+        WRITABLE.store(self.0, Ordering::Relaxed);
+    }
+}
+
+/// Fails unless a `WriteAccess` is held; call before opening anything for writing
+pub fn ensure_writable(file: &CStr16) -> Result {
+    match WRITABLE.load(Ordering::Relaxed) {
+        true => Ok(()),
+        false => Err(Error::new_without_source(format!("refusing to write {file} outside of setup"))),
+    }
+}
+
+/// Creates or replaces the file with `data`
+pub fn write_full_file(
+    st: &SystemTable<Boot>,
+    device: Handle,
+    file: &CStr16,
+    data: &[u8],
+) -> Result<()> {
+    ensure_writable(file)?;
+    let mut sfs = st
+        .boot_services()
+        .open_protocol_exclusive::<SimpleFileSystem>(device)
+        .context(format!("can't get SimpleFileSystem from device to write file {}", file))?;
+    let mut root = sfs.open_volume().context(format!("can't open SimpleFileSystem to write file {}", file))?;
+
+    // truncate by recreating, FAT has no way to shrink a file through SimpleFileSystem otherwise
+    if let Ok(existing) = root.open(file, FileMode::ReadWrite, FileAttribute::empty()) {
+        existing.delete().context(format!("can't delete old file {}", file))?;
+    }
+    let file_handle = root
+        .open(file, FileMode::CreateReadWrite, FileAttribute::empty())
+        .context(format!("can't create file {}", file))?;
+    let mut f = file_handle.into_regular_file()
+        .ok_or_else(|| Error::new_without_source(format!("file {} is a directory", file)))?;
+    f.write(data)
+        .map_err(|_| uefi::Error::new(uefi::Status::VOLUME_FULL, ()))
+        .context(format!("error writing to file {}", file))?;
+    f.flush().context(format!("error flushing file {}", file))?;
+    Ok(())
+}
+
+/// Appends `data` to `file`, creating it if it's missing; returns the file's new size
+pub fn append_file(
+    st: &SystemTable<Boot>,
+    device: Handle,
+    file: &CStr16,
+    data: &[u8],
+) -> Result<u64> {
+    ensure_writable(file)?;
+    let mut sfs = st
+        .boot_services()
+        .open_protocol_exclusive::<SimpleFileSystem>(device)
+        .context(format!("can't get SimpleFileSystem from device to write file {}", file))?;
+    let mut root = sfs.open_volume().context(format!("can't open SimpleFileSystem to write file {}", file))?;
+    let file_handle = root
+        .open(file, FileMode::CreateReadWrite, FileAttribute::empty())
+        .context(format!("can't open file {}", file))?;
+    let mut f = file_handle.into_regular_file()
+        .ok_or_else(|| Error::new_without_source(format!("file {} is a directory", file)))?;
+    f.set_position(uefi::proto::media::file::RegularFile::END_OF_FILE)
+        .context(format!("can't seek to the end of file {}", file))?;
+    f.write(data)
+        .map_err(|_| uefi::Error::new(uefi::Status::VOLUME_FULL, ()))
+        .context(format!("error writing to file {}", file))?;
+    f.flush().context(format!("error flushing file {}", file))?;
+    f.get_position().context(format!("can't get the size of file {}", file))
+}
+
+/// Creates `\\opal-greeter` on the volume, which holds crash dumps and state files, if it's missing
+pub fn create_greeter_dir(st: &SystemTable<Boot>, volume: Handle) -> Result {
+    ensure_writable(cstr16!("\\opal-greeter"))?;
+    let mut sfs = st.boot_services()
+        .open_protocol_exclusive::<SimpleFileSystem>(volume)
+        .context("can't get SimpleFileSystem of the greeter's volume")?;
+    let dir = sfs.open_volume().context("can't open the greeter's volume")?
+        .open(cstr16!("opal-greeter"), FileMode::CreateReadWrite, FileAttribute::DIRECTORY)
+        .context("can't create \\opal-greeter")?;
+    match dir.is_directory() {
+        Ok(true) => Ok(()),
+        _ => Err(Error::new_without_source("\\opal-greeter exists, but isn't a directory")),
+    }
+}
+
+/// Deletes the file if it exists; returns whether it did
+pub fn delete_file(st: &SystemTable<Boot>, device: Handle, file: &CStr16) -> Result<bool> {
+    ensure_writable(file)?;
+    let mut sfs = st
+        .boot_services()
+        .open_protocol_exclusive::<SimpleFileSystem>(device)
+        .context(format!("can't get SimpleFileSystem from device to delete file {}", file))?;
+    let mut root = sfs.open_volume().context(format!("can't open SimpleFileSystem to delete file {}", file))?;
+    match root.open(file, FileMode::ReadWrite, FileAttribute::empty()) {
+        Ok(existing) => {
+            existing.delete().context(format!("can't delete file {}", file))?;
+            Ok(true)
+        }
+        Err(_) => Ok(false),
+    }
+}
+
+/// Overwrites the file with random bytes, deletes it and checks it's gone, for files holding secrets.
+///
+/// Only the file's current clusters are overwritten; copies the filesystem or the medium's wear leveling left behind stay.
+pub fn wipe_file(st: &SystemTable<Boot>, device: Handle, file: &CStr16) -> Result<()> {
+    ensure_writable(file)?;
+    let mut sfs = st
+        .boot_services()
+        .open_protocol_exclusive::<SimpleFileSystem>(device)
+        .context(format!("can't get SimpleFileSystem from device to wipe file {}", file))?;
+    let mut root = sfs.open_volume().context(format!("can't open SimpleFileSystem to wipe file {}", file))?;
+    let mut f = root.open(file, FileMode::ReadWrite, FileAttribute::empty())
+        .context(format!("can't open file {}", file))?
+        .into_regular_file()
+        .ok_or_else(|| Error::new_without_source(format!("file {} is a directory", file)))?;
+    let size = f.get_boxed_info::<FileInfo>().context(format!("can't get file info for file {}", file))?.file_size();
+    let mut noise = vec![0; size as usize];
+    crate::rng::fill(&mut noise);
+    f.write(&noise)
+        .map_err(|_| uefi::Error::new(uefi::Status::DEVICE_ERROR, ()))
+        .context(format!("error overwriting file {}", file))?;
+    f.flush().context(format!("error flushing file {}", file))?;
+    f.delete().context(format!("can't delete file {}", file))?;
+    if root.open(file, FileMode::Read, FileAttribute::empty()).is_ok() {
+        return Err(Error::new_without_source(format!("file {} still exists after deleting it", file)));
+    }
+    Ok(())
+}
+
+/// Renames the file within its directory; `new_name` must not contain a path
+pub fn rename_file(st: &SystemTable<Boot>, device: Handle, file: &CStr16, new_name: &CStr16) -> Result<()> {
+    ensure_writable(file)?;
+    let mut sfs = st
+        .boot_services()
+        .open_protocol_exclusive::<SimpleFileSystem>(device)
+        .context(format!("can't get SimpleFileSystem from device to rename file {}", file))?;
+    let mut f = sfs.open_volume().context(format!("can't open SimpleFileSystem to rename file {}", file))?
+        .open(file, FileMode::ReadWrite, FileAttribute::empty())
+        .context(format!("can't open file {}", file))?;
+    let info = f.get_boxed_info::<FileInfo>().context(format!("can't get file info for file {}", file))?;
+    let mut buf = vec![0; 1024];
+    let buf = FileInfo::align_buf(&mut buf).unwrap();
+    let renamed = FileInfo::new(
+        buf,
+        info.file_size(),
+        info.physical_size(),
+        *info.create_time(),
+        *info.last_access_time(),
+        *info.modification_time(),
+        info.attribute(),
+        new_name,
+    ).map_err(|e| Error::new_without_source(format!("can't rename {file} to {new_name}: {e:?}")))?;
+    // FAT replaces the directory entry in place, so the file exists under exactly one of the names at any time
+    f.set_info(renamed).context(format!("can't rename {file} to {new_name}"))?;
+    Ok(())
+}
+
+/// The device path of a file on a volume, for letting the firmware load an image itself instead of handing it a buffer
+pub fn file_device_path(st: &SystemTable<Boot>, device: Handle, file: &CStr16) -> Result<Box<DevicePath>> {
+    let volume = st
+        .boot_services()
+        .open_protocol_exclusive::<DevicePath>(device)
+        .context(format!("can't get DevicePath of the volume with file {}", file))?;
+    let mut buf = Vec::new();
+    let mut builder = DevicePathBuilder::with_vec(&mut buf);
+    for node in volume.node_iter() {
+        builder = builder.push(&node)
+            .map_err(|e| Error::new_without_source(format!("can't build device path of file {file}: {e:?}")))?;
+    }
+    let path = builder.push(&build::media::FilePath { path_name: file })
+        .and_then(|builder| builder.finalize())
+        .map_err(|e| Error::new_without_source(format!("can't build device path of file {file}: {e:?}")))?;
+    Ok(path.to_boxed())
+}
+
+fn read_to_vec(
+    st: &SystemTable<Boot>,
+    device: Handle,
+    file: &CStr16,
+    vec: &mut Vec<u8>,
+    full: bool,
+) -> Result<usize> {
+    let mut sfs = st
+        .boot_services()
+        .open_protocol_exclusive::<SimpleFileSystem>(device)
+        .context(format!("can't get SimpleFileSystem from device to get file {}", file))?;
+
+    let file_handle = sfs
+        .open_volume().context(format!("can't open SimpleFileSystem to get file {}", file))?
+        .open(file, FileMode::Read, FileAttribute::empty())
+        .context(format!("can't open file {}", file))?;
+
+    let file_type = file_handle.into_type()
+        .context(format!("error converting file handle to file type for file {}", file))?;
+    if let FileType::Regular(mut f) = file_type {
+        if full {
+            let info = f.get_boxed_info::<FileInfo>()
+                .context(format!("can't get file info for file {}", file))?;
+            let size = info.file_size() as usize;
+            vec.resize(size, 0);
+        }
+
+        let read = f
+            .read(vec)
+            .map_err(|_| uefi::Error::new(uefi::Status::BUFFER_TOO_SMALL, ()))
+            .context(format!("error reading from file {}", file))?;
+        vec.truncate(read);
+        Ok(read)
+    } else {
+        Err(Error::new_without_source(format!("file {} note found", file)))
+    }
+}
+
+/// A byte count in the largest binary unit there's at least one of, e.g. `1.5 GiB`
+pub fn human_size(bytes: u64) -> alloc::string::String {
+    const UNITS: [&str; 5] = ["bytes", "KiB", "MiB", "GiB", "TiB"];
+    let unit = (1..UNITS.len()).rev().find(|&unit| bytes >> (10 * unit) > 0).unwrap_or(0);
+    if unit == 0 {
+        return format!("{bytes} bytes");
+    }
+    let tenths = (bytes as u128 * 10) >> (10 * unit);
+    format!("{}.{} {}", tenths / 10, tenths % 10, UNITS[unit])
+}
@@ -0,0 +1,138 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use uefi::{CStr16, CString16, Status};
+use uefi::table::{Boot, SystemTable};
+use uefi::table::runtime::VariableAttributes;
+use super::VENDOR;
+use crate::{Error, Result};
+
+/// Greeter state kept in variables the OS can't touch.
+///
+/// They lack runtime access, so they disappear from GetVariable/SetVariable once the OS called ExitBootServices.
+/// A variable of ours that has runtime access can't have been written by us and may have been planted
+/// from the OS to spoof state, so it's deleted instead of trusted.
+///
+/// The greeter doesn't write time-based authenticated variables itself: signing them needs a private key,
+/// and one stored next to the greeter would be readable by the same OS it's meant to keep out.
+/// Boot-service-only access gives the same protection against the OS without a key.
+/// Variables signed elsewhere can still be read with [`read_authenticated`].
+const ATTRIBUTES: VariableAttributes = VariableAttributes::NON_VOLATILE.union(VariableAttributes::BOOTSERVICE_ACCESS);
+
+/// largest variable we read; firmware commonly limits variables to 32 KiB
+const MAX_SIZE: usize = 64 * 1024;
+
+/// set once the firmware refused SetVariable, from then on our variables are files on the greeter's volume
+static FILES: AtomicBool = AtomicBool::new(false);
+
+/// The content of one of our variables, if it exists and was written by us,
+/// or else of the file it's kept in on firmware that blocks SetVariable
+pub fn read(st: &SystemTable<Boot>, name: &CStr16) -> Option<Vec<u8>> {
+    let Some((data, attributes)) = get(st, name) else { return read_file(st, name) };
+    if attributes.contains(VariableAttributes::RUNTIME_ACCESS) {
+        log::warn!("variable {name} is accessible from the OS and may have been planted, deleting it");
+        delete(st, name);
+        return None;
+    }
+    Some(data)
+}
+
+/// Creates or replaces one of our variables
+pub fn write(st: &SystemTable<Boot>, name: &CStr16, data: &[u8]) -> Result {
+    // attributes of an existing variable can't be changed by writing it
+    if get(st, name).map_or(false, |(_, attributes)| attributes != ATTRIBUTES) {
+        delete(st, name);
+    }
+    if FILES.load(Ordering::Relaxed) {
+        return write_file(st, name, data);
+    }
+    match st.runtime_services().set_variable(name, &VENDOR, ATTRIBUTES, data) {
+        Ok(()) => Ok(()),
+        Err(e) if blocked(e.status()) => {
+            log::warn!("the firmware refuses to write variable {name} ({:?}), keeping greeter state in files on its volume", e.status());
+            FILES.store(true, Ordering::Relaxed);
+            write_file(st, name, data)
+        }
+        Err(e) => Err(Error::new_from_uefi(e, format!("can't write variable {name}"))),
+    }
+}
+
+pub fn delete(st: &SystemTable<Boot>, name: &CStr16) {
+    match st.runtime_services().delete_variable(name, &VENDOR) {
+        Ok(()) => (),
+        Err(e) if e.status() == uefi::Status::NOT_FOUND => (),
+        Err(e) => log::warn!("can't delete variable {name}: {e:?}"),
+    }
+    if FILES.load(Ordering::Relaxed) {
+        if let Err(e) = file_path(name).and_then(|path| {
+            let volume = crate::config::image_volume(st.boot_services().image_handle(), st)?;
+            let _write_access = super::WriteAccess::grant();
+            super::delete_file(st, volume, &path)
+        }) {
+            log::warn!("can't delete the file of variable {name}: {e}");
+        }
+    }
+}
+
+/// statuses of firmware that doesn't let third parties write variables at boot time, rather than of a full or broken store
+fn blocked(status: Status) -> bool {
+    [Status::WRITE_PROTECTED, Status::SECURITY_VIOLATION, Status::ACCESS_DENIED, Status::UNSUPPORTED].contains(&status)
+}
+
+/// Where a variable is kept instead. Unlike the variables, the OS can change these files,
+/// so only state whose tampering does no harm beyond a wrong sort order or count, or a run in safe mode, goes through here
+fn file_path(name: &CStr16) -> Result<CString16> {
+    CString16::try_from(&*format!("\\opal-greeter\\{name}.var"))
+        .map_err(|_| Error::new_without_source(format!("variable name {name} is no valid file name")))
+}
+
+fn write_file(st: &SystemTable<Boot>, name: &CStr16, data: &[u8]) -> Result {
+    let path = file_path(name)?;
+    let volume = crate::config::image_volume(st.boot_services().image_handle(), st)?;
+    let _write_access = super::WriteAccess::grant();
+    super::create_greeter_dir(st, volume)?;
+    super::write_full_file(st, volume, &path, data)
+}
+
+/// A file written by an earlier boot that fell back to files; none exist on firmware that never blocked SetVariable
+fn read_file(st: &SystemTable<Boot>, name: &CStr16) -> Option<Vec<u8>> {
+    let path = file_path(name).ok()?;
+    let volume = crate::config::image_volume(st.boot_services().image_handle(), st).ok()?;
+    let data = super::read_full_file(st, volume, &path).ok()?;
+    // keep writing files, the variable would stay blocked anyway
+    FILES.store(true, Ordering::Relaxed);
+    Some(data)
+}
+
+/// The content of a variable provisioned with time-based authenticated writes.
+///
+/// Those are signed outside the greeter; the firmware only accepts updates signed by the same key
+/// with a newer timestamp, so neither the OS nor a replay of an older update can change them.
+/// The key is whichever signed the first write; who that was isn't checked.
+pub fn read_authenticated(st: &SystemTable<Boot>, name: &CStr16) -> Option<Vec<u8>> {
+    let (data, attributes) = get(st, name)?;
+    if !attributes.contains(VariableAttributes::TIME_BASED_AUTHENTICATED_WRITE_ACCESS) {
+        log::warn!("{name} is not an authenticated variable and could have been set by anyone, ignoring it");
+        return None;
+    }
+    Some(data)
+}
+
+fn get(st: &SystemTable<Boot>, name: &CStr16) -> Option<(Vec<u8>, VariableAttributes)> {
+    let rt = st.runtime_services();
+    let size = match rt.get_variable_size(name, &VENDOR) {
+        Ok(size) => size,
+        Err(e) if e.status() == uefi::Status::NOT_FOUND => return None,
+        Err(e) => {
+            log::warn!("can't get size of variable {name}: {e:?}");
+            return None;
+        }
+    };
+    let mut buf = vec![0; size.min(MAX_SIZE)];
+    match rt.get_variable(name, &VENDOR, &mut buf) {
+        Ok((data, attributes)) => Some((data.to_vec(), attributes)),
+        Err(e) => {
+            log::warn!("can't read variable {name}: {:?}", e.status());
+            None
+        }
+    }
+}
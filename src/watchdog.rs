@@ -0,0 +1,44 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use uefi::table::{Boot, SystemTable};
+
+const WATCHDOG_CODE: u64 = 0x31338;
+
+/// armed timeout in seconds, 0 if disarmed
+static TIMEOUT: AtomicU64 = AtomicU64::new(0);
+
+/// Keeps the watchdog armed while alive; disarms it on drop.
+///
+/// Firmware hanging inside a passthru command then leads to a reset
+/// instead of freezing at a blank screen forever.
+pub struct Armed<'a> {
+    st: &'a SystemTable<Boot>,
+}
+
+pub fn arm(st: &SystemTable<Boot>, timeout_secs: u64) -> Armed<'_> {
+    TIMEOUT.store(timeout_secs, Ordering::Relaxed);
+    pet();
+    log::trace!("armed {timeout_secs}s watchdog");
+    Armed { st }
+}
+
+/// Restarts the watchdog countdown if it's armed; called between secure protocol commands
+pub fn pet() {
+    let timeout = TIMEOUT.load(Ordering::Relaxed);
+    if timeout == 0 {
+        return;
+    }
+    let bt = unsafe { uefi_services::system_table().as_ref() }.boot_services();
+    if let Err(e) = bt.set_watchdog_timer(timeout as usize, WATCHDOG_CODE, None) {
+        log::warn!("can't pet watchdog: {e:?}");
+    }
+}
+
+impl Drop for Armed<'_> {
+    fn drop(&mut self) {
+        TIMEOUT.store(0, Ordering::Relaxed);
+        if let Err(e) = self.st.boot_services().set_watchdog_timer(0, WATCHDOG_CODE, None) {
+            log::error!("can't disarm watchdog: {e:?}");
+        }
+        log::trace!("disarmed watchdog");
+    }
+}
@@ -0,0 +1,110 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use opal::{Capabilities, OpalDrive, SecureProtocol, Ssc};
+use uefi::table::{Boot, SystemTable};
+use uefi::CString16;
+use crate::admin::{self, DriveAction};
+use crate::config::Config;
+use crate::error::ErrorSource;
+use crate::provision::{self, Setup};
+use crate::{console, power, ui, util, Context, Result};
+
+/// Guides through putting a fresh drive into a locked state: taking ownership, activating the Locking SP,
+/// setting the Admin1 and optionally a User1 password, enabling locking of the global range
+/// and optionally writing and enabling a shadow MBR
+pub fn run(st: &SystemTable<Boot>, config: &Config) -> Result {
+    console::clear(st)?;
+    console::write_str(st, "Setup wizard: select the drive to put into a locked state\r\n");
+    let Some(serial) = admin::select_drive(st, config)? else { return Ok(()) };
+    admin::for_each_drive(st, config, Some(&serial), &mut Wizard)
+}
+
+struct Wizard;
+
+impl DriveAction for Wizard {
+    fn run<P: SecureProtocol>(&mut self, st: &SystemTable<Boot>, kind: &str, drive: &mut OpalDrive<P>) -> Result
+    where opal::Error<P::Error>: Into<ErrorSource>
+    {
+        let serial = admin::serial_str(drive.serial());
+        if drive.ssc() == Ssc::Enterprise {
+            return ui::popup(st, "Setup wizard", &[format!("{kind} drive {serial} is an Enterprise SSC drive, which the wizard doesn't set up")]);
+        }
+        if drive.sid_blocked() {
+            return ui::popup(st, "Setup wizard", &[
+                format!("the firmware blocked authenticating as SID on {kind} drive {serial}, so nobody can take ownership"),
+                "disable Block SID in the firmware setup or power cycle the drive, then try again".to_string(),
+            ]);
+        }
+
+        console::clear(st)?;
+        let Some(admin_password) = admin::new_password(st, "Admin1")? else { return Ok(()) };
+        let user_password = match ask(st, "Add User1, who may only unlock the drive? [y/N] ")?.as_str() {
+            "y" | "Y" => match admin::new_password(st, "User1")? {
+                Some(password) => Some(password),
+                None => return Ok(()),
+            },
+            _ => None,
+        };
+        let image = match ask(st, "Pre-boot image on the greeter's volume to write to the shadow MBR (empty for none): ")?.as_str() {
+            "" => None,
+            _ if !drive.capabilities().contains(Capabilities::MBR_SHADOW) => {
+                return ui::popup(st, "Setup wizard", &[format!("{kind} drive {serial} has no shadow MBR, nothing was changed")]);
+            }
+            path => {
+                let path = CString16::try_from(&*path.replace('/', "\\")).context("pre-boot image path is not valid UTF-16")?;
+                let volume = crate::config::image_volume(st.boot_services().image_handle(), st)?;
+                Some(util::read_full_file(st, volume, &path)?)
+            }
+        };
+
+        let mut warning = vec![
+            format!("{kind} drive {serial} will be taken ownership of and set up:"),
+            "  the Locking SP is activated and Admin1 gets the new password".to_string(),
+        ];
+        if user_password.is_some() {
+            warning.push("  User1 is enabled and may unlock the global range".to_string());
+        }
+        warning.push("  locking of the global range is enabled, the drive locks on its next power cycle".to_string());
+        if let Some(image) = &image {
+            warning.push(format!("  the shadow MBR is enabled and gets the {} byte pre-boot image", image.len()));
+        }
+        warning.push("Data stays, but is inaccessible without the password from then on.".to_string());
+        if !ui::confirm_destructive(st, "Setup wizard", &warning, &serial)? {
+            return Ok(());
+        }
+        if image.is_some() && !power::check(st, "Setup wizard")? {
+            return Ok(());
+        }
+
+        let setup = Setup {
+            admin_password: admin_password.as_bytes(),
+            lock_global: true,
+            ranges: Vec::new(),
+            users: user_password.iter().map(|password| (1, password.as_bytes(), vec![0])).collect(),
+            image: image.as_deref(),
+        };
+        if !provision::set_up(st, &serial, drive, &setup)? {
+            log::error!("the MSID of drive {serial} was rejected, it already has an owner");
+            return ui::popup(st, "Setup wizard", &[
+                format!("{kind} drive {serial} already has an owner, nothing was changed"),
+                "the wizard only sets up drives in factory state, revert it with its PSID first".to_string(),
+            ]);
+        }
+
+        log::warn!("drive {serial} was set up by the wizard");
+        let mut summary: Vec<String> = vec![
+            format!("{kind} drive {serial} is set up and locks on its next power cycle"),
+            format!("add a partition with `uuid = \"{serial}\"` and a keyslot to config.toml to unlock it"),
+        ];
+        if user_password.is_some() {
+            summary.push("add a [[users]] entry with `authority = 1` to unlock as User1".to_string());
+        }
+        ui::popup(st, "Setup wizard", &summary)
+    }
+}
+
+/// Asks a question and returns the trimmed answer; Escape answers with an empty line
+fn ask(st: &SystemTable<Boot>, question: &str) -> Result<String> {
+    console::write_str(st, question);
+    Ok(ui::line_cancelable(st)?.unwrap_or_default().trim().to_string())
+}